@@ -0,0 +1,74 @@
+//! `--archive-dir`: keeping every chunk for good, under a name of its own.
+//!
+//! Each chunk is archived as it is done with, on the thread that uploaded it, whether or not
+//! the upload succeeded. A chunk that keeps its own name is hard-linked into the archive, so
+//! it stays there when `--keep` deletes it; a scratch file, which a later chunk will be
+//! recorded to, is moved there instead, so recording into it again cannot touch the archived
+//! copy. Where a link cannot be made, across filesystems for one, the chunk is copied, as a
+//! chunk that has to be left as it is always is. Names never overwrite one another: a name
+//! that is taken gets a `-2`, `-3`, ... suffix.
+
+use crate::naming::format_rfc3339_millis;
+use anyhow::Context;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Whether the chunk stays where it was recorded as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Link,
+    Move,
+    /// Neither linked nor moved, for a scratch file the archive still needs where it is.
+    Copy,
+}
+
+/// The archive name of chunk `seq` that started at `started_millis` (Unix milliseconds), of
+/// the device `label` if there are several: `2024-05-12T14-03-22.531Z_000123.wav`.
+pub fn archive_name(started_millis: u64, seq: u64, label: Option<&str>) -> String {
+    let timestamp = format_rfc3339_millis(started_millis);
+    match label {
+        Some(label) => format!("{timestamp}_{label}_{seq:06}.wav"),
+        None => format!("{timestamp}_{seq:06}.wav"),
+    }
+}
+
+/// Puts the chunk at `path` into `dir` as `name`, or the first free variant of it, and returns
+/// where it went.
+pub fn archive(dir: &Path, path: &Path, name: &str, mode: Mode) -> Result<PathBuf, anyhow::Error> {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    for n in 1.. {
+        let target = match n {
+            1 => dir.join(name),
+            n if extension.is_empty() => dir.join(format!("{stem}-{n}")),
+            n => dir.join(format!("{stem}-{n}.{extension}")),
+        };
+        match store(path, &target, mode != Mode::Copy) {
+            Ok(()) => {
+                if mode == Mode::Move {
+                    std::fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))?;
+                }
+                return Ok(target);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err).with_context(|| format!("failed to archive {} as {}", path.display(), target.display())),
+        }
+    }
+    unreachable!("every name is taken")
+}
+
+/// Links `path` to `target` if `link` allows, or copies it there, failing if `target` exists.
+fn store(path: &Path, target: &Path, link: bool) -> std::io::Result<()> {
+    let linked = if link { std::fs::hard_link(path, target) } else { Err(ErrorKind::Unsupported.into()) };
+    match linked {
+        Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+            let mut to = OpenOptions::new().write(true).create_new(true).open(target)?;
+            let copied = std::io::copy(&mut File::open(path)?, &mut to);
+            if copied.is_err() {
+                std::fs::remove_file(target).ok();
+            }
+            copied.map(drop)
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,159 @@
+//! The `transcribe-dir` subcommand: uploads a directory of existing recordings.
+
+use crate::json;
+use crate::upload;
+use anyhow::Context;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// What the transcription server expects; other files are rejected.
+pub const TARGET_SAMPLE_RATE: u32 = 16000;
+pub const TARGET_CHANNELS: u16 = 1;
+
+pub struct BatchOptions<'a> {
+    pub dir: &'a Path,
+    pub recursive: bool,
+    /// Write one JSON line per file here instead of a transcript next to each input.
+    pub combined: Option<&'a Path>,
+    pub jobs: usize,
+    pub endpoint: &'a upload::Endpoint,
+    /// Stop at the first file the server turns down for good, as `--fail-fast-on-4xx` asks.
+    pub fail_fast: bool,
+}
+
+/// Checks that a recording is in the format the server expects.
+pub fn check_format(path: &Path) -> Result<(), anyhow::Error> {
+    let reader = hound::WavReader::open(path)
+        .with_context(|| format!("{} is not a readable WAV file", path.display()))?;
+    let spec = reader.spec();
+    if spec.sample_rate != TARGET_SAMPLE_RATE || spec.channels != TARGET_CHANNELS {
+        anyhow::bail!(
+            "{} is {} Hz / {} ch, but the server expects {TARGET_SAMPLE_RATE} Hz mono",
+            path.display(),
+            spec.sample_rate,
+            spec.channels
+        );
+    }
+    Ok(())
+}
+
+/// Where the transcript of `wav` goes when no combined output is requested: `foo.wav` becomes
+/// `foo.txt`.
+pub fn transcript_path(wav: &Path) -> PathBuf {
+    wav.with_extension("txt")
+}
+
+/// Uploads every `.wav` file under the directory with at most `jobs` requests in flight,
+/// reporting progress as `n/total`. Succeeds only if every file was transcribed.
+pub fn transcribe_dir(options: &BatchOptions) -> Result<(), anyhow::Error> {
+    let files = upload::find_wavs(options.dir, options.recursive)?;
+    let total = files.len();
+    if total == 0 {
+        info!("no .wav files found in {}", options.dir.display());
+        return Ok(());
+    }
+    let combined = match options.combined {
+        Some(path) => Some(Mutex::new(
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?,
+        )),
+        None => None,
+    };
+
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    // With `fail_fast`, what the server turned down and how.
+    let rejection = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, total) {
+            scope.spawn(|| {
+                while let Some(path) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if rejection.lock().unwrap().is_some() {
+                        break;
+                    }
+                    let result = transcribe_one(path, options.endpoint, combined.as_ref());
+                    let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    match result {
+                        Ok(()) => info!("{n}/{total} {}", path.display()),
+                        Err(err) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            error!("{n}/{total} {}: {err:#}", path.display());
+                            if let Some(rejected) = err.downcast_ref::<Rejected>().filter(|_| options.fail_fast) {
+                                rejection.lock().unwrap().get_or_insert((rejected.status, path));
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    if let Some((status, path)) = rejection.into_inner().unwrap() {
+        anyhow::bail!("the server answered {status} to {} (--fail-fast-on-4xx)", path.display());
+    }
+    let failed = failed.into_inner();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} file(s) failed");
+    }
+    Ok(())
+}
+
+/// A file the server turned down for good: the status it answered, and the start of what it
+/// said.
+#[derive(Debug)]
+struct Rejected {
+    status: u16,
+    body: String,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the server answered {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+fn transcribe_one(
+    path: &Path,
+    endpoint: &upload::Endpoint,
+    combined: Option<&Mutex<File>>,
+) -> Result<(), anyhow::Error> {
+    check_format(path)?;
+    let response = endpoint.upload_file(path)?;
+    if upload::rejected(response.status) {
+        return Err(Rejected { status: response.status, body: upload::excerpt(&response.body) }.into());
+    }
+    let transcription = endpoint
+        .transcript(&response)
+        .map_err(|body| anyhow::anyhow!("no transcript in the answer ({}): {body}", response.status))?;
+    if let Some(err) = &transcription.malformed {
+        warn!(body = upload::excerpt(&response.body), "{}: the answer is not valid JSON ({err}); taking it as text", path.display());
+    }
+    let text = transcription.text.as_str();
+    match combined {
+        Some(out) => {
+            let language = endpoint.language().map(|language| format!(",\"language\":{}", json::string(&language)));
+            // The whole answer too, when there is more to it than the text.
+            let json = transcription.json.as_ref().map(|json| format!(",\"json\":{json}"));
+            let line = format!(
+                "{{\"file\":{}{},\"response\":{}{}}}\n",
+                json::string(&path.to_string_lossy()),
+                language.unwrap_or_default(),
+                json::string(text.trim_end()),
+                json.unwrap_or_default()
+            );
+            out.lock().unwrap().write_all(line.as_bytes())?;
+        }
+        None => {
+            let out = transcript_path(path);
+            std::fs::write(&out, text.as_bytes())
+                .with_context(|| format!("failed to write {}", out.display()))?;
+        }
+    }
+    Ok(())
+}
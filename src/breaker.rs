@@ -0,0 +1,135 @@
+//! The circuit breaker in front of the uploads: `--breaker-failures` and `--breaker-cooldown`.
+//!
+//! While the server is down, every chunk would otherwise go through all its retries and end
+//! in the same error. Once that many uploads in a row have failed for good, the circuit opens:
+//! for the cooldown no request is made, and the chunks are spooled straight away or, without
+//! `--spool-dir`, wait in the upload queue. Then one upload is let through as a probe. If it
+//! gets an answer the circuit closes and uploads go on as before; if not it opens for another
+//! cooldown. A chunk turned down counts as answered, as the server is there; one still
+//! answered 5xx or 429 once out of retries does not. Each change is logged once, with when it
+//! happened, and the state is in the stats line.
+
+use crate::naming::format_timestamp_millis;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Where the circuit is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Uploads go ahead.
+    Closed,
+    /// None do, until the cooldown is over.
+    Open,
+    /// The cooldown is over and one upload is let through to see if the server is back.
+    HalfOpen,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half-open",
+        })
+    }
+}
+
+struct Inner {
+    state: State,
+    /// Uploads in a row that failed.
+    failures: u32,
+    opened: Instant,
+    /// Whether the half-open circuit's probe has been let through.
+    probing: bool,
+}
+
+/// The breaker; its clones share the circuit.
+#[derive(Clone)]
+pub struct Breaker {
+    /// Failures in a row that open it.
+    threshold: u32,
+    cooldown: Duration,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Breaker {
+    /// A closed circuit that opens after `threshold` failures in a row, for `cooldown`.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        let inner = Inner { state: State::Closed, failures: 0, opened: Instant::now(), probing: false };
+        Breaker { threshold: threshold.max(1), cooldown, inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn state(&self) -> State {
+        self.lock().state
+    }
+
+    /// Whether an upload may go ahead now. Past the cooldown the first to ask is the probe,
+    /// and the others are held back until it has been answered.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.lock();
+        match inner.state {
+            State::Closed => true,
+            State::Open if inner.opened.elapsed() >= self.cooldown => {
+                inner.state = State::HalfOpen;
+                inner.probing = true;
+                info!(at = now(), "upload circuit half-open: trying one upload to see if the server is back");
+                true
+            }
+            State::Open => false,
+            State::HalfOpen if !inner.probing => {
+                inner.probing = true;
+                true
+            }
+            State::HalfOpen => false,
+        }
+    }
+
+    /// An upload got an answer from the server.
+    pub fn success(&self) {
+        let mut inner = self.lock();
+        if inner.state != State::Closed {
+            info!(at = now(), "upload circuit closed: the server answered again");
+        }
+        inner.state = State::Closed;
+        inner.failures = 0;
+        inner.probing = false;
+    }
+
+    /// Counts an upload that has had all its retries: an answer is a success, an error a failure.
+    pub fn record<T>(&self, outcome: &Result<T, anyhow::Error>) {
+        match outcome {
+            Ok(_) => self.success(),
+            Err(_) => self.failure(),
+        }
+    }
+
+    /// An upload failed for good, retries and all.
+    pub fn failure(&self) {
+        let mut inner = self.lock();
+        inner.failures = inner.failures.saturating_add(1);
+        let opens = match inner.state {
+            State::Closed => inner.failures >= self.threshold,
+            // The probe failed, or an upload that went ahead before the circuit opened.
+            State::HalfOpen => inner.probing,
+            State::Open => false,
+        };
+        if opens {
+            inner.state = State::Open;
+            inner.opened = Instant::now();
+            inner.probing = false;
+            let cooldown_secs = self.cooldown.as_secs_f64();
+            warn!(at = now(), failures = inner.failures, cooldown_secs, "upload circuit open: no uploads are tried until the cooldown is over");
+        }
+    }
+}
+
+/// The time, for a log line.
+fn now() -> String {
+    format_timestamp_millis(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64)
+}
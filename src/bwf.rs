@@ -0,0 +1,84 @@
+//! `--bwf`: Broadcast Wave metadata, so archived chunks can be laid back on a timeline.
+//!
+//! Once a chunk is finalized a `bext` chunk is appended to it, after the audio, and the RIFF
+//! size patched to take it in; nothing already written moves. Readers that look for the
+//! chunks they know, as hound and DAWs do, find it wherever it is. It holds who recorded the
+//! chunk and when it started, and its time reference: the frames from the start of the
+//! session to its first one.
+
+use crate::naming::format_rfc3339_millis;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Size of a version 1 `bext` chunk with no coding history.
+pub const BEXT_LEN: usize = 602;
+
+/// What goes into a chunk's `bext` chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bext {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    /// When the chunk's first frame was recorded, in Unix milliseconds.
+    pub origination_millis: u64,
+    /// Frames since the start of the session.
+    pub time_reference: u64,
+}
+
+impl Bext {
+    /// The chunk's body: fixed-width ASCII fields, then the little-endian time reference.
+    pub fn encode(&self) -> Vec<u8> {
+        // 2024-05-12T14-03-22.531Z: the date, then the time with its dashes as the colons
+        // the field wants.
+        let stamp = format_rfc3339_millis(self.origination_millis);
+        let (date, time) = (&stamp[..10], stamp[11..19].replace('-', ":"));
+        let mut body = Vec::with_capacity(BEXT_LEN);
+        for (text, width) in [
+            (self.description.as_str(), 256),
+            (self.originator.as_str(), 32),
+            (self.originator_reference.as_str(), 32),
+            (date, 10),
+            (time.as_str(), 8),
+        ] {
+            put_ascii(&mut body, text, width);
+        }
+        body.extend_from_slice(&self.time_reference.to_le_bytes());
+        // Version 1: no loudness fields.
+        body.extend_from_slice(&1u16.to_le_bytes());
+        // No UMID, and the reserved bytes.
+        body.resize(BEXT_LEN, 0);
+        body
+    }
+}
+
+/// Writes `text` as `width` bytes of ASCII, cut short or padded with NULs; anything else
+/// becomes `?`.
+fn put_ascii(out: &mut Vec<u8>, text: &str, width: usize) {
+    let start = out.len();
+    out.extend(text.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' }).take(width));
+    out.resize(start + width, 0);
+}
+
+/// Appends `bext` to the WAV file in `file`, which hound has finalized.
+pub fn append<F: Read + Write + Seek>(file: &mut F, bext: &Bext) -> Result<(), anyhow::Error> {
+    let mut riff = [0; 12];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut riff)?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        anyhow::bail!("not a RIFF/WAVE file");
+    }
+    let end = file.seek(SeekFrom::End(0))?;
+    // Chunks start on even offsets.
+    if end % 2 == 1 {
+        file.write_all(&[0])?;
+    }
+    let body = bext.encode();
+    file.write_all(b"bext")?;
+    file.write_all(&(body.len() as u32).to_le_bytes())?;
+    file.write_all(&body)?;
+    let size = file.stream_position()? - 8;
+    let size = u32::try_from(size).map_err(|_| anyhow::anyhow!("the file is too large for RIFF"))?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&size.to_le_bytes())?;
+    file.flush()?;
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! Config file support.
+//!
+//! The config file is a flat TOML document whose keys are the long option names (with either
+//! `_` or `-`), e.g. `sample_rate = 16000` or `device = "USB"`. Rather than keeping a second
+//! description of every setting, the file is turned into extra command-line arguments that are
+//! placed before the real ones (after the subcommand name for the subcommand's own options), so
+//! clap applies its usual defaults and validation and anything given on the command line wins.
+
+use anyhow::Context;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// A value from the config file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// The value as it would be written on the command line.
+    fn to_arg(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Boolean(b) => b.to_string(),
+            Value::Array(_) => unreachable!("arrays are flattened before conversion"),
+        }
+    }
+}
+
+/// `~/.config/rs-audio-tokenizer/config.toml`, honouring `XDG_CONFIG_HOME`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("rs-audio-tokenizer").join("config.toml"))
+}
+
+/// Finds the value of `--config` in the raw arguments (or `AUDIOTOK_CONFIG`), before clap has
+/// parsed them.
+pub fn path_from_args(args: &[OsString]) -> Option<PathBuf> {
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return iter.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("AUDIOTOK_CONFIG").map(PathBuf::from)
+}
+
+/// Parses the flat subset of TOML used by the config file: `key = value` lines with strings,
+/// integers, floats, booleans and arrays of those, plus `#` comments.
+pub fn parse(text: &str) -> Result<Vec<(String, Value)>, anyhow::Error> {
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            anyhow::bail!("line {number}: tables are not supported, keys must be at the top level");
+        }
+        let (key, rest) = line
+            .split_once('=')
+            .with_context(|| format!("line {number}: expected `key = value`"))?;
+        let key = key.trim().trim_matches('"');
+        if key.is_empty() {
+            anyhow::bail!("line {number}: empty key");
+        }
+        let mut parser = ValueParser { rest: rest.trim_start() };
+        let value = parser
+            .value()
+            .with_context(|| format!("line {number}: invalid value for `{key}`"))?;
+        let trailing = parser.rest.trim_start();
+        if !trailing.is_empty() && !trailing.starts_with('#') {
+            anyhow::bail!("line {number}: unexpected `{trailing}` after value");
+        }
+        entries.push((key.to_owned(), value));
+    }
+    Ok(entries)
+}
+
+struct ValueParser<'a> {
+    rest: &'a str,
+}
+
+impl ValueParser<'_> {
+    fn value(&mut self) -> Result<Value, anyhow::Error> {
+        if let Some(rest) = self.rest.strip_prefix('"') {
+            self.rest = rest;
+            return self.basic_string().map(Value::String);
+        }
+        if let Some(rest) = self.rest.strip_prefix('\'') {
+            let end = rest.find('\'').context("unterminated string")?;
+            self.rest = &rest[end + 1..];
+            return Ok(Value::String(rest[..end].to_owned()));
+        }
+        if let Some(rest) = self.rest.strip_prefix('[') {
+            self.rest = rest;
+            return self.array();
+        }
+        let end = self
+            .rest
+            .find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace())
+            .unwrap_or(self.rest.len());
+        let token = &self.rest[..end];
+        self.rest = &self.rest[end..];
+        match token {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => {
+                let digits = token.replace('_', "");
+                if let Ok(i) = digits.parse::<i64>() {
+                    Ok(Value::Integer(i))
+                } else if let Ok(f) = digits.parse::<f64>() {
+                    Ok(Value::Float(f))
+                } else {
+                    anyhow::bail!("`{token}` is not a string, number or boolean")
+                }
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, anyhow::Error> {
+        let mut out = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some(other) => anyhow::bail!("unsupported escape `\\{other}`"),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        anyhow::bail!("unterminated string")
+    }
+
+    fn array(&mut self) -> Result<Value, anyhow::Error> {
+        let mut items = Vec::new();
+        loop {
+            self.rest = self.rest.trim_start();
+            if let Some(rest) = self.rest.strip_prefix(']') {
+                self.rest = rest;
+                return Ok(Value::Array(items));
+            }
+            if self.rest.is_empty() {
+                anyhow::bail!("unterminated array");
+            }
+            let item = self.value()?;
+            if matches!(item, Value::Array(_)) {
+                anyhow::bail!("nested arrays are not supported");
+            }
+            items.push(item);
+            self.rest = self.rest.trim_start();
+            if let Some(rest) = self.rest.strip_prefix(',') {
+                self.rest = rest;
+            }
+        }
+    }
+}
+
+/// The command-line arguments equivalent to the config `entries`, plus the keys that don't name
+/// any option of `cmd`. Settings whose environment variable is set are skipped so the
+/// environment still overrides the file.
+pub fn to_args(cmd: &clap::Command, entries: &[(String, Value)]) -> (Vec<OsString>, Vec<String>) {
+    let mut args = Vec::new();
+    let mut unknown = Vec::new();
+    for (key, value) in entries {
+        let long = key.replace('_', "-");
+        let Some(arg) = cmd
+            .get_arguments()
+            .find(|a| a.get_long() == Some(long.as_str()) && long != "config")
+        else {
+            unknown.push(key.clone());
+            continue;
+        };
+        if arg.get_env().is_some_and(|var| std::env::var_os(var).is_some()) {
+            continue;
+        }
+        let values = match value {
+            Value::Array(items) => items.clone(),
+            other => vec![other.clone()],
+        };
+        let is_flag = matches!(
+            arg.get_action(),
+            clap::ArgAction::SetTrue | clap::ArgAction::SetFalse | clap::ArgAction::Count
+        );
+        for value in values {
+            match (is_flag, value) {
+                (true, Value::Boolean(false)) => {}
+                (true, _) => args.push(OsString::from(format!("--{long}"))),
+                (false, value) => args.push(OsString::from(format!("--{long}={}", value.to_arg()))),
+            }
+        }
+    }
+    (args, unknown)
+}
+
+/// Merges the config file into the raw process arguments: the file named by `--config`, or the
+/// default location if it exists. `subcommand` is the index of the subcommand name in `args`;
+/// global settings go before it and the subcommand's own settings right after it, while
+/// settings that only belong to other subcommands are left out. Unknown keys are reported on
+/// stderr.
+pub fn apply(
+    cmd: &clap::Command,
+    mut args: Vec<OsString>,
+    subcommand: Option<usize>,
+) -> Result<Vec<OsString>, anyhow::Error> {
+    let path = match path_from_args(&args) {
+        Some(path) => path,
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(args),
+        },
+    };
+    let entries = read(&path)?;
+    let (global, rest) = to_args(cmd, &entries);
+    let rest: Vec<_> = entries.into_iter().filter(|(key, _)| rest.contains(key)).collect();
+    let sub = subcommand.and_then(|i| Some((i, cmd.find_subcommand(args[i].to_str()?)?)));
+    let (local, mut unknown) = match sub {
+        Some((_, sub)) => to_args(sub, &rest),
+        None => (Vec::new(), rest.into_iter().map(|(key, _)| key).collect()),
+    };
+    unknown.retain(|key| {
+        let long = key.replace('_', "-");
+        !cmd.get_subcommands()
+            .flat_map(|sub| sub.get_arguments())
+            .any(|a| a.get_long() == Some(long.as_str()))
+    });
+    for key in unknown {
+        eprintln!("warning: unknown key `{key}` in config file {}", path.display());
+    }
+    if let Some((i, _)) = sub {
+        args.splice(i + 1..i + 1, local);
+    }
+    let at = 1.min(args.len());
+    args.splice(at..at, global);
+    Ok(args)
+}
+
+fn read(path: &Path) -> Result<Vec<(String, Value)>, anyhow::Error> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    parse(&text).with_context(|| format!("invalid config file {}", path.display()))
+}
@@ -0,0 +1,77 @@
+//! SIGUSR1 and SIGUSR2: steering a running recording from outside.
+//!
+//! SIGUSR1 pauses the recording and, sent again, resumes it. The stream stays open, so there
+//! is no warm-up on resuming, but what it captures is thrown away: the chunk in progress is
+//! closed when the pause comes, and recording resumes in a fresh one. SIGUSR2 closes the
+//! chunk in progress at once, to mark a boundary by hand, e.g.
+//! `kill -USR2 $(pidof rs-audio-tokenizer)`.
+//!
+//! The handlers only count the signals; the recording loop acts on them within a tick. Each
+//! device recorded from has a [`Listener`] of its own, so a signal steers all of them.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// SIGUSR1s so far.
+static TOGGLES: AtomicUsize = AtomicUsize::new(0);
+
+/// SIGUSR2s so far.
+static CUTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the handler for SIGUSR1 and SIGUSR2.
+#[cfg(unix)]
+pub fn install() -> Result<(), anyhow::Error> {
+    for signal in [libc::SIGUSR1, libc::SIGUSR2] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only touches atomics, which is async-signal-safe.
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            anyhow::bail!("failed to handle signal {signal}: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// There are no such signals elsewhere.
+#[cfg(not(unix))]
+pub fn install() -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    let counter = if signal == libc::SIGUSR1 { &TOGGLES } else { &CUTS };
+    counter.fetch_add(1, Ordering::SeqCst);
+}
+
+/// The signals one recording has yet to act on.
+pub struct Listener {
+    toggles: usize,
+    cuts: usize,
+}
+
+impl Listener {
+    /// Listens from now on.
+    pub fn new() -> Self {
+        Listener { toggles: TOGGLES.load(Ordering::SeqCst), cuts: CUTS.load(Ordering::SeqCst) }
+    }
+
+    /// How many times pausing was toggled since the last call.
+    pub fn take_toggles(&mut self) -> usize {
+        take(&TOGGLES, &mut self.toggles)
+    }
+
+    /// How many times a cut was asked for since the last call.
+    pub fn take_cuts(&mut self) -> usize {
+        take(&CUTS, &mut self.cuts)
+    }
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener::new()
+    }
+}
+
+fn take(counter: &AtomicUsize, seen: &mut usize) -> usize {
+    let now = counter.load(Ordering::SeqCst);
+    now.wrapping_sub(std::mem::replace(seen, now))
+}
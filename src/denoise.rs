@@ -0,0 +1,297 @@
+//! `--denoise`: turning down steady background noise in the capture path.
+//!
+//! This is not RNNoise, a trained network whose weights come with its bindings, none of
+//! which can be had here, but classic spectral suppression. Each channel is taken to 48 kHz
+//! and cut into 20 ms frames overlapping by half under a square-root Hann window. The noise
+//! in each frequency bin is estimated as the lowest level it has had over the last couple of
+//! seconds, which speech, with its pauses, does not hold up but hiss, hum and fans do; each
+//! bin is then turned down by a Wiener gain from its signal-to-noise ratio, to no less than
+//! [`FLOOR_DB`], and the frames are overlap-added and taken back to the capture rate. A tone
+//! held for seconds on end is noise by that measure, and is turned down too.
+//!
+//! Frames are held until they are full, so the audio comes out [`Stage::latency`] later;
+//! chunk start times allow for it.
+
+use crate::dsp::Stage;
+use crate::resample::Resampler;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The rate frames are taken at, whatever the capture rate.
+const RATE: u32 = 48_000;
+
+/// Samples per frame, 20 ms, and between the starts of consecutive frames.
+const FRAME: usize = 960;
+const HOP: usize = FRAME / 2;
+
+/// Frequency bins of a frame, from DC to Nyquist.
+const BINS: usize = FRAME / 2 + 1;
+
+/// The most a bin is turned down by.
+pub const FLOOR_DB: f32 = -20.0;
+
+/// How much of the last frame's level carries over into the smoothed level each frame.
+const SMOOTHING: f32 = 0.8;
+
+/// The noise floor is the lowest smoothed level over this many sub-windows of
+/// [`SUBWINDOW_FRAMES`], about 1.9 s.
+const SUBWINDOWS: usize = 8;
+const SUBWINDOW_FRAMES: u32 = 24;
+
+/// The lowest of a fluctuating level lies below its mean; this brings it back up.
+const NOISE_BIAS: f32 = 1.8;
+
+/// Weight of the last frame's cleaned level in the a priori signal-to-noise ratio (the
+/// decision-directed estimate), which keeps the gains from flickering on noise alone.
+const DECISION_DIRECTED: f32 = 0.98;
+
+/// `--denoise`: spectral noise suppression on each channel.
+pub struct Denoise {
+    channels: usize,
+    rate: u32,
+    /// To and from [`RATE`], unless the capture rate is that already.
+    up: Option<Resampler>,
+    down: Option<Resampler>,
+    suppressors: Vec<Suppressor>,
+    fft: Fft,
+    window: Vec<f32>,
+    /// Denoised samples at the capture rate, interleaved; it starts with `delay` frames of
+    /// silence, so there are always enough to hand back.
+    output: VecDeque<f32>,
+    /// Frames the output runs behind the input, at the capture rate.
+    delay: usize,
+}
+
+impl Denoise {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let (up, down) = match sample_rate {
+            RATE => (None, None),
+            rate => (Some(Resampler::new(channels, rate, RATE)), Some(Resampler::new(channels, RATE, rate))),
+        };
+        // The most each step can hold back: the resamplers their lookahead, the frames all
+        // but one sample of a frame. The output waits that long, and two frames more for
+        // rounding.
+        let held = up.as_ref().map_or(0, Resampler::lookahead) as f64 / f64::from(sample_rate)
+            + (FRAME as u64 - 1 + down.as_ref().map_or(0, Resampler::lookahead)) as f64 / f64::from(RATE);
+        let delay = (held * f64::from(sample_rate)).ceil() as usize + 2;
+        let channels = usize::from(channels.max(1));
+        Denoise {
+            channels,
+            rate: sample_rate,
+            up,
+            down,
+            suppressors: (0..channels).map(|_| Suppressor::new()).collect(),
+            fft: Fft::new(FRAME),
+            window: (0..FRAME).map(|i| (std::f32::consts::PI * i as f32 / FRAME as f32).sin()).collect(),
+            output: std::iter::repeat_n(0.0, delay * channels).collect(),
+            delay,
+        }
+    }
+}
+
+impl Stage for Denoise {
+    fn process(&mut self, block: &mut [f32]) {
+        let mut high = Vec::new();
+        match &mut self.up {
+            Some(up) => up.process(block, &mut high),
+            None => high.extend_from_slice(block),
+        }
+        for frame in high.chunks_exact(self.channels) {
+            for (suppressor, &sample) in self.suppressors.iter_mut().zip(frame) {
+                suppressor.input.push(sample);
+            }
+        }
+        let mut denoised: Vec<Vec<f32>> = Vec::with_capacity(self.channels);
+        for suppressor in &mut self.suppressors {
+            let mut out = Vec::new();
+            while suppressor.input.len() >= FRAME {
+                suppressor.frame(&self.fft, &self.window, &mut out);
+            }
+            denoised.push(out);
+        }
+        let frames = denoised.first().map_or(0, Vec::len);
+        let interleaved: Vec<f32> = (0..frames).flat_map(|i| denoised.iter().map(move |channel| channel[i])).collect();
+        match &mut self.down {
+            Some(down) => {
+                let mut low = Vec::new();
+                down.process(&interleaved, &mut low);
+                self.output.extend(low);
+            }
+            None => self.output.extend(interleaved),
+        }
+        for sample in block {
+            *sample = self.output.pop_front().unwrap_or(0.0);
+        }
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::from_secs_f64(self.delay as f64 / f64::from(self.rate))
+    }
+}
+
+/// One channel's frames and noise estimate.
+struct Suppressor {
+    /// Samples at [`RATE`] from the start of the next frame on.
+    input: Vec<f32>,
+    /// Overlap-added output, from the start of the next frame; the first [`HOP`] samples
+    /// are complete once it has been added.
+    overlap: Vec<f32>,
+    spectrum: Vec<Complex>,
+    /// Per bin: the smoothed level, its lowest in the sub-window in progress and in each of
+    /// the last [`SUBWINDOWS`], and the last frame's cleaned level.
+    smoothed: Vec<f32>,
+    lowest: Vec<f32>,
+    minima: Vec<[f32; SUBWINDOWS]>,
+    cleaned: Vec<f32>,
+    /// Frames into the sub-window in progress, and which of `minima` it replaces.
+    frames: u32,
+    slot: usize,
+    /// Whether a frame has been seen, so that the smoothed level starts from the first.
+    started: bool,
+}
+
+impl Suppressor {
+    fn new() -> Self {
+        Suppressor {
+            input: Vec::with_capacity(2 * FRAME),
+            overlap: vec![0.0; FRAME],
+            spectrum: vec![Complex::default(); FRAME],
+            smoothed: vec![0.0; BINS],
+            lowest: vec![f32::INFINITY; BINS],
+            minima: vec![[f32::INFINITY; SUBWINDOWS]; BINS],
+            cleaned: vec![0.0; BINS],
+            frames: 0,
+            slot: 0,
+            started: false,
+        }
+    }
+
+    /// Denoises the frame at the start of `input`, appending the [`HOP`] samples it
+    /// completes to `out`.
+    fn frame(&mut self, fft: &Fft, window: &[f32], out: &mut Vec<f32>) {
+        let windowed: Vec<Complex> = self.input[..FRAME].iter().zip(window).map(|(&s, &w)| Complex::new(s * w, 0.0)).collect();
+        fft.forward(&windowed, &mut self.spectrum);
+        let floor = crate::dsp::db_to_linear(FLOOR_DB);
+        for bin in 0..BINS {
+            let power = self.spectrum[bin].norm();
+            let smoothed = match self.started {
+                true => SMOOTHING * self.smoothed[bin] + (1.0 - SMOOTHING) * power,
+                false => power,
+            };
+            self.smoothed[bin] = smoothed;
+            self.lowest[bin] = self.lowest[bin].min(smoothed);
+            let noise = (self.minima[bin].iter().fold(self.lowest[bin], |a, &b| a.min(b)) * NOISE_BIAS).max(f32::MIN_POSITIVE);
+            let prior = DECISION_DIRECTED * self.cleaned[bin] / noise + (1.0 - DECISION_DIRECTED) * (power / noise - 1.0).max(0.0);
+            let gain = (prior / (1.0 + prior)).max(floor);
+            self.cleaned[bin] = gain * gain * power;
+            self.spectrum[bin] = self.spectrum[bin].scale(gain);
+            if bin > 0 && bin < FRAME - bin {
+                self.spectrum[FRAME - bin] = self.spectrum[FRAME - bin].scale(gain);
+            }
+        }
+        self.started = true;
+        self.frames += 1;
+        if self.frames == SUBWINDOW_FRAMES {
+            for (minima, lowest) in self.minima.iter_mut().zip(&mut self.lowest) {
+                minima[self.slot] = std::mem::replace(lowest, f32::INFINITY);
+            }
+            self.frames = 0;
+            self.slot = (self.slot + 1) % SUBWINDOWS;
+        }
+        // The inverse transform, as the conjugate of the forward one of the conjugate.
+        let conjugate: Vec<Complex> = self.spectrum.iter().map(|c| c.conj()).collect();
+        fft.forward(&conjugate, &mut self.spectrum);
+        for ((sum, c), &w) in self.overlap.iter_mut().zip(&self.spectrum).zip(window) {
+            *sum += c.re / FRAME as f32 * w;
+        }
+        out.extend_from_slice(&self.overlap[..HOP]);
+        self.overlap.copy_within(HOP.., 0);
+        self.overlap[FRAME - HOP..].fill(0.0);
+        self.input.drain(..HOP);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Self {
+        Complex { re, im }
+    }
+
+    fn mul(self, other: Complex) -> Self {
+        Complex::new(self.re * other.re - self.im * other.im, self.re * other.im + self.im * other.re)
+    }
+
+    fn add(self, other: Complex) -> Self {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        Complex::new(self.re * factor, self.im * factor)
+    }
+
+    fn conj(self) -> Self {
+        Complex::new(self.re, -self.im)
+    }
+
+    /// The squared magnitude.
+    fn norm(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+/// A discrete Fourier transform by mixed-radix decimation in time, for lengths whose only
+/// prime factors are 2, 3 and 5, as [`FRAME`]'s are.
+struct Fft {
+    /// `e^(-2πik/n)` for each `k` below the length `n`.
+    twiddles: Vec<Complex>,
+}
+
+impl Fft {
+    fn new(len: usize) -> Self {
+        let twiddles = (0..len)
+            .map(|k| {
+                let angle = -2.0 * std::f64::consts::PI * k as f64 / len as f64;
+                Complex::new(angle.cos() as f32, angle.sin() as f32)
+            })
+            .collect();
+        Fft { twiddles }
+    }
+
+    fn forward(&self, input: &[Complex], output: &mut [Complex]) {
+        self.step(input, 1, output);
+    }
+
+    /// Transforms every `stride`th sample of `input` into `output`, whose length divides
+    /// the transform's.
+    fn step(&self, input: &[Complex], stride: usize, output: &mut [Complex]) {
+        let n = output.len();
+        if n == 1 {
+            output[0] = input[0];
+            return;
+        }
+        let radix = [2, 3, 5].into_iter().find(|&r| n.is_multiple_of(r)).unwrap_or(n);
+        let m = n / radix;
+        for q in 0..radix {
+            self.step(&input[q * stride..], stride * radix, &mut output[q * m..(q + 1) * m]);
+        }
+        // Output `k + r·m` combines bin `k` of each of the `radix` interleaved halves,
+        // thirds or fifths, turned by `e^(-2πi·q(k + r·m)/n)`.
+        let step = self.twiddles.len() / n;
+        let mut sums = vec![Complex::default(); radix];
+        for k in 0..m {
+            for (r, sum) in sums.iter_mut().enumerate() {
+                *sum = (0..radix).fold(Complex::default(), |acc, q| {
+                    acc.add(output[q * m + k].mul(self.twiddles[(q * (k + r * m)) % n * step]))
+                });
+            }
+            for (r, &sum) in sums.iter().enumerate() {
+                output[r * m + k] = sum;
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Input device lookup and stream config negotiation.
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{SampleFormat, SampleRate, SupportedStreamConfig, SupportedStreamConfigRange};
+
+/// Sample formats the recorder can take from a device.
+pub const CAPTURE_FORMATS: &[SampleFormat] =
+    &[SampleFormat::I16, SampleFormat::F32, SampleFormat::I32, SampleFormat::U16, SampleFormat::U8];
+
+/// The stream config `record` asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Wanted {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_format: SampleFormat,
+}
+
+/// Picks the cpal host whose name matches `name` case-insensitively, or the default host when
+/// no name is given.
+pub fn select_host(name: Option<&str>) -> Result<cpal::Host, anyhow::Error> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let available = cpal::available_hosts();
+    match available.iter().find(|id| id.name().eq_ignore_ascii_case(name)) {
+        Some(&id) => Ok(cpal::host_from_id(id)?),
+        None => {
+            let names: Vec<&str> = available.iter().map(|id| id.name()).collect();
+            anyhow::bail!("unknown audio host `{name}`; available hosts: {}", names.join(", "))
+        }
+    }
+}
+
+/// Resolves `--device`: "default" is the host default, otherwise the argument is matched
+/// against the input device names.
+pub fn select_device(host: &cpal::Host, query: &str) -> Result<cpal::Device, anyhow::Error> {
+    if query == "default" {
+        return host
+            .default_input_device()
+            .ok_or_else(|| anyhow::anyhow!("host {} has no default input device", host.id().name()));
+    }
+    let mut devices: Vec<cpal::Device> = host.input_devices()?.collect();
+    let names: Vec<Option<String>> = devices.iter().map(|d| d.name().ok()).collect();
+    let index = match_device(&names, query)?;
+    Ok(devices.swap_remove(index))
+}
+
+/// Finds the device `query` refers to among `names` (in `host.input_devices()` order; `None`
+/// for devices that could not report a name). An exact name wins, so a device literally
+/// called "3" is preferred over the fourth device; otherwise a number is taken as an index,
+/// and anything else falls back to a case-insensitive substring match that must be unique.
+pub fn match_device(names: &[Option<String>], query: &str) -> Result<usize, anyhow::Error> {
+    if let Some(index) = names.iter().position(|n| n.as_deref() == Some(query)) {
+        return Ok(index);
+    }
+    if let Ok(index) = query.parse::<usize>() {
+        if index < names.len() {
+            return Ok(index);
+        }
+        match names.len() {
+            0 => anyhow::bail!("device index {index} is out of range: there are no input devices"),
+            n => anyhow::bail!("device index {index} is out of range; valid indices are 0-{}", n - 1),
+        }
+    }
+    let needle = query.to_lowercase();
+    let candidates: Vec<(usize, &str)> = names
+        .iter()
+        .enumerate()
+        .filter_map(|(i, n)| n.as_deref().map(|n| (i, n)))
+        .filter(|(_, n)| n.to_lowercase().contains(&needle))
+        .collect();
+    match candidates.as_slice() {
+        [(index, _)] => Ok(*index),
+        [] => anyhow::bail!("no input device matches `{query}`; run the `devices` subcommand to list them"),
+        _ => {
+            let listing: Vec<String> =
+                candidates.iter().map(|(i, n)| format!("  {i}: {n}")).collect();
+            anyhow::bail!("`{query}` matches several input devices:\n{}", listing.join("\n"))
+        }
+    }
+}
+
+/// Picks the supported config closest to `wanted`, or `None` if the device offers no format in
+/// [`CAPTURE_FORMATS`]. The sample rate matters most, then the channel count, since neither is
+/// converted and a mismatch changes what reaches the server; the sample format comes last
+/// because any captured format is converted to the WAV's. Among near misses the nearest rate
+/// and channel count win, preferring more channels to fewer.
+pub fn negotiate(ranges: &[SupportedStreamConfigRange], wanted: Wanted) -> Option<SupportedStreamConfig> {
+    ranges
+        .iter()
+        .filter(|r| CAPTURE_FORMATS.contains(&r.sample_format()))
+        .map(|r| {
+            let rate = wanted.sample_rate.clamp(r.min_sample_rate().0, r.max_sample_rate().0);
+            let format_rank = CAPTURE_FORMATS.iter().position(|&f| f == r.sample_format());
+            let key = (
+                rate.abs_diff(wanted.sample_rate),
+                r.channels().abs_diff(wanted.channels),
+                r.channels() < wanted.channels,
+                r.sample_format() != wanted.sample_format,
+                format_rank,
+            );
+            (key, r.with_sample_rate(SampleRate(rate)))
+        })
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, config)| config)
+}
@@ -0,0 +1,53 @@
+//! The `devices` subcommand: lists the input devices `--device` can choose from, and the
+//! outputs `--loopback` can.
+
+use crate::cli::HostArgs;
+use crate::device::select_host;
+use crate::loopback;
+use cpal::traits::{DeviceTrait, HostTrait};
+use tracing::{debug, warn};
+
+/// Prints every input device with its index, name and default input config, marking the host
+/// default. Devices that fail to report something are listed with a warning instead of
+/// aborting the listing.
+pub fn run(args: &HostArgs) -> Result<(), anyhow::Error> {
+    let host = select_host(args.name())?;
+    let default_name = host.default_input_device().and_then(|d| d.name().ok());
+    let mut found = false;
+    for (index, device) in host.input_devices()?.enumerate() {
+        found = true;
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(err) => {
+                warn!("device {index} did not report a name: {err}");
+                continue;
+            }
+        };
+        let marker = if default_name.as_deref() == Some(name.as_str()) { " (default)" } else { "" };
+        println!("{index}: {name}{marker}");
+        match device.default_input_config() {
+            Ok(config) => println!(
+                "    {} ch, {} Hz, {}",
+                config.channels(),
+                config.sample_rate().0,
+                config.sample_format()
+            ),
+            Err(err) => warn!("no default input config for `{name}`: {err}"),
+        }
+    }
+    if !found {
+        println!("No input devices found on host {}", host.id().name());
+    }
+    match loopback::sources(&host) {
+        Ok(sources) if sources.is_empty() => {}
+        Ok(sources) => {
+            println!("Loopback sources (--loopback):");
+            for (index, source) in sources.iter().enumerate() {
+                println!("{index}: {source}");
+            }
+        }
+        // Most likely no PulseAudio to ask, and so nothing to list.
+        Err(err) => debug!("cannot list loopback sources: {err:#}"),
+    }
+    Ok(())
+}
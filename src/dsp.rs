@@ -0,0 +1,486 @@
+//! Sample processing applied between the capture callback and the WAV writer.
+
+use cpal::{FromSample, Sample};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Converts captured samples to the written format and applies a linear `gain`; at exactly
+/// 1.0 the conversion is left untouched. Floats map full scale to full scale (±1.0 to
+/// `i16::MIN`/`i16::MAX`), clamping anything beyond, and unsigned formats are offset binary.
+pub fn convert<T: Sample, U: Sample + FromSample<T> + Gain>(input: &[T], gain: f32) -> Vec<U> {
+    input
+        .iter()
+        .map(|&sample| {
+            let sample = U::from_sample(sample);
+            if gain != 1.0 {
+                sample.gain(gain)
+            } else {
+                sample
+            }
+        })
+        .collect()
+}
+
+/// Like [`convert`] for float samples written as 16-bit, but quantizing with TPDF dither
+/// instead of truncating, so quiet passages carry noise rather than distortion.
+pub fn convert_dithered<T, U>(input: &[T], gain: f32, dither: &Dither) -> Vec<U>
+where
+    T: Sample,
+    U: Sample + FromSample<i16>,
+    f32: FromSample<T>,
+{
+    input
+        .iter()
+        .map(|&sample| U::from_sample(dither.quantize(f32::from_sample(sample) * gain)))
+        .collect()
+}
+
+/// Triangular (TPDF) dither of ±1 LSB at 16 bits. Every sample gets fresh noise, so the
+/// channels of a frame are dithered independently.
+pub struct Dither {
+    state: Cell<u64>,
+}
+
+impl Dither {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves zero.
+        Dither { state: Cell::new(seed | 1) }
+    }
+
+    /// Quantizes a sample at full scale ±1.0 to 16 bits.
+    pub fn quantize(&self, sample: f32) -> i16 {
+        let noise = self.uniform() + self.uniform();
+        (sample * 32768.0 + noise)
+            .round()
+            .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+    }
+
+    /// Uniform noise in [-0.5, 0.5).
+    fn uniform(&self) -> f32 {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+    }
+
+    /// xorshift64*.
+    fn next(&self) -> u64 {
+        let mut x = self.state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+/// A filter run on interleaved f32 samples in the capture path. Its state carries over from
+/// one block to the next, and so across chunk boundaries.
+pub trait Stage: Send {
+    fn process(&mut self, block: &mut [f32]);
+
+    /// How far the output runs behind the input.
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Corner frequency of [`DcBlocker`]: far below speech, so voices pass untouched.
+const DC_CORNER_HZ: f32 = 5.0;
+
+/// `--remove-dc`: the one-pole high-pass `y[n] = x[n] - x[n-1] + r * y[n-1]`, which takes
+/// out a constant offset and little else.
+pub struct DcBlocker {
+    r: f32,
+    channels: usize,
+    /// The previous input and output of each channel.
+    x1: Vec<f32>,
+    y1: Vec<f32>,
+}
+
+impl DcBlocker {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let channels = usize::from(channels.max(1));
+        let r = (-2.0 * std::f32::consts::PI * DC_CORNER_HZ / sample_rate as f32).exp();
+        DcBlocker { r, channels, x1: vec![0.0; channels], y1: vec![0.0; channels] }
+    }
+}
+
+impl Stage for DcBlocker {
+    fn process(&mut self, block: &mut [f32]) {
+        for frame in block.chunks_exact_mut(self.channels) {
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let y = *sample - self.x1[c] + self.r * self.y1[c];
+                self.x1[c] = *sample;
+                self.y1[c] = y;
+                *sample = y;
+            }
+        }
+    }
+}
+
+/// `--highpass`: a second-order (12 dB/octave) Butterworth high-pass biquad, after the RBJ
+/// audio EQ cookbook, for rumble and handling noise.
+pub struct HighPass {
+    b: [f32; 3],
+    a: [f32; 2],
+    channels: usize,
+    /// The last two inputs and outputs of each channel.
+    x: Vec<[f32; 2]>,
+    y: Vec<[f32; 2]>,
+}
+
+impl HighPass {
+    pub fn new(channels: u16, sample_rate: u32, cutoff: f32) -> Self {
+        let channels = usize::from(channels.max(1));
+        let w0 = 2.0 * std::f64::consts::PI * f64::from(cutoff) / f64::from(sample_rate);
+        let alpha = w0.sin() / (2.0 * std::f64::consts::FRAC_1_SQRT_2);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        let b = [(1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0];
+        let a = [-2.0 * cos / a0, (1.0 - alpha) / a0];
+        HighPass {
+            b: b.map(|v| v as f32),
+            a: a.map(|v| v as f32),
+            channels,
+            x: vec![[0.0; 2]; channels],
+            y: vec![[0.0; 2]; channels],
+        }
+    }
+}
+
+impl Stage for HighPass {
+    fn process(&mut self, block: &mut [f32]) {
+        let (b, a) = (self.b, self.a);
+        for frame in block.chunks_exact_mut(self.channels) {
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let (x, y) = (&mut self.x[c], &mut self.y[c]);
+                let out = b[0] * *sample + b[1] * x[0] + b[2] * x[1] - a[0] * y[0] - a[1] * y[1];
+                *x = [*sample, x[0]];
+                *y = [out, y[0]];
+                *sample = out;
+            }
+        }
+    }
+}
+
+/// Length of the window [`NoiseGate`] measures the RMS level over.
+const GATE_WINDOW_MS: u32 = 10;
+
+/// How far [`NoiseGate`] listens ahead of what it outputs, so it has finished opening by the
+/// time a word starts. The gated stream comes out this much later.
+const GATE_LOOKAHEAD_MS: u32 = 5;
+
+/// How long [`NoiseGate`] takes to fade out once the hold runs out.
+const GATE_RELEASE_MS: u32 = 50;
+
+/// `--gate-threshold`: silences the input while its level stays below a threshold, so the
+/// hiss between utterances is not uploaded. The gate opens as soon as the short-term RMS
+/// reaches the threshold, stays open for the hold time after it drops below, then fades to
+/// zero.
+pub struct NoiseGate {
+    channels: usize,
+    /// Mean power per sample below which the gate closes.
+    threshold: f64,
+    hold_frames: u64,
+    attack_step: f32,
+    release_step: f32,
+    /// Power of each frame in the RMS window, and their sum.
+    window: VecDeque<f64>,
+    window_frames: usize,
+    window_sum: f64,
+    /// Input frames heard but not yet output, interleaved.
+    delay: VecDeque<f32>,
+    /// Frames the gate stays open for without the level reaching the threshold.
+    held: u64,
+    gain: f32,
+    lookahead: Duration,
+}
+
+impl NoiseGate {
+    pub fn new(channels: u16, sample_rate: u32, threshold_db: f32, hold_ms: u64) -> Self {
+        let channels = usize::from(channels.max(1));
+        let frames = |ms: u64| (u64::from(sample_rate) * ms / 1000).max(1);
+        let lookahead = frames(GATE_LOOKAHEAD_MS.into());
+        let window_frames = frames(GATE_WINDOW_MS.into()) as usize;
+        NoiseGate {
+            channels,
+            threshold: 10f64.powf(f64::from(threshold_db) / 10.0),
+            hold_frames: frames(hold_ms),
+            attack_step: 1.0 / lookahead as f32,
+            release_step: 1.0 / frames(GATE_RELEASE_MS.into()) as f32,
+            window: VecDeque::with_capacity(window_frames + 1),
+            window_frames,
+            window_sum: 0.0,
+            delay: std::iter::repeat_n(0.0, lookahead as usize * channels).collect(),
+            held: 0,
+            gain: 0.0,
+            lookahead: Duration::from_secs_f64(lookahead as f64 / f64::from(sample_rate.max(1))),
+        }
+    }
+}
+
+impl Stage for NoiseGate {
+    fn process(&mut self, block: &mut [f32]) {
+        for frame in block.chunks_exact_mut(self.channels) {
+            let power = frame.iter().map(|&s| f64::from(s) * f64::from(s)).sum::<f64>() / self.channels as f64;
+            self.window.push_back(power);
+            self.window_sum += power;
+            if self.window.len() > self.window_frames {
+                self.window_sum -= self.window.pop_front().unwrap_or(0.0);
+            }
+            if self.window_sum.max(0.0) / self.window.len() as f64 >= self.threshold {
+                self.held = self.hold_frames;
+            }
+            if self.held > 0 {
+                self.held -= 1;
+                self.gain = (self.gain + self.attack_step).min(1.0);
+            } else {
+                self.gain = (self.gain - self.release_step).max(0.0);
+            }
+            for sample in frame {
+                self.delay.push_back(*sample);
+                *sample = self.delay.pop_front().unwrap_or(0.0) * self.gain;
+            }
+        }
+    }
+
+    fn latency(&self) -> Duration {
+        self.lookahead
+    }
+}
+
+/// Length of the blocks [`Agc`] measures the level in.
+const AGC_BLOCK_MS: u64 = 10;
+
+/// Blocks [`Agc`] averages the level over, about a second.
+const AGC_WINDOW_BLOCKS: usize = 100;
+
+/// Time constant of [`Agc`]'s gain changes.
+const AGC_SMOOTHING_MS: f32 = 200.0;
+
+/// Blocks quieter than this are left out of [`Agc`]'s level, so gated silence and pauses
+/// neither pull the gain up nor count as speech.
+const AGC_FLOOR_DBFS: f64 = -70.0;
+
+/// The range [`Agc`] keeps its gain in, in dB; the upper limit stops it pumping a quiet room
+/// up to full scale.
+pub const AGC_MIN_GAIN_DB: f32 = -20.0;
+pub const AGC_MAX_GAIN_DB: f32 = 30.0;
+
+/// `--agc`: automatic gain control, steering the RMS level over the last second toward a
+/// target so near and far speakers come out alike.
+pub struct Agc {
+    channels: usize,
+    /// Target mean power per sample.
+    target: f64,
+    floor: f64,
+    block_frames: u64,
+    /// Power summed over the block in progress, and its frames so far.
+    block_sum: f64,
+    block_len: u64,
+    /// Mean power of the latest loud-enough blocks.
+    blocks: VecDeque<f64>,
+    /// Gain the level asks for, and the smoothed gain applied.
+    wanted: f32,
+    gain: f32,
+    smoothing: f32,
+}
+
+impl Agc {
+    pub fn new(channels: u16, sample_rate: u32, target_dbfs: f32) -> Self {
+        Agc {
+            channels: usize::from(channels.max(1)),
+            target: 10f64.powf(f64::from(target_dbfs) / 10.0),
+            floor: 10f64.powf(AGC_FLOOR_DBFS / 10.0),
+            block_frames: (u64::from(sample_rate) * AGC_BLOCK_MS / 1000).max(1),
+            block_sum: 0.0,
+            block_len: 0,
+            blocks: VecDeque::with_capacity(AGC_WINDOW_BLOCKS + 1),
+            wanted: 1.0,
+            gain: 1.0,
+            smoothing: 1.0 - (-1000.0 / (AGC_SMOOTHING_MS * sample_rate as f32)).exp(),
+        }
+    }
+
+    /// Re-aims the gain once a block is complete.
+    fn measure(&mut self) {
+        let power = self.block_sum / self.block_len as f64;
+        (self.block_sum, self.block_len) = (0.0, 0);
+        if power < self.floor {
+            return;
+        }
+        self.blocks.push_back(power);
+        if self.blocks.len() > AGC_WINDOW_BLOCKS {
+            self.blocks.pop_front();
+        }
+        let level = self.blocks.iter().sum::<f64>() / self.blocks.len() as f64;
+        let wanted = (self.target / level).sqrt() as f32;
+        self.wanted = wanted.clamp(db_to_linear(AGC_MIN_GAIN_DB), db_to_linear(AGC_MAX_GAIN_DB));
+    }
+}
+
+impl Stage for Agc {
+    fn process(&mut self, block: &mut [f32]) {
+        for frame in block.chunks_exact_mut(self.channels) {
+            self.block_sum += frame.iter().map(|&s| f64::from(s) * f64::from(s)).sum::<f64>() / self.channels as f64;
+            self.block_len += 1;
+            if self.block_len == self.block_frames {
+                self.measure();
+            }
+            self.gain += (self.wanted - self.gain) * self.smoothing;
+            frame.iter_mut().for_each(|sample| *sample *= self.gain);
+        }
+    }
+}
+
+/// Magnitude from which a sample counts as clipped: within 0.1 dB of full scale, which also
+/// takes in the top step of 8-bit input.
+const CLIP_LEVEL: f32 = 0.9886;
+
+/// The level of a stretch of raw capture, before gain or any filter.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Level {
+    /// Largest magnitude, 1.0 being full scale.
+    pub peak: f32,
+    /// Samples at full scale.
+    pub clipped: u64,
+    /// Sum of the squared samples.
+    pub energy: f64,
+    pub samples: u64,
+}
+
+impl Level {
+    pub fn measure<T: Sample>(input: &[T]) -> Self
+    where
+        f32: FromSample<T>,
+    {
+        let mut level = Level::default();
+        for &sample in input {
+            level.add_sample(f32::from_sample(sample));
+        }
+        level
+    }
+
+    /// Counts one more sample, 1.0 being full scale.
+    pub fn add_sample(&mut self, sample: f32) {
+        let magnitude = sample.abs();
+        self.peak = self.peak.max(magnitude);
+        self.energy += f64::from(magnitude) * f64::from(magnitude);
+        self.clipped += u64::from(magnitude >= CLIP_LEVEL);
+        self.samples += 1;
+    }
+
+    pub fn add(&mut self, other: Level) {
+        self.peak = self.peak.max(other.peak);
+        self.clipped += other.clipped;
+        self.energy += other.energy;
+        self.samples += other.samples;
+    }
+
+    pub fn peak_dbfs(&self) -> f32 {
+        20.0 * self.peak.log10()
+    }
+
+    pub fn rms_dbfs(&self) -> f32 {
+        (10.0 * (self.energy / self.samples.max(1) as f64).log10()) as f32
+    }
+
+    /// Fraction of the samples that clipped.
+    pub fn clipped_ratio(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.clipped as f64 / self.samples as f64
+        }
+    }
+}
+
+/// Converts a gain in decibels to a linear factor.
+pub fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Scaling a sample by a linear factor, saturating at full scale instead of wrapping.
+pub trait Gain: Copy {
+    fn gain(self, factor: f32) -> Self;
+}
+
+impl Gain for i16 {
+    fn gain(self, factor: f32) -> Self {
+        (f32::from(self) * factor)
+            .round()
+            .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+    }
+}
+
+impl Gain for i32 {
+    fn gain(self, factor: f32) -> Self {
+        (f64::from(self) * f64::from(factor))
+            .round()
+            .clamp(f64::from(i32::MIN), f64::from(i32::MAX)) as i32
+    }
+}
+
+impl Gain for f32 {
+    fn gain(self, factor: f32) -> Self {
+        (self * factor).clamp(-1.0, 1.0)
+    }
+}
+
+/// How the captured channels become the chunk's channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelMap {
+    /// Every channel as captured.
+    #[default]
+    Keep,
+    /// `--mono`: one channel, the average of all captured ones.
+    Mix,
+    /// `--input-channel`: one channel, the captured one at this (0-based) index.
+    Pick(u16),
+}
+
+impl ChannelMap {
+    /// Channels in the chunks for `captured` channels of input.
+    pub fn channels(self, captured: u16) -> u16 {
+        match self {
+            ChannelMap::Keep => captured,
+            ChannelMap::Mix | ChannelMap::Pick(_) => 1,
+        }
+    }
+
+    /// Maps an interleaved block of `captured`-channel frames.
+    pub fn apply<S: Downmix>(self, captured: u16, block: Vec<S>) -> Vec<S> {
+        match self {
+            ChannelMap::Mix if captured > 1 => block.chunks_exact(usize::from(captured)).map(S::mean).collect(),
+            ChannelMap::Pick(index) if captured > 1 => block
+                .chunks_exact(usize::from(captured))
+                .map(|frame| frame[usize::from(index)])
+                .collect(),
+            _ => block,
+        }
+    }
+}
+
+/// Averaging the samples of one frame, without overflowing however many channels there are.
+pub trait Downmix: Copy {
+    fn mean(frame: &[Self]) -> Self;
+}
+
+impl Downmix for i16 {
+    fn mean(frame: &[Self]) -> Self {
+        let sum: i64 = frame.iter().map(|&s| i64::from(s)).sum();
+        (sum as f64 / frame.len() as f64).round() as i16
+    }
+}
+
+impl Downmix for i32 {
+    fn mean(frame: &[Self]) -> Self {
+        let sum: i64 = frame.iter().map(|&s| i64::from(s)).sum();
+        (sum as f64 / frame.len() as f64).round() as i32
+    }
+}
+
+impl Downmix for f32 {
+    fn mean(frame: &[Self]) -> Self {
+        frame.iter().sum::<f32>() / frame.len() as f32
+    }
+}
@@ -0,0 +1,169 @@
+//! The formats chunks are stored and uploaded in (`--format`).
+//!
+//! The writer thread only sees a [`ChunkWriter`]: it hands over each sample as the stream
+//! reaches it and finalizes the writer when the chunk ends, so a format added here needs no
+//! changes to the capture path. [`Encoder`] is whichever of them `--format` picks.
+//!
+//! MP3, with the `mp3` feature, is the one lossy format; [`crate::mp3`] has its encoder.
+
+use crate::flac::FlacWriter;
+#[cfg(feature = "mp3")]
+use crate::mp3::Mp3Writer;
+use clap::ValueEnum;
+use cpal::{FromSample, Sample};
+use std::io::{Seek, Write};
+use std::path::Path;
+
+/// Chunk formats selectable with `--format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Wav,
+    /// Lossless, about half the size of WAV for speech
+    Flac,
+    /// Headerless little-endian 16-bit PCM, the rate and channels sent as request headers
+    Raw,
+    /// Lossy, at --bitrate, for servers that take nothing else
+    #[cfg(feature = "mp3")]
+    Mp3,
+}
+
+impl Format {
+    /// The file extension of chunks in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Wav => "wav",
+            Format::Flac => "flac",
+            Format::Raw => "raw",
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => "mp3",
+        }
+    }
+
+    /// The media type uploads in this format are sent as.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Wav => "audio/wav",
+            Format::Flac => "audio/flac",
+            Format::Raw => "application/octet-stream",
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => "audio/mpeg",
+        }
+    }
+
+    /// The format a file is in, going by its extension; `.pcm` is raw too.
+    pub fn of(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?;
+        if extension.eq_ignore_ascii_case("pcm") {
+            return Some(Format::Raw);
+        }
+        Format::value_variants().iter().copied().find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+
+    /// The bits per sample chunks of `spec` are stored with. FLAC stores integers of up to
+    /// 24 bits only, so 32-bit and float chunks become 24-bit ones; raw chunks are always
+    /// 16-bit, and so are MP3 chunks once decoded.
+    pub fn bits(self, spec: hound::WavSpec) -> u16 {
+        match self {
+            Format::Wav => spec.bits_per_sample,
+            Format::Flac if spec.sample_format == hound::SampleFormat::Int => spec.bits_per_sample.min(24),
+            Format::Flac => 24,
+            Format::Raw => 16,
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => 16,
+        }
+    }
+
+    /// Starts a chunk in `inner` with the layout of `spec`, at the depth [`Self::bits`] gives;
+    /// MP3 chunks are encoded at `bitrate` kbps, which the other formats have no use for.
+    #[cfg_attr(not(feature = "mp3"), allow(unused_variables))]
+    pub fn encoder<W: Write + Seek>(self, inner: W, spec: hound::WavSpec, bitrate: u32) -> Result<Encoder<W>, anyhow::Error> {
+        Ok(match self {
+            Format::Wav if spec.bits_per_sample == 24 => Encoder::Wav24(hound::WavWriter::new(inner, spec)?),
+            Format::Wav => Encoder::Wav(hound::WavWriter::new(inner, spec)?),
+            Format::Flac => {
+                let bits = self.bits(spec);
+                Encoder::Flac(FlacWriter::new(inner, spec.channels, spec.sample_rate, bits)?, bits)
+            }
+            Format::Raw => Encoder::Raw(inner),
+            #[cfg(feature = "mp3")]
+            Format::Mp3 => Encoder::Mp3(Mp3Writer::new(inner, spec.channels, spec.sample_rate, bitrate)?),
+        })
+    }
+}
+
+/// Where the writer thread puts the samples of one chunk.
+pub trait ChunkWriter<S> {
+    fn write_sample(&mut self, sample: S) -> std::io::Result<()>;
+
+    /// Completes the chunk once its last sample is written.
+    fn finalize(self) -> Result<(), anyhow::Error>;
+}
+
+impl<S: hound::Sample, W: Write + Seek> ChunkWriter<S> for hound::WavWriter<W> {
+    fn write_sample(&mut self, sample: S) -> std::io::Result<()> {
+        hound::WavWriter::write_sample(self, sample).map_err(std::io::Error::other)
+    }
+
+    fn finalize(self) -> Result<(), anyhow::Error> {
+        Ok(hound::WavWriter::finalize(self)?)
+    }
+}
+
+/// A chunk that is not written anywhere: with `--session-file` its audio is in the session
+/// file.
+pub struct Discard;
+
+impl<S> ChunkWriter<S> for Discard {
+    fn write_sample(&mut self, _: S) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// A chunk in the format `--format` picks.
+pub enum Encoder<W: Write + Seek> {
+    Wav(hound::WavWriter<W>),
+    /// A 24-bit WAV, which hound takes as i32 samples in the 24-bit range.
+    Wav24(hound::WavWriter<W>),
+    /// With the bit depth it stores.
+    Flac(FlacWriter<W>, u16),
+    /// Written to as is.
+    Raw(W),
+    #[cfg(feature = "mp3")]
+    Mp3(Mp3Writer<W>),
+}
+
+impl<S, W> ChunkWriter<S> for Encoder<W>
+where
+    S: hound::Sample + Sample,
+    i16: FromSample<S>,
+    i32: FromSample<S>,
+    f32: FromSample<S>,
+    W: Write + Seek,
+{
+    fn write_sample(&mut self, sample: S) -> std::io::Result<()> {
+        match self {
+            Encoder::Wav(writer) => ChunkWriter::write_sample(writer, sample),
+            Encoder::Wav24(writer) => ChunkWriter::write_sample(writer, i32::from_sample(sample) >> 8),
+            // Full-scale 32-bit, shifted down to the stream's depth.
+            Encoder::Flac(writer, bits) => writer.write_sample(i32::from_sample(sample) >> (32 - *bits)),
+            Encoder::Raw(writer) => writer.write_all(&i16::from_sample(sample).to_le_bytes()),
+            #[cfg(feature = "mp3")]
+            Encoder::Mp3(writer) => writer.write_sample(f32::from_sample(sample)),
+        }
+    }
+
+    fn finalize(self) -> Result<(), anyhow::Error> {
+        match self {
+            Encoder::Wav(writer) | Encoder::Wav24(writer) => ChunkWriter::<S>::finalize(writer),
+            Encoder::Flac(writer, _) => writer.finalize(),
+            Encoder::Raw(mut writer) => Ok(writer.flush()?),
+            #[cfg(feature = "mp3")]
+            Encoder::Mp3(writer) => writer.finalize(),
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! `--grpc-endpoint`: streaming the recording to a transcription server over one gRPC call,
+//! for the servers that speak gRPC rather than take chunks or a WebSocket.
+//!
+//! The call is the `StreamingRecognize` of `proto/transcriber.proto`, which comes with the
+//! source: audio frames in, transcript events out. The writer thread hands every frame to a
+//! [`GrpcStream`] through [`crate::pipe::RawStream`], as it does with `--ws-url`, so the samples
+//! are laid out as `--stdout-raw` writes them. They go in messages of [`FRAME`] each, after a
+//! first one that tells the server their layout and the `--language`. An event that is not
+//! final is only logged, at debug level, as a later one takes it back; a final one is printed
+//! and written to the transcript log as an upload's transcript is.
+//!
+//! The call goes over HTTP/2: in the clear for an http:// endpoint, and for https:// over TLS
+//! that trusts what uploads trust, `--ca-cert` and `--insecure` included. The uploads'
+//! credential and `--header`s go as its metadata, their names lowercased. A server that cannot
+//! be connected to fails the recording up front. One that ends the call while the recording
+//! goes on, or a call that fails, stops the recording as a stdout without a reader does, with
+//! why logged: unlike a WebSocket, the call is not made again, as the server's transcripts of
+//! it would start over. At the end the call is half-closed, and the server has up to
+//! [`CLOSE_WAIT`] to send its last events and end it. Proxies are not gone through.
+//!
+//! The messages are encoded by hand rather than by `prost`, and the HTTP/2 is `h2`'s rather
+//! than `tonic`'s: the messages have eight fields between them, and no code is generated at
+//! build time. Nothing is compressed.
+
+use crate::pipe::layout_name;
+use crate::transcript;
+use crate::upload::{Auth, ClientOptions, Header};
+use anyhow::Context;
+use bytes::Bytes;
+use futures::future::{self, Either};
+use h2::client::{ResponseFuture, SendRequest};
+use h2::SendStream;
+use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{debug, error, info, warn};
+
+/// The most connecting and the TLS handshake may take.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the server has, once the recording is over, to send its last events and end the call.
+pub const CLOSE_WAIT: Duration = Duration::from_secs(2);
+
+/// How much audio goes in each message.
+pub const FRAME: Duration = Duration::from_millis(100);
+
+/// The path of the call, after any in the endpoint.
+pub const PATH: &str = "/audiotok.v1.Transcriber/StreamingRecognize";
+
+/// The largest message taken from the server; a longer one fails the call.
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// The names of the gRPC status codes, by code.
+const STATUSES: [&str; 17] = [
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+/// What the call is made with.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// An http:// or https:// URL; a path in it goes before [`PATH`].
+    pub url: reqwest::Url,
+    /// Sent as metadata, as with each upload.
+    pub headers: Vec<Header>,
+    pub auth: Option<Auth>,
+    /// The `--ca-cert` and `--insecure` of an https:// URL; its proxy is not used.
+    pub client: ClientOptions,
+    /// Where final transcripts are appended.
+    pub log: Option<Arc<Mutex<File>>>,
+    /// Told to the server, and what each transcript in the log is marked with.
+    pub language: Option<String>,
+}
+
+/// The `AudioConfig` of the first message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub sample_format: String,
+    /// Empty if none was given.
+    pub language: String,
+}
+
+impl AudioConfig {
+    /// The config of samples laid out as `spec`.
+    pub fn new(spec: hound::WavSpec, language: Option<&str>) -> AudioConfig {
+        AudioConfig {
+            sample_rate: spec.sample_rate,
+            channels: u32::from(spec.channels),
+            sample_format: layout_name(spec).to_owned(),
+            language: language.unwrap_or_default().to_owned(),
+        }
+    }
+
+    /// The `AudioFrame` with this config and no audio, encoded.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut config = Vec::new();
+        put_uint(&mut config, 1, self.sample_rate);
+        put_uint(&mut config, 2, self.channels);
+        put_bytes(&mut config, 3, self.sample_format.as_bytes());
+        if !self.language.is_empty() {
+            put_bytes(&mut config, 4, self.language.as_bytes());
+        }
+        let mut frame = Vec::with_capacity(config.len() + 2);
+        put_bytes(&mut frame, 1, &config);
+        frame
+    }
+}
+
+/// The `AudioFrame` with `audio` and no config, encoded.
+pub fn audio_frame(audio: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(audio.len() + 6);
+    put_bytes(&mut frame, 2, audio);
+    frame
+}
+
+/// A `TranscriptEvent`: what the server heard.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TranscriptEvent {
+    pub text: String,
+    pub is_final: bool,
+}
+
+impl TranscriptEvent {
+    /// Decodes an event, skipping the fields it does not know.
+    pub fn decode(mut message: &[u8]) -> Result<TranscriptEvent, String> {
+        let input = &mut message;
+        let mut event = TranscriptEvent::default();
+        while !input.is_empty() {
+            let key = varint(input)?;
+            match (key >> 3, key & 7) {
+                (1, 2) => event.text = String::from_utf8_lossy(delimited(input)?).into_owned(),
+                (2, 0) => event.is_final = varint(input)? != 0,
+                (_, 0) => {
+                    varint(input)?;
+                }
+                (_, 1) => {
+                    take(input, 8)?;
+                }
+                (_, 2) => {
+                    delimited(input)?;
+                }
+                (_, 5) => {
+                    take(input, 4)?;
+                }
+                (number, wire) => return Err(format!("field {number} is of wire type {wire}, which proto3 does not have")),
+            }
+        }
+        Ok(event)
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_uint(out: &mut Vec<u8>, number: u64, value: u32) {
+    put_varint(out, number << 3);
+    put_varint(out, u64::from(value));
+}
+
+fn put_bytes(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+    put_varint(out, number << 3 | 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Takes a varint off the front of `input`.
+fn varint(input: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first().ok_or("the message ends inside a number")?;
+        *input = rest;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(String::from("a number runs past 64 bits"))
+}
+
+/// Takes a length-delimited field's bytes off the front of `input`.
+fn delimited<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = varint(input)?;
+    take(input, len)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: u64) -> Result<&'a [u8], String> {
+    let len = usize::try_from(len).ok().filter(|&len| len <= input.len()).ok_or("a field runs past the end of the message")?;
+    let (field, rest) = input.split_at(len);
+    *input = rest;
+    Ok(field)
+}
+
+/// `message` as gRPC sends it: uncompressed, after its length.
+fn framed(message: &[u8]) -> Bytes {
+    let mut framed = Vec::with_capacity(message.len() + 5);
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    Bytes::from(framed)
+}
+
+/// Takes the next whole message out of `received`, if one has come.
+fn next_message(received: &mut Vec<u8>) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let Some(&[compressed, a, b, c, d]) = received.get(..5) else {
+        return Ok(None);
+    };
+    if compressed != 0 {
+        anyhow::bail!("the server sent a compressed message, which the call did not ask for");
+    }
+    let len = u32::from_be_bytes([a, b, c, d]) as usize;
+    if len > MAX_MESSAGE {
+        anyhow::bail!("the server sent a message of {len} bytes, more than the {MAX_MESSAGE} taken");
+    }
+    if received.len() < 5 + len {
+        return Ok(None);
+    }
+    let message = received[5..5 + len].to_vec();
+    received.drain(..5 + len);
+    Ok(Some(message))
+}
+
+/// The sending end of the call. What is written is sent a message at a time by the call's
+/// thread; dropping it sends what is left, half-closes the call and waits for the thread.
+pub struct GrpcStream {
+    frames: Option<UnboundedSender<Vec<u8>>>,
+    /// The message being filled.
+    frame: Vec<u8>,
+    frame_bytes: usize,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl GrpcStream {
+    /// Makes the call to `options.url`, for samples laid out as `spec`.
+    pub fn connect(options: Options, spec: hound::WavSpec) -> Result<GrpcStream, anyhow::Error> {
+        let block_align = usize::from(spec.channels) * usize::from(spec.bits_per_sample / 8);
+        let frame_bytes = ((FRAME.as_secs_f64() * f64::from(spec.sample_rate)).round() as usize).max(1) * block_align;
+        let config = AudioConfig::new(spec, options.language.as_deref());
+        let url = options.url.clone();
+        let (frames, received) = tokio::sync::mpsc::unbounded_channel();
+        let (opened, outcome) = mpsc::channel();
+        let worker = std::thread::Builder::new().name(String::from("grpc")).spawn(move || {
+            match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime.block_on(call(options, config, received, opened)),
+                Err(err) => drop(opened.send(Err(err.into()))),
+            }
+        })?;
+        let opened = outcome.recv().unwrap_or_else(|_| Err(anyhow::anyhow!("the call's thread ended")));
+        if let Err(err) = opened {
+            worker.join().ok();
+            return Err(err.context(format!("cannot make a gRPC call to {url}")));
+        }
+        info!("Streaming to: {url}");
+        Ok(GrpcStream { frames: Some(frames), frame: Vec::with_capacity(frame_bytes), frame_bytes, worker: Some(worker) })
+    }
+
+    /// Hands the message filled so far to the call's thread.
+    fn send(&mut self) -> std::io::Result<()> {
+        let frame = std::mem::replace(&mut self.frame, Vec::with_capacity(self.frame_bytes));
+        match &self.frames {
+            Some(frames) if frames.send(frame).is_ok() => Ok(()),
+            _ => Err(std::io::Error::other("the gRPC call has ended")),
+        }
+    }
+}
+
+impl Write for GrpcStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let take = (self.frame_bytes - self.frame.len()).min(rest.len());
+            self.frame.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.frame.len() == self.frame_bytes {
+                self.send()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// A message goes once it is whole, not at each flush.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for GrpcStream {
+    fn drop(&mut self) {
+        if !self.frame.is_empty() {
+            self.send().ok();
+        }
+        // Which tells the thread to half-close the call.
+        self.frames = None;
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+    }
+}
+
+/// The call, from making it to its end; `opened` is told whether it could be made.
+async fn call(options: Options, config: AudioConfig, frames: UnboundedReceiver<Vec<u8>>, opened: mpsc::Sender<Result<(), anyhow::Error>>) {
+    let (_client, response, send) = match tokio::time::timeout(CONNECT_TIMEOUT, open(&options)).await {
+        Ok(Ok(call)) => call,
+        Ok(Err(err)) => return drop(opened.send(Err(err))),
+        Err(_) => return drop(opened.send(Err(anyhow::anyhow!("the server did not answer within {CONNECT_TIMEOUT:?}")))),
+    };
+    opened.send(Ok(())).ok();
+    let url = &options.url;
+    let sending = tokio::spawn(send_audio(send, config, frames));
+    let receiving = tokio::spawn(receive(response, options.log.clone(), options.language.clone()));
+    match future::select(sending, receiving).await {
+        Either::Left((sent, receiving)) => {
+            let received = tokio::time::timeout(CLOSE_WAIT, receiving).await.map(joined);
+            match (joined(sent), received) {
+                // How the server ended the call says more than that sending to it failed.
+                (_, Ok(Err(err))) | (Err(err), _) => error!(url = %url, "the gRPC call failed: {err:#}"),
+                (Ok(()), Ok(Ok(()))) => debug!(url = %url, "the gRPC call is over"),
+                (Ok(()), Err(_)) => {
+                    warn!(url = %url, "the server did not end the gRPC call within {CLOSE_WAIT:?}; its last transcripts may be missing");
+                }
+            }
+        }
+        Either::Right((received, sending)) => {
+            // Which drops the audio's receiver, so the recording stops.
+            sending.abort();
+            match joined(received) {
+                Ok(()) => error!(url = %url, "the server ended the gRPC call before the recording was over"),
+                Err(err) => error!(url = %url, "the gRPC call failed: {err:#}"),
+            }
+        }
+    }
+}
+
+fn joined(outcome: Result<Result<(), anyhow::Error>, tokio::task::JoinError>) -> Result<(), anyhow::Error> {
+    outcome.map_err(anyhow::Error::from).and_then(|outcome| outcome)
+}
+
+/// Connects to the server and makes the call, which the server then answers in its own time.
+async fn open(options: &Options) -> Result<(SendRequest<Bytes>, ResponseFuture, SendStream<Bytes>), anyhow::Error> {
+    let url = &options.url;
+    let host = url.host_str().unwrap_or_default();
+    let addrs = url.socket_addrs(|| None).with_context(|| format!("cannot resolve {host}"))?;
+    let tcp = TcpStream::connect(&addrs[..]).await.with_context(|| format!("cannot connect to {host}"))?;
+    tcp.set_nodelay(true)?;
+    let client = match url.scheme() {
+        "https" => {
+            // Servers may well turn down a TLS connection that does not ask for HTTP/2.
+            let tls = options.client.tls()?.request_alpns(&["h2"]).build()?;
+            let domain = host.trim_start_matches('[').trim_end_matches(']');
+            let tls = tokio_native_tls::TlsConnector::from(tls).connect(domain, tcp).await;
+            handshake(tls.map_err(|err| anyhow::anyhow!("the TLS handshake failed: {err}"))?).await?
+        }
+        _ => handshake(tcp).await?,
+    };
+    let mut client = client.ready().await?;
+    let (response, send) = client.send_request(request(options)?, false)?;
+    Ok((client, response, send))
+}
+
+/// Starts HTTP/2 on `io`, leaving a task to drive the connection.
+async fn handshake<T>(io: T) -> Result<SendRequest<Bytes>, anyhow::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("the HTTP/2 connection ended: {err}");
+        }
+    });
+    Ok(client)
+}
+
+/// The call's request, with the credential and headers of the uploads as its metadata.
+fn request(options: &Options) -> Result<Request<()>, anyhow::Error> {
+    let url = &options.url;
+    let authority = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+    let uri = format!("{}://{authority}{}{PATH}", url.scheme(), url.path().trim_end_matches('/'));
+    let mut request = Request::post(uri)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header("user-agent", format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))
+        .body(())?;
+    let name = |name: &str| HeaderName::from_bytes(name.as_bytes()).with_context(|| format!("`{name}` cannot be gRPC metadata"));
+    let metadata = request.headers_mut();
+    for header in &options.headers {
+        let value = HeaderValue::from_str(&header.value).with_context(|| format!("the value of `{}` cannot be gRPC metadata", header.name))?;
+        metadata.append(name(&header.name)?, value);
+    }
+    if let Some(auth) = &options.auth {
+        let mut value = HeaderValue::from_str(auth.value.expose()).context("the credential cannot be gRPC metadata")?;
+        value.set_sensitive(true);
+        metadata.insert(name(&auth.header)?, value);
+    }
+    Ok(request)
+}
+
+/// Sends the config, then the audio as it comes, and half-closes the call once it stops coming.
+async fn send_audio(mut send: SendStream<Bytes>, config: AudioConfig, mut frames: UnboundedReceiver<Vec<u8>>) -> Result<(), anyhow::Error> {
+    send.send_data(framed(&config.encode()), false)?;
+    while let Some(frame) = frames.recv().await {
+        // h2 holds on to what the server has no room for yet, rather than keep the audio waiting.
+        send.send_data(framed(&audio_frame(&frame)), false)?;
+    }
+    send.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+/// Takes in the server's events until it ends the call, and fails if it ended it with an error.
+async fn receive(response: ResponseFuture, log: Option<Arc<Mutex<File>>>, language: Option<String>) -> Result<(), anyhow::Error> {
+    let (head, mut body) = response.await?.into_parts();
+    // A call that fails at once has its status in the headers, and has no body or trailers.
+    let ended = status(&head.headers)?;
+    if head.status != StatusCode::OK {
+        anyhow::bail!("the server answered {} rather than taking the call; is it a gRPC server?", head.status);
+    }
+    let mut received = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data?;
+        body.flow_control().release_capacity(data.len())?;
+        received.extend_from_slice(&data);
+        while let Some(message) = next_message(&mut received)? {
+            match TranscriptEvent::decode(&message) {
+                Ok(event) if !event.is_final => debug!(text = event.text.trim(), "partial transcript"),
+                Ok(event) if event.text.trim().is_empty() => {}
+                Ok(event) => transcript::deliver(event.text.trim(), log.as_deref(), language.as_deref()),
+                Err(err) => warn!("skipping an event from the server that does not decode: {err}"),
+            }
+        }
+    }
+    match body.trailers().await? {
+        Some(trailers) if status(&trailers)? => Ok(()),
+        None if ended => Ok(()),
+        _ => anyhow::bail!("the server ended the call without a status"),
+    }
+}
+
+/// Whether `headers` have the call's status in them; an error if that is not OK.
+fn status(headers: &HeaderMap) -> Result<bool, anyhow::Error> {
+    let Some(code) = headers.get("grpc-status") else {
+        return Ok(false);
+    };
+    let code = code.to_str().unwrap_or_default();
+    if code == "0" {
+        return Ok(true);
+    }
+    let name = code.parse().ok().and_then(|code: usize| STATUSES.get(code)).unwrap_or(&"an unknown status");
+    match headers.get("grpc-message").map(|message| unescape(message.as_bytes())).filter(|message| !message.is_empty()) {
+        Some(message) => anyhow::bail!("the server ended the call with {name} ({code}): {message}"),
+        None => anyhow::bail!("the server ended the call with {name} ({code})"),
+    }
+}
+
+/// A `grpc-message`, with its percent-encoding undone.
+fn unescape(message: &[u8]) -> String {
+    let mut out = Vec::with_capacity(message.len());
+    let mut at = 0;
+    while let Some(&byte) = message.get(at) {
+        let hex = message.get(at + 1..at + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                at += 3;
+            }
+            _ => {
+                out.push(byte);
+                at += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,433 @@
+//! Gzip, for `--compress-upload`: a deflate encoder that is read as it goes.
+//!
+//! [`Gzip`] takes what it compresses from another reader, a block at a time, and is itself
+//! read as the body of the request, so that no more of a chunk is in memory than the block
+//! being compressed, the 32 KiB before it that matches may reach back into, and what the
+//! request has not taken yet. Each block is LZ77-matched, then coded with Huffman codes of its
+//! own, built from how often its symbols come, or stored as it is if that is shorter: little
+//! repeats in the noisy low bytes of PCM.
+
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::io::{self, ErrorKind, Read};
+
+/// Bytes compressed at a time, in one deflate block.
+const BLOCK: usize = 32 * 1024;
+/// How far back a match may be.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Earlier places with the same three bytes looked at for each match.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+const NONE: u32 = u32::MAX;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] =
+    [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order the code length code's lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// The gzip stream of what `inner` reads.
+pub struct Gzip<R> {
+    inner: R,
+    /// The window, then the block being compressed.
+    data: Vec<u8>,
+    /// Compressed bytes not read yet, from `at` on.
+    out: Vec<u8>,
+    at: usize,
+    bits: Bits,
+    crc: u32,
+    size: u32,
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> Gzip<R> {
+    pub fn new(inner: R) -> Self {
+        Gzip { inner, data: Vec::new(), out: Vec::new(), at: 0, bits: Bits::default(), crc: 0, size: 0, started: false, done: false }
+    }
+
+    /// Compresses the next block into `out`, or ends the stream.
+    fn fill(&mut self) -> io::Result<()> {
+        if !self.started {
+            // No name, no time, an unknown OS.
+            self.out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+            self.started = true;
+        }
+        let start = self.data.len();
+        self.data.resize(start + BLOCK, 0);
+        let mut end = start;
+        while end < self.data.len() {
+            match self.inner.read(&mut self.data[end..]) {
+                Ok(0) => break,
+                Ok(n) => end += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.data.truncate(end);
+        if end == start {
+            // A last, empty block with the fixed codes: just their end of block.
+            self.bits.put(1, 1);
+            self.bits.put(1, 2);
+            self.bits.put(0, 7);
+            self.bits.align();
+            self.bits.drain(&mut self.out);
+            self.out.extend_from_slice(&self.crc.to_le_bytes());
+            self.out.extend_from_slice(&self.size.to_le_bytes());
+            self.done = true;
+            return Ok(());
+        }
+        self.crc = crc32(self.crc, &self.data[start..]);
+        self.size = self.size.wrapping_add((end - start) as u32);
+        let tokens = matches(&self.data, start);
+        block(&mut self.bits, &tokens, &self.data[start..]);
+        self.bits.drain(&mut self.out);
+        let before = self.data.len().saturating_sub(WINDOW);
+        self.data.drain(..before);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Gzip<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.at == self.out.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.out.clear();
+            self.at = 0;
+            self.fill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.at);
+        buf[..n].copy_from_slice(&self.out[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+/// `data` gzip-compressed.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    Gzip::new(data).read_to_end(&mut out).expect("reading memory does not fail");
+    out
+}
+
+/// A literal byte, or a match of `len` bytes `dist` back.
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { len: u16, dist: u16 },
+}
+
+/// The tokens of `data[start..]`, with matches reaching back into `data[..start]` too.
+fn matches(data: &[u8], start: usize) -> Vec<Token> {
+    let hash = |p: usize| (((u32::from(data[p]) << 10) ^ (u32::from(data[p + 1]) << 5) ^ u32::from(data[p + 2])) & ((1 << HASH_BITS) - 1)) as usize;
+    // The last place each hash was seen, and for each place the one before it.
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; data.len()];
+    let insert = |p: usize, head: &mut [u32], prev: &mut [u32]| {
+        if p + MIN_MATCH <= data.len() {
+            let h = hash(p);
+            prev[p] = head[h];
+            head[h] = p as u32;
+        }
+    };
+    for p in 0..start {
+        insert(p, &mut head, &mut prev);
+    }
+    let mut tokens = Vec::new();
+    let mut p = start;
+    while p < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if p + MIN_MATCH <= data.len() {
+            let longest = MAX_MATCH.min(data.len() - p);
+            let mut candidate = head[hash(p)];
+            let mut chain = 0;
+            while candidate != NONE && chain < MAX_CHAIN {
+                let at = candidate as usize;
+                if p - at > WINDOW {
+                    break;
+                }
+                let len = data[at..].iter().zip(&data[p..p + longest]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, p - at);
+                    if len == longest {
+                        break;
+                    }
+                }
+                candidate = prev[at];
+                chain += 1;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            tokens.push(Token::Match { len: best_len as u16, dist: best_dist as u16 });
+            for q in p..p + best_len {
+                insert(q, &mut head, &mut prev);
+            }
+            p += best_len;
+        } else {
+            tokens.push(Token::Literal(data[p]));
+            insert(p, &mut head, &mut prev);
+            p += 1;
+        }
+    }
+    tokens
+}
+
+/// The code of `value` among `base`'s, and its extra bits.
+fn code(base: &[u16], value: u16) -> (usize, u16) {
+    let code = base.partition_point(|&base| base <= value) - 1;
+    (code, value - base[code])
+}
+
+/// Writes `tokens`, which stand for `raw`, as one block: with Huffman codes of their own, or
+/// stored if that comes out shorter.
+fn block(bits: &mut Bits, tokens: &[Token], raw: &[u8]) {
+    let mut lit_freqs = [0u32; 286];
+    let mut dist_freqs = [0u32; 30];
+    lit_freqs[256] = 1;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => lit_freqs[usize::from(byte)] += 1,
+            Token::Match { len, dist } => {
+                lit_freqs[257 + code(&LENGTH_BASE, len).0] += 1;
+                dist_freqs[code(&DIST_BASE, dist).0] += 1;
+            }
+        }
+    }
+    let lit_lens = lengths(&lit_freqs, 15);
+    let dist_lens = lengths(&dist_freqs, 15);
+    let hlit = 257 + lit_lens[257..].iter().rposition(|&len| len > 0).map_or(0, |last| last + 1);
+    let hdist = 1 + dist_lens.iter().rposition(|&len| len > 0).unwrap_or(0);
+    let runs = runs(&[&lit_lens[..hlit], &dist_lens[..hdist]].concat());
+    let mut cl_freqs = [0u32; 19];
+    for &(symbol, _) in &runs {
+        cl_freqs[usize::from(symbol)] += 1;
+    }
+    let cl_lens = lengths(&cl_freqs, 7);
+    let hclen = 4.max(1 + CODE_LENGTH_ORDER.iter().rposition(|&symbol| cl_lens[symbol] > 0).unwrap_or(0));
+
+    let run_bits = |&(symbol, _): &(u8, u8)| u64::from(cl_lens[usize::from(symbol)]) + [2, 3, 7].get(usize::from(symbol).wrapping_sub(16)).copied().unwrap_or(0);
+    let header = 17 + 3 * hclen as u64 + runs.iter().map(run_bits).sum::<u64>();
+    let body: u64 = (0..286).map(|symbol| u64::from(lit_freqs[symbol]) * u64::from(lit_lens[symbol])).sum::<u64>()
+        + (0..30).map(|symbol| u64::from(dist_freqs[symbol]) * u64::from(dist_lens[symbol] + DIST_EXTRA[symbol])).sum::<u64>()
+        + (0..29).map(|code| u64::from(lit_freqs[257 + code]) * u64::from(LENGTH_EXTRA[code])).sum::<u64>();
+    if header + body >= 3 + 7 + 32 + 8 * raw.len() as u64 {
+        bits.put(0, 1);
+        bits.put(0, 2);
+        bits.align();
+        let len = raw.len() as u16;
+        bits.put(u32::from(len), 16);
+        bits.put(u32::from(!len), 16);
+        bits.bytes(raw);
+        return;
+    }
+
+    bits.put(0, 1);
+    bits.put(2, 2);
+    bits.put((hlit - 257) as u32, 5);
+    bits.put((hdist - 1) as u32, 5);
+    bits.put((hclen - 4) as u32, 4);
+    for &symbol in &CODE_LENGTH_ORDER[..hclen] {
+        bits.put(u32::from(cl_lens[symbol]), 3);
+    }
+    let cl_codes = codes(&cl_lens);
+    for &(symbol, extra) in &runs {
+        bits.code(cl_codes[usize::from(symbol)], cl_lens[usize::from(symbol)]);
+        match symbol {
+            16 => bits.put(u32::from(extra), 2),
+            17 => bits.put(u32::from(extra), 3),
+            18 => bits.put(u32::from(extra), 7),
+            _ => {}
+        }
+    }
+    let (lit_codes, dist_codes) = (codes(&lit_lens), codes(&dist_lens));
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => bits.code(lit_codes[usize::from(byte)], lit_lens[usize::from(byte)]),
+            Token::Match { len, dist } => {
+                let (len_code, len_extra) = code(&LENGTH_BASE, len);
+                bits.code(lit_codes[257 + len_code], lit_lens[257 + len_code]);
+                bits.put(u32::from(len_extra), LENGTH_EXTRA[len_code]);
+                let (dist_code, dist_extra) = code(&DIST_BASE, dist);
+                bits.code(dist_codes[dist_code], dist_lens[dist_code]);
+                bits.put(u32::from(dist_extra), DIST_EXTRA[dist_code]);
+            }
+        }
+    }
+    bits.code(lit_codes[256], lit_lens[256]);
+}
+
+/// The code lengths, as deflate sends them: runs of a length and of zeros shortened with the
+/// symbols 16 (the last length again, 3 to 6 times), 17 (3 to 10 zeros) and 18 (11 to 138),
+/// each with the extra bits' value.
+fn runs(lens: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < lens.len() {
+        let len = lens[i];
+        let mut run = lens[i..].iter().take_while(|&&next| next == len).count();
+        i += run;
+        if len == 0 {
+            while run >= 11 {
+                let n = run.min(138);
+                runs.push((18, (n - 11) as u8));
+                run -= n;
+            }
+            if run >= 3 {
+                runs.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            runs.push((len, 0));
+            run -= 1;
+            while run >= 3 {
+                let n = run.min(6);
+                runs.push((16, (n - 3) as u8));
+                run -= n;
+            }
+        }
+        runs.extend(std::iter::repeat_n((len, 0), run));
+    }
+    runs
+}
+
+/// Huffman code lengths for `freqs`, none longer than `limit`. Every code has at least two
+/// symbols, so that it is complete.
+fn lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    for symbol in 0..2 {
+        if freqs.iter().filter(|&&freq| freq > 0).count() < 2 && freqs[symbol] == 0 {
+            freqs[symbol] = 1;
+        }
+    }
+    loop {
+        let lens = tree(&freqs);
+        if lens.iter().all(|&len| len <= limit) {
+            return lens;
+        }
+        // Flatter counts make a shallower tree; at worst they all come out the same.
+        for freq in freqs.iter_mut().filter(|freq| **freq > 0) {
+            *freq = freq.div_ceil(2);
+        }
+    }
+}
+
+/// The depth of each symbol in the Huffman tree of `freqs`.
+fn tree(freqs: &[u32]) -> Vec<u8> {
+    let mut parents: Vec<usize> = Vec::new();
+    let mut heap = BinaryHeap::new();
+    for (symbol, &freq) in freqs.iter().enumerate().filter(|(_, &freq)| freq > 0) {
+        heap.push(Reverse((u64::from(freq), parents.len(), symbol)));
+        parents.push(usize::MAX);
+    }
+    let leaves: Vec<usize> = freqs.iter().enumerate().filter(|(_, &freq)| freq > 0).map(|(symbol, _)| symbol).collect();
+    while heap.len() > 1 {
+        let Reverse((a, a_node, _)) = heap.pop().expect("two nodes");
+        let Reverse((b, b_node, _)) = heap.pop().expect("two nodes");
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[a_node] = node;
+        parents[b_node] = node;
+        heap.push(Reverse((a + b, node, usize::MAX)));
+    }
+    let mut lens = vec![0; freqs.len()];
+    for (node, &symbol) in leaves.iter().enumerate() {
+        let (mut depth, mut at) = (0u8, node);
+        while parents[at] != usize::MAX {
+            at = parents[at];
+            depth = depth.saturating_add(1);
+        }
+        lens[symbol] = depth;
+    }
+    lens
+}
+
+/// The canonical codes of the lengths `lens`.
+fn codes(lens: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &len in lens.iter().filter(|&&len| len > 0) {
+        counts[usize::from(len)] += 1;
+    }
+    let mut next = [0u16; 16];
+    let mut code = 0u16;
+    for len in 1..16 {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+    lens.iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let code = next[usize::from(len)];
+            next[usize::from(len)] += 1;
+            code
+        })
+        .collect()
+}
+
+/// Bits gathered least significant first, as deflate packs them.
+#[derive(Default)]
+struct Bits {
+    acc: u64,
+    count: u8,
+    bytes: Vec<u8>,
+}
+
+impl Bits {
+    fn put(&mut self, value: u32, count: u8) {
+        self.acc |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// A Huffman code, which goes most significant bit first.
+    fn code(&mut self, code: u16, len: u8) {
+        self.put(u32::from(code.reverse_bits() >> (16 - len)), len);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.put(0, 8 - self.count);
+        }
+    }
+
+    /// Whole bytes, once aligned.
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Moves the whole bytes so far to `out`.
+    fn drain(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.bytes);
+    }
+}
+
+/// The CRC-32 of `data` carried on from `crc`, as gzip's trailer has it.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut n = 0;
+        while n < 256 {
+            let mut c = n as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[n] = c;
+            n += 1;
+        }
+        table
+    };
+    !data.iter().fold(!crc, |crc, &byte| TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8))
+}
@@ -0,0 +1,85 @@
+//! The health check: asking the transcription server whether it is there before anything is
+//! recorded, so that a typo in `--url` or a server that is not running shows at once rather
+//! than at the first upload, minutes in.
+//!
+//! The probe is a HEAD request to the URL, with the uploads' headers and credential. A server
+//! that only takes POSTs there answers it 404, 405 or 501, and is then sent 100 ms of silence
+//! as a chunk would be. Whether the server could be reached, how long it took to answer and
+//! with what status is the first line logged. The check passes unless the server could not be
+//! reached or answered 401, 403, 404, 407 or 5xx: it is there, at that URL, takes the
+//! credential and works, though it may well turn the silence down. A failed check is a
+//! warning, and recording goes on; with `--require-healthcheck` it is an error instead, and
+//! `--skip-healthcheck` sends nothing, for a server that cannot take probes.
+
+use crate::upload::{excerpt, Endpoint};
+use reqwest::Method;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The most the probe waits for an answer, whatever `--timeout` allows an upload.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the probe went.
+#[derive(Debug)]
+pub struct Health {
+    /// HEAD, or POST for the silence.
+    pub method: Method,
+    /// The status the server answered, or why it could not be reached.
+    pub answer: Result<u16, String>,
+    /// How long the server took to answer, or to fail.
+    pub latency: Duration,
+}
+
+impl Health {
+    /// Whether the server is there, at the URL, and takes the credential.
+    pub fn passed(&self) -> bool {
+        self.answer.as_ref().is_ok_and(|&status| !matches!(status, 401 | 403 | 404 | 407 | 500..))
+    }
+}
+
+/// Probes `endpoint`, whose URL is `url` as given, and logs how it went.
+pub fn check(endpoint: &Endpoint, url: &str) -> Health {
+    let timeout = Some(endpoint.timeout.map_or(PROBE_TIMEOUT, |timeout| timeout.min(PROBE_TIMEOUT)));
+    let probe = |silence: Option<&[u8]>| {
+        let started = Instant::now();
+        let answer = endpoint.probe(silence, timeout);
+        (answer, started.elapsed())
+    };
+    let (mut method, (mut answer, mut latency)) = (Method::HEAD, probe(None));
+    if answer.as_ref().is_ok_and(|response| matches!(response.status, 404 | 405 | 501)) {
+        method = Method::POST;
+        (answer, latency) = probe(Some(&silence()));
+    }
+    let latency_ms = latency.as_millis() as u64;
+    let health = Health { method, answer: answer.as_ref().map(|response| response.status).map_err(|err| format!("{err:#}")), latency };
+    let method = health.method.as_str();
+    match answer {
+        Ok(response) if health.passed() => {
+            info!(url, reachable = true, method, status = response.status, latency_ms, "health check passed");
+        }
+        Ok(response) => {
+            let why = match response.status {
+                401 | 403 | 407 => "the credential was not taken",
+                404 => "there is nothing at that URL; is it right?",
+                _ => "the server is failing",
+            };
+            let body = excerpt(&response.body);
+            warn!(url, reachable = true, method, status = response.status, latency_ms, body, "health check failed: {why}");
+        }
+        Err(err) => warn!(url, reachable = false, method, latency_ms, "health check failed: the server cannot be reached: {err:#}"),
+    }
+    health
+}
+
+/// 100 ms of 16 kHz mono silence, as a WAV.
+fn silence() -> Vec<u8> {
+    let spec = hound::WavSpec { channels: 1, sample_rate: 16_000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut wav = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut wav, spec).expect("a WAV header fits in memory");
+    for _ in 0..1600 {
+        writer.write_sample(0i16).expect("a sample fits in memory");
+    }
+    writer.finalize().expect("a WAV fits in memory");
+    wav.into_inner()
+}
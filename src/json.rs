@@ -0,0 +1,266 @@
+//! Just enough JSON for log lines, and for reading back the little the recorder writes.
+
+use std::fmt::Write;
+
+/// `s` as a quoted JSON string.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                write!(out, "\\u{:04x}", u32::from(c)).ok();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A parsed JSON value. Objects keep their members in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// A number that is a whole one, and not negative.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= u64::MAX as f64 => Some(n as u64),
+            _ => None,
+        }
+    }
+}
+
+/// Written out again as compact JSON.
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) => write!(f, "{n}"),
+            Value::String(s) => f.write_str(&string(s)),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{item}", if i > 0 { "," } else { "" })?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(members) => {
+                f.write_str("{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    write!(f, "{}{}:{value}", if i > 0 { "," } else { "" }, string(name))?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// Parses `text`, which must hold one value and nothing else but whitespace.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { text: text.as_bytes(), at: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.at < parser.text.len() {
+        return parser.error("unexpected text after the value");
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.at).is_some_and(|b| b" \t\r\n".contains(b)) {
+            self.at += 1;
+        }
+    }
+
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{what} at byte {}", self.at))
+    }
+
+    /// Consumes `literal` if it comes next.
+    fn eat(&mut self, literal: &str) -> bool {
+        let found = self.text[self.at..].starts_with(literal.as_bytes());
+        if found {
+            self.at += literal.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.text.get(self.at) {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            _ if self.eat("null") => Ok(Value::Null),
+            Some(_) => self.error("expected a value"),
+            None => self.error("unexpected end"),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.at += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.text.get(self.at) != Some(&b'"') {
+                return self.error("expected a member name");
+            }
+            let name = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(":") {
+                return self.error("expected `:`");
+            }
+            members.push((name, self.value()?));
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Ok(Value::Object(members));
+            }
+            if !self.eat(",") {
+                return self.error("expected `,` or `}`");
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.at += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Ok(Value::Array(items));
+            }
+            if !self.eat(",") {
+                return self.error("expected `,` or `]`");
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.at += 1;
+        let mut out = Vec::new();
+        loop {
+            let Some(&byte) = self.text.get(self.at) else {
+                return self.error("unterminated string");
+            };
+            self.at += 1;
+            match byte {
+                b'"' => return String::from_utf8(out).or_else(|_| self.error("invalid UTF-8")),
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.at) else {
+                        return self.error("unterminated string");
+                    };
+                    self.at += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return self.error("invalid escape"),
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte => out.push(byte),
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, just past the `u`, and of the low surrogate after it
+    /// if it is a high one.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xD800..=0xDBFF if self.eat("\\u") => {
+                let low = self.hex4()?;
+                0x10000 + ((high - 0xD800) << 10) + low.wrapping_sub(0xDC00)
+            }
+            code => code,
+        };
+        char::from_u32(code).map_or_else(|| self.error("invalid \\u escape"), Ok)
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.at..self.at + 4).and_then(|digits| std::str::from_utf8(digits).ok());
+        match digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()) {
+            Some(code) => {
+                self.at += 4;
+                Ok(code)
+            }
+            None => self.error("invalid \\u escape"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.at;
+        while self.text.get(self.at).is_some_and(|b| b"+-.eE0123456789".contains(b)) {
+            self.at += 1;
+        }
+        let text = std::str::from_utf8(&self.text[start..self.at]).unwrap_or_default();
+        match text.parse() {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) => {
+                self.at = start;
+                self.error("invalid number")
+            }
+        }
+    }
+}
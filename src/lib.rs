@@ -0,0 +1,55 @@
+//! Building blocks of the recorder, split out of the binary so they can be tested.
+
+pub mod archive;
+pub mod batch;
+pub mod breaker;
+pub mod bwf;
+pub mod cli;
+pub mod config;
+pub mod control;
+#[cfg(feature = "denoise")]
+pub mod denoise;
+pub mod device;
+pub mod devices;
+pub mod dsp;
+pub mod encode;
+pub mod flac;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod gzip;
+pub mod health;
+pub mod json;
+pub mod logging;
+pub mod loopback;
+pub mod memory;
+pub mod meter;
+pub mod mix;
+#[cfg(feature = "mp3")]
+pub mod mp3;
+pub mod naming;
+pub mod output;
+pub mod pipe;
+pub mod proxy;
+pub mod ptt;
+pub mod queue;
+pub mod reconnect;
+pub mod record;
+pub mod resample;
+pub mod retention;
+pub mod rf64;
+pub mod ring;
+pub mod session;
+pub mod shutdown;
+pub mod sink;
+#[cfg(unix)]
+pub mod socket;
+pub mod space;
+pub mod spool;
+pub mod stream;
+pub mod transcript;
+pub mod upload;
+pub mod vad;
+#[cfg(feature = "webrtc-vad")]
+pub mod webrtc_vad;
+#[cfg(feature = "websocket")]
+pub mod websocket;
@@ -0,0 +1,173 @@
+//! Diagnostics.
+//!
+//! Everything except the transcripts themselves goes through `tracing` to stderr, so stdout
+//! carries nothing but transcript text and can be piped. This is a deliberately small
+//! subscriber: one line per event, filtered by level, no spans. Recording from several
+//! devices, each line says which one it is about instead: the threads of a device's session
+//! carry its label (see [`set_device`] and [`spawn`]).
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// How often a [`Ticker`] between ticks looks whether it has been stopped.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Maps `--verbose` / `--quiet` to a level filter: info by default, each `-v` one step more
+/// verbose, `--quiet` only warnings and errors.
+pub fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::WARN;
+    }
+    match verbose {
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+thread_local! {
+    /// The device this thread records or uploads for, when there are several.
+    static DEVICE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Labels the events of this thread with `device=label`.
+pub fn set_device(label: &str) {
+    DEVICE.with(|device| *device.borrow_mut() = Some(label.to_owned()));
+}
+
+/// Like [`std::thread::spawn`], but the new thread labels its events with this one's device.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let device = DEVICE.with(|device| device.borrow().clone());
+    std::thread::spawn(move || {
+        if let Some(device) = device {
+            set_device(&device);
+        }
+        f()
+    })
+}
+
+/// A thread doing something every so often, such as logging the stats line.
+pub struct Ticker {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Ticker {
+    /// Runs `tick` every `interval`, the first time one `interval` from now, on a thread
+    /// spawned as [`spawn`] does.
+    pub fn every(interval: Duration, mut tick: impl FnMut() + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let handle = spawn(move || {
+            let mut next = Instant::now() + interval;
+            loop {
+                while Instant::now() < next {
+                    if stopping.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    std::thread::sleep(next.saturating_duration_since(Instant::now()).min(TICK));
+                }
+                tick();
+                next += interval;
+            }
+        });
+        Ticker { stop, handle }
+    }
+
+    /// Stops it, between ticks.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().ok();
+    }
+}
+
+/// Installs a [`Logger`] writing to stderr as the global subscriber.
+pub fn init(max_level: LevelFilter) {
+    let logger = Logger::new(max_level, Box::new(std::io::stderr()));
+    tracing::subscriber::set_global_default(logger).expect("logger installed twice");
+}
+
+/// Writes each enabled event as `LEVEL message key=value...` on its own line.
+pub struct Logger {
+    max_level: LevelFilter,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(max_level: LevelFilter, out: Box<dyn Write + Send>) -> Self {
+        Logger { max_level, out: Mutex::new(out) }
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut line = LineVisitor::default();
+        event.record(&mut line);
+        DEVICE.with(|device| {
+            if let Some(device) = device.borrow().as_deref() {
+                write!(line.fields, " device={device:?}").ok();
+            }
+        });
+        let level = event.metadata().level();
+        if let Ok(mut out) = self.out.lock() {
+            writeln!(out, "{level:>5} {}{}", line.message, line.fields).ok();
+        }
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[derive(Default)]
+struct LineVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for LineVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            write!(self.fields, " {}={value:?}", field.name()).ok();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{value:?}").ok();
+        } else {
+            write!(self.fields, " {}={value:?}", field.name()).ok();
+        }
+    }
+}
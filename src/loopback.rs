@@ -0,0 +1,98 @@
+//! `--loopback`: recording what the machine plays instead of a microphone.
+//!
+//! How that works depends on the host. WASAPI captures an output device in loopback mode when
+//! an input stream is built on it, so the device is simply looked up among the outputs. ALSA
+//! has no loopback of its own, so the capture goes through the PulseAudio plugin, which
+//! PipeWire provides as well: its `pulse` device records from the source `PULSE_SOURCE` names,
+//! and every sink has a monitor source, `<sink>.monitor`, carrying what it plays. Other hosts
+//! have no loopback, and the option fails rather than record the microphone instead.
+
+use crate::device::match_device;
+use cpal::traits::{DeviceTrait, HostTrait};
+
+/// The PulseAudio name for the monitor of whatever sink is the default.
+pub const DEFAULT_MONITOR: &str = "@DEFAULT_MONITOR@";
+
+/// The ALSA device of the PulseAudio plugin.
+const PULSE_DEVICE: &str = "pulse";
+
+/// Finds the device that records what `output` plays: "default" for the default output, or an
+/// output device (WASAPI) or sink (PulseAudio) matched as `--device` matches inputs.
+pub fn select(host: &cpal::Host, output: &str) -> Result<cpal::Device, anyhow::Error> {
+    match host.id().name() {
+        "WASAPI" => select_output(host, output),
+        "ALSA" => select_monitor(host, output),
+        name => anyhow::bail!(
+            "--loopback is not supported on the {name} host; it needs WASAPI on Windows, or \
+             PulseAudio or PipeWire through ALSA on Linux"
+        ),
+    }
+}
+
+fn select_output(host: &cpal::Host, query: &str) -> Result<cpal::Device, anyhow::Error> {
+    if query == "default" {
+        return host
+            .default_output_device()
+            .ok_or_else(|| anyhow::anyhow!("host {} has no default output device", host.id().name()));
+    }
+    let mut devices: Vec<cpal::Device> = host.output_devices()?.collect();
+    let names: Vec<Option<String>> = devices.iter().map(|d| d.name().ok()).collect();
+    let index = match_device(&names, query)?;
+    Ok(devices.swap_remove(index))
+}
+
+fn select_monitor(host: &cpal::Host, sink: &str) -> Result<cpal::Device, anyhow::Error> {
+    let sink = match sink.parse::<usize>() {
+        // An index into the sinks as `devices` lists them.
+        Ok(index) => sinks()?
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| anyhow::anyhow!("loopback index {index} is out of range; run `devices` to list them"))?,
+        Err(_) => sink.to_owned(),
+    };
+    // Read by the plugin each time the device is opened, so a reconnect finds the same source.
+    std::env::set_var("PULSE_SOURCE", monitor_source(&sink));
+    host.input_devices()?.find(|device| device.name().is_ok_and(|name| name == PULSE_DEVICE)).ok_or_else(|| {
+        anyhow::anyhow!(
+            "--loopback on ALSA records through the `{PULSE_DEVICE}` device of the PulseAudio or PipeWire \
+             plugin, and there is none"
+        )
+    })
+}
+
+/// The PulseAudio source that carries what `sink` plays.
+pub fn monitor_source(sink: &str) -> String {
+    match sink {
+        "default" => DEFAULT_MONITOR.to_owned(),
+        monitor if monitor.ends_with(".monitor") || monitor.starts_with('@') => monitor.to_owned(),
+        sink => format!("{sink}.monitor"),
+    }
+}
+
+/// What `--loopback` can record from on `host`, for the `devices` listing: the output devices
+/// with WASAPI, the PulseAudio sinks with ALSA, nothing elsewhere.
+pub fn sources(host: &cpal::Host) -> Result<Vec<String>, anyhow::Error> {
+    match host.id().name() {
+        "WASAPI" => Ok(host.output_devices()?.filter_map(|device| device.name().ok()).collect()),
+        "ALSA" => sinks(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The PulseAudio sinks, as `pactl` lists them.
+fn sinks() -> Result<Vec<String>, anyhow::Error> {
+    let output = std::process::Command::new("pactl")
+        .args(["list", "short", "sinks"])
+        .output()
+        .map_err(|err| anyhow::anyhow!("failed to run pactl to list the sinks: {err}"))?;
+    if !output.status.success() {
+        anyhow::bail!("pactl failed to list the sinks: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(parse_sinks(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The sink names in `pactl list short sinks` output: one sink per line, tab-separated, the
+/// name second.
+pub fn parse_sinks(listing: &str) -> Vec<String> {
+    listing.lines().filter_map(|line| line.split('\t').nth(1)).map(str::to_owned).collect()
+}
@@ -0,0 +1,97 @@
+//! `--in-memory`: chunks built and uploaded without touching the filesystem.
+//!
+//! The writer thread writes each chunk into a [`MemoryFile`] instead of a file on disk. hound
+//! keeps its writer until it finalizes the chunk, and gives nothing back then, so the file
+//! leaves its bytes on a [`Shelf`] as it is dropped, under the chunk's number, for the upload
+//! to take; no later chunk can overwrite one while it is being sent. One that is discarded
+//! and opened again under the same number is replaced on the shelf by what follows.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+/// The finalized chunks waiting to be uploaded, by number.
+#[derive(Clone, Default)]
+pub struct Shelf(Arc<Mutex<HashMap<u64, Vec<u8>>>>);
+
+impl Shelf {
+    /// A file for chunk `seq`, with room for `capacity` bytes up front.
+    pub fn file(&self, seq: u64, capacity: usize) -> MemoryFile {
+        MemoryFile { data: Cursor::new(Vec::with_capacity(capacity)), seq, shelf: self.clone() }
+    }
+
+    /// Whether chunk `seq` is on the shelf.
+    pub fn contains(&self, seq: u64) -> bool {
+        self.0.lock().is_ok_and(|shelf| shelf.contains_key(&seq))
+    }
+
+    /// Takes chunk `seq` off the shelf.
+    pub fn take(&self, seq: u64) -> Option<Vec<u8>> {
+        self.0.lock().ok()?.remove(&seq)
+    }
+}
+
+/// A chunk file held in memory, shelved when dropped: see [`Shelf`].
+pub struct MemoryFile {
+    data: Cursor<Vec<u8>>,
+    seq: u64,
+    shelf: Shelf,
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let data = std::mem::take(self.data.get_mut());
+        if let Ok(mut shelf) = self.shelf.0.lock() {
+            shelf.insert(self.seq, data);
+        }
+    }
+}
+
+/// Where a chunk is recorded without `--in-memory`: to its file, or into memory while the disk
+/// is all but full (see [`crate::space`]).
+pub enum ChunkFile {
+    Disk(BufWriter<File>),
+    Memory(MemoryFile),
+}
+
+impl Write for ChunkFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ChunkFile::Disk(file) => file.write(buf),
+            ChunkFile::Memory(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ChunkFile::Disk(file) => file.flush(),
+            ChunkFile::Memory(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for ChunkFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            ChunkFile::Disk(file) => file.seek(pos),
+            ChunkFile::Memory(file) => file.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! `--meter`: a live input level display on stderr.
+//!
+//! The meter runs on the writer thread and is fed the [`Level`] each callback buffer carries
+//! through the queue, so the audio callback does no extra work for it. Readings are counted in
+//! captured samples rather than wall-clock time, so the display keeps pace with the audio.
+
+use crate::dsp::Level;
+use std::io::Write;
+
+/// How much audio each reading covers.
+const INTERVAL_MS: u64 = 100;
+
+/// Peak level below which the input counts as silent.
+const NO_SIGNAL_DBFS: f32 = -60.0;
+
+/// How long the input has to stay silent before the meter asks whether the device is right.
+const NO_SIGNAL_SECS: u64 = 5;
+
+/// Width of the bar, which spans -60 to 0 dBFS.
+const BAR_WIDTH: usize = 30;
+
+pub struct Meter {
+    device: String,
+    interval_samples: u64,
+    no_signal_samples: u64,
+    /// What has been captured since the last reading.
+    pending: Level,
+    /// Samples since the level was last above [`NO_SIGNAL_DBFS`].
+    quiet: u64,
+    hinted: bool,
+    out: Box<dyn Write + Send>,
+}
+
+impl Meter {
+    /// A meter for `device`, whose buffers hold `channels` interleaved channels at
+    /// `sample_rate`.
+    pub fn new(device: &str, sample_rate: u32, channels: u16, out: Box<dyn Write + Send>) -> Self {
+        let samples_per_sec = u64::from(sample_rate) * u64::from(channels.max(1));
+        Meter {
+            device: device.to_owned(),
+            interval_samples: (samples_per_sec * INTERVAL_MS / 1000).max(1),
+            no_signal_samples: samples_per_sec * NO_SIGNAL_SECS,
+            pending: Level::default(),
+            quiet: 0,
+            hinted: false,
+            out,
+        }
+    }
+
+    /// Adds a buffer's level, redrawing the meter each time a reading's worth has arrived.
+    pub fn add(&mut self, level: Level) {
+        self.pending.add(level);
+        if self.pending.samples < self.interval_samples {
+            return;
+        }
+        let reading = std::mem::take(&mut self.pending);
+        if reading.peak_dbfs() < NO_SIGNAL_DBFS {
+            self.quiet += reading.samples;
+        } else {
+            self.quiet = 0;
+            self.hinted = false;
+        }
+        write!(self.out, "\r{}", render(&reading)).ok();
+        if self.quiet >= self.no_signal_samples && !self.hinted {
+            self.hinted = true;
+            writeln!(
+                self.out,
+                "\nno signal? `{}` has stayed below {NO_SIGNAL_DBFS} dBFS for {NO_SIGNAL_SECS}s; check it is unmuted and the right --device",
+                self.device
+            )
+            .ok();
+        }
+        self.out.flush().ok();
+    }
+}
+
+/// One meter line: a bar filled to the RMS level with `|` at the peak, then both in dBFS.
+pub fn render(level: &Level) -> String {
+    let position = |dbfs: f32| (((dbfs - NO_SIGNAL_DBFS) / -NO_SIGNAL_DBFS).clamp(0.0, 1.0) * BAR_WIDTH as f32) as usize;
+    let (rms, peak) = (level.rms_dbfs(), level.peak_dbfs());
+    let filled = position(rms);
+    let mut bar: Vec<char> = (0..BAR_WIDTH).map(|i| if i < filled { '#' } else { ' ' }).collect();
+    if peak >= NO_SIGNAL_DBFS {
+        bar[position(peak).min(BAR_WIDTH - 1)] = '|';
+    }
+    format!("[{}] rms {rms:6.1} dBFS  peak {peak:6.1} dBFS", bar.into_iter().collect::<String>())
+}
@@ -0,0 +1,149 @@
+//! `--mix`: several input devices mixed into one mono stream before it is cut into chunks.
+//!
+//! The first `--device` sets the clock. Each of its callbacks is mixed with as much audio
+//! from each of the other devices, taken from a buffer their own callbacks fill, so the chunks
+//! keep the first device's pace. No two devices run at quite the same rate, and left alone
+//! those buffers would slowly fill up or run dry. A [`Mixer`] keeps each near its target
+//! instead: one holding too much drops a sample, one holding too little repeats one, at most
+//! once per callback, far too seldom to hear at the rates clocks drift apart. A device that
+//! captures at another rate altogether is resampled on the way in.
+//!
+//! The callbacks themselves only copy their audio into a ring each; the mixing happens on a
+//! thread of its own, reading the other devices through a [`Source`] each.
+
+use crate::resample::Resampler;
+use crate::ring::Consumer;
+use cpal::{FromSample, Sample};
+use std::collections::VecDeque;
+
+/// The audio of one device on the way to the mix.
+struct Lane {
+    gain: f32,
+    buffer: VecDeque<f32>,
+    /// Set once the buffer first reaches the target; until then the device is left out.
+    primed: bool,
+}
+
+/// Mixes the devices of a `--mix` into one mono signal; lane 0 is the device that sets the
+/// clock.
+pub struct Mixer {
+    lanes: Vec<Lane>,
+    /// Samples each of the other lanes is kept at, give or take `slack`.
+    target: usize,
+    slack: usize,
+}
+
+impl Mixer {
+    /// A mixer for devices with the linear `gains`, the clock device's first, keeping `target`
+    /// samples buffered from each of the others.
+    pub fn new(gains: &[f32], target: usize) -> Self {
+        let lanes = gains.iter().map(|&gain| Lane { gain, buffer: VecDeque::new(), primed: false }).collect();
+        Mixer { lanes, target: target.max(1), slack: (target / 2).max(1) }
+    }
+
+    /// Adds mono audio from one of the other devices.
+    pub fn push(&mut self, lane: usize, samples: &[f32]) {
+        let target = self.target;
+        let Some(lane) = self.lanes.get_mut(lane) else {
+            return;
+        };
+        lane.buffer.extend(samples);
+        // A device that ran on while the clock device stalled starts over from the target.
+        if lane.buffer.len() > 8 * target {
+            lane.buffer.drain(..lane.buffer.len() - target);
+        }
+    }
+
+    /// Mixes one callback of the clock device, mono, with the same length of the others.
+    pub fn mix(&mut self, clock: &[f32]) -> Vec<f32> {
+        let (target, slack) = (self.target, self.slack);
+        let Some((first, others)) = self.lanes.split_first_mut() else {
+            return clock.to_vec();
+        };
+        let mut mixed: Vec<f32> = clock.iter().map(|&s| s * first.gain).collect();
+        let n = clock.len();
+        for lane in others {
+            lane.primed |= lane.buffer.len() >= target;
+            if !lane.primed || n == 0 {
+                continue;
+            }
+            let left = lane.buffer.len().saturating_sub(n);
+            // Take a sample more or less than the clock did to steer the buffer back.
+            let taken = if lane.buffer.len() < n {
+                lane.buffer.len()
+            } else if left > target + slack {
+                n + 1
+            } else if left + slack < target {
+                n - 1
+            } else {
+                n
+            };
+            let samples: Vec<f32> = lane.buffer.drain(..taken).collect();
+            if samples.is_empty() {
+                lane.primed = false;
+                continue;
+            }
+            for (i, out) in mixed.iter_mut().enumerate() {
+                // Spread over the callback, so a dropped or repeated sample falls in the middle.
+                let at = (i * samples.len() / n).min(samples.len() - 1);
+                *out += samples[at] * lane.gain;
+            }
+        }
+        mixed
+    }
+}
+
+/// Averages interleaved `channels` of `data` down to mono f32.
+pub fn mono<T: Sample>(data: &[T], channels: usize) -> Vec<f32>
+where
+    f32: FromSample<T>,
+{
+    let channels = channels.max(1);
+    data.chunks_exact(channels)
+        .map(|frame| frame.iter().map(|&s| f32::from_sample(s)).sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// One of the other devices of a `--mix`, as the mixing thread reads it.
+pub trait Source: Send {
+    /// Appends what arrived since the last call to `out`, mono and at the clock device's rate.
+    fn read(&mut self, out: &mut Vec<f32>);
+}
+
+/// A [`Source`] reading `channels` of `T` from `ring`, resampled if `resampler` is given.
+pub fn source<T>(ring: Consumer<T>, channels: u16, resampler: Option<Resampler>) -> Box<dyn Source>
+where
+    T: Sample + Send + 'static,
+    f32: FromSample<T>,
+{
+    let channels = usize::from(channels.max(1));
+    Box::new(Ring { ring, channels, buffer: vec![T::EQUILIBRIUM; 4096 * channels], resampler })
+}
+
+struct Ring<T> {
+    ring: Consumer<T>,
+    channels: usize,
+    /// Whole frames' worth of samples.
+    buffer: Vec<T>,
+    resampler: Option<Resampler>,
+}
+
+impl<T> Source for Ring<T>
+where
+    T: Sample + Send,
+    f32: FromSample<T>,
+{
+    fn read(&mut self, out: &mut Vec<f32>) {
+        loop {
+            let n = self.ring.pop_slice(&mut self.buffer);
+            if n == 0 {
+                return;
+            }
+            let samples = mono(&self.buffer[..n], self.channels);
+            match &mut self.resampler {
+                Some(resampler) => resampler.process(&samples, out),
+                None => out.extend(samples),
+            }
+        }
+    }
+}
@@ -0,0 +1,772 @@
+//! `--format mp3`: a small MPEG audio Layer III encoder, for servers that take nothing else.
+//!
+//! Neither LAME's bindings nor `mp3lame-encoder` can be had here, so this is written from the
+//! standard: MPEG-1 at 32, 44.1 and 48 kHz and MPEG-2 at 16, 22.05 and 24 kHz, mono, or
+//! stereo as two independent channels, at a constant bitrate. Each granule of 576 samples per
+//! channel goes through the standard's polyphase filterbank and a long-block MDCT; every
+//! scalefactor band is then quantized as finely as the frame's bits allow, with its loudest
+//! value kept to [`LARGEST`] so that the small Huffman tables, the only ones written out
+//! here, hold it. That holds the noise to some 17 to 20 dB below the signal in every band
+//! however high the bitrate, and above about 64 kbps the rest of each frame is padding. There
+//! is no psychoacoustic model, no short blocks and no bit reservoir either, so a sharp onset
+//! smears a little ahead of itself: it is meant for speech to be transcribed, not for music.
+//!
+//! [`Mp3Writer::finalize`] pads the last frame with silence until every sample written has
+//! come out of the decoder, so each chunk plays alone. Decoded audio runs [`DELAY`] samples
+//! behind the samples written; there is no Xing/LAME header to tell players to trim them, and
+//! uploads send the figure as X-Audio-Encoder-Delay instead.
+
+use crate::flac::BitWriter;
+use std::io::Write;
+use std::sync::OnceLock;
+
+/// Samples the decoded audio runs behind the audio written: the filterbank's 481 and a
+/// granule of MDCT overlap.
+pub const DELAY: u32 = 481 + LINES as u32;
+
+/// Frequency lines, or samples per channel, in a granule.
+const LINES: usize = 576;
+
+/// The largest quantized value: table 7's, the largest of [`TABLES`].
+pub const LARGEST: u32 = 5;
+
+/// Where `|x|^(3/4)` is rounded up, short of a half as the reference encoders have it, which
+/// leaves less noise than rounding at a half does.
+const ROUNDING: f32 = 0.4054;
+
+/// Bitrates in kbps, in the order of their header index from 1, of MPEG-1 and of MPEG-2.
+const MPEG1_BITRATES: [u32; 14] = [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MPEG2_BITRATES: [u32; 14] = [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// Sample rates in the order of their header index, of MPEG-1 and of MPEG-2.
+const MPEG1_RATES: [u32; 3] = [44_100, 48_000, 32_000];
+const MPEG2_RATES: [u32; 3] = [22_050, 24_000, 16_000];
+
+/// The frequency lines long-block scalefactor bands start at, at each rate, and the last
+/// band's end. The last band, from line `BANDS[20]`, has no scalefactor.
+const BANDS_44100: [u16; 23] =
+    [0, 4, 8, 12, 16, 20, 24, 30, 36, 44, 52, 62, 74, 90, 110, 134, 162, 196, 238, 288, 342, 418, 576];
+const BANDS_48000: [u16; 23] =
+    [0, 4, 8, 12, 16, 20, 24, 30, 36, 42, 50, 60, 72, 88, 106, 128, 156, 190, 230, 276, 330, 384, 576];
+const BANDS_32000: [u16; 23] =
+    [0, 4, 8, 12, 16, 20, 24, 30, 36, 44, 54, 66, 82, 102, 126, 156, 194, 240, 296, 364, 448, 550, 576];
+const BANDS_22050: [u16; 23] =
+    [0, 6, 12, 18, 24, 30, 36, 44, 54, 66, 80, 96, 116, 140, 168, 200, 238, 284, 336, 396, 464, 522, 576];
+const BANDS_24000: [u16; 23] =
+    [0, 6, 12, 18, 24, 30, 36, 44, 54, 66, 80, 96, 114, 136, 162, 194, 232, 278, 332, 394, 464, 540, 576];
+
+/// Bands with a scalefactor: all but the last.
+const SCALED: usize = 21;
+
+/// Scalefactor bits of the first 11 bands and of the other 10 for each MPEG-1
+/// `scalefac_compress`.
+const SLEN: [(u32, u32); 16] = [
+    (0, 0), (0, 1), (0, 2), (0, 3), (3, 0), (1, 1), (1, 2), (1, 3),
+    (2, 1), (2, 2), (2, 3), (3, 1), (3, 2), (3, 3), (4, 2), (4, 3),
+];
+
+/// The largest scalefactor of each band: 4 bits for the first 11, 3 for the rest, in both
+/// versions.
+fn largest_scalefactor(band: usize) -> u8 {
+    if band < 11 {
+        15
+    } else {
+        7
+    }
+}
+
+/// The first half of the standard's synthesis window `D`, to its middle; the rest mirrors
+/// it. The analysis window is it over 32.
+const WINDOW: [f64; 257] = [
+    0.000000000, -0.000015259, -0.000015259, -0.000015259, -0.000015259, -0.000015259, -0.000015259, -0.000030518,
+    -0.000030518, -0.000030518, -0.000030518, -0.000045776, -0.000045776, -0.000061035, -0.000061035, -0.000076294,
+    -0.000076294, -0.000091553, -0.000106812, -0.000106812, -0.000122070, -0.000137329, -0.000152588, -0.000167847,
+    -0.000198364, -0.000213623, -0.000244141, -0.000259399, -0.000289917, -0.000320435, -0.000366211, -0.000396729,
+    -0.000442505, -0.000473022, -0.000534058, -0.000579834, -0.000625610, -0.000686646, -0.000747681, -0.000808716,
+    -0.000885010, -0.000961304, -0.001037598, -0.001113892, -0.001205444, -0.001296997, -0.001388550, -0.001480103,
+    -0.001586914, -0.001693726, -0.001785278, -0.001907349, -0.002014160, -0.002120972, -0.002243042, -0.002349854,
+    -0.002456665, -0.002578735, -0.002685547, -0.002792358, -0.002899170, -0.002990723, -0.003082275, -0.003173828,
+    0.003250122, 0.003326416, 0.003387451, 0.003433228, 0.003463745, 0.003479004, 0.003479004, 0.003463745,
+    0.003417969, 0.003372192, 0.003280640, 0.003173828, 0.003051758, 0.002883911, 0.002700806, 0.002487183,
+    0.002227783, 0.001937866, 0.001617432, 0.001266479, 0.000869751, 0.000442505, -0.000030518, -0.000549316,
+    -0.001098633, -0.001693726, -0.002334595, -0.003005981, -0.003723145, -0.004486084, -0.005294800, -0.006118774,
+    -0.007003784, -0.007919312, -0.008865356, -0.009841919, -0.010848999, -0.011886597, -0.012939453, -0.014022827,
+    -0.015121460, -0.016235352, -0.017349243, -0.018463135, -0.019577026, -0.020690918, -0.021789551, -0.022857666,
+    -0.023910522, -0.024932861, -0.025909424, -0.026840210, -0.027725220, -0.028533936, -0.029281616, -0.029937744,
+    -0.030532837, -0.031005859, -0.031387329, -0.031661987, -0.031814575, -0.031845093, -0.031738281, -0.031478882,
+    0.031082153, 0.030517578, 0.029785156, 0.028884888, 0.027801514, 0.026535034, 0.025085449, 0.023422241,
+    0.021575928, 0.019531250, 0.017257690, 0.014801025, 0.012115479, 0.009231567, 0.006134033, 0.002822876,
+    -0.000686646, -0.004394531, -0.008316040, -0.012420654, -0.016708374, -0.021179199, -0.025817871, -0.030609131,
+    -0.035552979, -0.040634155, -0.045837402, -0.051132202, -0.056533813, -0.061996460, -0.067520142, -0.073059082,
+    -0.078628540, -0.084182739, -0.089706421, -0.095169067, -0.100540161, -0.105819702, -0.110946655, -0.115921021,
+    -0.120697021, -0.125259399, -0.129562378, -0.133590698, -0.137298584, -0.140670776, -0.143676758, -0.146255493,
+    -0.148422241, -0.150115967, -0.151306152, -0.151962280, -0.152069092, -0.151596069, -0.150497437, -0.148773193,
+    -0.146362305, -0.143264771, -0.139450073, -0.134887695, -0.129577637, -0.123474121, -0.116577148, -0.108856201,
+    0.100311279, 0.090927124, 0.080688477, 0.069595337, 0.057617187, 0.044784546, 0.031082153, 0.016510010,
+    0.001068115, -0.015228271, -0.032379150, -0.050354004, -0.069168091, -0.088775635, -0.109161377, -0.130310059,
+    -0.152206421, -0.174789429, -0.198059082, -0.221984863, -0.246505737, -0.271591187, -0.297210693, -0.323318481,
+    -0.349868774, -0.376800537, -0.404083252, -0.431655884, -0.459472656, -0.487472534, -0.515609741, -0.543823242,
+    -0.572036743, -0.600219727, -0.628295898, -0.656219482, -0.683914185, -0.711318970, -0.738372803, -0.765029907,
+    -0.791213989, -0.816864014, -0.841949463, -0.866363525, -0.890090942, -0.913055420, -0.935195923, -0.956481934,
+    -0.976852417, -0.996246338, -1.014617920, -1.031936646, -1.048156738, -1.063217163, -1.077117920, -1.089782715,
+    -1.101211548, -1.111373901, -1.120223999, -1.127746582, -1.133926392, -1.138763428, -1.142211914, -1.144287109,
+    1.144989014,
+];
+
+/// One of the standard's Huffman tables for pairs of values below `size`: the code of pair
+/// `(x, y)` and its length in bits are at `x·size + y`.
+pub struct Table {
+    /// The table's number in the standard, which side information names it by.
+    pub number: u32,
+    pub size: usize,
+    pub codes: &'static [u16],
+    pub lengths: &'static [u8],
+}
+
+/// The tables without linbits that the quantizer keeps to, smallest first.
+pub const TABLES: [Table; 6] = [
+    Table { number: 1, size: 2, codes: &[1, 1, 1, 0], lengths: &[1, 3, 2, 3] },
+    Table { number: 2, size: 3, codes: &[1, 2, 1, 3, 1, 1, 3, 2, 0], lengths: &[1, 3, 6, 3, 3, 5, 5, 5, 6] },
+    Table { number: 3, size: 3, codes: &[3, 2, 1, 1, 1, 1, 3, 2, 0], lengths: &[2, 2, 6, 3, 2, 5, 5, 5, 6] },
+    Table {
+        number: 5,
+        size: 4,
+        codes: &[1, 2, 6, 5, 3, 1, 4, 4, 7, 5, 7, 1, 6, 1, 1, 0],
+        lengths: &[1, 3, 6, 7, 3, 3, 6, 7, 6, 6, 7, 8, 7, 6, 7, 8],
+    },
+    Table {
+        number: 6,
+        size: 4,
+        codes: &[7, 3, 5, 1, 6, 2, 3, 2, 5, 4, 4, 1, 3, 3, 2, 0],
+        lengths: &[3, 3, 5, 7, 3, 2, 4, 5, 4, 4, 5, 6, 6, 5, 6, 7],
+    },
+    Table {
+        number: 7,
+        size: 6,
+        codes: &[
+            1, 2, 10, 19, 16, 10, 3, 3, 7, 10, 5, 3, 11, 4, 13, 17, 8, 4, 12, 11, 18, 15, 11, 2, 7, 6, 9, 14, 3, 1, 6, 4,
+            5, 3, 2, 0,
+        ],
+        lengths: &[
+            1, 3, 6, 8, 8, 9, 3, 4, 6, 7, 7, 8, 6, 5, 7, 8, 8, 9, 7, 7, 8, 9, 9, 9, 7, 7, 8, 9, 9, 10, 8, 8, 9, 10, 10, 10,
+        ],
+    },
+];
+
+/// Checks that MP3 can hold `channels` at `sample_rate` and `bitrate` kbps.
+pub fn check(channels: u16, sample_rate: u32, bitrate: u32) -> Result<(), anyhow::Error> {
+    Layout::new(channels, sample_rate, bitrate).map(drop)
+}
+
+/// The standard's synthesis window `D`, which decoders weight the output of their
+/// filterbank by; the encoder's analysis window is it over 32.
+pub fn synthesis_window() -> [f32; 512] {
+    let mut window = [0.0; 512];
+    for (n, value) in WINDOW.iter().enumerate() {
+        window[n] = *value as f32;
+    }
+    // The prototype filter is symmetric about the middle, and the window alternates its
+    // sign every 64 samples.
+    for n in 1..256 {
+        window[512 - n] = if n % 64 == 0 { window[n] } else { -window[n] };
+    }
+    window
+}
+
+/// The first frequency line of each scalefactor band at `sample_rate`, and the last one's
+/// end, or `None` at rates MP3 does not have.
+pub fn bands(sample_rate: u32) -> Option<&'static [u16; 23]> {
+    match sample_rate {
+        44_100 => Some(&BANDS_44100),
+        48_000 => Some(&BANDS_48000),
+        32_000 => Some(&BANDS_32000),
+        22_050 | 16_000 => Some(&BANDS_22050),
+        24_000 => Some(&BANDS_24000),
+        _ => None,
+    }
+}
+
+/// What the frames of a stream share.
+#[derive(Clone, Copy)]
+struct Layout {
+    mpeg1: bool,
+    channels: usize,
+    sample_rate: u32,
+    rate_index: u32,
+    bitrate_index: u32,
+    bands: &'static [u16; 23],
+    /// Bytes per frame times the sample rate, without padding.
+    frame_numerator: u32,
+}
+
+impl Layout {
+    fn new(channels: u16, sample_rate: u32, bitrate: u32) -> Result<Self, anyhow::Error> {
+        if !(1..=2).contains(&channels) {
+            anyhow::bail!("MP3 holds one or two channels, not {channels}; record fewer with --channels or --mono");
+        }
+        let (mpeg1, rates, bitrates) = match MPEG1_RATES.contains(&sample_rate) {
+            true => (true, MPEG1_RATES, MPEG1_BITRATES),
+            false => (false, MPEG2_RATES, MPEG2_BITRATES),
+        };
+        let (Some(rate_index), Some(bands)) = (rates.iter().position(|&rate| rate == sample_rate), bands(sample_rate)) else {
+            anyhow::bail!(
+                "MP3 holds audio at 16, 22.05, 24, 32, 44.1 or 48 kHz, not {sample_rate} Hz; record at one of them with --sample-rate"
+            );
+        };
+        let Some(bitrate_index) = bitrates.iter().position(|&rate| rate == bitrate) else {
+            let list: Vec<String> = bitrates.iter().map(u32::to_string).collect();
+            anyhow::bail!(
+                "MP3 at {} kHz has bitrates of {} kbps, not {bitrate}",
+                f64::from(sample_rate) / 1000.0,
+                list.join(", ")
+            );
+        };
+        Ok(Layout {
+            mpeg1,
+            channels: usize::from(channels),
+            sample_rate,
+            rate_index: rate_index as u32,
+            bitrate_index: bitrate_index as u32 + 1,
+            bands,
+            frame_numerator: if mpeg1 { 144_000 } else { 72_000 } * bitrate,
+        })
+    }
+
+    /// Granules per frame: MPEG-2 halves the frame.
+    fn granules(self) -> usize {
+        if self.mpeg1 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Bytes of side information after the header.
+    fn side_info(self) -> usize {
+        match (self.mpeg1, self.channels) {
+            (true, 1) => 17,
+            (true, _) => 32,
+            (false, 1) => 9,
+            (false, _) => 17,
+        }
+    }
+}
+
+/// Writes samples into an MP3 stream, like `hound::WavWriter` does into a WAV file.
+pub struct Mp3Writer<W: Write> {
+    inner: W,
+    layout: Layout,
+    channels: Vec<Channel>,
+    /// Interleaved samples of the frame in progress.
+    pending: Vec<f32>,
+    /// Frames of samples written in, and encoded into MP3 frames.
+    written: u64,
+    encoded: u64,
+    /// What padding has made up of the fractional bytes per frame so far, times the rate.
+    slack: u32,
+}
+
+impl<W: Write> Mp3Writer<W> {
+    /// Starts a stream of `channels` (one or two) at `sample_rate` and `bitrate` kbps in
+    /// `inner`.
+    pub fn new(inner: W, channels: u16, sample_rate: u32, bitrate: u32) -> Result<Self, anyhow::Error> {
+        let layout = Layout::new(channels, sample_rate, bitrate)?;
+        Ok(Mp3Writer {
+            inner,
+            layout,
+            channels: (0..layout.channels).map(|_| Channel::new()).collect(),
+            pending: Vec::with_capacity(2 * LINES * layout.channels),
+            written: 0,
+            encoded: 0,
+            slack: 0,
+        })
+    }
+
+    /// Adds one sample, full scale at ±1; channels are interleaved.
+    pub fn write_sample(&mut self, sample: f32) -> std::io::Result<()> {
+        self.pending.push(sample);
+        if self.pending.len().is_multiple_of(self.layout.channels) {
+            self.written += 1;
+        }
+        if self.pending.len() == self.layout.granules() * LINES * self.layout.channels {
+            self.write_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Pads with silence until the decoder has put out every sample written, and writes the
+    /// last frames.
+    pub fn finalize(mut self) -> Result<(), anyhow::Error> {
+        // A sample short of a whole frame cannot be stored.
+        self.pending.truncate(self.pending.len() / self.layout.channels * self.layout.channels);
+        let frame = (self.layout.granules() * LINES) as u64;
+        let wanted = (self.written + u64::from(DELAY)).div_ceil(frame) * frame;
+        while self.encoded < wanted {
+            self.pending.resize(frame as usize * self.layout.channels, 0.0);
+            self.write_frame()?;
+        }
+        Ok(self.inner.flush()?)
+    }
+
+    fn write_frame(&mut self) -> std::io::Result<()> {
+        let layout = self.layout;
+        let granules = layout.granules();
+        let padding = {
+            self.slack += layout.frame_numerator % layout.sample_rate;
+            let padding = self.slack >= layout.sample_rate;
+            if padding {
+                self.slack -= layout.sample_rate;
+            }
+            padding
+        };
+        let bytes = (layout.frame_numerator / layout.sample_rate) as usize + usize::from(padding);
+        let mut spectra = Vec::with_capacity(granules * layout.channels);
+        for granule in 0..granules {
+            for (ch, channel) in self.channels.iter_mut().enumerate() {
+                let samples: Vec<f32> = (0..LINES)
+                    .map(|i| self.pending[(granule * LINES + i) * layout.channels + ch])
+                    .collect();
+                spectra.push(channel.spectrum(&samples));
+            }
+        }
+        // Granules take what is left of the frame in turn, so bits a quiet one leaves go to
+        // those after it.
+        let mut left = (bytes - 4 - layout.side_info()) * 8;
+        let mut coded = Vec::with_capacity(spectra.len());
+        for (i, spectrum) in spectra.iter().enumerate() {
+            let budget = (left / (spectra.len() - i)).min(4095);
+            let granule = Granule::best(spectrum, layout, budget);
+            left -= granule.bits();
+            coded.push(granule);
+        }
+        let mut bits = BitWriter::default();
+        bits.put(0xFFF, 12);
+        bits.put(if layout.mpeg1 { 1 } else { 0 }, 1);
+        // Layer III, with no CRC.
+        bits.put(0b01, 2);
+        bits.put(1, 1);
+        bits.put(u64::from(layout.bitrate_index), 4);
+        bits.put(u64::from(layout.rate_index), 2);
+        bits.put(u64::from(padding), 1);
+        bits.put(0, 1);
+        // Mono, or stereo with nothing joint; no mode extension, copyright or emphasis.
+        bits.put(if layout.channels == 1 { 0b11 } else { 0b00 }, 2);
+        bits.put(0, 6);
+        // There is no bit reservoir: each frame's main data starts in that frame.
+        match layout.mpeg1 {
+            true => {
+                bits.put(0, 9);
+                bits.put(0, if layout.channels == 1 { 5 } else { 3 });
+                // No scalefactors are shared between granules.
+                bits.put(0, 4 * layout.channels as u32);
+            }
+            false => {
+                bits.put(0, 8);
+                bits.put(0, layout.channels as u32);
+            }
+        }
+        for granule in &coded {
+            granule.put_side_info(&mut bits, layout.mpeg1);
+        }
+        for granule in &coded {
+            granule.put_main_data(&mut bits, layout);
+        }
+        bits.align();
+        bits.bytes.resize(bytes, 0);
+        self.inner.write_all(&bits.bytes)?;
+        self.pending.clear();
+        self.encoded += (granules * LINES) as u64;
+        Ok(())
+    }
+}
+
+/// The transforms' coefficients, worked out once.
+struct Transforms {
+    /// The analysis window, `D / 32`.
+    window: [f32; 512],
+    /// `cos((2i + 1)(k - 16)π/64)`, taking the 64 windowed sums to subband `i`.
+    matrix: Vec<[f32; 64]>,
+    /// The sine window times `cos(π/72·(2i + 19)(2k + 1))`, over 9 so that the decoder's
+    /// inverse transform, which is not scaled, gives back the subband samples.
+    mdct: Vec<[f32; 36]>,
+    /// The butterflies that undo the decoder's alias reduction: `cs` and `ca` for each of
+    /// the 8 lines either side of a subband boundary.
+    alias: [(f32, f32); 8],
+}
+
+fn transforms() -> &'static Transforms {
+    static TRANSFORMS: OnceLock<Transforms> = OnceLock::new();
+    TRANSFORMS.get_or_init(|| {
+        use std::f64::consts::PI;
+        let mut window = synthesis_window();
+        window.iter_mut().for_each(|w| *w /= 32.0);
+        let matrix = (0..32)
+            .map(|i| std::array::from_fn(|k| ((2 * i + 1) as f64 * (k as f64 - 16.0) * PI / 64.0).cos() as f32))
+            .collect();
+        let mdct = (0..18)
+            .map(|k| {
+                std::array::from_fn(|i| {
+                    let sine = (PI / 36.0 * (i as f64 + 0.5)).sin();
+                    (sine * (PI / 72.0 * (2 * i + 19) as f64 * (2 * k + 1) as f64).cos() / 9.0) as f32
+                })
+            })
+            .collect();
+        let alias = [-0.6, -0.535, -0.33, -0.185, -0.095, -0.041, -0.0142, -0.0037].map(|c: f64| {
+            let norm = (1.0 + c * c).sqrt();
+            ((1.0 / norm) as f32, (c / norm) as f32)
+        });
+        Transforms { window, matrix, mdct, alias }
+    })
+}
+
+/// One channel's filterbank.
+struct Channel {
+    /// The last 512 samples, the newest first.
+    history: [f32; 512],
+    /// Each subband's samples of the last granule, the first half of its next MDCT block.
+    previous: [[f32; 18]; 32],
+}
+
+impl Channel {
+    fn new() -> Self {
+        Channel { history: [0.0; 512], previous: [[0.0; 18]; 32] }
+    }
+
+    /// The frequency lines of the next granule, `samples`.
+    fn spectrum(&mut self, samples: &[f32]) -> [f32; LINES] {
+        let transforms = transforms();
+        let mut subbands = [[0.0f32; 18]; 32];
+        for (t, block) in samples.chunks_exact(32).enumerate() {
+            self.history.copy_within(..480, 32);
+            for (i, &sample) in block.iter().enumerate() {
+                self.history[31 - i] = sample;
+            }
+            let sums: [f32; 64] = std::array::from_fn(|i| {
+                (0..8).map(|j| transforms.window[i + 64 * j] * self.history[i + 64 * j]).sum()
+            });
+            for (subband, row) in subbands.iter_mut().zip(&transforms.matrix) {
+                subband[t] = row.iter().zip(&sums).map(|(m, y)| m * y).sum();
+            }
+        }
+        let mut lines = [0.0; LINES];
+        for (sb, (current, previous)) in subbands.iter_mut().zip(&mut self.previous).enumerate() {
+            // The decoder turns every other sample of the odd subbands over; so does this.
+            if sb % 2 == 1 {
+                current.iter_mut().skip(1).step_by(2).for_each(|s| *s = -*s);
+            }
+            for (k, row) in transforms.mdct.iter().enumerate() {
+                let block = previous.iter().chain(current.iter());
+                lines[18 * sb + k] = row.iter().zip(block).map(|(c, s)| c * s).sum();
+            }
+            *previous = *current;
+        }
+        for sb in 1..32 {
+            for (i, &(cs, ca)) in transforms.alias.iter().enumerate() {
+                let (up, down) = (lines[18 * sb - 1 - i], lines[18 * sb + i]);
+                lines[18 * sb - 1 - i] = up * cs + down * ca;
+                lines[18 * sb + i] = down * cs - up * ca;
+            }
+        }
+        lines
+    }
+}
+
+/// The scalefactor bands each of the four scalefactor lengths covers; MPEG-1's first length
+/// covers the first two of them and its second the last two.
+const GROUPS: [std::ops::Range<usize>; 4] = [0..6, 6..11, 11..16, 16..21];
+
+/// One granule of one channel, quantized.
+struct Granule {
+    /// Quantized lines, at most [`LARGEST`] either way.
+    values: [i8; LINES],
+    scalefactors: [u8; SCALED],
+    global_gain: u32,
+    /// The bits of the scalefactors in each of [`GROUPS`], and the side information's name
+    /// for them.
+    lengths: [u32; 4],
+    scalefac_compress: u32,
+    code: Code,
+}
+
+/// How a granule's quantized lines are Huffman coded.
+#[derive(Clone, Copy, Default)]
+struct Code {
+    /// Pairs coded with `tables`, which hold values up to [`LARGEST`]; after them come
+    /// quadruples of values up to 1 to `end`, and then zeros.
+    big_values: usize,
+    end: usize,
+    /// The table numbers of the three regions, and the bands in the first two less one.
+    tables: [u32; 3],
+    regions: (u32, u32),
+    bits: usize,
+}
+
+impl Granule {
+    fn silent() -> Self {
+        Granule {
+            values: [0; LINES],
+            scalefactors: [0; SCALED],
+            global_gain: 0,
+            lengths: [0; 4],
+            scalefac_compress: 0,
+            code: Code::default(),
+        }
+    }
+
+    /// Scalefactor and Huffman bits together, `part2_3_length`.
+    fn bits(&self) -> usize {
+        self.part2() + self.code.bits
+    }
+
+    fn part2(&self) -> usize {
+        GROUPS.iter().zip(self.lengths).map(|(group, length)| group.len() * length as usize).sum()
+    }
+
+    /// The quantization of `lines` that fits in `budget` bits and leaves the least noise
+    /// against each band's own level, summed over the bands.
+    ///
+    /// Each band is quantized as finely as its loudest line allows, but with no finer a
+    /// step than `limit` scalefactor steps below the global gain's; the search is over the
+    /// limit and, for each limit, the finest global gain that fits.
+    fn best(lines: &[f32; LINES], layout: Layout, budget: usize) -> Self {
+        let bands = layout.bands;
+        let magnitudes = lines.map(|x| x.abs().powf(0.75));
+        let band = |b: usize| usize::from(bands[b])..usize::from(bands[b + 1]);
+        let peaks: [f32; SCALED + 1] = std::array::from_fn(|b| lines[band(b)].iter().fold(0.0f32, |a, x| a.max(x.abs())));
+        let energies: [f32; SCALED + 1] = std::array::from_fn(|b| lines[band(b)].iter().map(|x| x * x).sum());
+        let peak = peaks.iter().fold(0.0f32, |a, &b| a.max(b));
+        if peak < f32::MIN_POSITIVE {
+            return Granule::silent();
+        }
+        // The largest line over its step that still rounds to LARGEST.
+        let reach = (LARGEST as f32 + 1.0 - ROUNDING).powf(4.0 / 3.0);
+        let finest = ((210.0 + 4.0 * (peak / reach).log2()).floor() as i32 + 1).clamp(0, 255) as u32;
+        let quantized = |gain, limit| Granule::quantized(&magnitudes, lines, &peaks, layout, gain, limit, reach);
+        // Every band as finely as it goes, when that fits, leaves the least noise of all.
+        let finest_of_all = quantized(finest, 15);
+        if finest_of_all.bits() <= budget {
+            return finest_of_all;
+        }
+        let mut best: Option<(f32, Granule)> = None;
+        // Every third limit does about as well as every one, in a third of the time.
+        for limit in (0..15).rev().step_by(3) {
+            let quantized = |gain| quantized(gain, limit);
+            let (mut low, mut high) = (finest, 255);
+            while low < high {
+                let mid = (low + high) / 2;
+                match quantized(mid).bits() <= budget {
+                    true => high = mid,
+                    false => low = mid + 1,
+                }
+            }
+            let granule = quantized(high);
+            if granule.bits() > budget {
+                continue;
+            }
+            let noise = granule.noise(lines, &energies, bands);
+            if best.as_ref().is_none_or(|(least, _)| noise < *least) {
+                best = Some((noise, granule));
+            }
+        }
+        best.map_or_else(Granule::silent, |(_, granule)| granule)
+    }
+
+    /// `lines` quantized at `global_gain`, each band with the largest scalefactor up to
+    /// `limit` that keeps its values to [`LARGEST`].
+    fn quantized(
+        magnitudes: &[f32; LINES],
+        lines: &[f32; LINES],
+        peaks: &[f32; SCALED + 1],
+        layout: Layout,
+        global_gain: u32,
+        limit: u8,
+        reach: f32,
+    ) -> Self {
+        let bands = layout.bands;
+        let exponent = (global_gain as f32 - 210.0) / 4.0;
+        let mut granule = Granule::silent();
+        granule.global_gain = global_gain;
+        for b in 0..=SCALED {
+            let mut scalefactor = 0;
+            if b < SCALED && peaks[b] > 0.0 {
+                let room = (exponent - (peaks[b] / reach).log2()).ceil() - 1.0;
+                scalefactor = room.clamp(0.0, f32::from(limit.min(largest_scalefactor(b)))) as u8;
+            }
+            // The step is 2^(exponent - scalefactor), and values go as the 3/4 power of it.
+            let factor = (-0.75 * (exponent - f32::from(scalefactor))).exp2();
+            let mut any = false;
+            for i in usize::from(bands[b])..usize::from(bands[b + 1]) {
+                let value = ((magnitudes[i] * factor + ROUNDING) as u32).min(LARGEST) as i8;
+                any |= value != 0;
+                granule.values[i] = if lines[i] < 0.0 { -value } else { value };
+            }
+            // A band left silent needs no scalefactor.
+            if b < SCALED && any {
+                granule.scalefactors[b] = scalefactor;
+            }
+        }
+        let widths = GROUPS.clone().map(|group| {
+            let largest = granule.scalefactors[group].iter().copied().max().unwrap_or(0);
+            u8::BITS - largest.leading_zeros()
+        });
+        (granule.lengths, granule.scalefac_compress) = match layout.mpeg1 {
+            true => {
+                let (first, second) = (widths[0].max(widths[1]), widths[2].max(widths[3]));
+                let compress = (0..16)
+                    .filter(|&c| SLEN[c].0 >= first && SLEN[c].1 >= second)
+                    .min_by_key(|&c| 11 * SLEN[c].0 + 10 * SLEN[c].1)
+                    .unwrap_or(15);
+                let (first, second) = SLEN[compress];
+                ([first, first, second, second], compress as u32)
+            }
+            false => (widths, ((widths[0] * 5 + widths[1]) << 4) | (widths[2] << 2) | widths[3]),
+        };
+        granule.code = Code::of(&granule.values, bands);
+        granule
+    }
+
+    /// The noise the quantization leaves in each band with anything in it, over the band's
+    /// energy and at most 1, summed.
+    fn noise(&self, lines: &[f32; LINES], energies: &[f32; SCALED + 1], bands: &[u16; 23]) -> f32 {
+        let exponent = (self.global_gain as f32 - 210.0) / 4.0;
+        let mut total = 0.0;
+        for (b, &energy) in energies.iter().enumerate() {
+            if energy <= 0.0 {
+                continue;
+            }
+            let scalefactor = self.scalefactors.get(b).copied().unwrap_or(0);
+            let step = (exponent - f32::from(scalefactor)).exp2();
+            let noise: f32 = (usize::from(bands[b])..usize::from(bands[b + 1]))
+                .map(|i| {
+                    let value = f32::from(self.values[i].unsigned_abs());
+                    (lines[i].abs() - value.powf(4.0 / 3.0) * step).powi(2)
+                })
+                .sum();
+            total += (noise / energy).min(1.0);
+        }
+        total
+    }
+
+    fn put_side_info(&self, bits: &mut BitWriter, mpeg1: bool) {
+        bits.put(self.bits() as u64, 12);
+        bits.put(self.code.big_values as u64, 9);
+        bits.put(u64::from(self.global_gain), 8);
+        bits.put(u64::from(self.scalefac_compress), if mpeg1 { 4 } else { 9 });
+        // Long blocks only, so no window switching.
+        bits.put(0, 1);
+        for table in self.code.tables {
+            bits.put(u64::from(table), 5);
+        }
+        bits.put(u64::from(self.code.regions.0), 4);
+        bits.put(u64::from(self.code.regions.1), 3);
+        if mpeg1 {
+            // No preemphasis.
+            bits.put(0, 1);
+        }
+        // Scalefactors in steps of 2 rather than √2, and count1 table B.
+        bits.put(1, 1);
+        bits.put(1, 1);
+    }
+
+    fn put_main_data(&self, bits: &mut BitWriter, layout: Layout) {
+        for (group, length) in GROUPS.iter().zip(self.lengths) {
+            for &scalefactor in &self.scalefactors[group.clone()] {
+                bits.put(u64::from(scalefactor), length);
+            }
+        }
+        let code = self.code;
+        let (first, second) = code.boundaries(layout.bands);
+        for (p, pair) in self.values[..2 * code.big_values].chunks_exact(2).enumerate() {
+            let region = if p < first { 0 } else if p < second { 1 } else { 2 };
+            let Some(table) = TABLES.iter().find(|table| table.number == code.tables[region]) else {
+                continue;
+            };
+            let (x, y) = (usize::from(pair[0].unsigned_abs()), usize::from(pair[1].unsigned_abs()));
+            let index = x * table.size + y;
+            bits.put(u64::from(table.codes[index]), u32::from(table.lengths[index]));
+            put_signs(bits, pair);
+        }
+        for quad in self.values[2 * code.big_values..code.end].chunks_exact(4) {
+            let index = quad.iter().fold(0, |acc, &v| acc << 1 | u64::from(v != 0));
+            bits.put(15 - index, 4);
+            put_signs(bits, quad);
+        }
+    }
+}
+
+/// Sign bits, 1 for negative, of the values that are not zero.
+fn put_signs(bits: &mut BitWriter, values: &[i8]) {
+    for &value in values.iter().filter(|&&value| value != 0) {
+        bits.put(u64::from(value < 0), 1);
+    }
+}
+
+/// Pair bits beyond any table's reach.
+const UNCODABLE: usize = 1 << 24;
+
+impl Code {
+    /// The cheapest coding of `values`.
+    fn of(values: &[i8; LINES], bands: &[u16; 23]) -> Self {
+        let magnitudes = values.map(|v| usize::from(v.unsigned_abs()));
+        let mut end = LINES;
+        while end >= 2 && magnitudes[end - 1] == 0 && magnitudes[end - 2] == 0 {
+            end -= 2;
+        }
+        let mut start = end;
+        while start >= 4 && magnitudes[start - 4..start].iter().all(|&m| m <= 1) {
+            start -= 4;
+        }
+        let quads: usize = magnitudes[start..end].chunks_exact(4).map(|quad| 4 + quad.iter().sum::<usize>()).sum();
+        let pairs = start / 2;
+        if pairs == 0 {
+            return Code { big_values: 0, end, tables: [0; 3], regions: (0, 0), bits: quads };
+        }
+        // Running totals over the pairs: bits with each table, and pairs not both zero.
+        let mut costs = vec![[0usize; TABLES.len()]; pairs + 1];
+        let mut nonzero = vec![0usize; pairs + 1];
+        for p in 0..pairs {
+            let (x, y) = (magnitudes[2 * p], magnitudes[2 * p + 1]);
+            for (t, table) in TABLES.iter().enumerate() {
+                let bits = match x < table.size && y < table.size {
+                    true => usize::from(table.lengths[x * table.size + y]) + usize::from(x > 0) + usize::from(y > 0),
+                    false => UNCODABLE,
+                };
+                costs[p + 1][t] = costs[p][t] + bits;
+            }
+            nonzero[p + 1] = nonzero[p] + usize::from(x + y > 0);
+        }
+        // The table, and its bits, for pairs `from..to`.
+        let region = |from: usize, to: usize| -> (u32, usize) {
+            if nonzero[to] == nonzero[from] {
+                return (0, 0);
+            }
+            TABLES
+                .iter()
+                .enumerate()
+                .map(|(t, table)| (table.number, costs[to][t] - costs[from][t]))
+                .min_by_key(|&(_, bits)| bits)
+                .unwrap_or((0, UNCODABLE))
+        };
+        let mut best = Code { big_values: pairs, end, bits: usize::MAX, ..Code::default() };
+        for r0 in 0..16 {
+            for r1 in 0..8.min(21 - r0) {
+                let candidate = Code { regions: (r0, r1), ..best };
+                let (first, second) = candidate.boundaries(bands);
+                let parts = [region(0, first), region(first, second), region(second, pairs)];
+                let bits = quads + parts.iter().map(|&(_, bits)| bits).sum::<usize>();
+                if bits < best.bits {
+                    best = Code { tables: parts.map(|(table, _)| table), bits, ..candidate };
+                }
+            }
+        }
+        best
+    }
+
+    /// The pairs regions 1 and 2 start at.
+    fn boundaries(&self, bands: &[u16; 23]) -> (usize, usize) {
+        let (r0, r1) = (self.regions.0 as usize, self.regions.1 as usize);
+        let first = (usize::from(bands[r0 + 1]) / 2).min(self.big_values);
+        let second = (usize::from(bands[r0 + r1 + 2]) / 2).min(self.big_values);
+        (first, second)
+    }
+}
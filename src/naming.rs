@@ -0,0 +1,221 @@
+//! Chunk file names: those built from `--name-template`, and the scratch files recorded to
+//! without one.
+//!
+//! Scratch files are reused, so that a long recording needs no more than a couple, and carry
+//! the process ID, so that two recorders sharing a directory never write each other's. A
+//! file is only taken for a new chunk once the upload of the one before it is done ([`Slots`]).
+
+use crate::encode::Format;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Width `{seq}` is zero-padded to, so names sort in recording order.
+const SEQ_WIDTH: usize = 5;
+
+const PLACEHOLDERS: &str = "{seq}, {timestamp}, {device}, {duration}";
+
+/// A parsed `--name-template` such as `meeting_{timestamp}_{seq}.wav`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Seq,
+    Timestamp,
+    Device,
+    Duration,
+}
+
+/// What a chunk's name may be built from.
+pub struct ChunkInfo<'a> {
+    /// Position of the chunk in the session, starting at 0.
+    pub seq: u64,
+    /// When the chunk started, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub device: &'a str,
+    pub duration: Duration,
+}
+
+impl NameTemplate {
+    /// Parses a template, rejecting unknown placeholders, unbalanced braces, path separators,
+    /// and templates that would give every chunk the same name. `{{` and `}}` stand for
+    /// literal braces.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s.contains(['/', '\\']) {
+            return Err(format!("`{s}` must be a file name, not a path; use --output-dir for the directory"));
+        }
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed `{{` in `{s}`; placeholders are {PLACEHOLDERS}"))?;
+                    let part = match &rest[..end] {
+                        "seq" => Part::Seq,
+                        "timestamp" => Part::Timestamp,
+                        "device" => Part::Device,
+                        "duration" => Part::Duration,
+                        other => {
+                            return Err(format!("unknown placeholder `{{{other}}}`; valid placeholders are {PLACEHOLDERS}"))
+                        }
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched `}}` in `{s}`; write `}}}}` for a literal brace")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        // Chunks are still being uploaded while the next one records, so they must not share a file.
+        if !parts.iter().any(|p| matches!(p, Part::Seq | Part::Timestamp)) {
+            return Err(format!("`{s}` needs {{seq}} or {{timestamp}} so chunks don't overwrite each other"));
+        }
+        Ok(NameTemplate { source: s.to_owned(), parts })
+    }
+
+    /// Whether the names tell devices apart.
+    pub fn has_device(&self) -> bool {
+        self.parts.contains(&Part::Device)
+    }
+
+    /// The file name for one chunk.
+    pub fn render(&self, chunk: &ChunkInfo) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => name.push_str(text),
+                Part::Seq => name.push_str(&format!("{:0width$}", chunk.seq, width = SEQ_WIDTH)),
+                Part::Timestamp => name.push_str(&format_timestamp(chunk.timestamp)),
+                Part::Device => name.push_str(&sanitize(chunk.device)),
+                Part::Duration => name.push_str(&chunk.duration.as_secs_f64().to_string()),
+            }
+        }
+        name
+    }
+}
+
+impl std::fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// The scratch file names a recorder cycles through, by slot, and which chunks hold them.
+#[derive(Clone, Default)]
+pub struct Slots(Arc<Mutex<BTreeMap<u64, usize>>>);
+
+impl Slots {
+    /// The slot for chunk `seq`: the one it already holds, or else the lowest one free.
+    pub fn acquire(&self, seq: u64) -> usize {
+        let Ok(mut held) = self.0.lock() else {
+            return 0;
+        };
+        if let Some(&slot) = held.get(&seq) {
+            return slot;
+        }
+        let slot = (0..).find(|slot| !held.values().any(|held| held == slot)).unwrap_or_default();
+        held.insert(seq, slot);
+        slot
+    }
+
+    /// Frees the slot of chunk `seq` once its file is uploaded or deleted.
+    pub fn release(&self, seq: u64) {
+        if let Ok(mut held) = self.0.lock() {
+            held.remove(&seq);
+        }
+    }
+}
+
+/// The name of scratch file `slot` of the process `pid`: `recorded_0-4242.wav`.
+pub fn scratch_name(prefix: &str, slot: usize, pid: u32) -> String {
+    format!("{prefix}_{slot}-{pid}.wav")
+}
+
+/// The process that recorded to `name`, if it is a scratch file (see [`scratch_name`]) in any
+/// of the `--format`s.
+pub fn scratch_pid(name: &str) -> Option<u32> {
+    Format::of(Path::new(name))?;
+    let (stem, _) = name.strip_prefix("recorded")?.rsplit_once('.')?;
+    let (rest, pid) = stem.rsplit_once('-')?;
+    let (_, slot) = rest.rsplit_once('_')?;
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    (digits(slot) && digits(pid)).then(|| pid.parse().ok()).flatten()
+}
+
+/// Formats Unix seconds as a file-name friendly UTC time, e.g. `20231114T221320Z`.
+pub fn format_timestamp(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Like [`format_timestamp`], to the millisecond: `20231114T221320.123Z`.
+pub fn format_timestamp_millis(millis: u64) -> String {
+    let secs = format_timestamp(millis / 1000);
+    format!("{}.{:03}Z", &secs[..secs.len() - 1], millis % 1000)
+}
+
+/// Formats Unix milliseconds as an RFC 3339 UTC time with `-` for `:`, which Windows does not
+/// allow in file names: `2023-11-14T22-13-20.123Z`.
+pub fn format_rfc3339_millis(millis: u64) -> String {
+    let (secs, millis) = (millis / 1000, millis % 1000);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}-{:02}-{:02}.{millis:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian (year, month, day), after Howard
+/// Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Device names may contain spaces, colons and the like; keep names portable.
+pub fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect()
+}
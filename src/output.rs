@@ -0,0 +1,63 @@
+//! Files written next to the recordings: the output directory and the transcript log.
+
+use crate::naming::scratch_pid;
+use anyhow::Context;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Opens the transcript log for appending, so restarts keep earlier transcripts.
+pub fn open_log(path: &Path) -> Result<File, anyhow::Error> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open log file {}", path.display()))
+}
+
+/// Creates the output directory if needed and checks that we can actually write into it, so a
+/// bad `--output-dir` fails up front with the path in the message.
+pub fn prepare_output_dir(dir: &Path) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create output directory {}", dir.display()))?;
+    let probe = dir.join(".rs-audio-tokenizer-write-test");
+    File::create(&probe)
+        .with_context(|| format!("output directory {} is not writable", dir.display()))?;
+    std::fs::remove_file(&probe).ok();
+    Ok(())
+}
+
+/// Scratch chunk files in `dir` left behind by recorders that are no longer running, which
+/// crashed or were killed before they could clean up. Those of running ones are left alone.
+pub fn stale_chunks(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let own = std::process::id();
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let pid = name.to_str().and_then(scratch_pid);
+            pid.is_some_and(|pid| pid != own && !is_running(pid))
+        })
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// Whether process `pid` exists. Without a way to tell, it is assumed to.
+fn is_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // SAFETY: signal 0 only checks whether the process could be signalled.
+        let alive = unsafe { libc::kill(pid, 0) } == 0;
+        alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
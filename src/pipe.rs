@@ -0,0 +1,223 @@
+//! `--stdout-raw` and `--fifo`: the stream as bare PCM on stdout or into a named pipe, for
+//! another program to read.
+//!
+//! The writer thread hands every frame that reaches it to the [`RawStream`], as it does to a
+//! session file, so the other end gets the stream without a gap, push-to-talk and VAD or not.
+//! The samples are interleaved little-endian ones of the chunks' rate, channels and
+//! `--bit-depth`: s16le unless told otherwise. Nothing else happens in this mode. The chunks
+//! are still cut, so `--max-chunks` and `--total-duration` still end the recording, but they
+//! are not written anywhere or uploaded; stdout carries nothing but audio, and the log goes
+//! to stderr as ever.
+//!
+//! Rust ignores SIGPIPE, so when the reader of stdout goes away the next write fails with a
+//! broken pipe instead of killing the process, and the recording shuts down as it does on
+//! Ctrl+C.
+//!
+//! A [`Fifo`] is a named pipe the reader made and keeps opening. Its reader can come and go:
+//! while there is none the stream is held in memory, up to `--fifo-buffer` seconds of it with
+//! the oldest dropped past that, and handed over once a reader opens the pipe again.
+
+use cpal::{FromSample, Sample};
+use std::io::{BufWriter, Write};
+#[cfg(unix)]
+use std::{
+    collections::VecDeque,
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use tracing::{info, warn};
+
+/// Where the writer thread writes the stream as raw samples.
+pub struct RawStream {
+    out: Box<dyn Write + Send>,
+    spec: hound::WavSpec,
+}
+
+impl RawStream {
+    /// Writes samples laid out as `spec` into `out`.
+    pub fn new(out: Box<dyn Write + Send>, spec: hound::WavSpec) -> Self {
+        RawStream { out, spec }
+    }
+
+    /// Writes to stdout.
+    pub fn stdout(spec: hound::WavSpec) -> Self {
+        Self::new(Box::new(BufWriter::new(std::io::stdout())), spec)
+    }
+
+    /// Writes whole frames, converted as the chunks' encoder converts them, and passes them on
+    /// at once.
+    pub fn write<U: Sample>(&mut self, samples: &[U]) -> std::io::Result<()>
+    where
+        i16: FromSample<U>,
+        i32: FromSample<U>,
+        f32: FromSample<U>,
+    {
+        let bits = self.spec.bits_per_sample;
+        for &sample in samples {
+            match (self.spec.sample_format, bits) {
+                (hound::SampleFormat::Float, _) => hound::Sample::write(f32::from_sample(sample), &mut self.out, bits),
+                (_, 16) => hound::Sample::write(i16::from_sample(sample), &mut self.out, bits),
+                (_, 24) => hound::Sample::write(i32::from_sample(sample) >> 8, &mut self.out, bits),
+                _ => hound::Sample::write(i32::from_sample(sample), &mut self.out, bits),
+            }
+            .map_err(|err| match err {
+                hound::Error::IoError(err) => err,
+                err => std::io::Error::other(err),
+            })?;
+        }
+        self.out.flush()
+    }
+}
+
+/// The name of the layout of samples written as `spec`, for a server that is told it.
+pub fn layout_name(spec: hound::WavSpec) -> &'static str {
+    match (spec.sample_format, spec.bits_per_sample) {
+        (hound::SampleFormat::Float, _) => "f32le",
+        (_, 16) => "s16le",
+        (_, 24) => "s24le",
+        _ => "s32le",
+    }
+}
+
+/// How often a FIFO whose reader went away is tried again.
+#[cfg(unix)]
+const REOPEN_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A named pipe the stream is written into, which outlives its readers.
+#[cfg(unix)]
+pub struct Fifo {
+    path: PathBuf,
+    /// While a reader has it open.
+    file: Option<File>,
+    /// What was written while there was no reader, starting on a frame.
+    held: VecDeque<u8>,
+    /// At most this many bytes, whole frames, are held.
+    capacity: usize,
+    block_align: usize,
+    bytes_per_second: f64,
+    /// Bytes dropped since the reader went away.
+    lost: u64,
+    last_try: Instant,
+}
+
+#[cfg(unix)]
+impl Fifo {
+    /// Opens the FIFO at `path` for samples laid out as `spec`, waiting up to `timeout` (or for
+    /// ever, if 0) for a reader, and holding up to `hold` of the stream when it has none.
+    pub fn open(path: &Path, timeout: Duration, spec: hound::WavSpec, hold: Duration) -> Result<Self, anyhow::Error> {
+        use std::os::unix::fs::FileTypeExt;
+        let metadata = std::fs::metadata(path).map_err(|err| anyhow::anyhow!("cannot open FIFO {}: {err}", path.display()))?;
+        if !metadata.file_type().is_fifo() {
+            anyhow::bail!("{} is not a FIFO; create it with mkfifo", path.display());
+        }
+        let block_align = usize::from(spec.channels) * usize::from(spec.bits_per_sample / 8);
+        let bytes_per_second = spec.sample_rate as f64 * block_align as f64;
+        info!("Waiting for a reader on {}", path.display());
+        let started = Instant::now();
+        let file = loop {
+            if let Some(file) = try_open(path)? {
+                break file;
+            }
+            if !timeout.is_zero() && started.elapsed() >= timeout {
+                anyhow::bail!("nothing opened FIFO {} for reading within {:.0?}", path.display(), timeout);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        info!("Streaming to: {}", path.display());
+        Ok(Fifo {
+            path: path.to_path_buf(),
+            file: Some(file),
+            held: VecDeque::new(),
+            capacity: (hold.as_secs_f64() * bytes_per_second) as usize / block_align * block_align,
+            block_align,
+            bytes_per_second,
+            lost: 0,
+            last_try: started,
+        })
+    }
+
+    /// Keeps `buf` for the next reader, dropping the oldest frames past the capacity.
+    fn hold(&mut self, buf: &[u8]) {
+        self.held.extend(buf);
+        if self.held.len() > self.capacity {
+            let excess = (self.held.len() - self.capacity).div_ceil(self.block_align) * self.block_align;
+            let excess = excess.min(self.held.len());
+            self.held.drain(..excess);
+            self.lost += excess as u64;
+        }
+    }
+
+    /// Opens the FIFO again if a reader has, handing it what was held.
+    fn reopen(&mut self) -> std::io::Result<()> {
+        if self.last_try.elapsed() < REOPEN_INTERVAL {
+            return Ok(());
+        }
+        self.last_try = Instant::now();
+        let Some(mut file) = try_open(&self.path)? else {
+            return Ok(());
+        };
+        let (front, back) = self.held.as_slices();
+        match file.write_all(front).and_then(|()| file.write_all(back)) {
+            Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result?,
+        }
+        let gap = self.lost as f64 / self.bytes_per_second;
+        info!(path = %self.path.display(), held_secs = format!("{:.2}", self.held.len() as f64 / self.bytes_per_second), lost_secs = format!("{gap:.2}"), "FIFO reader is back");
+        self.held.clear();
+        self.lost = 0;
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Write for Fifo {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.file.is_none() {
+            self.reopen()?;
+        }
+        let Some(file) = &mut self.file else {
+            self.hold(buf);
+            return Ok(buf.len());
+        };
+        match file.write_all(buf) {
+            Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {
+                let hold = self.capacity as f64 / self.bytes_per_second;
+                warn!(path = %self.path.display(), "FIFO reader went away; holding up to {hold:.0}s of audio until one is back");
+                self.file = None;
+                self.last_try = Instant::now();
+                self.hold(buf);
+            }
+            result => result?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The FIFO at `path` opened for writing, or `None` while it has no reader.
+#[cfg(unix)]
+fn try_open(path: &Path) -> std::io::Result<Option<File>> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    // Without O_NONBLOCK the open would wait for a reader for as long as it took.
+    let file = match std::fs::OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path) {
+        Ok(file) => file,
+        Err(err) if err.raw_os_error() == Some(libc::ENXIO) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    // Then writes block while the reader lags, as they would on stdout.
+    // SAFETY: fcntl only reads and sets the flags of a descriptor the file owns.
+    unsafe {
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(Some(file))
+}
@@ -0,0 +1,150 @@
+//! `--push-to-talk`: recording only while a key is held down.
+//!
+//! Terminals report key presses, not releases, so a held key is told from a tapped one by
+//! its auto-repeat: [`Hold`] takes the key as released once the presses stop coming. That
+//! puts the end of a chunk up to [`FIRST_REPEAT`] after a tap and [`REPEAT_GAP`] after a
+//! longer hold. Each hold has the writer thread start recording with the pre-roll from before
+//! the press, and close the chunk when it ends, whatever its length.
+//!
+//! The terminal is switched to unbuffered input without echo ([`RawTerminal`]) for as long as
+//! the recording runs, and set back on the way out: on returning, on a panic, and on the
+//! second Ctrl+C that exits at once. Ctrl+C itself still signals as usual.
+
+use std::io::Read;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How long after a first press the key counts as held without repeating: a little more than
+/// the usual auto-repeat delay.
+pub const FIRST_REPEAT: Duration = Duration::from_millis(700);
+
+/// How long the key counts as held after a repeat: a few repeats' worth.
+pub const REPEAT_GAP: Duration = Duration::from_millis(150);
+
+/// A change in whether the key is held.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Pressed,
+    Released,
+}
+
+/// Whether the key is held, going by when it was last pressed.
+#[derive(Debug, Default)]
+pub struct Hold {
+    last: Option<Instant>,
+    repeating: bool,
+}
+
+impl Hold {
+    /// Notes a press of the key at `now`: [`Edge::Pressed`] if it starts a hold, or nothing if
+    /// it repeats one.
+    pub fn press(&mut self, now: Instant) -> Option<Edge> {
+        let starts = self.last.is_none();
+        self.repeating = !starts;
+        self.last = Some(now);
+        starts.then_some(Edge::Pressed)
+    }
+
+    /// [`Edge::Released`] once the presses of a hold have stopped by `now`.
+    pub fn check(&mut self, now: Instant) -> Option<Edge> {
+        let last = self.last?;
+        let gap = if self.repeating { REPEAT_GAP } else { FIRST_REPEAT };
+        if now.saturating_duration_since(last) < gap {
+            return None;
+        }
+        *self = Hold::default();
+        Some(Edge::Released)
+    }
+}
+
+/// The key as the recording loop sees it: presses read from stdin in the background.
+pub struct Keys {
+    presses: mpsc::Receiver<Instant>,
+    hold: Hold,
+}
+
+impl Keys {
+    /// Starts reading stdin for presses of `key`, the byte the terminal sends for it.
+    pub fn watch(key: u8) -> Self {
+        let (sender, presses) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin().lock();
+            let mut byte = [0u8];
+            while let Ok(1) = stdin.read(&mut byte) {
+                if byte[0] == key && sender.send(Instant::now()).is_err() {
+                    break;
+                }
+            }
+        });
+        Keys { presses, hold: Hold::default() }
+    }
+
+    /// What changed since the last call, if anything. A hold that starts and ends between two
+    /// calls is reported as starting, and as ending on the next.
+    pub fn poll(&mut self, now: Instant) -> Option<Edge> {
+        while let Ok(at) = self.presses.try_recv() {
+            if let Some(edge) = self.hold.press(at) {
+                return Some(edge);
+            }
+        }
+        self.hold.check(now)
+    }
+}
+
+/// The terminal's settings from before [`RawTerminal::enable`], to go back to.
+#[cfg(unix)]
+static SAVED: std::sync::OnceLock<libc::termios> = std::sync::OnceLock::new();
+
+/// The terminal on stdin, switched to reading keys as they are pressed, without echoing them,
+/// until dropped.
+pub struct RawTerminal(());
+
+impl RawTerminal {
+    #[cfg(unix)]
+    pub fn enable() -> Result<Self, anyhow::Error> {
+        // SAFETY: `isatty` and `tcgetattr` only read the descriptor's state into `saved`.
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+            anyhow::bail!("--push-to-talk reads the key from a terminal, but stdin is not one");
+        }
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            anyhow::bail!("failed to read the terminal settings: {}", std::io::Error::last_os_error());
+        }
+        let saved = *SAVED.get_or_init(|| saved);
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a complete copy of the settings just read.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            anyhow::bail!("failed to set up the terminal: {}", std::io::Error::last_os_error());
+        }
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore();
+            previous(info);
+        }));
+        Ok(RawTerminal(()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn enable() -> Result<Self, anyhow::Error> {
+        anyhow::bail!("--push-to-talk needs a Unix terminal")
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Puts the terminal back as [`RawTerminal::enable`] found it, if it changed it. Safe to call
+/// from a signal handler.
+pub fn restore() {
+    #[cfg(unix)]
+    if let Some(saved) = SAVED.get() {
+        // SAFETY: `tcsetattr` is async-signal-safe, and `saved` came from `tcgetattr`.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+    }
+}
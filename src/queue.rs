@@ -0,0 +1,219 @@
+//! The uploads of a recording: a bounded queue of chunks and a fixed pool of workers that
+//! take them off it (`--upload-workers`).
+//!
+//! The recording loop only ever queues a chunk. When the server falls behind the queue fills
+//! up, and `--overflow` says what gives: the loop waits for room for a while, or the oldest
+//! or newest chunk waiting is dropped. A dropped chunk is logged and its job still runs, told
+//! it was dropped, to clean up after it. A job that panics is logged with its chunk, and its
+//! worker goes on to the next. At the end the workers get through what is left; whatever a
+//! shutdown's `--shutdown-grace` cuts short is logged by chunk.
+
+use crate::logging;
+use clap::ValueEnum;
+use std::collections::{BTreeSet, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// What gives when a chunk is queued with the queue full, as `--overflow` picks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// Wait for room, up to a chunk's duration, then drop the new chunk
+    #[default]
+    Block,
+    /// Drop the chunk that has waited longest
+    DropOldest,
+    /// Drop the new chunk
+    DropNewest,
+}
+
+/// Whether a job gets to do its work, or was dropped and only cleans up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fate {
+    Run,
+    Dropped,
+}
+
+/// One chunk's work.
+pub type Job = Box<dyn FnOnce(Fate) + Send>;
+
+#[derive(Default)]
+struct State {
+    waiting: VecDeque<(u64, Job)>,
+    /// The chunks the workers are on.
+    running: BTreeSet<u64>,
+    /// Jobs run to the end, or to a panic.
+    done: u64,
+    dropped: u64,
+    closed: bool,
+}
+
+/// How busy the queue is, for the stats line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Load {
+    pub queued: usize,
+    /// Uploads in flight.
+    pub running: usize,
+    pub done: u64,
+    pub dropped: u64,
+}
+
+/// A handle to read the [`Load`] of an [`UploadQueue`] through from another thread.
+#[derive(Clone)]
+pub struct Gauge(Arc<Shared>);
+
+impl Gauge {
+    pub fn load(&self) -> Load {
+        let state = self.0.lock();
+        Load { queued: state.waiting.len(), running: state.running.len(), done: state.done, dropped: state.dropped }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a job is queued or the queue closes.
+    queued: Condvar,
+    /// Signalled when a job is taken or done.
+    taken: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The queue and its workers.
+pub struct UploadQueue {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    capacity: usize,
+    overflow: Overflow,
+    /// How long [`Overflow::Block`] waits for room.
+    block_wait: Duration,
+}
+
+impl UploadQueue {
+    /// Starts `workers` workers on a queue of at most `capacity` chunks.
+    pub fn new(workers: usize, capacity: usize, overflow: Overflow, block_wait: Duration) -> Self {
+        let shared = Arc::new(Shared { state: Mutex::default(), queued: Condvar::new(), taken: Condvar::new() });
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                logging::spawn(move || work(&shared))
+            })
+            .collect();
+        UploadQueue { shared, workers, capacity: capacity.max(1), overflow, block_wait }
+    }
+
+    /// Queues `job`, the work of chunk `seq`, making room as `--overflow` says if need be.
+    pub fn push(&self, seq: u64, job: Job) {
+        let mut state = self.shared.lock();
+        if self.overflow == Overflow::Block {
+            let deadline = Instant::now() + self.block_wait;
+            while state.waiting.len() >= self.capacity && Instant::now() < deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                state = self.shared.taken.wait_timeout(state, left).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+            }
+        }
+        let dropped = if state.waiting.len() < self.capacity {
+            state.waiting.push_back((seq, job));
+            None
+        } else if self.overflow == Overflow::DropOldest {
+            let oldest = state.waiting.pop_front();
+            state.waiting.push_back((seq, job));
+            oldest
+        } else {
+            Some((seq, job))
+        };
+        let queued = state.waiting.len();
+        state.dropped += u64::from(dropped.is_some());
+        drop(state);
+        match dropped {
+            Some((dropped, job)) => {
+                if dropped != seq {
+                    self.shared.queued.notify_one();
+                }
+                warn!(chunk = dropped, queued, "upload queue full; chunk dropped without uploading");
+                job(Fate::Dropped);
+            }
+            None => self.shared.queued.notify_one(),
+        }
+    }
+
+    pub fn gauge(&self) -> Gauge {
+        Gauge(self.shared.clone())
+    }
+
+    /// Lets the workers get through the queue and joins them, waiting at most `wait` if given.
+    /// Past that, chunks still queued are dropped and those still uploading are left to it;
+    /// both are logged. Returns whether every job ran to the end.
+    pub fn finish(self, wait: Option<Duration>) -> bool {
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_all();
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        let mut state = self.shared.lock();
+        while !(state.waiting.is_empty() && state.running.is_empty()) {
+            match deadline {
+                Some(deadline) if Instant::now() >= deadline => break,
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    state = self.shared.taken.wait_timeout(state, left).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+                }
+                None => state = self.shared.taken.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner()),
+            }
+        }
+        let left: Vec<_> = state.waiting.drain(..).collect();
+        state.dropped += left.len() as u64;
+        let running: Vec<u64> = state.running.iter().copied().collect();
+        drop(state);
+        for (seq, job) in left {
+            warn!(chunk = seq, "shutting down; chunk dropped without uploading");
+            job(Fate::Dropped);
+        }
+        if !running.is_empty() {
+            let chunks = running.iter().map(u64::to_string).collect::<Vec<_>>().join(", ");
+            warn!("exiting with {} upload(s) still running: chunk(s) {chunks}", running.len());
+            return false;
+        }
+        for worker in self.workers {
+            worker.join().ok();
+        }
+        true
+    }
+}
+
+/// A worker: runs jobs until the queue is closed and empty.
+fn work(shared: &Shared) {
+    loop {
+        let mut state = shared.lock();
+        let (seq, job) = loop {
+            if let Some(next) = state.waiting.pop_front() {
+                break next;
+            }
+            if state.closed {
+                return;
+            }
+            state = shared.queued.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        };
+        state.running.insert(seq);
+        drop(state);
+        shared.taken.notify_all();
+        // A job that panics takes only its own chunk down, not the worker.
+        if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| job(Fate::Run))) {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("no message");
+            error!(chunk = seq, "the upload panicked: {message}");
+        }
+        let mut state = shared.lock();
+        state.running.remove(&seq);
+        state.done += 1;
+        drop(state);
+        shared.taken.notify_all();
+    }
+}
@@ -0,0 +1,417 @@
+//! Riding out a failed input stream or a lost input device.
+//!
+//! cpal reports trouble through the stream's error callback, and after some errors the stream
+//! captures nothing more. Those errors come over a channel to [`Input`], which looks at them
+//! while the recording loop waits for chunks and [`classify`]s each one. Transient errors are
+//! logged and nothing more. A broken stream is dropped, the writer thread closes the chunk in
+//! progress with what it holds, and the stream is built again on the same device with the same
+//! config, right away and then every [`RETRY_INTERVAL`]; after `--stream-retries` failures in
+//! a row the recording ends with an error. A lost device (unplugged, or a Bluetooth headset
+//! dropping out) is looked for every [`RETRY_INTERVAL`] for as long as it takes, and the
+//! recording carries on into the next chunk once it is back. With `--no-reconnect` either
+//! ends the recording instead, for a supervisor to restart.
+//!
+//! Some streams fail without a word: they open and play, and the callback never runs. A
+//! [`Watchdog`] counts the callbacks, warns when they stop for much longer than they should,
+//! and after `--stall-timeout` has the stream treated as broken.
+
+use crate::control;
+use crate::naming::format_timestamp;
+use crate::ptt::{Edge, Keys};
+use crate::shutdown;
+use crate::sink::{Chunk, ChunkSink, Control};
+use cpal::traits::StreamTrait;
+use cpal::{StreamError, SupportedStreamConfig};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+/// How often a failed stream is rebuilt, or a lost device looked for.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How long a stream has to run before its failure no longer counts as one in a row with the
+/// failures before it.
+pub const STABLE_AFTER: Duration = Duration::from_secs(10);
+
+/// How often the recording loop looks at the stream's errors while it waits for a chunk.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The shortest gap between callbacks the [`Watchdog`] warns about, whatever the buffer size.
+const QUIET_MIN: Duration = Duration::from_millis(500);
+
+/// What a stream error means for the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The stream carries on, e.g. after an overrun.
+    Transient,
+    /// The stream may capture nothing more, but the device is still there.
+    Broken,
+    /// The device went away.
+    Lost,
+}
+
+/// Sorts a stream error by what it does to the stream. Backends describe their errors in
+/// their own words: ALSA reports an unplugged USB device as `ENODEV`, say. Anything not known
+/// to be harmless is taken to have broken the stream.
+pub fn classify(err: &StreamError) -> Severity {
+    let description = match err {
+        StreamError::DeviceNotAvailable => return Severity::Lost,
+        StreamError::BackendSpecific { err } => err.description.to_lowercase(),
+    };
+    let mentions = |words: &[&str]| words.iter().any(|word| description.contains(word));
+    if mentions(&["no such device", "disconnected", "not available"]) {
+        Severity::Lost
+    } else if mentions(&["overrun", "underrun", "xrun"]) {
+        Severity::Transient
+    } else {
+        Severity::Broken
+    }
+}
+
+/// Builds the input streams that feed the writer thread.
+pub trait Feed {
+    /// Opens a stream on `device` with `config`, sending its errors to `errors`.
+    fn connect(
+        &mut self,
+        device: &cpal::Device,
+        config: &SupportedStreamConfig,
+        errors: mpsc::Sender<StreamError>,
+    ) -> Result<cpal::Stream, anyhow::Error>;
+
+    /// Passes `control` to the writer thread; see [`crate::sink::SampleQueue::control`].
+    fn control(&self, control: Control);
+
+    /// Counts the data callbacks of every stream it connects.
+    fn callbacks(&self) -> Arc<AtomicU64>;
+}
+
+/// What the [`Watchdog`] has to report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alarm {
+    /// No audio for this long, much longer than the callbacks should take.
+    Quiet(Duration),
+    /// Audio again after a quiet spell this long.
+    Resumed(Duration),
+    /// No audio for this long, past the stall timeout.
+    Stalled(Duration),
+}
+
+/// Tells when a stream stops delivering audio, from a count of its callbacks.
+pub struct Watchdog {
+    callbacks: Arc<AtomicU64>,
+    seen: u64,
+    last_data: Instant,
+    quiet_after: Duration,
+    timeout: Duration,
+    warned: bool,
+}
+
+impl Watchdog {
+    /// A watchdog for callbacks expected every `interval`, which gives up on a stream after
+    /// `timeout` without one.
+    pub fn new(callbacks: Arc<AtomicU64>, interval: Duration, timeout: Duration, now: Instant) -> Self {
+        let seen = callbacks.load(Ordering::Relaxed);
+        Watchdog { callbacks, seen, last_data: now, quiet_after: (2 * interval).max(QUIET_MIN), timeout, warned: false }
+    }
+
+    /// Starts watching afresh, as for a new stream.
+    pub fn reset(&mut self, now: Instant) {
+        self.seen = self.callbacks.load(Ordering::Relaxed);
+        self.last_data = now;
+        self.warned = false;
+    }
+
+    /// Looks at the count, reporting a quiet spell once when it begins and once when it ends,
+    /// and a stall on every look past the timeout.
+    pub fn check(&mut self, now: Instant) -> Option<Alarm> {
+        let callbacks = self.callbacks.load(Ordering::Relaxed);
+        let quiet = now.saturating_duration_since(self.last_data);
+        if callbacks != self.seen {
+            self.seen = callbacks;
+            self.last_data = now;
+            return std::mem::take(&mut self.warned).then_some(Alarm::Resumed(quiet));
+        }
+        if quiet >= self.timeout {
+            Some(Alarm::Stalled(quiet))
+        } else if quiet >= self.quiet_after && !self.warned {
+            self.warned = true;
+            Some(Alarm::Quiet(quiet))
+        } else {
+            None
+        }
+    }
+}
+
+/// Looks for the input device again, returning it with the config to record it with.
+pub type Find = Box<dyn FnMut() -> Result<(cpal::Device, SupportedStreamConfig), anyhow::Error>>;
+
+/// What [`Input`] does when the stream fails.
+pub struct Recovery {
+    /// How to find a lost device again; `None` with `--no-reconnect`, when any failure ends
+    /// the recording.
+    pub find: Option<Find>,
+    /// Failures in a row after which a broken stream is given up on.
+    pub retries: u32,
+    /// How often the callback should run, and how long without it makes a stream broken.
+    pub callback_interval: Duration,
+    pub stall_timeout: Duration,
+}
+
+/// The input stream of a recording, rebuilt when it breaks and when its device comes back.
+pub struct Input {
+    feed: Box<dyn Feed>,
+    device: cpal::Device,
+    config: SupportedStreamConfig,
+    recovery: Recovery,
+    state: State,
+    errors: mpsc::Receiver<StreamError>,
+    watchdog: Watchdog,
+    signals: control::Listener,
+    /// Between two SIGUSR1s.
+    paused: bool,
+    /// With `--push-to-talk`.
+    keys: Option<Keys>,
+    /// Failures of the stream in a row; see [`STABLE_AFTER`].
+    failures: u32,
+    failure: Option<anyhow::Error>,
+}
+
+enum State {
+    /// Recording since `since`, for as long as the stream is held.
+    Running { _stream: cpal::Stream, since: Instant },
+    Down(Outage),
+}
+
+/// A time without a stream.
+struct Outage {
+    severity: Severity,
+    since: Instant,
+    at: SystemTime,
+    next_attempt: Instant,
+}
+
+impl Input {
+    /// Starts recording from `device` with `config`.
+    pub fn start(
+        device: cpal::Device,
+        config: SupportedStreamConfig,
+        mut feed: Box<dyn Feed>,
+        recovery: Recovery,
+    ) -> Result<Self, anyhow::Error> {
+        let watchdog = Watchdog::new(feed.callbacks(), recovery.callback_interval, recovery.stall_timeout, Instant::now());
+        let (stream, errors) = open(feed.as_mut(), &device, &config)?;
+        Ok(Input {
+            feed,
+            device,
+            config,
+            recovery,
+            state: State::Running { _stream: stream, since: Instant::now() },
+            errors,
+            watchdog,
+            signals: control::Listener::new(),
+            paused: false,
+            keys: None,
+            failures: 0,
+            failure: None,
+        })
+    }
+
+    /// Waits for the next chunk like [`ChunkSink::next_chunk`], minding the stream and passing
+    /// on pauses and cuts (see [`crate::control`]) meanwhile. Also `None` once a failure has
+    /// ended the recording (see [`Self::failure`]) or a shutdown was asked for.
+    pub fn next_chunk(&mut self, sink: &ChunkSink) -> Option<Result<Chunk, anyhow::Error>> {
+        loop {
+            if shutdown::requested() {
+                return None;
+            }
+            match sink.next_chunk_timeout(TICK) {
+                Ok(chunk) => return Some(chunk),
+                Err(RecvTimeoutError::Disconnected) => return None,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            if self.failure.is_some() {
+                return None;
+            }
+            self.check_controls();
+            self.check_errors();
+            self.check_watchdog();
+            self.recover();
+        }
+    }
+
+    /// Records only while the push-to-talk key is held; the writer thread must have been
+    /// planned for it (see [`crate::sink::ChunkPlan::push_to_talk`]).
+    pub fn push_to_talk(&mut self, keys: Keys) {
+        self.keys = Some(keys);
+    }
+
+    /// Why the recording ended early, if it did.
+    pub fn failure(&mut self) -> Option<anyhow::Error> {
+        self.failure.take()
+    }
+
+    fn check_controls(&mut self) {
+        if self.signals.take_toggles() % 2 == 1 {
+            self.paused = !self.paused;
+            let at = format_timestamp(unix_secs(SystemTime::now()));
+            if self.paused {
+                self.feed.control(Control::Pause);
+                info!(at = %at, "paused; send SIGUSR1 again to resume");
+            } else {
+                self.feed.control(Control::Resume);
+                info!(at = %at, "resumed");
+            }
+        }
+        if self.signals.take_cuts() > 0 && !self.paused {
+            info!("cutting the chunk here (SIGUSR2)");
+            self.feed.control(Control::Cut);
+        }
+        let Some(keys) = self.keys.as_mut() else {
+            return;
+        };
+        match keys.poll(Instant::now()) {
+            Some(Edge::Pressed) => {
+                debug!("key down; recording");
+                self.feed.control(Control::Hold);
+            }
+            Some(Edge::Released) => {
+                debug!("key up; closing the chunk");
+                self.feed.control(Control::Release);
+            }
+            None => {}
+        }
+    }
+
+    fn check_errors(&mut self) {
+        while let Ok(err) = self.errors.try_recv() {
+            match classify(&err) {
+                Severity::Transient => error!("an error occurred on stream: {}", err),
+                severity => self.fail(severity, err.to_string()),
+            }
+        }
+    }
+
+    fn check_watchdog(&mut self) {
+        if !matches!(self.state, State::Running { .. }) {
+            return;
+        }
+        let secs = |quiet: Duration| format!("{:.1}", quiet.as_secs_f64());
+        match self.watchdog.check(Instant::now()) {
+            Some(Alarm::Quiet(quiet)) => warn!(stalled_secs = secs(quiet), "no audio from the input stream"),
+            Some(Alarm::Resumed(quiet)) => info!(stalled_secs = secs(quiet), "audio from the input stream again"),
+            Some(Alarm::Stalled(quiet)) => {
+                self.fail(Severity::Broken, format!("no audio for {}s (--stall-timeout)", secs(quiet)))
+            }
+            None => {}
+        }
+    }
+
+    /// Drops the stream for what `cause` did to it, and decides what happens next.
+    fn fail(&mut self, severity: Severity, cause: String) {
+        // Errors that come after the first are the stream being dropped.
+        let State::Running { since, .. } = &self.state else {
+            return;
+        };
+        let ran = since.elapsed();
+        let (now, at) = (Instant::now(), SystemTime::now());
+        let next_attempt = match severity {
+            Severity::Lost => now + RETRY_INTERVAL,
+            _ => now,
+        };
+        self.state = State::Down(Outage { severity, since: now, at, next_attempt });
+        if self.recovery.find.is_none() {
+            self.failure = Some(anyhow::anyhow!("the input stream failed: {cause}"));
+            return;
+        }
+        self.feed.control(Control::Interrupt);
+        let lost_at = format_timestamp(unix_secs(at));
+        if severity == Severity::Lost {
+            warn!(lost_at = %lost_at, "input device lost: {cause}; looking for it every {}s", RETRY_INTERVAL.as_secs());
+            return;
+        }
+        if ran < STABLE_AFTER {
+            self.failures += 1;
+        } else {
+            self.failures = 0;
+        }
+        warn!(lost_at = %lost_at, "input stream failed: {cause}; rebuilding it");
+        self.give_up_after(cause);
+    }
+
+    /// Ends the recording if the stream has failed too many times in a row.
+    fn give_up_after(&mut self, cause: String) {
+        if self.failures >= self.recovery.retries {
+            self.failure = Some(anyhow::anyhow!(
+                "gave up on the input stream after {} failures in a row: {cause}",
+                self.failures
+            ));
+        }
+    }
+
+    /// Rebuilds the stream or looks for the lost device if it is time to.
+    fn recover(&mut self) {
+        let State::Down(outage) = &mut self.state else {
+            return;
+        };
+        if self.failure.is_some() || Instant::now() < outage.next_attempt {
+            return;
+        }
+        outage.next_attempt = Instant::now() + RETRY_INTERVAL;
+        let (severity, since, at) = (outage.severity, outage.since, outage.at);
+        let reopened = match severity {
+            Severity::Lost => match self.recovery.find.as_mut().map(|find| find()) {
+                Some(Ok((device, config))) => {
+                    (self.device, self.config) = (device, config);
+                    open(self.feed.as_mut(), &self.device, &self.config)
+                }
+                Some(Err(err)) => Err(err),
+                None => return,
+            },
+            _ => open(self.feed.as_mut(), &self.device, &self.config),
+        };
+        let (stream, errors) = match reopened {
+            Ok(reopened) => reopened,
+            Err(err) if severity == Severity::Lost => {
+                debug!("input device not back yet: {err:#}");
+                return;
+            }
+            Err(err) => {
+                self.failures += 1;
+                warn!(attempt = self.failures, "rebuilding the input stream failed: {err:#}");
+                self.give_up_after(format!("{err:#}"));
+                return;
+            }
+        };
+        let what = match severity {
+            Severity::Lost => "input device is back",
+            _ => "input stream rebuilt",
+        };
+        info!(
+            lost_at = %format_timestamp(unix_secs(at)),
+            resumed_at = %format_timestamp(unix_secs(SystemTime::now())),
+            gap_secs = format!("{:.1}", since.elapsed().as_secs_f64()),
+            "{what}; recording resumed"
+        );
+        self.state = State::Running { _stream: stream, since: Instant::now() };
+        self.errors = errors;
+        self.watchdog.reset(Instant::now());
+    }
+}
+
+/// Opens and starts a stream, with a channel of its own for its errors.
+fn open(
+    feed: &mut dyn Feed,
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+) -> Result<(cpal::Stream, mpsc::Receiver<StreamError>), anyhow::Error> {
+    let (sender, errors) = mpsc::channel();
+    let stream = feed.connect(device, config, sender)?;
+    stream.play()?;
+    Ok((stream, errors))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
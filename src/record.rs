@@ -0,0 +1,1754 @@
+//! The `record` subcommand: continually records short WAV chunks and uploads each one.
+//!
+//! The input data is recorded to scratch files of this process, usually alternating between
+//! "recorded_0-<pid>.wav" and "recorded_1-<pid>.wav", inside the output directory (the system
+//! temp directory by default), next to the transcript "log.txt", unless `--name-template` gives
+//! every chunk a name of its own.
+
+use crate::archive;
+use crate::breaker::{Breaker, State};
+use crate::bwf::{self, Bext};
+use crate::cli::{BitDepth, GlobalOpts, RecordArgs, VadMode};
+use crate::control;
+#[cfg(feature = "denoise")]
+use crate::denoise::Denoise;
+use crate::device::{negotiate, select_device, select_host, Wanted};
+use crate::dsp::{db_to_linear, Agc, ChannelMap, DcBlocker, Downmix, Gain, HighPass, Level, NoiseGate, Stage};
+use crate::encode::{ChunkWriter, Discard, Encoder, Format};
+use crate::health;
+use crate::meter::Meter;
+use crate::mix::{self, Mixer};
+use crate::logging::{self, Ticker};
+use crate::loopback;
+use crate::naming::{format_timestamp_millis, sanitize, scratch_name, ChunkInfo, NameTemplate, Slots};
+use crate::memory::{ChunkFile, MemoryFile, Shelf};
+use crate::output::{open_log, prepare_output_dir, stale_chunks};
+#[cfg(unix)]
+use crate::pipe::Fifo;
+use crate::pipe::RawStream;
+use crate::ptt::{Keys, RawTerminal};
+use crate::queue::{Fate, Load, UploadQueue};
+use crate::reconnect::{Feed, Find, Input, Recovery};
+use crate::resample::Resampler;
+use crate::retention::{Housekeeper, Purger, Uploaded};
+use crate::session::{self, Entry, SessionFile, SessionIndex};
+use crate::shutdown;
+use crate::space::{self, Watch};
+use crate::spool::{Retrier, Spool};
+use crate::stream::{Streams, Tee};
+use crate::sink::{self, Chunk, ChunkPlan, ChunkSink, Control, OpenChunk, SampleQueue, QUEUE_BUFFERS};
+use crate::upload::{channels_header, raw_headers, excerpt, rejected, Endpoint, Metadata, RawLayout, Response, Retries};
+use crate::vad::{EnergyVad, Limits, Segmenter};
+#[cfg(feature = "webrtc-vad")]
+use crate::webrtc_vad::WebrtcVad;
+#[cfg(feature = "grpc")]
+use crate::grpc::{self, GrpcStream};
+#[cfg(feature = "websocket")]
+use crate::websocket::{self, WsStream};
+use anyhow::Context;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{
+    BufferSize, FromSample, SampleFormat, SizedSample, StreamError, SupportedBufferSize, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+/// Fraction of clipped samples above which a chunk is reported as clipped.
+const CLIP_WARN_RATIO: f64 = 0.001;
+
+/// Peak level under which one channel of several is reported as carrying nothing, about the
+/// noise floor of 16 bits.
+const DEAD_CHANNEL_DBFS: f32 = -90.0;
+
+/// How often `--spool-dir` is gone through for chunks to upload again.
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often an upload held back by the open circuit, without `--spool-dir`, looks again.
+const BREAKER_WAIT: Duration = Duration::from_millis(100);
+
+/// Callbacks' worth of audio the mixer keeps buffered from each device but the first.
+const MIX_BUFFERS: usize = 4;
+
+/// How often the mixing thread of a `--mix` looks for more audio.
+const MIX_POLL: Duration = Duration::from_millis(5);
+
+/// Roughly how often backends call back when they pick the buffer size.
+const DEFAULT_CALLBACK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Records until `--max-chunks` or `--total-duration` is reached (forever otherwise) or
+/// Ctrl+C, then waits for the outstanding uploads.
+pub fn run(global: &GlobalOpts, args: &RecordArgs) -> Result<(), anyhow::Error> {
+    if args.overlap >= args.duration {
+        anyhow::bail!(
+            "--overlap ({}s) must be less than the chunk --duration ({}s)",
+            args.overlap.as_secs_f64(),
+            args.duration.as_secs_f64()
+        );
+    }
+    if args.vad.is_some() {
+        if args.min_chunk >= args.max_chunk {
+            anyhow::bail!(
+                "--min-chunk ({}s) must be less than --max-chunk ({}s)",
+                args.min_chunk.as_secs_f64(),
+                args.max_chunk.as_secs_f64()
+            );
+        }
+        if args.total_duration.is_some() {
+            anyhow::bail!("--total-duration counts fixed-length chunks and cannot be combined with --vad");
+        }
+    }
+    if args.bwf && args.format != Format::Wav {
+        anyhow::bail!("--bwf writes Broadcast Wave files and needs --format wav");
+    }
+    if args.format == Format::Raw && args.bit_depth.is_some_and(|depth| depth != BitDepth::I16) {
+        anyhow::bail!("--format raw writes 16-bit samples; drop --bit-depth or make it 16");
+    }
+    if args.session_file.is_some() && args.format != Format::Wav {
+        anyhow::bail!("--session-file records a WAV file and needs --format wav");
+    }
+    if args.stream_upload && global.compress_upload {
+        anyhow::bail!("--stream-upload sends chunks as they are recorded and cannot compress them; drop --compress-upload");
+    }
+    #[cfg(unix)]
+    if args.stream_upload && (global.unix_socket.is_some() || global.url.scheme() == "unix") {
+        anyhow::bail!("--stream-upload sends chunks over TCP and cannot go through a Unix socket");
+    }
+    #[cfg(all(unix, feature = "websocket"))]
+    if args.ws_url.is_some() && args.fifo.is_some() {
+        anyhow::bail!("--ws-url and --fifo both take the stream; keep one");
+    }
+    #[cfg(all(unix, feature = "grpc"))]
+    if args.grpc_endpoint.is_some() && args.fifo.is_some() {
+        anyhow::bail!("--grpc-endpoint and --fifo both take the stream; keep one");
+    }
+    #[cfg(all(feature = "websocket", feature = "grpc"))]
+    if args.ws_url.is_some() && args.grpc_endpoint.is_some() {
+        anyhow::bail!("--ws-url and --grpc-endpoint both take the stream; keep one");
+    }
+    if args.stream_upload && args.streams_raw() {
+        anyhow::bail!("--stream-upload uploads chunks, which {} does not record", args.raw_option());
+    }
+    if args.require_healthcheck && args.streams_raw() {
+        anyhow::bail!("--require-healthcheck checks the server for uploads, which {} makes none of", args.raw_option());
+    }
+    if args.push_to_talk && args.total_duration.is_some() {
+        anyhow::bail!("--total-duration counts fixed-length chunks and cannot be combined with --push-to-talk");
+    }
+    if args.mix {
+        if args.device.len() < 2 {
+            anyhow::bail!("--mix needs at least two --device inputs to mix");
+        }
+        if args.mix_gain.len() > args.device.len() {
+            anyhow::bail!("--mix-gain is given {} times for {} devices", args.mix_gain.len(), args.device.len());
+        }
+    } else if !args.mix_gain.is_empty() {
+        anyhow::bail!("--mix-gain only applies with --mix");
+    }
+    if let Some(query) = args.device.iter().enumerate().find_map(|(i, query)| args.device[..i].contains(query).then_some(query)) {
+        anyhow::bail!("--device `{query}` is given more than once");
+    }
+    if args.device.len() > 1 && !args.mix {
+        if args.push_to_talk {
+            anyhow::bail!("--push-to-talk records from a single --device");
+        }
+        if args.meter {
+            anyhow::bail!("--meter shows a single --device");
+        }
+        if args.streams_raw() || args.stream_url().is_some() {
+            anyhow::bail!("--stdout-raw, --fifo, --ws-url and --grpc-endpoint stream a single --device");
+        }
+        if args.name_template.as_ref().is_some_and(|template| !template.has_device()) {
+            anyhow::bail!("--name-template needs {{device}} to tell the chunks of several devices apart");
+        }
+    }
+    // A missing credential is an error now rather than a 401 once recording.
+    let endpoint = global.endpoint_unchecked()?;
+    // The health check is the first line logged, before even what the client is set up with.
+    if !args.skip_healthcheck && !args.dry_run && !args.streams_raw() {
+        let health = health::check(&endpoint, global.url.as_str());
+        if args.require_healthcheck && !health.passed() {
+            anyhow::bail!("the health check of {} failed (--require-healthcheck)", global.url);
+        }
+    }
+    // A dry run uploads nothing, so it needs no client, nor a TLS or proxy setup that works.
+    if !args.dry_run {
+        endpoint.check()?;
+    }
+    shutdown::install()?;
+    control::install()?;
+    // Set back when dropped at the end of the recording.
+    let _terminal = args.push_to_talk.then(RawTerminal::enable).transpose()?;
+    match &args.device[..] {
+        [query] => session(global, args, std::slice::from_ref(query), None),
+        queries if args.mix => session(global, args, queries, None),
+        _ => record_all(global, args),
+    }
+}
+
+/// Records from every `--device` at once, each in a session of its own on its own thread, so
+/// one that fails leaves the others recording.
+fn record_all(global: &GlobalOpts, args: &RecordArgs) -> Result<(), anyhow::Error> {
+    let labels = device_labels(&args.device);
+    let failed = std::thread::scope(|scope| {
+        let sessions: Vec<_> = args
+            .device
+            .iter()
+            .zip(&labels)
+            .map(|(query, label)| {
+                let handle = scope.spawn(move || {
+                    logging::set_device(label);
+                    session(global, args, std::slice::from_ref(query), Some(label))
+                });
+                (label, handle)
+            })
+            .collect();
+        let mut failed = 0;
+        for (label, handle) in sessions {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    error!(device = label.as_str(), "{err:#}");
+                    failed += 1;
+                }
+                Err(_) => failed += 1,
+            }
+        }
+        failed
+    });
+    if failed > 0 {
+        anyhow::bail!("recording failed on {failed} of {} devices", labels.len());
+    }
+    Ok(())
+}
+
+/// Labels for the devices `queries` name, for their chunk files and log lines: the queries
+/// made file-name safe, numbered where two come out the same.
+pub fn device_labels(queries: &[String]) -> Vec<String> {
+    let mut labels: Vec<String> = Vec::new();
+    for query in queries {
+        let base = sanitize(query);
+        let mut label = base.clone();
+        let mut n = 2;
+        while labels.contains(&label) {
+            label = format!("{base}-{n}");
+            n += 1;
+        }
+        labels.push(label);
+    }
+    labels
+}
+
+/// Records from the device `queries` name, or with `--mix` from all of them mixed into the
+/// stream of the first. `label` tells its files and log lines from those of the other
+/// devices, if there are any.
+fn session(global: &GlobalOpts, args: &RecordArgs, queries: &[String], label: Option<&str>) -> Result<(), anyhow::Error> {
+    let query = &queries[0];
+    let (device, mut device_name, config) = open_input(args, query)?;
+    let mixed: Vec<_> = queries[1..].iter().map(|query| open_input(args, query)).collect::<Result<_, _>>()?;
+    let devices = (!mixed.is_empty()).then(|| {
+        let names: Vec<&str> = std::iter::once(&device_name).chain(mixed.iter().map(|(_, name, _)| name)).map(String::as_str).collect();
+        info!("Mixing {} devices into one mono stream: {}", names.len(), names.join(", "));
+        names.join(", ")
+    });
+    if devices.is_some() {
+        device_name = String::from("mix");
+    }
+
+    let buffer_size = match args.buffer_size {
+        Some(frames) => BufferSize::Fixed(frames),
+        None => BufferSize::Default,
+    };
+    debug!("Buffer size: {:?}", buffer_size);
+
+    let gain = db_to_linear(args.gain);
+    if args.gain != 0.0 {
+        info!("Input gain: {:+.1} dB", args.gain);
+    }
+
+    // No transcripts without uploads, or a WebSocket or gRPC call.
+    let log_path = global.log_path().filter(|_| !args.streams_raw() || args.stream_url().is_some()).map(|path| match label {
+        Some(label) => labelled(&path, label),
+        None => path,
+    });
+    if !args.in_memory && !args.streams_raw() {
+        prepare_output_dir(&global.output_dir)?;
+        remove_stale_chunks(&global.output_dir);
+        if let Some(dir) = &args.archive_dir {
+            prepare_output_dir(dir)?;
+            info!("Archiving chunks to: {}", dir.display());
+        }
+    } else if let Some(dir) = log_path.as_deref().and_then(Path::parent).filter(|dir| !dir.as_os_str().is_empty()) {
+        // Nothing else goes to disk.
+        prepare_output_dir(dir)?;
+    }
+
+    let namer = ChunkNamer {
+        output_dir: global.output_dir.clone(),
+        template: args.name_template.clone(),
+        dry_run: args.dry_run,
+        session_id: unix_secs(SystemTime::now()),
+        device: device_name,
+        label: label.map(str::to_owned),
+        duration: args.duration,
+        slots: Slots::default(),
+        extension: args.format.extension(),
+    };
+    let bwf = args.bwf.then(|| Broadcast {
+        originator: args.originator.clone(),
+        session_id: namer.session_id,
+        device: namer.device.clone(),
+    });
+    if args.dry_run {
+        info!("Dry run: nothing is uploaded");
+    }
+    match &args.name_template {
+        _ if args.in_memory => info!("Recording to: memory"),
+        _ if args.streams_raw() => info!("Streaming raw samples; no chunks are kept or uploaded"),
+        _ if args.session_file.is_some() => {}
+        Some(template) => info!("Recording to: {}", global.output_dir.join(template.to_string()).display()),
+        None if args.dry_run => info!(
+            "Recording to: {}",
+            global.output_dir.join(format!("{}_{}_*.{}", namer.prefix(), namer.session_id, namer.extension)).display()
+        ),
+        None => info!("Recording to: {} / {}", namer.scratch(0).display(), namer.scratch(1).display()),
+    }
+    match &log_path {
+        Some(path) => info!("Transcript log: {}", path.display()),
+        None => info!("Transcript log: disabled"),
+    }
+    match args.stream_url() {
+        Some(url) if args.streams_raw() => info!("Transcription endpoint: {url}"),
+        Some(url) => info!("Transcription endpoints: {url} (streamed), {} (chunks)", global.url),
+        None => info!("Transcription endpoint: {}", global.url),
+    }
+
+    let file = match &log_path {
+        Some(path) => Some(Arc::new(Mutex::new(open_log(path)?))),
+        None => None,
+    };
+
+    // Checked, but for a dry run, before any session started.
+    let mut endpoint = global.endpoint_unchecked()?;
+    let housekeeper = args.retention().map(Housekeeper::spawn);
+
+    // One stream for the whole session, barring a lost device. The writer thread cuts it into chunks of exactly
+    // `frames_per_chunk` frames, so no audio is lost between chunks and all chunks are the
+    // same length.
+    let captured_rate = config.sample_rate().0;
+    let rate = args.target_rate.unwrap_or(captured_rate);
+    // A mix reaches the queue already mono.
+    let (captured_channels, channel_map) = match devices {
+        Some(_) => (1, ChannelMap::Keep),
+        None => (config.channels(), args.channel_map()),
+    };
+    let channels = channel_map.channels(captured_channels);
+    match channel_map {
+        ChannelMap::Mix if captured_channels > 1 => info!("Mixing {captured_channels} channels down to mono"),
+        ChannelMap::Pick(index) => info!("Recording input channel {} of {captured_channels}", index + 1),
+        _ => {}
+    }
+    let resampler = (rate != captured_rate).then(|| {
+        info!("Resampling {captured_rate} Hz to {rate} Hz");
+        Resampler::new(channels, captured_rate, rate)
+    });
+    let frames_per_chunk = ((args.duration.as_secs_f64() * f64::from(rate)).round() as u64).max(1);
+    debug!("Chunk length: {frames_per_chunk} frames");
+    let limit = chunk_limit(args);
+    // Rounded to whole frames; the first chunk has nothing before it to repeat.
+    let preroll_frames = (args.preroll_ms * u64::from(rate) + 500) / 1000;
+    let overlap_frames = ((args.overlap.as_secs_f64() * f64::from(rate)).round() as u64).min(frames_per_chunk - 1);
+    if overlap_frames > 0 {
+        debug!("Chunk overlap: {overlap_frames} frames");
+    }
+    let depth = args.bit_depth.unwrap_or_default();
+    let written = depth.written();
+    let spec = depth.spec(channels, rate);
+    // MP3 chunks' kbps; the other formats have none.
+    #[cfg(feature = "mp3")]
+    let bitrate = args.bitrate;
+    #[cfg(not(feature = "mp3"))]
+    let bitrate = 0;
+    #[cfg(feature = "mp3")]
+    if args.format == Format::Mp3 {
+        crate::mp3::check(channels, rate, bitrate)?;
+    }
+    let metadata = audio_metadata(args.format, spec, &namer.device);
+    let session = match &args.session_file {
+        Some(path) => {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                prepare_output_dir(dir)?;
+            }
+            let index = SessionIndex::create(path, spec)?;
+            info!("Recording to: {} (index: {})", path.display(), index.path().display());
+            Some((SessionFile::create(path, spec)?, Session { path: path.clone(), spec, index }))
+        }
+        None => None,
+    };
+    let (session_file, session) = session.unzip();
+    let session_file = match &args.tee {
+        Some(path) => {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                prepare_output_dir(dir)?;
+            }
+            info!("Teeing the session to: {}", path.display());
+            Some(SessionFile::create(path, spec)?)
+        }
+        None => session_file,
+    };
+    let stages = filter_stages(args, channels, captured_rate);
+    let plan = ChunkPlan {
+        sample_rate: rate,
+        captured_channels,
+        channel_map,
+        channels,
+        capacity: QUEUE_BUFFERS,
+        frames_per_chunk,
+        preroll_frames,
+        overlap_frames,
+        limit,
+        resampler,
+        dither: !args.no_dither,
+        latency: stages.iter().map(|stage| stage.latency()).sum(),
+        stages,
+        meter: args
+            .meter
+            .then(|| Meter::new(&namer.device, captured_rate, captured_channels, Box::new(std::io::stderr()))),
+        segmenter: segmenter(args, channels, rate),
+        push_to_talk: args.push_to_talk,
+        warmup_frames: (args.warmup_ms * u64::from(captured_rate) + 500) / 1000,
+        session: session_file,
+        raw: raw_stream(args, spec, &endpoint, file.as_ref())?,
+    };
+    let format = args.format;
+    let slots = namer.slots.clone();
+    let stage_args = args.clone();
+    let stages = Box::new(move || filter_stages(&stage_args, channels, captured_rate));
+    let mixing = devices.is_some().then(|| Mixing {
+        gains: (0..queries.len()).map(|i| db_to_linear(args.mix_gain.get(i).copied().unwrap_or(0.0))).collect(),
+        others: mixed.into_iter().map(|(device, _, config)| (device, config)).collect(),
+        target: (callback_interval(buffer_size, captured_rate).as_secs_f64() * f64::from(captured_rate)) as usize * MIX_BUFFERS,
+        streams: Vec::new(),
+    });
+    let connection = Connection { buffer_size, gain, stages, mixing };
+    let shelf = args.in_memory.then(Shelf::default);
+    // The longest chunk the plan can make.
+    let longest_frames = match args.vad {
+        Some(_) => (args.max_chunk.as_secs_f64() * f64::from(rate)) as u64 + args.vad_silence_ms * u64::from(rate) / 1000,
+        None => frames_per_chunk,
+    } + preroll_frames.max(overlap_frames);
+    let longest = Duration::from_secs_f64(longest_frames as f64 / f64::from(rate));
+    // Room for it, so it is not copied as it grows.
+    let capacity = 44 + longest_frames as usize * usize::from(spec.channels) * usize::from(spec.bits_per_sample / 8);
+    // Raw chunks say nothing of their layout, and the others are told apart by their channels.
+    match format {
+        Format::Raw => endpoint.headers.extend(raw_headers(RawLayout { sample_rate: rate, channels })),
+        _ => endpoint.headers.push(channels_header(channels)),
+    }
+    let streams = args.stream_upload.then(Streams::default);
+    let breaker = (args.breaker_failures > 0).then(|| Breaker::new(args.breaker_failures, args.breaker_cooldown));
+    // What opens each chunk's streamed upload, if it gets one.
+    let stream = {
+        let (streams, endpoint, metadata, breaker) = (streams.clone(), endpoint.clone(), metadata.clone(), breaker.clone());
+        move |seq: u64, path: &Path| {
+            let streams = streams.as_ref()?;
+            // Not while the server is failing; the chunk is uploaded whole, when the circuit lets it.
+            if breaker.as_ref().is_some_and(|breaker| breaker.state() != State::Closed) {
+                return None;
+            }
+            streams.start(seq, &endpoint.for_chunk(&metadata.clone().with("X-Chunk-Seq", "chunk_seq", seq)), path, longest)
+        }
+    };
+    // Chunks recorded to memory while the disk is too full for them.
+    let spilled = Shelf::default();
+    let (sink, feed) = match &shelf {
+        _ if session.is_some() => {
+            let open: OpenChunk<Discard> = Box::new(move |seq| {
+                debug!(chunk = seq, "recording chunk into the session file");
+                Ok((namer.path(seq), Discard))
+            });
+            spawn_sink(written, plan, open, connection)?
+        }
+        _ if args.streams_raw() => {
+            let open: OpenChunk<Discard> = Box::new(move |seq| Ok((namer.path(seq), Discard)));
+            spawn_sink(written, plan, open, connection)?
+        }
+        Some(shelf) => {
+            let shelf = shelf.clone();
+            let open: OpenChunk<Encoder<Tee<MemoryFile>>> = Box::new(move |seq| {
+                let path = namer.path(seq);
+                let writer = format.encoder(Tee::new(shelf.file(seq, capacity), stream(seq, &path)), spec, bitrate)?;
+                info!(chunk = seq, "recording chunk in memory");
+                Ok((path, writer))
+            });
+            spawn_sink(written, plan, open, connection)?
+        }
+        None => {
+            let mut watch = Watch::new(args.space_thresholds());
+            let purger = housekeeper.as_ref().map(Housekeeper::purger);
+            let (spilled, in_memory) = (spilled.clone(), args.low_space_in_memory);
+            let open: OpenChunk<Encoder<Tee<ChunkFile>>> = Box::new(move |seq| {
+                let path = namer.path(seq);
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                if check_space(&mut watch, dir, purger.as_ref(), in_memory) == space::Level::Exhausted {
+                    let file = ChunkFile::Memory(spilled.file(seq, capacity));
+                    let writer = format.encoder(Tee::new(file, stream(seq, &path)), spec, bitrate)?;
+                    debug!(chunk = seq, "recording chunk in memory (low on disk space)");
+                    return Ok((path, writer));
+                }
+                let file = File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+                let writer = format.encoder(Tee::new(ChunkFile::Disk(BufWriter::new(file)), stream(seq, &path)), spec, bitrate)?;
+                info!(chunk = seq, path = %path.display(), "recording chunk");
+                Ok((path, writer))
+            });
+            spawn_sink(written, plan, open, connection)?
+        }
+    };
+    let recovery = Recovery {
+        find: (!args.no_reconnect).then(|| find_again(args, query, &config)),
+        retries: args.stream_retries,
+        callback_interval: callback_interval(buffer_size, captured_rate),
+        stall_timeout: args.stall_timeout,
+    };
+    let mut input = Input::start(device, config, feed, recovery)?;
+    if args.push_to_talk {
+        input.push_to_talk(Keys::watch(args.ptt_key));
+        info!("Push-to-talk: hold {} to record", key_name(args.ptt_key));
+    }
+
+    let spool = args
+        .spool_dir
+        .as_deref()
+        .map(|dir| {
+            info!("Spooling failed uploads to: {}", dir.display());
+            // Scratch files are recorded to again; a chunk's own file can be linked.
+            let mode = if args.name_template.is_none() { archive::Mode::Copy } else { archive::Mode::Link };
+            Spool::open(dir, args.spool_max_mb << 20, label, mode)
+        })
+        .transpose()?;
+    let retrier = spool.as_ref().map(|spool| {
+        let (endpoint, log, timed) = (endpoint.clone(), file.clone(), label.is_some());
+        // The spool is gone through again soon enough; no retries in between.
+        let once = Retries { retries: 0, ..args.retries() };
+        let (ready, breaker) = (breaker.clone(), breaker.clone());
+        let ready = move || ready.as_ref().is_none_or(Breaker::allow);
+        spool.retry_when(SPOOL_RETRY_INTERVAL, ready, move |spooled| {
+            // The sidecar has no more to tell of it.
+            let metadata = Metadata::default()
+                .with("X-Chunk-Seq", "chunk_seq", spooled.seq)
+                .with("X-Chunk-Start", "chunk_start", format_timestamp_millis(spooled.started_millis));
+            let endpoint = endpoint.for_chunk(&metadata);
+            let (uploaded, _) = once.run(spooled.seq, || endpoint.upload_file(&spooled.path));
+            if let Some(breaker) = &breaker {
+                breaker.record(&uploaded);
+            }
+            match uploaded {
+                // It would only be turned down again.
+                Ok(response) if !(200..300).contains(&response.status) => {
+                    let body = excerpt(&response.body);
+                    error!(chunk = spooled.seq, status = response.status, body, "the server did not take the spooled chunk; deleting it from the spool");
+                    true
+                }
+                Ok(response) => {
+                    info!(chunk = spooled.seq, status = response.status, attempts = spooled.attempts + 1, "uploaded from the spool");
+                    let timestamp = timed.then(|| format_timestamp_millis(spooled.started_millis));
+                    transcribe(&endpoint, spooled.seq, log.as_deref(), timestamp.as_deref(), &response);
+                    true
+                }
+                Err(err) => {
+                    debug!(chunk = spooled.seq, path = %spooled.path.display(), "spooled chunk not uploaded yet: {err:#}");
+                    false
+                }
+            }
+        })
+    });
+    let uploads = UploadQueue::new(args.upload_workers as usize, args.upload_queue as usize, args.overflow, args.duration);
+    let stats = (!args.stats_interval.is_zero()).then(|| {
+        let (gauge, breaker) = (uploads.gauge(), breaker.clone());
+        Ticker::every(args.stats_interval, move || {
+            let Load { queued, running, done, dropped } = gauge.load();
+            let circuit = breaker.as_ref().map(|breaker| breaker.state().to_string());
+            info!(queued, in_flight = running, done, dropped, circuit, "upload stats");
+        })
+    });
+    let mut delivery = Delivery {
+        args,
+        rate,
+        metadata,
+        timed: label.is_some(),
+        devices,
+        endpoint,
+        log: file,
+        shelf,
+        slots,
+        archive: args.archive_dir.clone().map(|dir| Archive {
+            dir,
+            label: label.map(str::to_owned),
+            // Scratch files are recorded to again; a chunk's own file can be linked.
+            mode: if args.name_template.is_none() && !args.dry_run { archive::Mode::Move } else { archive::Mode::Link },
+        }),
+        housekeeper,
+        bwf,
+        session,
+        spilled,
+        streams,
+        breaker,
+        uploads,
+        spool,
+        retrier,
+        stats,
+        rejection: global.fail_fast_on_4xx.then(Arc::default),
+        clipped_chunks: 0,
+        recent: VecDeque::new(),
+        failure: None,
+    };
+    let rejection = delivery.rejection.clone();
+    while let Some(chunk) = input.next_chunk(&sink) {
+        delivery.deliver(chunk?)?;
+        if delivery.failure.is_some() {
+            break;
+        }
+    }
+    let mut failure = delivery.failure.take().or_else(|| input.failure());
+    drop(input);
+    if shutdown::requested() {
+        info!("shutting down");
+        match sink.finish() {
+            // Only the pre-roll of a chunk that had just begun.
+            Some(Ok(chunk)) if chunk.frames == chunk.repeated_frames => {
+                delivery.remove(chunk.seq, &chunk.path).ok();
+            }
+            Some(Ok(chunk)) if args.discard_partial => {
+                info!(chunk = chunk.seq, "discarding the chunk in progress (--discard-partial)");
+                delivery.remove(chunk.seq, &chunk.path).ok();
+            }
+            Some(chunk) => delivery.deliver(chunk?)?,
+            None => {}
+        }
+        failure = failure.or(delivery.failure.take());
+        delivery.finish(Some(args.shutdown_grace))?;
+    } else {
+        if let (Some(limit), None) = (limit, &failure) {
+            info!("recorded {limit} chunk(s), stopping");
+        }
+        sink.finish();
+        delivery.finish(None)?;
+    }
+    let rejected = rejection.and_then(|rejection| rejection.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take());
+    failure.or(rejected).map_or(Ok(()), Err)
+}
+
+/// Prints the transcript in the answer to chunk `seq` and appends it to the log, after the
+/// chunk's start time if given and the language the server was told, if any; an answer
+/// without one is logged instead.
+fn transcribe(endpoint: &Endpoint, seq: u64, log: Option<&Mutex<File>>, timestamp: Option<&str>, response: &Response) {
+    let transcription = match endpoint.transcript(response) {
+        Ok(transcription) => transcription,
+        Err(body) => {
+            warn!(chunk = seq, status = response.status, body, "no transcript in the server's answer");
+            return;
+        }
+    };
+    if let Some(err) = &transcription.malformed {
+        warn!(chunk = seq, body = excerpt(&response.body), "the server's answer is not valid JSON ({err}); taking it as text");
+    }
+    let body = transcription.text.as_bytes();
+    println!("{}", transcription.text);
+    //append to a log file
+    if let Some(file) = log {
+        let mut line = Vec::with_capacity(body.len() + 64);
+        if let Some(timestamp) = timestamp {
+            write!(line, "{timestamp}\t").ok();
+        }
+        if let Some(language) = endpoint.language() {
+            write!(line, "{language}\t").ok();
+        }
+        line.extend_from_slice(body);
+        line.push(b'\n');
+        // A transcript that cannot be logged has still been printed; the uploads go on.
+        if let Err(err) = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).write_all(&line) {
+            error!(chunk = seq, "failed to write the transcript to the log: {err}");
+        }
+    }
+}
+
+/// Puts chunk `seq` into the spool, logging where it went.
+fn keep_for_later(spool: &Spool, seq: u64, path: &Path, data: Option<&[u8]>, started_millis: u64, attempts: u32) {
+    match spool.store(seq, path, data, started_millis, attempts) {
+        Ok(spooled) => info!(chunk = seq, path = %spooled.path.display(), "spooled for a later upload"),
+        Err(err) => error!(chunk = seq, "failed to spool the chunk: {err:#}"),
+    }
+}
+
+/// Where `--archive-dir` keeps the chunks, and how.
+#[derive(Clone)]
+struct Archive {
+    dir: PathBuf,
+    /// The device's label, with several devices.
+    label: Option<String>,
+    mode: archive::Mode,
+}
+
+impl Archive {
+    /// Archives chunk `seq`, logging where it went.
+    fn store(&self, seq: u64, path: &Path, started_millis: u64) {
+        let name = archive::archive_name(started_millis, seq, self.label.as_deref());
+        // Named as a WAV; the chunk keeps the extension of its format.
+        let name = match path.extension() {
+            Some(extension) => Path::new(&name).with_extension(extension).to_string_lossy().into_owned(),
+            None => name,
+        };
+        match archive::archive(&self.dir, path, &name, self.mode) {
+            Ok(archived) => debug!(chunk = seq, path = %archived.display(), "archived"),
+            Err(err) => error!(chunk = seq, "{err:#}"),
+        }
+    }
+}
+
+/// The session file `--session-file` records into, and its index.
+struct Session {
+    path: PathBuf,
+    spec: hound::WavSpec,
+    index: SessionIndex,
+}
+
+/// The session-wide part of the `bext` chunk `--bwf` gives every chunk.
+struct Broadcast {
+    originator: String,
+    session_id: u64,
+    device: String,
+}
+
+impl Broadcast {
+    fn bext(&self, seq: u64, clocked_start: SystemTime, time_reference: u64) -> Bext {
+        Bext {
+            description: format!("session {}, chunk {seq}, device {}", self.session_id, self.device),
+            originator: self.originator.clone(),
+            originator_reference: format!("{}-{seq}", self.session_id),
+            origination_millis: unix_millis(clocked_start),
+            time_reference,
+        }
+    }
+}
+
+/// Appends `bext` to chunk `seq`, in `data` if it is in memory and at `path` otherwise,
+/// warning if it cannot; the chunk goes on either way.
+fn stamp(seq: u64, path: &Path, data: Option<&mut Vec<u8>>, bext: &Bext) {
+    let stamped = match data {
+        Some(data) => bwf::append(&mut std::io::Cursor::new(data), bext),
+        None => std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut file| bwf::append(&mut file, bext)),
+    };
+    if let Err(err) = stamped {
+        warn!(chunk = seq, path = %path.display(), "failed to add the bext chunk: {err:#}");
+    }
+}
+
+/// What becomes of each finished chunk: it is reported and checked for clipping, then
+/// uploaded, listed (in a dry run) or deleted (if silent).
+struct Delivery<'a> {
+    args: &'a RecordArgs,
+    rate: u32,
+    /// What goes with every chunk's upload; each adds its own number, start and length.
+    metadata: Metadata,
+    /// Recording from several devices, each transcript in the log is preceded by its chunk's
+    /// start time, so the devices' logs can be interleaved.
+    timed: bool,
+    /// With `--mix`, the devices in it, listed with each chunk.
+    devices: Option<String>,
+    endpoint: Endpoint,
+    log: Option<Arc<Mutex<File>>>,
+    /// With `--in-memory`, where the chunks are instead of on disk.
+    shelf: Option<Shelf>,
+    /// The scratch files the chunks are recorded to without `--name-template`.
+    slots: Slots,
+    archive: Option<Archive>,
+    housekeeper: Option<Housekeeper>,
+    /// With `--bwf`, what goes into each chunk's `bext` chunk.
+    bwf: Option<Broadcast>,
+    /// With `--session-file`, where the chunks are instead of files of their own.
+    session: Option<Session>,
+    /// Chunks recorded to memory while the disk was below `--min-free-mb`.
+    spilled: Shelf,
+    /// With `--stream-upload`, the chunks' uploads opened as they were recorded.
+    streams: Option<Streams>,
+    /// Unless `--breaker-failures` is 0, what holds uploads back while the server is failing.
+    breaker: Option<Breaker>,
+    uploads: UploadQueue,
+    /// With `--spool-dir`, where failed uploads wait to be tried again, and what tries them.
+    spool: Option<Spool>,
+    retrier: Option<Retrier>,
+    /// Logs the stats line every `--stats-interval`.
+    stats: Option<Ticker>,
+    /// With `--fail-fast-on-4xx`, set by the first upload the server turns down for good,
+    /// which stops the recording.
+    rejection: Option<Arc<Mutex<Option<anyhow::Error>>>>,
+    /// Clipped chunks in a row.
+    clipped_chunks: u32,
+    /// With `--dedupe-window`, the digests of the last chunks, each with the chunk first
+    /// delivered with those samples.
+    recent: VecDeque<(u64, u64)>,
+    /// Set when `--fail-on-clipping` ends the recording.
+    failure: Option<anyhow::Error>,
+}
+
+impl Delivery<'_> {
+    /// Deletes chunk `seq`, at `path` unless it is in memory, instead of uploading it.
+    fn remove(&self, seq: u64, path: &Path) -> std::io::Result<()> {
+        self.slots.release(seq);
+        if let Some(stream) = self.streams.as_ref().and_then(|streams| streams.take(seq)) {
+            stream.abandon();
+        }
+        match &self.shelf {
+            Some(shelf) => {
+                shelf.take(seq);
+                Ok(())
+            }
+            // Its frames stay in the session file.
+            None if self.session.is_some() || self.args.streams_raw() => Ok(()),
+            None if self.spilled.take(seq).is_some() => Ok(()),
+            None => std::fs::remove_file(path),
+        }
+    }
+
+    /// With `--dedupe-window`, the chunk among the last ones that chunk `seq` repeats, going
+    /// by their `digest`; the chunk is counted among them either way.
+    fn repeats(&mut self, seq: u64, digest: u64) -> Option<u64> {
+        let window = self.args.dedupe_window;
+        if window == 0 {
+            return None;
+        }
+        let original = self.recent.iter().rev().find(|(seen, _)| *seen == digest).map(|&(_, original)| original);
+        self.recent.push_back((digest, original.unwrap_or(seq)));
+        if self.recent.len() > window {
+            self.recent.pop_front();
+        }
+        original
+    }
+
+    fn deliver(&mut self, chunk: Chunk) -> Result<(), anyhow::Error> {
+        let Chunk { seq, path, frames, repeated_frames, dropped_frames, level, channel_levels, loudest_dbfs, digest, speech, started, clocked, time_reference, span } =
+            chunk;
+        let (args, rate) = (self.args, self.rate);
+        let finished = Instant::now();
+        if dropped_frames > 0 {
+            warn!(chunk = seq, dropped_frames, "audio capture overran; frames were dropped");
+        }
+        let peak_dbfs = format!("{:.1}", level.peak_dbfs());
+        let bext = self.bwf.as_ref().map(|bwf| bwf.bext(seq, clocked.start, time_reference));
+        // How far the system clock has run ahead of the audio clock since the stream started.
+        let drift_ms = unix_millis(started) as i64 - unix_millis(clocked.start) as i64;
+        let started_millis = unix_millis(started);
+        let started = format_timestamp_millis(started_millis);
+        let (clock_start, clock_end) =
+            (format_timestamp_millis(unix_millis(clocked.start)), format_timestamp_millis(unix_millis(clocked.end)));
+        match speech {
+            Some(speech) => {
+                let (start, end) = (seconds(speech.start, rate), seconds(speech.end, rate));
+                info!(chunk = seq, frames, repeated_frames, dropped_frames, peak_dbfs, clipped_samples = level.clipped, started, clock_start, clock_end, drift_ms, speech_start = start, speech_end = end, devices = self.devices.as_deref(), "chunk finished");
+            }
+            None => info!(chunk = seq, frames, repeated_frames, dropped_frames, peak_dbfs, clipped_samples = level.clipped, started, clock_start, clock_end, drift_ms, devices = self.devices.as_deref(), "chunk finished"),
+        }
+        // Its frames went out raw; there is nothing more to it.
+        if args.streams_raw() {
+            return Ok(());
+        }
+        if let Some(session) = &mut self.session {
+            let entry = Entry { seq, frames: span.clone(), started: &started, clock_start: &clock_start };
+            if let Err(err) = session.index.append(&entry) {
+                error!(chunk = seq, path = %session.index.path().display(), "failed to index the chunk: {err:#}");
+            }
+        }
+        if channel_levels.len() > 1 {
+            let list = |dbfs: fn(&Level) -> f32| {
+                channel_levels.iter().map(|level| format!("{:.1}", dbfs(level))).collect::<Vec<_>>().join(",")
+            };
+            info!(chunk = seq, peak_dbfs = list(Level::peak_dbfs), rms_dbfs = list(Level::rms_dbfs), "channel levels");
+            for (channel, level) in channel_levels.iter().enumerate() {
+                if level.samples > 0 && level.peak_dbfs() < DEAD_CHANNEL_DBFS {
+                    warn!(chunk = seq, "channel {} carried no signal; is its input connected?", channel + 1);
+                }
+            }
+        }
+        if level.clipped_ratio() > CLIP_WARN_RATIO {
+            self.clipped_chunks += 1;
+            warn!(
+                chunk = seq,
+                "{:.2}% of the samples clipped; lower the input level or --gain",
+                100.0 * level.clipped_ratio()
+            );
+            if args.fail_on_clipping.is_some_and(|limit| self.clipped_chunks >= limit) {
+                self.failure = Some(anyhow::anyhow!("{} chunks in a row clipped (--fail-on-clipping)", self.clipped_chunks));
+            }
+        } else {
+            self.clipped_chunks = 0;
+        }
+
+        if self.spilled.contains(seq) && (!args.low_space_in_memory || args.dry_run) {
+            error!(chunk = seq, "not recorded: the disk is below --min-free-mb");
+            self.remove(seq, &path).ok();
+            return Ok(());
+        }
+
+        if args.skip_silence.is_some_and(|threshold| loudest_dbfs < threshold) {
+            info!(chunk = seq, loudest_dbfs = format!("{loudest_dbfs:.1}"), "skipped (silent)");
+            // Never uploaded, so retention would never delete it either.
+            if let Err(err) = self.remove(seq, &path) {
+                warn!(chunk = seq, path = %path.display(), "failed to delete silent chunk: {err}");
+            }
+        } else if let Some(original) = self.repeats(seq, digest) {
+            info!(chunk = seq, "skipped (duplicate of chunk #{original})");
+            if let Err(err) = self.remove(seq, &path) {
+                warn!(chunk = seq, path = %path.display(), "failed to delete duplicate chunk: {err}");
+            }
+        } else if args.dry_run {
+            if let Some(bext) = &bext {
+                stamp(seq, &path, None, bext);
+            }
+            let (duration, peak) = match args.format {
+                Format::Wav => chunk_stats(&path)?,
+                // Only WAVs are read back; other formats report the capture's own peak.
+                _ => (frames as f64 / f64::from(rate), f64::from(level.peak_dbfs())),
+            };
+            println!("{}\t{duration:.2}s\tpeak {peak:.1} dBFS", path.display());
+            if let Some(archive) = self.archive.clone() {
+                self.uploads.push(seq, Box::new(move |_| archive.store(seq, &path, started_millis)));
+            }
+        } else {
+            // Uploaded by the queue's workers, so recording goes on meanwhile.
+            let file_clone = self.log.clone();
+            let timestamp = self.timed.then(|| started.clone());
+            let metadata = self
+                .metadata
+                .clone()
+                .with("X-Chunk-Seq", "chunk_seq", seq)
+                .with("X-Chunk-Start", "chunk_start", &clock_start)
+                .with("X-Chunk-Duration", "chunk_duration", seconds(frames, rate));
+            let endpoint = self.endpoint.for_chunk(&metadata);
+            let retries = args.retries();
+            let retention = self.housekeeper.as_ref().filter(|_| !self.spilled.contains(seq)).map(Housekeeper::sender);
+            let slots = self.slots.clone();
+            let archive = self.archive.clone();
+            let spool = self.spool.clone();
+            let rejection = self.rejection.clone();
+            let streams = self.streams.clone();
+            let stream = streams.as_ref().and_then(|streams| streams.take(seq));
+            let breaker = self.breaker.clone();
+            let mut data = match (&self.shelf, &self.session) {
+                (Some(shelf), _) => Some(shelf.take(seq).with_context(|| format!("chunk {seq} went missing from memory"))?),
+                (None, Some(session)) => match session::slice(&session.path, session.spec, span) {
+                    Ok(data) => Some(data),
+                    Err(err) => {
+                        error!(chunk = seq, path = %session.path.display(), "failed to read the chunk back: {err:#}");
+                        self.slots.release(seq);
+                        return Ok(());
+                    }
+                },
+                (None, None) => self.spilled.take(seq),
+            };
+            if let Some(bext) = &bext {
+                stamp(seq, &path, data.as_mut(), bext);
+            }
+            self.uploads.push(seq, Box::new(move |fate| {
+                if fate == Fate::Dropped {
+                    if let Some(stream) = stream {
+                        stream.abandon();
+                    }
+                    if let Some(spool) = &spool {
+                        keep_for_later(spool, seq, &path, data.as_deref(), started_millis, 0);
+                    }
+                    if let Some(archive) = &archive {
+                        archive.store(seq, &path, started_millis);
+                    }
+                    slots.release(seq);
+                    return;
+                }
+                // A streamed upload that was taken is the chunk's upload; otherwise it is uploaded whole.
+                let mut turned_down = false;
+                if let Some(stream) = stream {
+                    let started = stream.started;
+                    match stream.answer() {
+                        (Ok(response), answered) if (200..300).contains(&response.status) => {
+                            if let Some(archive) = &archive {
+                                archive.store(seq, &path, started_millis);
+                            }
+                            slots.release(seq);
+                            if let Some(breaker) = &breaker {
+                                breaker.success();
+                            }
+                            let latency_ms = answered.saturating_duration_since(finished).as_millis() as u64;
+                            let head_start_ms = finished.saturating_duration_since(started).as_millis() as u64;
+                            info!(chunk = seq, status = response.status, latency_ms, head_start_ms, "uploaded while recording");
+                            transcribe(&endpoint, seq, file_clone.as_deref(), timestamp.as_deref(), &response);
+                            if let Some(retention) = retention {
+                                retention.send(Uploaded { seq, path, recorded: finished }).ok();
+                            }
+                            return;
+                        }
+                        (Ok(response), _) => {
+                            turned_down = (400..500).contains(&response.status) && response.status != 429;
+                            warn!(chunk = seq, status = response.status, "the streamed upload was turned down; uploading the chunk whole");
+                        }
+                        (Err(err), _) => warn!(chunk = seq, "the streamed upload failed; uploading the chunk whole: {err:#}"),
+                    }
+                }
+                // With the circuit open the chunk is not tried: it is spooled, or waits its turn.
+                if let Some(breaker) = &breaker {
+                    let mut allowed = breaker.allow();
+                    if let (false, Some(spool)) = (allowed, &spool) {
+                        debug!(chunk = seq, "the upload circuit is open; spooling the chunk without trying it");
+                        keep_for_later(spool, seq, &path, data.as_deref(), started_millis, 0);
+                        if let Some(archive) = &archive {
+                            archive.store(seq, &path, started_millis);
+                        }
+                        slots.release(seq);
+                        return;
+                    }
+                    while !allowed {
+                        std::thread::sleep(BREAKER_WAIT);
+                        allowed = breaker.allow();
+                    }
+                }
+                let upload_started = Instant::now();
+                let (uploaded, attempts) = retries.run(seq, || match &data {
+                    Some(data) => endpoint.upload_bytes(&path, data),
+                    None => endpoint.upload_file(&path),
+                });
+                if let Some(breaker) = &breaker {
+                    breaker.record(&uploaded);
+                }
+                // Done with the file either way; it has had all its retries.
+                if let (Err(_), Some(spool)) = (&uploaded, &spool) {
+                    keep_for_later(spool, seq, &path, data.as_deref(), started_millis, attempts);
+                }
+                if let Some(archive) = &archive {
+                    archive.store(seq, &path, started_millis);
+                }
+                slots.release(seq);
+                let elapsed_ms = upload_started.elapsed().as_millis() as u64;
+                let response = match uploaded {
+                    Ok(response) => response,
+                    Err(err) => {
+                        error!(chunk = seq, path = %path.display(), attempts, elapsed_ms, "{err:#}");
+                        return;
+                    }
+                };
+                // Never a transcript; all it had to say goes into the error.
+                if !(200..300).contains(&response.status) {
+                    let body = excerpt(&response.body);
+                    error!(chunk = seq, status = response.status, attempts, elapsed_ms, body, "the server did not take the chunk");
+                    if let (true, Some(rejection)) = (rejected(response.status), &rejection) {
+                        let mut rejection = rejection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                        if rejection.is_none() {
+                            error!(chunk = seq, "--fail-fast-on-4xx: stopping the recording");
+                            *rejection = Some(anyhow::anyhow!("the server answered {} to chunk {seq} (--fail-fast-on-4xx)", response.status));
+                            shutdown::request();
+                        }
+                    }
+                    return;
+                }
+                let latency_ms = finished.elapsed().as_millis() as u64;
+                info!(chunk = seq, status = response.status, attempts, elapsed_ms, latency_ms, "uploaded");
+                if let (true, Some(streams)) = (turned_down && (200..300).contains(&response.status), &streams) {
+                    streams.refuse();
+                }
+                transcribe(&endpoint, seq, file_clone.as_deref(), timestamp.as_deref(), &response);
+                if let Some(retention) = retention {
+                    retention.send(Uploaded { seq, path, recorded: finished }).ok();
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    /// Lets the outstanding uploads land in the log, waiting at most `wait` if given, then
+    /// flushes the log.
+    fn finish(self, wait: Option<Duration>) -> Result<(), anyhow::Error> {
+        if let Some(retrier) = self.retrier {
+            retrier.finish();
+        }
+        if let Some(stats) = self.stats {
+            stats.finish();
+        }
+        if self.uploads.finish(wait) {
+            if let Some(housekeeper) = self.housekeeper {
+                // Waits for the upload jobs' senders, so only once they are all gone.
+                housekeeper.finish();
+            }
+        }
+        if let Some(file) = &self.log {
+            file.lock().unwrap().flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the requested device and picks the stream config closest to the requested one,
+/// warning when the chunks will not have the requested rate or channel count.
+fn open_input(args: &RecordArgs, query: &str) -> Result<(cpal::Device, String, SupportedStreamConfig), anyhow::Error> {
+    let host = select_host(args.host.name())?;
+    let device = match &args.loopback {
+        Some(output) => {
+            info!("Loopback: recording what `{output}` plays");
+            loopback::select(&host, output)?
+        }
+        None => select_device(&host, query)?,
+    };
+
+    let device_name = device.name()?;
+    info!("Input device: {}", device_name);
+    info!("Chunk duration: {}s", args.duration.as_secs_f64());
+
+    let ranges: Vec<_> = device.supported_input_configs()?.collect();
+    // `--input-channel` opens the device with every channel it has and keeps just the one.
+    let channels = match args.input_channel {
+        Some(_) => ranges.iter().map(|r| r.channels()).max().unwrap_or(args.channels),
+        None => args.channels,
+    };
+    let wanted = Wanted { channels, sample_rate: args.sample_rate, sample_format: args.sample_format.into() };
+    let Some(config) = negotiate(&ranges, wanted) else {
+        anyhow::bail!(
+            "input device `{device_name}` offers no sample format the recorder can capture; supported configs:\n{}",
+            describe_ranges(&ranges)
+        );
+    };
+    if let Some(channel) = args.input_channel {
+        if channel > config.channels() {
+            anyhow::bail!(
+                "--input-channel {channel} is out of range: input device `{device_name}` has {} channel(s)",
+                config.channels()
+            );
+        }
+    }
+    let resampled = args.target_rate.is_some();
+    let picked = args.input_channel.is_some();
+    if (config.sample_rate().0 != wanted.sample_rate && !resampled) || (config.channels() != wanted.channels && !picked) {
+        warn!(
+            "input device `{device_name}` cannot record {} ch / {} Hz; recording {} ch / {} Hz instead",
+            wanted.channels,
+            wanted.sample_rate,
+            config.channels(),
+            config.sample_rate().0
+        );
+    }
+    if config.sample_format() != wanted.sample_format {
+        info!("Capturing {} and converting to {}", config.sample_format(), args.bit_depth.unwrap_or_default().written());
+    }
+    info!(
+        "Stream config: {} ch, {} Hz, {}",
+        config.channels(),
+        config.sample_rate().0,
+        config.sample_format()
+    );
+    Ok((device, device_name, config))
+}
+
+/// Where `--stdout-raw`, `--fifo`, `--ws-url` or `--grpc-endpoint` sends the stream laid out as
+/// `spec`. Opening a FIFO waits for its reader; a WebSocket or gRPC call is made with the
+/// credential and headers of `endpoint`, and writes its transcripts to `log`.
+#[cfg_attr(not(any(feature = "websocket", feature = "grpc")), allow(unused_variables))]
+fn raw_stream(args: &RecordArgs, spec: hound::WavSpec, endpoint: &Endpoint, log: Option<&Arc<Mutex<File>>>) -> Result<Option<RawStream>, anyhow::Error> {
+    #[cfg(feature = "websocket")]
+    if let Some(url) = &args.ws_url {
+        let options = websocket::Options {
+            url: url.clone(),
+            headers: endpoint.headers.clone(),
+            auth: endpoint.auth.clone(),
+            client: endpoint.client.clone(),
+            frame: Duration::from_millis(args.ws_frame_ms),
+            replay: Duration::from_millis(args.preroll_ms),
+            retries: args.retries(),
+            log: log.cloned(),
+            language: endpoint.language(),
+        };
+        return Ok(Some(RawStream::new(Box::new(WsStream::connect(options, spec)?), spec)));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(url) = &args.grpc_endpoint {
+        let options = grpc::Options {
+            url: url.clone(),
+            headers: endpoint.headers.clone(),
+            auth: endpoint.auth.clone(),
+            client: endpoint.client.clone(),
+            log: log.cloned(),
+            language: endpoint.language(),
+        };
+        return Ok(Some(RawStream::new(Box::new(GrpcStream::connect(options, spec)?), spec)));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.fifo {
+        let fifo = Fifo::open(path, args.fifo_timeout, spec, Duration::from_secs(args.fifo_buffer))?;
+        return Ok(Some(RawStream::new(Box::new(BufWriter::new(fifo)), spec)));
+    }
+    Ok(args.stdout_raw.then(|| RawStream::stdout(spec)))
+}
+
+/// What the uploads of chunks of `spec` in `format` say of their audio: the depth is the one
+/// the format stores, which need not be the one recorded, and MP3 chunks give their delay.
+pub fn audio_metadata(format: Format, spec: hound::WavSpec, device: &str) -> Metadata {
+    let metadata = Metadata::default()
+        .with("X-Audio-Sample-Rate", "sample_rate", spec.sample_rate)
+        .with("X-Audio-Channels", "channels", spec.channels)
+        .with("X-Audio-Bit-Depth", "bit_depth", format.bits(spec))
+        .with("X-Device", "device", device);
+    // Decoded MP3 starts late by this many samples, and nothing in the file says so.
+    #[cfg(feature = "mp3")]
+    if format == Format::Mp3 {
+        return metadata.with("X-Audio-Encoder-Delay", "encoder_delay", crate::mp3::DELAY);
+    }
+    metadata
+}
+
+/// The filters the options ask for, in the order they run. They see the captured rate and the
+/// chunk's channels.
+fn filter_stages(args: &RecordArgs, channels: u16, rate: u32) -> Vec<Box<dyn Stage>> {
+    let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+    if args.remove_dc {
+        stages.push(Box::new(DcBlocker::new(channels, rate)));
+    }
+    if let Some(cutoff) = args.highpass {
+        stages.push(Box::new(HighPass::new(channels, rate, cutoff)));
+    }
+    // Before the gate, so it opens on speech rather than on the noise taken out.
+    #[cfg(feature = "denoise")]
+    if args.denoise {
+        stages.push(Box::new(Denoise::new(channels, rate)));
+    }
+    if let Some(threshold) = args.gate_threshold {
+        stages.push(Box::new(NoiseGate::new(channels, rate, threshold, args.gate_hold_ms)));
+    }
+    // After the gate, so the silence it leaves is not turned up.
+    if let Some(target) = args.agc {
+        stages.push(Box::new(Agc::new(channels, rate, target)));
+    }
+    stages
+}
+
+/// The `--vad` segmenter, cutting chunks of `channels` at `rate`.
+fn segmenter(args: &RecordArgs, channels: u16, rate: u32) -> Option<Segmenter> {
+    let frames = |d: Duration| (d.as_secs_f64() * f64::from(rate)).round() as u64;
+    let limits = Limits {
+        silence_frames: args.vad_silence_ms * u64::from(rate) / 1000,
+        min_frames: frames(args.min_chunk),
+        max_frames: frames(args.max_chunk).max(1),
+    };
+    match args.vad? {
+        VadMode::Energy => {
+            info!("Cutting chunks at pauses in speech above {} dBFS", args.vad_threshold);
+            Some(Segmenter::new(Box::new(EnergyVad::new(args.vad_threshold)), channels, rate, limits))
+        }
+        #[cfg(feature = "webrtc-vad")]
+        VadMode::Webrtc => {
+            info!("Cutting chunks at pauses in speech with the WebRTC VAD at aggressiveness {}", args.vad_aggressiveness);
+            Some(Segmenter::new(Box::new(WebrtcVad::new(channels, rate, args.vad_aggressiveness)), channels, rate, limits))
+        }
+    }
+}
+
+/// A frame offset as seconds, for the log.
+fn seconds(frames: u64, rate: u32) -> String {
+    format!("{:.2}", frames as f64 / f64::from(rate))
+}
+
+/// How many chunks `--max-chunks` and `--total-duration` allow, whichever is fewer. The total
+/// is rounded up to whole chunks, since the chunk in progress is always finished.
+fn chunk_limit(args: &RecordArgs) -> Option<u64> {
+    let by_duration = args
+        .total_duration
+        .map(|total| ((total.as_secs_f64() / args.duration.as_secs_f64()).ceil() as u64).max(1));
+    match (args.max_chunks, by_duration) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Names chunk files; lives on the writer thread, which opens each chunk.
+struct ChunkNamer {
+    output_dir: PathBuf,
+    template: Option<NameTemplate>,
+    dry_run: bool,
+    /// A dry run keeps every chunk, so each one gets its own name within this session.
+    session_id: u64,
+    device: String,
+    /// With several devices, this one's label, which stands in for `{device}` and goes into
+    /// the default names.
+    label: Option<String>,
+    duration: Duration,
+    /// Which scratch file each chunk has.
+    slots: Slots,
+    /// That of the `--format`, which every name gets.
+    extension: &'static str,
+}
+
+impl ChunkNamer {
+    /// Where the default names start.
+    fn prefix(&self) -> String {
+        match &self.label {
+            Some(label) => format!("recorded_{label}"),
+            None => "recorded".to_owned(),
+        }
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        let prefix = self.prefix();
+        let name = match &self.template {
+            Some(template) => template.render(&ChunkInfo {
+                seq,
+                timestamp: unix_secs(SystemTime::now()),
+                device: self.label.as_deref().unwrap_or(&self.device),
+                duration: self.duration,
+            }),
+            None if self.dry_run => format!("{prefix}_{}_{seq:05}.wav", self.session_id),
+            None => return self.scratch(self.slots.acquire(seq)),
+        };
+        self.output_dir.join(name).with_extension(self.extension)
+    }
+
+    /// Scratch file `slot` of this process.
+    fn scratch(&self, slot: usize) -> PathBuf {
+        self.output_dir.join(scratch_name(&self.prefix(), slot, std::process::id())).with_extension(self.extension)
+    }
+}
+
+/// Deletes the scratch files in `dir` of recorders that died without cleaning up.
+fn remove_stale_chunks(dir: &Path) {
+    let stale = stale_chunks(dir);
+    let removed = stale.iter().filter(|path| match std::fs::remove_file(path) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!(path = %path.display(), "failed to delete a chunk left by an earlier run: {err}");
+            false
+        }
+    });
+    let removed = removed.count();
+    if removed > 0 {
+        info!("Deleted {removed} chunk file(s) left in {} by earlier runs", dir.display());
+    }
+}
+
+/// `path` with `-label` added to its file stem: `log.txt` becomes `log-room.txt`.
+fn labelled(path: &Path, label: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-{label}"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+/// Finds `--device` again after it went away. It has to come back recording the rate and
+/// channels the chunks are written with; the sample format may change.
+fn find_again(args: &RecordArgs, query: &str, recording: &SupportedStreamConfig) -> Find {
+    let (host, query, output) = (args.host.name().map(str::to_owned), query.to_owned(), args.loopback.clone());
+    let wanted = Wanted {
+        channels: recording.channels(),
+        sample_rate: recording.sample_rate().0,
+        sample_format: recording.sample_format(),
+    };
+    Box::new(move || {
+        let host = select_host(host.as_deref())?;
+        let device = match &output {
+            Some(output) => loopback::select(&host, output)?,
+            None => select_device(&host, &query)?,
+        };
+        let ranges: Vec<_> = device.supported_input_configs()?.collect();
+        match negotiate(&ranges, wanted) {
+            Some(config) if config.channels() == wanted.channels && config.sample_rate().0 == wanted.sample_rate => {
+                Ok((device, config))
+            }
+            _ => anyhow::bail!(
+                "input device `{}` no longer offers {} ch / {} Hz",
+                device.name()?,
+                wanted.channels,
+                wanted.sample_rate
+            ),
+        }
+    })
+}
+
+/// How `--ptt-key` is shown in the log.
+fn key_name(key: u8) -> String {
+    match key {
+        b' ' => "space".to_string(),
+        key => format!("`{}`", char::from(key)),
+    }
+}
+
+/// How often the callback should run: once per buffer, or, leaving the size to the backend,
+/// about [`DEFAULT_CALLBACK_INTERVAL`].
+fn callback_interval(buffer_size: BufferSize, rate: u32) -> Duration {
+    match buffer_size {
+        BufferSize::Fixed(frames) => Duration::from_secs_f64(f64::from(frames) / f64::from(rate)),
+        BufferSize::Default => DEFAULT_CALLBACK_INTERVAL,
+    }
+}
+
+/// How each input stream is opened and what it runs through before the queue.
+struct Connection {
+    buffer_size: BufferSize,
+    gain: f32,
+    /// Filter stages for each new stream; those of a lost one keep its state.
+    stages: Box<dyn Fn() -> Vec<Box<dyn Stage>>>,
+    mixing: Option<Mixing>,
+}
+
+/// With `--mix`, the devices mixed into the stream of the first; see [`crate::mix`].
+struct Mixing {
+    /// Linear gain of each device, the first's included.
+    gains: Vec<f32>,
+    others: Vec<(cpal::Device, SupportedStreamConfig)>,
+    /// Samples of each of the others the mixer keeps buffered.
+    target: usize,
+    /// The others' streams, built again with each stream of the first.
+    streams: Vec<cpal::Stream>,
+}
+
+/// Checks the free space in `dir` before a chunk file is created there, asking for the kept
+/// chunks to go while it is low and reporting each change of level once.
+fn check_space(watch: &mut Watch, dir: &Path, purger: Option<&Purger>, in_memory: bool) -> space::Level {
+    let free = match space::available(dir) {
+        Ok(free) => free,
+        Err(err) => {
+            debug!(dir = %dir.display(), "cannot tell the free space: {err}");
+            return space::Level::Plenty;
+        }
+    };
+    let (level, changed) = watch.update(free);
+    if level != space::Level::Plenty {
+        if let Some(purger) = purger {
+            purger.purge();
+        }
+    }
+    if changed {
+        let (free_mb, dir) = (free >> 20, dir.display());
+        match level {
+            space::Level::Plenty => info!(free_mb, "{dir} has room again"),
+            space::Level::Low => warn!(free_mb, "{dir} is low on space (--low-free-mb); deleting the uploaded chunks kept"),
+            space::Level::Exhausted if in_memory => {
+                error!(free_mb, "{dir} is nearly full (--min-free-mb); recording chunks in memory until there is room")
+            }
+            space::Level::Exhausted => {
+                error!(free_mb, "{dir} is nearly full (--min-free-mb); NOT RECORDING until there is room")
+            }
+        }
+    }
+    level
+}
+
+/// Spawns the chunk writer thread for the `written` sample type, returning it with the feed
+/// that connects input streams to it.
+fn spawn_sink<W>(
+    written: SampleFormat,
+    plan: ChunkPlan,
+    open: OpenChunk<W>,
+    connection: Connection,
+) -> Result<(ChunkSink, Box<dyn Feed>), anyhow::Error>
+where
+    W: ChunkWriter<f32> + ChunkWriter<i32> + ChunkWriter<i16> + Send + 'static,
+{
+    match written {
+        SampleFormat::F32 => Feeder::<f32>::spawn(plan, open, connection),
+        SampleFormat::I32 => Feeder::<i32>::spawn(plan, open, connection),
+        _ => Feeder::<i16>::spawn(plan, open, connection),
+    }
+}
+
+/// Connects input streams to the writer thread's queue of `U` samples, converting from
+/// whatever format each captures.
+struct Feeder<U> {
+    /// The queue for the first stream, built with the plan's stages.
+    first: Option<SampleQueue<U>>,
+    /// Forked for each stream after it.
+    spare: SampleQueue<U>,
+    connection: Connection,
+}
+
+impl<U> Feeder<U>
+where
+    U: hound::Sample + SizedSample + FromSample<f32> + Send + 'static,
+    f32: FromSample<U>,
+    i16: FromSample<U>,
+    i32: FromSample<U>,
+{
+    fn spawn<W: ChunkWriter<U> + Send + 'static>(
+        plan: ChunkPlan,
+        open: OpenChunk<W>,
+        connection: Connection,
+    ) -> Result<(ChunkSink, Box<dyn Feed>), anyhow::Error>
+    where
+        Self: Feed,
+    {
+        let (sink, queue) = sink::spawn::<U, _>(plan, open)?;
+        let spare = queue.fork(Vec::new());
+        Ok((sink, Box::new(Feeder { first: Some(queue), spare, connection })))
+    }
+}
+
+impl<U> Feed for Feeder<U>
+where
+    U: SizedSample
+        + FromSample<i16>
+        + FromSample<i32>
+        + FromSample<f32>
+        + FromSample<u16>
+        + FromSample<u8>
+        + Gain
+        + Downmix
+        + Send
+        + 'static,
+{
+    fn connect(
+        &mut self,
+        device: &cpal::Device,
+        config: &SupportedStreamConfig,
+        errors: mpsc::Sender<StreamError>,
+    ) -> Result<cpal::Stream, anyhow::Error> {
+        let queue = match self.first.take() {
+            Some(queue) => queue,
+            None => self.spare.fork((self.connection.stages)()),
+        };
+        let mut stream_config = config.config();
+        stream_config.buffer_size = self.connection.buffer_size;
+        let gain = self.connection.gain;
+        if let Some(mixing) = self.connection.mixing.as_mut() {
+            return connect_mixed(mixing, device, config, stream_config, queue, gain, errors);
+        }
+        let on_error = move |err| {
+            errors.send(err).ok();
+        };
+        let stream = match config.sample_format() {
+            SampleFormat::I16 => capture::<i16, U>(device, &stream_config, queue, gain, on_error),
+            SampleFormat::I32 => capture::<i32, U>(device, &stream_config, queue, gain, on_error),
+            SampleFormat::F32 => capture::<f32, U>(device, &stream_config, queue, gain, on_error),
+            SampleFormat::U16 => capture::<u16, U>(device, &stream_config, queue, gain, on_error),
+            SampleFormat::U8 => capture::<u8, U>(device, &stream_config, queue, gain, on_error),
+            format => anyhow::bail!("unsupported sample format '{format}'"),
+        };
+        stream.map_err(|err| match self.connection.buffer_size {
+            BufferSize::Fixed(frames) => buffer_size_hint(err.into(), device, config, frames),
+            BufferSize::Default => err.into(),
+        })
+    }
+
+    fn control(&self, control: Control) {
+        self.spare.control(control);
+    }
+
+    fn callbacks(&self) -> Arc<AtomicU64> {
+        self.spare.callbacks()
+    }
+}
+
+/// Builds a stream capturing `T` samples into a ring, pumped on into `queue`.
+fn capture<T, U>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: SampleQueue<U>,
+    gain: f32,
+    on_error: impl FnMut(StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + Send + 'static,
+    U: SizedSample + FromSample<T> + FromSample<i16> + FromSample<f32> + Gain + Downmix + Send + 'static,
+    f32: FromSample<T>,
+{
+    let (mut capture, ring) = queue.capture::<T>(ring_capacity(config), config.channels, true);
+    queue.pump(ring, gain);
+    device.build_input_stream(config, move |data: &[T], _: &_| capture.push(data), on_error, None)
+}
+
+/// Samples of [`sink::RING_SECONDS`] of audio as `config` captures it.
+fn ring_capacity(config: &cpal::StreamConfig) -> usize {
+    sink::RING_SECONDS * config.sample_rate.0 as usize * usize::from(config.channels)
+}
+
+/// Builds the streams of a `--mix`: the other devices' first, each into a ring of its own, and
+/// then that of the first, returned, whose audio a thread then mixes with theirs into `queue`.
+fn connect_mixed<U>(
+    mixing: &mut Mixing,
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+    stream_config: cpal::StreamConfig,
+    queue: SampleQueue<U>,
+    gain: f32,
+    errors: mpsc::Sender<StreamError>,
+) -> Result<cpal::Stream, anyhow::Error>
+where
+    U: SizedSample + FromSample<i16> + FromSample<f32> + Gain + Downmix + Send + 'static,
+{
+    mixing.streams.clear();
+    let rate = config.sample_rate().0;
+    let mut sources = Vec::new();
+    for (other, other_config) in &mixing.others {
+        let other_rate = other_config.sample_rate().0;
+        let resampler = (other_rate != rate).then(|| Resampler::new(1, other_rate, rate));
+        let other_stream_config = cpal::StreamConfig { buffer_size: stream_config.buffer_size, ..other_config.config() };
+        let (stream, source) = match other_config.sample_format() {
+            SampleFormat::I16 => capture_other::<i16, U>(other, &other_stream_config, &queue, resampler, &errors),
+            SampleFormat::I32 => capture_other::<i32, U>(other, &other_stream_config, &queue, resampler, &errors),
+            SampleFormat::F32 => capture_other::<f32, U>(other, &other_stream_config, &queue, resampler, &errors),
+            SampleFormat::U16 => capture_other::<u16, U>(other, &other_stream_config, &queue, resampler, &errors),
+            SampleFormat::U8 => capture_other::<u8, U>(other, &other_stream_config, &queue, resampler, &errors),
+            format => anyhow::bail!("unsupported sample format '{format}'"),
+        }?;
+        stream.play()?;
+        mixing.streams.push(stream);
+        sources.push(source);
+    }
+    let mixer = Mixer::new(&mixing.gains, mixing.target);
+    let stream = match config.sample_format() {
+        SampleFormat::I16 => capture_mixed::<i16, U>(device, &stream_config, queue, sources, mixer, gain, errors),
+        SampleFormat::I32 => capture_mixed::<i32, U>(device, &stream_config, queue, sources, mixer, gain, errors),
+        SampleFormat::F32 => capture_mixed::<f32, U>(device, &stream_config, queue, sources, mixer, gain, errors),
+        SampleFormat::U16 => capture_mixed::<u16, U>(device, &stream_config, queue, sources, mixer, gain, errors),
+        SampleFormat::U8 => capture_mixed::<u8, U>(device, &stream_config, queue, sources, mixer, gain, errors),
+        format => anyhow::bail!("unsupported sample format '{format}'"),
+    };
+    Ok(stream?)
+}
+
+/// Builds a stream capturing `T` samples from one of the other devices of a `--mix` into a
+/// ring, returned with the [`mix::Source`] reading it.
+fn capture_other<T, U>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: &SampleQueue<U>,
+    resampler: Option<Resampler>,
+    errors: &mpsc::Sender<StreamError>,
+) -> Result<(cpal::Stream, Box<dyn mix::Source>), anyhow::Error>
+where
+    T: SizedSample + Send + 'static,
+    U: Send + 'static,
+    f32: FromSample<T>,
+{
+    let (mut capture, ring) = queue.capture::<T>(ring_capacity(config), config.channels, false);
+    let errors = errors.clone();
+    let on_error = move |err| {
+        errors.send(err).ok();
+    };
+    let stream = device.build_input_stream(config, move |data: &[T], _: &_| capture.push(data), on_error, None)?;
+    Ok((stream, mix::source(ring, config.channels, resampler)))
+}
+
+/// Builds the stream of the device that sets the clock of a `--mix`, capturing `T` samples
+/// into a ring, and starts the thread that mixes them with `sources` into `queue`. The thread
+/// ends once the stream is dropped.
+fn capture_mixed<T, U>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut queue: SampleQueue<U>,
+    mut sources: Vec<Box<dyn mix::Source>>,
+    mut mixer: Mixer,
+    gain: f32,
+    errors: mpsc::Sender<StreamError>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + Send + 'static,
+    U: SizedSample + FromSample<i16> + FromSample<f32> + Gain + Downmix + Send + 'static,
+    f32: FromSample<T>,
+{
+    let channels = usize::from(config.channels.max(1));
+    let (mut capture, mut ring) = queue.capture::<T>(ring_capacity(config), config.channels, true);
+    logging::spawn(move || {
+        let mut buffer = vec![T::EQUILIBRIUM; 4096 * channels];
+        let mut other = Vec::new();
+        loop {
+            // Closed first, so nothing pushed before the close is left behind.
+            let closed = ring.is_closed();
+            for (lane, source) in sources.iter_mut().enumerate() {
+                other.clear();
+                source.read(&mut other);
+                mixer.push(lane + 1, &other);
+            }
+            let n = ring.pop_slice(&mut buffer);
+            if n > 0 {
+                let mixed = mixer.mix(&mix::mono(&buffer[..n], channels));
+                queue.forward::<f32>(&mixed, gain, n);
+            } else if closed {
+                break;
+            } else {
+                std::thread::sleep(MIX_POLL);
+            }
+        }
+    });
+    let on_error = move |err| {
+        errors.send(err).ok();
+    };
+    device.build_input_stream(config, move |data: &[T], _: &_| capture.push(data), on_error, None)
+}
+
+/// Adds the device's supported buffer size range to a stream build error caused (most
+/// likely) by `--buffer-size`.
+fn buffer_size_hint(
+    err: anyhow::Error,
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+    frames: u32,
+) -> anyhow::Error {
+    let supported = device.supported_input_configs().ok().and_then(|mut ranges| {
+        ranges.find(|r| r.channels() == config.channels() && r.sample_format() == config.sample_format())
+    });
+    let range = match supported.as_ref().map(|r| r.buffer_size()) {
+        Some(SupportedBufferSize::Range { min, max }) => format!("{min}-{max} frames"),
+        _ => String::from("unknown"),
+    };
+    err.context(format!(
+        "the device rejected --buffer-size {frames}; supported buffer sizes: {range}"
+    ))
+}
+
+/// Reads a finalized chunk back and returns its duration in seconds and its peak level in
+/// dBFS.
+fn chunk_stats(path: &Path) -> Result<(f64, f64), anyhow::Error> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let peak = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .try_fold(0f64, |peak, s| s.map(|s| peak.max(f64::from(s.abs()))))?,
+        hound::SampleFormat::Int => {
+            let full_scale = (1i64 << (spec.bits_per_sample - 1)) as f64;
+            reader
+                .samples::<i32>()
+                .try_fold(0f64, |peak, s| s.map(|s| peak.max(f64::from(s).abs() / full_scale)))?
+        }
+    };
+    let frames = reader.duration();
+    Ok((f64::from(frames) / f64::from(spec.sample_rate), 20.0 * peak.log10()))
+}
+
+fn describe_ranges(ranges: &[SupportedStreamConfigRange]) -> String {
+    ranges
+        .iter()
+        .map(|r| {
+            format!(
+                "  {} ch, {}-{} Hz, {}",
+                r.channels(),
+                r.min_sample_rate().0,
+                r.max_sample_rate().0,
+                r.sample_format()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,170 @@
+//! `--target-rate`: converting the captured stream to another sample rate.
+//!
+//! A windowed-sinc resampler. Each output sample is a weighted sum of the input samples
+//! within [`ZERO_CROSSINGS`] lobes of it; when downsampling the sinc is widened to cut off
+//! below the output Nyquist frequency, so tones the output rate cannot hold are filtered out
+//! instead of folding back as aliases. The resampler carries its history from one call to
+//! the next, so a stream fed in arbitrary pieces comes out exactly as if fed in one.
+
+use std::f64::consts::PI;
+
+/// Lobes of the sinc kept on either side of each output sample.
+const ZERO_CROSSINGS: f64 = 16.0;
+
+/// Fraction of the lower Nyquist frequency passed; the rest is the filter's transition band.
+const PASSBAND: f64 = 0.92;
+
+/// Distinct fractional positions above which weights are computed per sample instead of
+/// being tabulated.
+const MAX_PHASES: u64 = 1024;
+
+/// Converts interleaved f32 audio from one sample rate to another.
+pub struct Resampler {
+    from: u64,
+    to: u64,
+    channels: usize,
+    /// Cutoff relative to the input Nyquist frequency.
+    cutoff: f64,
+    /// Input frames used on either side of an output sample.
+    half: i64,
+    /// Kernel weights for each fractional position, `2 * half` per phase, when the ratio has
+    /// few enough phases.
+    table: Option<Vec<f32>>,
+    /// Buffered input, interleaved; frame 0 is input frame `start`.
+    buffer: Vec<f32>,
+    start: i64,
+    /// Input frames received so far.
+    consumed: u64,
+    /// Output frames produced so far.
+    produced: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: u16, from: u32, to: u32) -> Self {
+        let gcd = gcd(u64::from(from), u64::from(to));
+        let (from, to) = (u64::from(from) / gcd, u64::from(to) / gcd);
+        let cutoff = (to as f64 / from as f64).min(1.0) * PASSBAND;
+        let half = (ZERO_CROSSINGS / cutoff).ceil() as i64;
+        let channels = usize::from(channels.max(1));
+        let mut resampler = Resampler {
+            from,
+            to,
+            channels,
+            cutoff,
+            half,
+            table: None,
+            // Silence before the first sample, so the first output frame has a full window.
+            buffer: vec![0.0; half as usize * channels],
+            start: -half,
+            consumed: 0,
+            produced: 0,
+        };
+        if to <= MAX_PHASES {
+            let table = (0..to).flat_map(|phase| resampler.weights(phase)).collect();
+            resampler.table = Some(table);
+        }
+        resampler
+    }
+
+    /// Resamples `input` (whole frames), appending what can be computed so far to `output`.
+    /// The last few milliseconds wait for the input that follows them; see [`Self::flush`].
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.buffer.extend_from_slice(input);
+        self.consumed += (input.len() / self.channels) as u64;
+        let end = self.start + (self.buffer.len() / self.channels) as i64;
+        let taps = 2 * self.half as usize;
+        loop {
+            let scratch: Vec<f32>;
+            let position = self.produced * self.from;
+            let center = (position / self.to) as i64;
+            if center + self.half >= end {
+                break;
+            }
+            let phase = position % self.to;
+            let weights: &[f32] = match &self.table {
+                Some(table) => &table[phase as usize * taps..][..taps],
+                None => {
+                    scratch = self.weights(phase).collect();
+                    &scratch
+                }
+            };
+            let first = (center - self.half + 1 - self.start) as usize * self.channels;
+            for channel in 0..self.channels {
+                let sum: f32 = weights
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| w * self.buffer[first + k * self.channels + channel])
+                    .sum();
+                output.push(sum);
+            }
+            self.produced += 1;
+        }
+        // Keep only the frames the next output sample still needs.
+        let next = ((self.produced * self.from) / self.to) as i64 - self.half + 1;
+        let unneeded = (next - self.start).clamp(0, (self.buffer.len() / self.channels) as i64);
+        self.buffer.drain(..unneeded as usize * self.channels);
+        self.start += unneeded;
+    }
+
+    /// The most input frames [`Self::process`] holds back, waiting for the input that
+    /// follows them.
+    pub fn lookahead(&self) -> u64 {
+        self.half as u64 + 1
+    }
+
+    /// Produces the output still held back for lack of following input, treating what comes
+    /// after the end as silence, so the whole stream comes out at the new rate.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let total = (self.consumed * self.to).div_ceil(self.from);
+        let remaining = total.saturating_sub(self.produced) as usize * self.channels;
+        let mut tail = Vec::new();
+        self.process(&vec![0.0; (self.half as usize + 1) * self.channels], &mut tail);
+        tail.truncate(remaining);
+        output.extend(tail);
+    }
+
+    /// Forgets the stream so far, so the next input is resampled as the start of a new one.
+    pub fn restart(&mut self) {
+        self.buffer.clear();
+        self.buffer.resize(self.half as usize * self.channels, 0.0);
+        self.start = -self.half;
+        self.consumed = 0;
+        self.produced = 0;
+    }
+
+    /// The kernel weights for an output sample `phase / to` of the way between two input
+    /// frames, oldest first.
+    fn weights(&self, phase: u64) -> impl Iterator<Item = f32> + '_ {
+        let fraction = phase as f64 / self.to as f64;
+        (-self.half + 1..=self.half).map(move |k| {
+            // Distance from the output sample to input frame `center + k`.
+            let x = fraction - k as f64;
+            (self.cutoff * sinc(self.cutoff * x) * blackman(x / self.half as f64)) as f32
+        })
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// The Blackman window over [-1, 1].
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let t = PI * (x + 1.0);
+    0.42 - 0.5 * t.cos() + 0.08 * (2.0 * t).cos()
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
@@ -0,0 +1,153 @@
+//! `--keep` / `--keep-duration`: deleting uploaded chunks once they fall out of the retention
+//! window.
+//!
+//! Only chunks whose upload succeeded are ever handed to the [`Housekeeper`], so a failed
+//! upload always stays on disk. Deletion runs on its own thread and never holds up recording.
+
+use crate::logging;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Longest the housekeeper sleeps before re-checking ages.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How many uploaded chunks to keep. A chunk is deleted once no configured rule covers it any
+/// more; with neither rule it would be deleted right away, which is what `--keep 0` asks for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Keep the newest `keep` uploaded chunks.
+    pub keep: Option<usize>,
+    /// Keep chunks recorded less than this long ago.
+    pub keep_duration: Option<Duration>,
+}
+
+/// A chunk that was uploaded successfully.
+#[derive(Clone, Debug)]
+pub struct Uploaded {
+    pub seq: u64,
+    pub path: PathBuf,
+    /// When the chunk finished recording.
+    pub recorded: Instant,
+}
+
+impl Policy {
+    /// Indices into `uploaded` (sorted by `seq`) of the chunks no rule keeps at `now`.
+    pub fn expired(&self, uploaded: &[Uploaded], now: Instant) -> Vec<usize> {
+        let newest = uploaded.len().saturating_sub(self.keep.unwrap_or(0));
+        (0..uploaded.len())
+            .filter(|&i| {
+                let kept_by_count = self.keep.is_some() && i >= newest;
+                let kept_by_age = self
+                    .keep_duration
+                    .is_some_and(|window| now.saturating_duration_since(uploaded[i].recorded) < window);
+                !kept_by_count && !kept_by_age
+            })
+            .collect()
+    }
+
+    /// Why an expired chunk goes, for the debug log.
+    fn reason(&self) -> String {
+        let mut reasons = Vec::new();
+        if let Some(keep) = self.keep {
+            reasons.push(format!("not among the newest {keep}"));
+        }
+        if let Some(window) = self.keep_duration {
+            reasons.push(format!("older than {}s", window.as_secs_f64()));
+        }
+        if reasons.is_empty() {
+            String::from("uploaded")
+        } else {
+            format!("uploaded, {}", reasons.join(" and "))
+        }
+    }
+}
+
+/// The background thread applying a [`Policy`].
+pub struct Housekeeper {
+    sender: mpsc::Sender<Uploaded>,
+    handle: JoinHandle<()>,
+    purge: Arc<AtomicBool>,
+}
+
+/// Asks a [`Housekeeper`] to delete every uploaded chunk it is keeping, whatever its policy
+/// says: the disk is running out.
+#[derive(Clone)]
+pub struct Purger(Arc<AtomicBool>);
+
+impl Purger {
+    pub fn purge(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Housekeeper {
+    pub fn spawn(policy: Policy) -> Self {
+        let (sender, receiver) = mpsc::channel::<Uploaded>();
+        let purge = Arc::new(AtomicBool::new(false));
+        let purging = purge.clone();
+        let handle = logging::spawn(move || {
+            let mut uploaded: Vec<Uploaded> = Vec::new();
+            let add = |uploaded: &mut Vec<Uploaded>, chunk: Uploaded| {
+                let at = uploaded.partition_point(|c| c.seq < chunk.seq);
+                uploaded.insert(at, chunk);
+            };
+            loop {
+                let open = match receiver.recv_timeout(policy.keep_duration.map_or(TICK, |d| d.min(TICK))) {
+                    Ok(chunk) => {
+                        add(&mut uploaded, chunk);
+                        true
+                    }
+                    Err(RecvTimeoutError::Timeout) => true,
+                    Err(RecvTimeoutError::Disconnected) => false,
+                };
+                if purging.swap(false, Ordering::Relaxed) {
+                    // Everything reported by now goes.
+                    for chunk in receiver.try_iter() {
+                        add(&mut uploaded, chunk);
+                    }
+                    if !uploaded.is_empty() {
+                        warn!(chunks = uploaded.len(), "low on disk space; deleting the uploaded chunks kept so far");
+                        sweep(&Policy::default(), &mut uploaded);
+                    }
+                }
+                sweep(&policy, &mut uploaded);
+                if !open {
+                    break;
+                }
+            }
+        });
+        Housekeeper { sender, handle, purge }
+    }
+
+    /// A handle for the chunk writer to ask for room through.
+    pub fn purger(&self) -> Purger {
+        Purger(self.purge.clone())
+    }
+
+    /// A handle for upload threads to report successful uploads through.
+    pub fn sender(&self) -> mpsc::Sender<Uploaded> {
+        self.sender.clone()
+    }
+
+    /// Waits for a final pass once every sender is gone.
+    pub fn finish(self) {
+        drop(self.sender);
+        self.handle.join().ok();
+    }
+}
+
+fn sweep(policy: &Policy, uploaded: &mut Vec<Uploaded>) {
+    let expired = policy.expired(uploaded, Instant::now());
+    for &i in expired.iter().rev() {
+        let chunk = uploaded.remove(i);
+        match std::fs::remove_file(&chunk.path) {
+            Ok(()) => debug!(chunk = chunk.seq, path = %chunk.path.display(), reason = policy.reason(), "deleted chunk"),
+            Err(err) => warn!(chunk = chunk.seq, path = %chunk.path.display(), "failed to delete chunk: {err}"),
+        }
+    }
+}
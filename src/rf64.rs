@@ -0,0 +1,124 @@
+//! A WAV writer for recordings too long for RIFF, which counts sizes in 32 bits and so ends
+//! at 4 GiB: about six hours of 48 kHz stereo 32-bit audio, far less with more channels.
+//!
+//! The chunks are small and stay plain WAVs; this is for the files a whole session goes into.
+//! Every file starts out as an ordinary WAV with a 28-byte `JUNK` chunk reserved ahead of
+//! `fmt `, as EBU Tech 3306 recommends. Once the audio outgrows RIFF, [`Rf64Writer::flush`]
+//! and [`Rf64Writer::finalize`] upgrade the file in place: `RIFF` becomes `RF64`, the `JUNK` chunk becomes the `ds64`
+//! chunk holding the real sizes, and the 32-bit sizes are set to `0xFFFFFFFF`. The header
+//! keeps its length, so no audio moves, and a file that stays small remains a WAV anyone
+//! can read.
+
+use std::io::{Seek, SeekFrom, Write};
+
+/// Length of the header, up to the first byte of audio.
+pub const HEADER_LEN: usize = 80;
+
+/// Writes samples into a WAV file with no size limit, like `hound::WavWriter` does into one
+/// of at most 4 GiB.
+pub struct Rf64Writer<W: Write + Seek> {
+    inner: W,
+    spec: hound::WavSpec,
+    /// Where the header starts.
+    start: u64,
+    /// Bytes of audio written so far.
+    data_bytes: u64,
+}
+
+impl<W: Write + Seek> Rf64Writer<W> {
+    /// Starts a file with the layout of `spec` in `inner`.
+    pub fn new(mut inner: W, spec: hound::WavSpec) -> Result<Self, anyhow::Error> {
+        if spec.channels == 0 || !matches!(spec.bits_per_sample, 8 | 16 | 24 | 32) {
+            anyhow::bail!("cannot write {} channel(s) of {}-bit samples", spec.channels, spec.bits_per_sample);
+        }
+        let start = inner.stream_position()?;
+        inner.write_all(&header(spec, 0))?;
+        Ok(Rf64Writer { inner, spec, start, data_bytes: 0 })
+    }
+
+    /// Adds one sample; channels are interleaved.
+    pub fn write_sample<S: hound::Sample>(&mut self, sample: S) -> std::io::Result<()> {
+        sample.write(&mut self.inner, self.spec.bits_per_sample).map_err(std::io::Error::other)?;
+        self.data_bytes += u64::from(self.spec.bits_per_sample / 8);
+        Ok(())
+    }
+
+    /// Writes the header for the audio so far, so the file reads as complete up to here.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.seek(SeekFrom::Start(self.start))?;
+        self.inner.write_all(&header(self.spec, self.data_bytes))?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()
+    }
+
+    /// Pads the audio to an even length and writes the header for its final size.
+    pub fn finalize(self) -> Result<(), anyhow::Error> {
+        self.finalize_with(&[])
+    }
+
+    /// Like [`Self::finalize`], with `chunks`, whole RIFF chunks, after the audio.
+    pub fn finalize_with(mut self, chunks: &[u8]) -> Result<(), anyhow::Error> {
+        if self.data_bytes % 2 == 1 {
+            self.inner.write_all(&[0])?;
+        }
+        self.inner.write_all(chunks)?;
+        self.inner.seek(SeekFrom::Start(self.start))?;
+        self.inner.write_all(&file_header(self.spec, self.data_bytes, chunks.len() as u64))?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// The header of a file holding `data_bytes` of audio laid out as `spec`: a WAV one while the
+/// sizes fit in 32 bits, an RF64 one of the same length past that.
+pub fn header(spec: hound::WavSpec, data_bytes: u64) -> [u8; HEADER_LEN] {
+    file_header(spec, data_bytes, 0)
+}
+
+/// The header of a file with `trailer` bytes of chunks after the audio.
+fn file_header(spec: hound::WavSpec, data_bytes: u64, trailer: u64) -> [u8; HEADER_LEN] {
+    let padded = data_bytes + data_bytes % 2;
+    let riff_size = HEADER_LEN as u64 - 8 + padded + trailer;
+    let rf64 = u32::try_from(riff_size).is_err();
+    let block_align = spec.channels * (spec.bits_per_sample / 8);
+    let mut out = Vec::with_capacity(HEADER_LEN);
+    out.extend_from_slice(if rf64 { b"RF64" } else { b"RIFF" });
+    out.extend_from_slice(&size32(riff_size, rf64).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(if rf64 { b"ds64" } else { b"JUNK" });
+    out.extend_from_slice(&28u32.to_le_bytes());
+    if rf64 {
+        out.extend_from_slice(&riff_size.to_le_bytes());
+        out.extend_from_slice(&data_bytes.to_le_bytes());
+        out.extend_from_slice(&(data_bytes / u64::from(block_align)).to_le_bytes());
+        // No table of other chunks' sizes.
+        out.extend_from_slice(&0u32.to_le_bytes());
+    } else {
+        out.resize(out.len() + 28, 0);
+    }
+    let tag: u16 = match spec.sample_format {
+        hound::SampleFormat::Int => 1,
+        hound::SampleFormat::Float => 3,
+    };
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&spec.channels.to_le_bytes());
+    out.extend_from_slice(&spec.sample_rate.to_le_bytes());
+    out.extend_from_slice(&(spec.sample_rate * u32::from(block_align)).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&spec.bits_per_sample.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&size32(data_bytes, rf64).to_le_bytes());
+    out.try_into().expect("the header has a fixed length")
+}
+
+/// A size as the 32-bit fields hold it: `0xFFFFFFFF` in an RF64 file, where `ds64` has it.
+fn size32(size: u64, rf64: bool) -> u32 {
+    if rf64 {
+        u32::MAX
+    } else {
+        size as u32
+    }
+}
@@ -0,0 +1,94 @@
+//! A wait-free ring buffer with one producer and one consumer, for getting samples out of the
+//! audio callback.
+//!
+//! The slots are allocated up front, and each end only loads and stores a pair of counters, so
+//! the producer never allocates, locks or waits. A push that does not fit is refused whole,
+//! for the caller to count as an overrun.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Items read so far; only the consumer advances it.
+    head: AtomicUsize,
+    /// Items written so far; only the producer advances it.
+    tail: AtomicUsize,
+    /// Set when the producer is dropped.
+    closed: AtomicBool,
+}
+
+// SAFETY: a slot is only written by the producer while it is free and only read by the
+// consumer once the producer has published it, with the counters ordering the two.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// The writing end, for the callback.
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The reading end.
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// A ring holding up to `capacity` items.
+pub fn channel<T: Copy + Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let slots = (0..capacity.max(1)).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+    let shared =
+        Arc::new(Shared { slots, head: AtomicUsize::new(0), tail: AtomicUsize::new(0), closed: AtomicBool::new(false) });
+    (Producer { shared: shared.clone() }, Consumer { shared })
+}
+
+impl<T: Copy> Producer<T> {
+    /// Appends all of `items`, or none of them if they do not fit.
+    pub fn push_slice(&mut self, items: &[T]) -> bool {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+        if capacity - tail.wrapping_sub(head) < items.len() {
+            return false;
+        }
+        for (i, &item) in items.iter().enumerate() {
+            let slot = &shared.slots[tail.wrapping_add(i) % capacity];
+            // SAFETY: the slot lies between tail and head + capacity, so the consumer is done
+            // with it and will not read it before the store below publishes it.
+            unsafe { (*slot.get()).write(item) };
+        }
+        shared.tail.store(tail.wrapping_add(items.len()), Ordering::Release);
+        true
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Moves as many items as are waiting, up to `out.len()`, into `out`, returning how many.
+    pub fn pop_slice(&mut self, out: &mut [T]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        let n = tail.wrapping_sub(head).min(out.len());
+        for (i, item) in out[..n].iter_mut().enumerate() {
+            let slot = &shared.slots[head.wrapping_add(i) % capacity];
+            // SAFETY: the slot lies between head and tail, so the producer has written it and
+            // will not touch it again before the store below frees it.
+            *item = unsafe { (*slot.get()).assume_init() };
+        }
+        shared.head.store(head.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Whether the producer is gone, so that once empty the ring stays empty.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,184 @@
+//! `--session-file`: the whole session in one continuous recording, with an index of its
+//! chunks, instead of a file for each.
+//!
+//! The writer thread appends every frame that reaches it to the [`SessionFile`], whatever
+//! becomes of the chunks, so frame `n` of the stream is frame `n` of the file. A chunk then
+//! only records where it lies: the [`SessionIndex`] gets a line for it as it is delivered,
+//! written through at once so a crash loses the entry of the chunk in progress at most, and
+//! its upload reads its frames back out of the session file with [`slice`] and sends them as
+//! a WAV of their own, built in memory. The file is an [`Rf64Writer`]'s, so it can go on past
+//! 4 GiB; its header is brought up to date each time a chunk closes.
+//!
+//! Where each chunk starts is also marked in the file itself: once it is complete, a `cue `
+//! chunk with a point at the first frame of every chunk follows the audio, and an `adtl` list
+//! labels each point with its chunk's number, so an editor such as Audacity or a DAW can
+//! step from one chunk boundary to the next.
+//!
+//! `--tee` writes the same file, and nothing more: the chunks are recorded and uploaded as
+//! ever, and the session file is only kept.
+
+use crate::rf64::{self, Rf64Writer, HEADER_LEN};
+use cpal::{FromSample, Sample};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The continuous recording the writer thread appends the stream to.
+pub struct SessionFile {
+    writer: Rf64Writer<BufWriter<File>>,
+    spec: hound::WavSpec,
+    path: PathBuf,
+    /// Each chunk's number and first frame.
+    cues: Vec<(u64, u64)>,
+}
+
+impl SessionFile {
+    /// Creates the file at `path`, laid out as `spec`.
+    pub fn create(path: &Path, spec: hound::WavSpec) -> Result<Self, anyhow::Error> {
+        let file = File::create(path).map_err(|err| anyhow::anyhow!("failed to create {}: {err}", path.display()))?;
+        Ok(SessionFile { writer: Rf64Writer::new(BufWriter::new(file), spec)?, spec, path: path.to_path_buf(), cues: Vec::new() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends whole frames, converted as the chunks' encoder converts them.
+    pub fn write<U: Sample>(&mut self, samples: &[U]) -> std::io::Result<()>
+    where
+        i16: FromSample<U>,
+        i32: FromSample<U>,
+        f32: FromSample<U>,
+    {
+        for &sample in samples {
+            match (self.spec.sample_format, self.spec.bits_per_sample) {
+                (hound::SampleFormat::Float, _) => self.writer.write_sample(f32::from_sample(sample)),
+                (_, 16) => self.writer.write_sample(i16::from_sample(sample)),
+                (_, 24) => self.writer.write_sample(i32::from_sample(sample) >> 8),
+                _ => self.writer.write_sample(i32::from_sample(sample)),
+            }?;
+        }
+        Ok(())
+    }
+
+    /// Makes everything written so far readable from the file, header and all.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Marks frame `frame` as the start of chunk `seq`.
+    pub fn mark(&mut self, seq: u64, frame: u64) {
+        self.cues.push((seq, frame));
+    }
+
+    /// Completes the file, with the chunk starts marked after the audio.
+    pub fn finalize(self) -> Result<(), anyhow::Error> {
+        let chunks = if self.cues.is_empty() { Vec::new() } else { cue_chunks(&self.cues) };
+        self.writer.finalize_with(&chunks)
+    }
+}
+
+/// A `cue ` chunk with a point at each of `cues`, then a `LIST` chunk of type `adtl` giving
+/// them labels: cue point `n + 1` is the start of chunk `n`.
+pub fn cue_chunks(cues: &[(u64, u64)]) -> Vec<u8> {
+    let id = |seq: u64| u32::try_from(seq + 1).unwrap_or(u32::MAX);
+    let mut out = Vec::new();
+    out.extend_from_slice(b"cue ");
+    out.extend_from_slice(&(4 + 24 * cues.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(cues.len() as u32).to_le_bytes());
+    for &(seq, frame) in cues {
+        // Past 2^32 frames, a day and more at 48 kHz, the position no longer fits.
+        let frame = u32::try_from(frame).unwrap_or(u32::MAX);
+        out.extend_from_slice(&id(seq).to_le_bytes());
+        out.extend_from_slice(&frame.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&frame.to_le_bytes());
+    }
+    let mut labels = Vec::new();
+    for &(seq, _) in cues {
+        let text = format!("chunk {seq}\0");
+        labels.extend_from_slice(b"labl");
+        labels.extend_from_slice(&(4 + text.len() as u32).to_le_bytes());
+        labels.extend_from_slice(&id(seq).to_le_bytes());
+        labels.extend_from_slice(text.as_bytes());
+        if text.len() % 2 == 1 {
+            labels.push(0);
+        }
+    }
+    out.extend_from_slice(b"LIST");
+    out.extend_from_slice(&(4 + labels.len() as u32).to_le_bytes());
+    out.extend_from_slice(b"adtl");
+    out.extend_from_slice(&labels);
+    out
+}
+
+/// The bytes of a WAV file holding `frames` of the session file at `path`, laid out as `spec`.
+pub fn slice(path: &Path, spec: hound::WavSpec, frames: Range<u64>) -> Result<Vec<u8>, anyhow::Error> {
+    let block_align = u64::from(spec.channels) * u64::from(spec.bits_per_sample / 8);
+    let len = (frames.end.saturating_sub(frames.start) * block_align) as usize;
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(HEADER_LEN as u64 + frames.start * block_align))?;
+    let mut data = Vec::with_capacity(HEADER_LEN + len + 1);
+    data.extend_from_slice(&rf64::header(spec, len as u64));
+    file.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != HEADER_LEN + len {
+        anyhow::bail!("{} ends before frame {}", path.display(), frames.end);
+    }
+    if len % 2 == 1 {
+        data.push(0);
+    }
+    Ok(data)
+}
+
+/// Where in the session file each chunk lies, as CSV.
+pub struct SessionIndex {
+    file: File,
+    path: PathBuf,
+    block_align: u64,
+}
+
+/// One chunk's line in the index.
+pub struct Entry<'a> {
+    pub seq: u64,
+    /// The frames of the session file the chunk spans.
+    pub frames: Range<u64>,
+    /// When its first frame was recorded, as the chunk log gives it.
+    pub started: &'a str,
+    pub clock_start: &'a str,
+}
+
+impl SessionIndex {
+    /// The header of the index.
+    pub const COLUMNS: &'static str = "chunk,first_frame,frames,byte_offset,byte_length,started,clock_start";
+
+    /// Creates the index of the session file at `session`, next to it: `session.wav` is
+    /// indexed in `session.index.csv`.
+    pub fn create(session: &Path, spec: hound::WavSpec) -> Result<Self, anyhow::Error> {
+        let path = index_path(session);
+        let mut file = File::create(&path).map_err(|err| anyhow::anyhow!("failed to create {}: {err}", path.display()))?;
+        writeln!(file, "{}", Self::COLUMNS)?;
+        let block_align = u64::from(spec.channels) * u64::from(spec.bits_per_sample / 8);
+        Ok(SessionIndex { file, path, block_align })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a chunk's line and writes it through to the disk.
+    pub fn append(&mut self, entry: &Entry) -> Result<(), anyhow::Error> {
+        let Entry { seq, frames, started, clock_start } = entry;
+        let count = frames.end.saturating_sub(frames.start);
+        let offset = HEADER_LEN as u64 + frames.start * self.block_align;
+        writeln!(self.file, "{seq},{},{count},{offset},{},{started},{clock_start}", frames.start, count * self.block_align)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Where the index of the session file at `session` goes.
+pub fn index_path(session: &Path) -> PathBuf {
+    session.with_extension("index.csv")
+}
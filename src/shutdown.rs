@@ -0,0 +1,52 @@
+//! Ctrl+C and SIGTERM: ending a recording without leaving a chunk half-written.
+//!
+//! The first signal only raises a flag. The recording loop sees it within a tick, stops the
+//! stream, closes the chunk in progress so its WAV header is complete, gives the outstanding
+//! uploads a while to land, and exits 0. A second signal exits at once.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Signals received so far.
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Exit status after a second signal, what a shell reports for death by SIGINT.
+#[cfg(unix)]
+const FORCED_EXIT: i32 = 130;
+
+/// Installs the handler for SIGINT and SIGTERM.
+#[cfg(unix)]
+pub fn install() -> Result<(), anyhow::Error> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only touches an atomic and calls `_exit`, both async-signal-safe.
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            anyhow::bail!("failed to handle signal {signal}: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Elsewhere Ctrl+C keeps its default effect.
+#[cfg(not(unix))]
+pub fn install() -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    if SIGNALS.fetch_add(1, Ordering::SeqCst) > 0 {
+        crate::ptt::restore();
+        // SAFETY: `_exit` is async-signal-safe; nothing else runs on the way out.
+        unsafe { libc::_exit(FORCED_EXIT) };
+    }
+}
+
+/// Whether a shutdown has been asked for.
+pub fn requested() -> bool {
+    SIGNALS.load(Ordering::SeqCst) > 0
+}
+
+/// Asks for a shutdown, as the first signal does.
+pub fn request() {
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+}
@@ -0,0 +1,1132 @@
+//! The hand-off between the audio callback and the chunk files.
+//!
+//! One input stream stays open for the whole session, unless its device goes away and a new
+//! stream takes over, feeding the same writer thread after closing the chunk the break fell in
+//! early. The callback never touches a file or a lock, and never allocates: it copies each
+//! buffer as captured into a preallocated ring ([`Capture`]). A pump thread takes the audio
+//! from there, converts and filters it, and hands it through a bounded queue
+//! ([`SampleQueue`]) to a dedicated writer thread, which writes the current chunk's
+//! [`ChunkWriter`].
+//! Chunk boundaries are decided by the writer thread from the number of frames written, so
+//! every chunk holds exactly the same number of frames: a buffer that crosses a boundary is
+//! split and its remainder starts the next chunk. Each chunk after the first can open with a
+//! pre-roll: the most recent frames seen before it started, so a word begun just before the
+//! boundary is heard whole. With an overlap, chunks also start that much earlier, so they keep
+//! their length while sharing audio with their neighbours. A [`Resampler`] in the plan
+//! converts the stream on the writer thread, before it is cut, keeping its state across
+//! chunk boundaries. If the ring is full the buffer is dropped and counted, and the count is
+//! reported with the chunk it belonged to. Each buffer carries the [`Level`] of the
+//! raw capture it came from, which adds up to the chunk's peak and clipping figures.
+//!
+//! With a [`Segmenter`] in the plan, chunks are cut at pauses in speech instead: the writer
+//! thread feeds it the stream frame by frame and opens and closes chunks where it says,
+//! writing nothing while there is no speech.
+
+use crate::dsp::{convert, convert_dithered, ChannelMap, Dither, Downmix, Gain, Level, Stage};
+use crate::encode::ChunkWriter;
+use crate::logging;
+use crate::meter::Meter;
+use crate::pipe::RawStream;
+use crate::resample::Resampler;
+use crate::session::SessionFile;
+use crate::shutdown;
+use crate::ring::{self, Consumer, Producer};
+use crate::vad::{Decision, Segmenter};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+
+pub type WavFileWriter = hound::WavWriter<BufWriter<File>>;
+
+/// Callback buffers the queue holds; at typical buffer sizes that is a few seconds of audio.
+pub const QUEUE_BUFFERS: usize = 256;
+
+/// Seconds of audio a [`Capture`]'s ring holds.
+pub const RING_SECONDS: usize = 4;
+
+/// Frames the pump takes from the ring at a time, at most.
+const PUMP_FRAMES: usize = 4096;
+
+/// How often the writer thread checks for a stop request while no audio arrives, and the
+/// pump looks for more in the ring.
+const POLL: Duration = Duration::from_millis(5);
+
+/// How long [`SampleQueue::control`] waits for the audio captured before it to be passed on.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// Length of the windows a chunk's loudness is measured over.
+const LOUDNESS_WINDOW_MS: u64 = 20;
+
+/// Start of each chunk left out of its loudness, where a device powering on may pop.
+const LOUDNESS_SKIP_MS: u64 = 20;
+
+/// A converted callback buffer and the level of what was captured.
+struct Block<U> {
+    samples: Vec<U>,
+    level: Level,
+}
+
+/// What goes through the queue to the writer thread.
+enum Message<U> {
+    Block(Block<U>),
+    Control(Control),
+}
+
+/// What the recording loop can have the writer thread do, in order with the audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    /// The stream broke off: close the chunk in progress with what it holds. Whatever comes
+    /// next starts a new one that repeats nothing from before the break.
+    Interrupt,
+    /// Close the chunk in progress as for [`Control::Interrupt`], then ignore the audio until
+    /// [`Control::Resume`].
+    Pause,
+    Resume,
+    /// Close the chunk in progress now and go on into the next, as at a chunk boundary.
+    Cut,
+    /// With [`ChunkPlan::push_to_talk`], the key went down: start recording into the chunk,
+    /// with the pre-roll before it.
+    Hold,
+    /// The key came up: close the chunk now and keep only the pre-roll until the next
+    /// [`Control::Hold`].
+    Release,
+}
+
+/// The callback's end of the queue, owned by the callback.
+pub struct SampleQueue<U> {
+    sender: mpsc::SyncSender<Message<U>>,
+    dropped: Arc<AtomicU64>,
+    /// Calls to [`Self::write`] by this queue and its forks, and to [`Capture::push`] of the
+    /// captures that count them.
+    callbacks: Arc<AtomicU64>,
+    /// Samples in the captures' rings, or popped and not yet queued.
+    backlog: Arc<AtomicUsize>,
+    captured: u16,
+    map: ChannelMap,
+    channels: u16,
+    /// Set when float input written as 16-bit is to be dithered.
+    dither: Option<Dither>,
+    stages: Vec<Box<dyn Stage>>,
+    /// See [`ChunkPlan::warmup_frames`], and how many of them this queue's stream has yet to
+    /// deliver.
+    warmup: u64,
+    warming: u64,
+}
+
+impl<U: Send + 'static> SampleQueue<U> {
+    /// Converts the callback samples to the output type, maps their channels, and queues them
+    /// for the writer thread, counting them as dropped if the queue is full. `gain` is a
+    /// linear factor; at exactly 1.0 the conversion is left untouched. Float input written as
+    /// 16-bit is dithered if the plan asked for it; integer input never is. With filter
+    /// stages the samples go through f32 on the way, and are dithered if the output is 16-bit.
+    pub fn write<T>(&mut self, input: &[T], gain: f32)
+    where
+        T: SizedSample,
+        U: SizedSample + FromSample<T> + FromSample<i16> + FromSample<f32> + Gain + Downmix,
+        f32: FromSample<T>,
+    {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        self.queue(input, gain);
+    }
+
+    /// Like [`Self::write`] for `input` popped from a [`Capture`]'s ring, `captured` samples
+    /// of it, whose callbacks were counted as they came.
+    pub fn forward<T>(&mut self, input: &[T], gain: f32, captured: usize)
+    where
+        T: SizedSample,
+        U: SizedSample + FromSample<T> + FromSample<i16> + FromSample<f32> + Gain + Downmix,
+        f32: FromSample<T>,
+    {
+        self.queue(input, gain);
+        self.backlog.fetch_sub(captured, Ordering::Relaxed);
+    }
+
+    fn queue<T>(&mut self, mut input: &[T], gain: f32)
+    where
+        T: SizedSample,
+        U: SizedSample + FromSample<T> + FromSample<i16> + FromSample<f32> + Gain + Downmix,
+        f32: FromSample<T>,
+    {
+        let captured = usize::from(self.captured.max(1));
+        if self.warming > 0 {
+            let frames = (input.len() / captured) as u64;
+            let skipped = frames.min(self.warming);
+            self.warming -= skipped;
+            if self.warming == 0 {
+                debug!(frames = self.warmup, "discarded the stream's warm-up");
+            }
+            if skipped == frames {
+                return;
+            }
+            input = &input[skipped as usize * captured..];
+        }
+        let level = Level::measure(input);
+        let samples = if !self.stages.is_empty() {
+            self.filter(input, gain)
+        } else {
+            let block = match &self.dither {
+                Some(dither) if T::FORMAT.is_float() && U::FORMAT == SampleFormat::I16 => {
+                    convert_dithered(input, gain, dither)
+                }
+                _ => convert(input, gain),
+            };
+            self.map.apply(self.captured, block)
+        };
+        let frames = samples.len() as u64 / u64::from(self.channels.max(1));
+        if self.sender.try_send(Message::Block(Block { samples, level })).is_err() {
+            self.dropped.fetch_add(frames, Ordering::Relaxed);
+        }
+    }
+
+    /// A ring of `capacity` samples for a callback delivering `channels` of `T` to push into,
+    /// returned with its reading end: for [`Self::pump`], or for mixing first. A `clock`
+    /// capture counts its callbacks (see [`Self::callbacks`]) and has its backlog waited for
+    /// by [`Self::control`].
+    pub fn capture<T: Copy + Send>(
+        &self,
+        capacity: usize,
+        channels: u16,
+        clock: bool,
+    ) -> (Capture<T>, Consumer<T>) {
+        let (producer, consumer) = ring::channel(capacity);
+        let capture = Capture {
+            producer,
+            channels: usize::from(channels.max(1)),
+            dropped: self.dropped.clone(),
+            clock: clock.then(|| (self.callbacks.clone(), self.backlog.clone())),
+        };
+        (capture, consumer)
+    }
+
+    /// Passes on what arrives in `ring` on a thread of its own until its [`Capture`] is dropped
+    /// and it is empty.
+    pub fn pump<T>(mut self, mut ring: Consumer<T>, gain: f32)
+    where
+        T: SizedSample + Send + 'static,
+        U: SizedSample + FromSample<T> + FromSample<i16> + FromSample<f32> + Gain + Downmix,
+        f32: FromSample<T>,
+    {
+        logging::spawn(move || {
+            let channels = usize::from(self.captured.max(1));
+            let mut buffer = vec![T::EQUILIBRIUM; PUMP_FRAMES * channels];
+            loop {
+                // Closed first, so nothing pushed before the close is left behind.
+                let closed = ring.is_closed();
+                let n = ring.pop_slice(&mut buffer);
+                if n > 0 {
+                    self.forward(&buffer[..n], gain, n);
+                } else if closed {
+                    break;
+                } else {
+                    std::thread::sleep(POLL);
+                }
+            }
+        });
+    }
+
+    /// A queue into the same writer thread for a stream that replaces this one's, with filter
+    /// `stages` of its own.
+    pub fn fork(&self, stages: Vec<Box<dyn Stage>>) -> Self {
+        SampleQueue {
+            sender: self.sender.clone(),
+            dropped: self.dropped.clone(),
+            callbacks: self.callbacks.clone(),
+            backlog: self.backlog.clone(),
+            captured: self.captured,
+            map: self.map,
+            channels: self.channels,
+            dither: self.dither.as_ref().map(|_| Dither::new(seed())),
+            stages,
+            warmup: self.warmup,
+            warming: self.warmup,
+        }
+    }
+
+    /// Counts the buffers written to this queue and its forks, for telling whether audio is
+    /// arriving.
+    pub fn callbacks(&self) -> Arc<AtomicU64> {
+        self.callbacks.clone()
+    }
+
+    /// Has the writer thread act on `control` after what has been captured. Waits, briefly,
+    /// for the pump to pass that on, then for room in the queue, so it is not for the callback.
+    pub fn control(&self, control: Control) {
+        let deadline = std::time::Instant::now() + SETTLE;
+        while self.backlog.load(Ordering::Relaxed) > 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        self.sender.send(Message::Control(control)).ok();
+    }
+}
+
+/// The callback's end of a ring into a [`SampleQueue`], owned by the callback: see
+/// [`SampleQueue::capture`].
+pub struct Capture<T> {
+    producer: Producer<T>,
+    channels: usize,
+    dropped: Arc<AtomicU64>,
+    /// The callback counter and backlog of a clock capture.
+    clock: Option<(Arc<AtomicU64>, Arc<AtomicUsize>)>,
+}
+
+impl<T: Copy> Capture<T> {
+    /// Copies a callback buffer into the ring, or counts it as dropped if the ring is full.
+    /// Wait-free and allocation-free.
+    pub fn push(&mut self, input: &[T]) {
+        if let Some((callbacks, backlog)) = &self.clock {
+            callbacks.fetch_add(1, Ordering::Relaxed);
+            // Counted before the push, so the pump cannot take it below zero.
+            backlog.fetch_add(input.len(), Ordering::Relaxed);
+        }
+        if !self.producer.push_slice(input) {
+            self.dropped.fetch_add((input.len() / self.channels) as u64, Ordering::Relaxed);
+            if let Some((_, backlog)) = &self.clock {
+                backlog.fetch_sub(input.len(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<U> SampleQueue<U>
+where
+    U: SizedSample + FromSample<i16> + FromSample<f32>,
+{
+    /// Applies `gain` and runs the filter stages in f32, then quantizes to the output type. The
+    /// gain comes first so level-dependent stages such as the noise gate see what is written.
+    fn filter<T: SizedSample>(&mut self, input: &[T], gain: f32) -> Vec<U>
+    where
+        f32: FromSample<T>,
+    {
+        let block: Vec<f32> = input.iter().map(|&sample| f32::from_sample(sample)).collect();
+        let mut block = self.map.apply(self.captured, block);
+        if gain != 1.0 {
+            block.iter_mut().for_each(|sample| *sample *= gain);
+        }
+        for stage in &mut self.stages {
+            stage.process(&mut block);
+        }
+        match &self.dither {
+            Some(dither) if U::FORMAT == SampleFormat::I16 => {
+                block.into_iter().map(|sample| U::from_sample(dither.quantize(sample))).collect()
+            }
+            _ => block.into_iter().map(|sample| U::from_sample(sample.clamp(-1.0, 1.0))).collect(),
+        }
+    }
+}
+
+/// A chunk file that has been written and finalized.
+#[derive(Debug)]
+pub struct Chunk {
+    pub seq: u64,
+    pub path: PathBuf,
+    /// Frames in the file, repeated frames included.
+    pub frames: u64,
+    /// Frames at the start of the file that are repeated from the end of the previous chunk
+    /// (the pre-roll or overlap); a stitcher joining transcripts drops these.
+    pub repeated_frames: u64,
+    /// Frames lost to a full queue while this chunk was being written.
+    pub dropped_frames: u64,
+    /// Level of the raw capture that went into the chunk's live frames.
+    pub level: Level,
+    /// Level of each of the file's channels, as written.
+    pub channel_levels: Vec<Level>,
+    /// RMS level in dBFS of the loudest 20 ms of the chunk as written, its first 20 ms aside.
+    pub loudest_dbfs: f32,
+    /// FNV-1a hash of the samples as written, the file's header aside, so chunks of the same
+    /// audio compare equal whatever their format: `--dedupe-window`.
+    pub digest: u64,
+    /// With VAD chunking, the frames of the file from the first speech detected to the end of
+    /// the last.
+    pub speech: Option<Range<u64>>,
+    /// Wall-clock time of the file's first frame, judged from when it reached the writer
+    /// thread, so chunks from different devices can be lined up.
+    pub started: SystemTime,
+    /// Audio-clock time of the file's first frame and of the end of its last: when the stream
+    /// started, plus the frames it had delivered before them at the chunk's sample rate. Unlike
+    /// `started` this is exact from one chunk to the next; the two drift apart as far as the
+    /// device's clock and the system's do. The stream starts over after a pause or a break.
+    pub clocked: Range<SystemTime>,
+    /// Frames from the start of the session to the file's first frame: within the first
+    /// stream counted exactly, across a break reckoned from the audio-clock times.
+    pub time_reference: u64,
+    /// Frames of the stream from the file's first to the one after its last, as numbered in
+    /// the session file. With VAD chunking the file can leave out some of those in between.
+    pub span: Range<u64>,
+}
+
+/// How the writer thread splits the stream into chunks.
+pub struct ChunkPlan {
+    /// Sample rate of the chunks.
+    pub sample_rate: u32,
+    /// Channels the callback delivers.
+    pub captured_channels: u16,
+    /// How those become the chunk's channels; `channels` must be what this yields.
+    pub channel_map: ChannelMap,
+    /// Channels in the chunks.
+    pub channels: u16,
+    /// Callback buffers the queue holds before dropping.
+    pub capacity: usize,
+    /// Frames the first chunk records, at the output rate; later chunks record `frames_per_chunk - overlap_frames`
+    /// after their repeated frames.
+    pub frames_per_chunk: u64,
+    /// Frames of history replayed at the start of every chunk but the first.
+    pub preroll_frames: u64,
+    /// How far each chunk after the first reaches back into the previous one; less than
+    /// `frames_per_chunk`. Replays at least this much history, whatever `preroll_frames` says.
+    pub overlap_frames: u64,
+    /// Stop after this many chunks.
+    pub limit: Option<u64>,
+    /// Converts the stream to the output rate before it reaches the chunks.
+    pub resampler: Option<Resampler>,
+    /// Dither float input that is written as 16-bit.
+    pub dither: bool,
+    /// Filters run in the callback, in order, on the chunk's channels.
+    pub stages: Vec<Box<dyn Stage>>,
+    /// How far the stages hold the audio back; chunk start times are taken back by as much.
+    pub latency: Duration,
+    /// Shows the level of each buffer as the writer thread receives it.
+    pub meter: Option<Meter>,
+    /// Cuts chunks at pauses in speech; `frames_per_chunk` and `overlap_frames` then go
+    /// unused.
+    pub segmenter: Option<Segmenter>,
+    /// Record only between [`Control::Hold`] and [`Control::Release`]. Not for VAD chunking.
+    pub push_to_talk: bool,
+    /// Frames, at the capture rate, that each new stream delivers before its audio is kept:
+    /// drivers tend to open with a pop, stale buffer contents or silence.
+    pub warmup_frames: u64,
+    /// Where every frame that reaches the writer thread also goes, whatever becomes of the
+    /// chunks: `--session-file` or `--tee`. If it cannot be written to, that is logged and it
+    /// is completed where it is, while the chunks go on.
+    pub session: Option<SessionFile>,
+    /// Where every frame goes too, as raw samples: `--stdout-raw` or `--fifo`. Once it cannot
+    /// be written to the recording is shut down.
+    pub raw: Option<RawStream>,
+}
+
+impl ChunkPlan {
+    fn loudness(&self) -> Loudness {
+        let samples = |ms: u64| u64::from(self.sample_rate) * ms / 1000 * u64::from(self.channels.max(1));
+        Loudness { window: samples(LOUDNESS_WINDOW_MS).max(1), skip: samples(LOUDNESS_SKIP_MS), ..Loudness::default() }
+    }
+
+    /// Frames of history replayed at the start of a chunk.
+    fn replay_frames(&self) -> u64 {
+        self.preroll_frames.max(self.overlap_frames)
+    }
+
+    /// Frames of history the writer thread keeps: those it replays, and with VAD chunking
+    /// those before the end of a chunk it may be cut at.
+    fn history_frames(&self) -> u64 {
+        self.replay_frames() + self.segmenter.as_ref().map_or(0, Segmenter::lookback_frames)
+    }
+
+    /// Frames chunk `seq` takes from the live stream before the next one starts.
+    fn live_frames(&self, seq: u64) -> u64 {
+        if seq == 0 {
+            self.frames_per_chunk
+        } else {
+            self.frames_per_chunk - self.overlap_frames
+        }
+    }
+}
+
+/// Opens the writer for chunk `seq`, returning where it writes to.
+pub type OpenChunk<W> = Box<dyn FnMut(u64) -> Result<(PathBuf, W), anyhow::Error> + Send>;
+
+/// The recording loop's end: receives the finished chunks and owns the writer thread.
+pub struct ChunkSink {
+    finished: mpsc::Receiver<Result<Chunk, anyhow::Error>>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Option<Result<Chunk, anyhow::Error>>>,
+}
+
+/// Starts the writer thread, returning the sink and the queue to hand to the callback. The
+/// first chunk is opened right away, so a bad output path fails before any audio is captured.
+pub fn spawn<U, W>(mut plan: ChunkPlan, mut open: OpenChunk<W>) -> Result<(ChunkSink, SampleQueue<U>), anyhow::Error>
+where
+    U: hound::Sample + Sample + FromSample<f32> + Send + 'static,
+    f32: FromSample<U>,
+    i16: FromSample<U>,
+    i32: FromSample<U>,
+    W: ChunkWriter<U> + Send + 'static,
+{
+    let (channels, captured_channels, channel_map, dither, warmup) =
+        (plan.channels, plan.captured_channels, plan.channel_map, plan.dither, plan.warmup_frames);
+    let stages = std::mem::take(&mut plan.stages);
+    let (sender, samples) = mpsc::sync_channel::<Message<U>>(plan.capacity);
+    let (finished_tx, finished) = mpsc::channel();
+    let dropped = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let first = open(0)?;
+    let mut thread = WriterThread {
+        open,
+        current: Some(Current {
+            seq: 0,
+            path: first.0,
+            writer: first.1,
+            written: 0,
+            frames: 0,
+            repeated: 0,
+            started: None,
+            first: None,
+            next: 0,
+            level: Level::default(),
+            channel_levels: vec![Level::default(); usize::from(plan.channels.max(1))],
+            loudness: plan.loudness(),
+            digest: FNV_OFFSET,
+        }),
+        history: VecDeque::with_capacity((plan.history_frames() * u64::from(channels)) as usize),
+        resampler: plan.resampler.take(),
+        pending: Vec::new(),
+        lookback: VecDeque::new(),
+        paused: false,
+        standby: plan.push_to_talk,
+        seen: 0,
+        epoch: None,
+        session: None,
+        lookback_runs: VecDeque::new(),
+        plan,
+        dropped: dropped.clone(),
+        finished: Some(finished_tx),
+    };
+    let handle = {
+        let stop = stop.clone();
+        logging::spawn(move || {
+            loop {
+                match samples.recv_timeout(POLL) {
+                    Ok(message) => thread.handle(message),
+                    Err(RecvTimeoutError::Timeout) if !stop.load(Ordering::Relaxed) => continue,
+                    Err(_) => break,
+                }
+            }
+            // Whatever was captured before the stop still belongs to the chunk in progress.
+            while let Ok(message) = samples.try_recv() {
+                thread.handle(message);
+            }
+            thread.flush();
+            let last = thread.finish();
+            thread.end_session(None);
+            last
+        })
+    };
+    let queue = SampleQueue {
+        sender,
+        dropped,
+        callbacks: Arc::new(AtomicU64::new(0)),
+        backlog: Arc::new(AtomicUsize::new(0)),
+        captured: captured_channels,
+        map: channel_map,
+        channels,
+        dither: dither.then(|| Dither::new(seed())),
+        stages,
+        warmup,
+        warming: warmup,
+    };
+    Ok((ChunkSink { finished, stop, handle }, queue))
+}
+
+struct Current<W> {
+    seq: u64,
+    path: PathBuf,
+    writer: W,
+    /// Frames in the file.
+    written: u64,
+    /// Live frames of a fixed-length chunk, not counting the repeated ones.
+    frames: u64,
+    repeated: u64,
+    /// See [`Chunk::started`]; set when the first frame is written.
+    started: Option<SystemTime>,
+    /// Frames of the stream the file starts with and would go on with; set as frames are
+    /// written. With VAD chunking a file can leave out some of the stream in between.
+    first: Option<u64>,
+    next: u64,
+    level: Level,
+    channel_levels: Vec<Level>,
+    loudness: Loudness,
+    /// See [`Chunk::digest`].
+    digest: u64,
+}
+
+/// The loudest window of a chunk, for `--skip-silence`.
+#[derive(Default)]
+struct Loudness {
+    /// Samples per window, and at the start of the chunk to leave out.
+    window: u64,
+    skip: u64,
+    seen: u64,
+    /// Sum of squares and length of the window in progress.
+    energy: f64,
+    len: u64,
+    /// Highest mean square of a window so far.
+    loudest: f64,
+}
+
+impl Loudness {
+    fn add(&mut self, sample: f32) {
+        self.seen += 1;
+        if self.seen <= self.skip {
+            return;
+        }
+        self.energy += f64::from(sample) * f64::from(sample);
+        self.len += 1;
+        if self.len == self.window {
+            self.end_window();
+        }
+    }
+
+    fn end_window(&mut self) {
+        if self.len > 0 {
+            self.loudest = self.loudest.max(self.energy / self.len as f64);
+        }
+        (self.energy, self.len) = (0.0, 0);
+    }
+
+    fn dbfs(mut self) -> f32 {
+        self.end_window();
+        (10.0 * self.loudest.log10()) as f32
+    }
+}
+
+struct WriterThread<U, W> {
+    open: OpenChunk<W>,
+    current: Option<Current<W>>,
+    /// The last [`ChunkPlan::history_frames`] frames seen, whole frames only.
+    history: VecDeque<U>,
+    resampler: Option<Resampler>,
+    /// Samples short of a whole segmenter frame, waiting for the next block.
+    pending: Vec<U>,
+    /// With VAD chunking, the end of the current chunk, held back from the file while a split
+    /// could still move it to the next one.
+    lookback: VecDeque<U>,
+    /// Between [`Control::Pause`] and [`Control::Resume`], when audio is ignored.
+    paused: bool,
+    /// With push-to-talk, while the key is up: audio only goes to the history.
+    standby: bool,
+    /// Frames of the stream added to the history so far, whether or not it keeps them.
+    seen: u64,
+    /// Since when, and from which frame of the stream, audio has been arriving without a break.
+    epoch: Option<(SystemTime, u64)>,
+    /// The first `epoch` there was, which chunks' time references start from.
+    session: Option<(SystemTime, u64)>,
+    /// The frames of the stream the lookback holds, in unbroken runs: a resumed chunk leaves
+    /// out the silence before the resumption.
+    lookback_runs: VecDeque<Range<u64>>,
+    plan: ChunkPlan,
+    dropped: Arc<AtomicU64>,
+    finished: Option<mpsc::Sender<Result<Chunk, anyhow::Error>>>,
+}
+
+impl<U, W> WriterThread<U, W>
+where
+    U: hound::Sample + Sample + FromSample<f32>,
+    f32: FromSample<U>,
+    i16: FromSample<U>,
+    i32: FromSample<U>,
+    W: ChunkWriter<U>,
+{
+    fn handle(&mut self, message: Message<U>) {
+        match message {
+            Message::Block(_) if self.paused => {}
+            Message::Block(block) => self.receive(block),
+            Message::Control(Control::Interrupt) => self.interrupt(),
+            Message::Control(Control::Pause) => {
+                self.interrupt();
+                self.paused = true;
+            }
+            Message::Control(Control::Resume) => self.paused = false,
+            Message::Control(Control::Cut) => self.cut_now(),
+            Message::Control(Control::Hold) if self.standby => {
+                self.standby = false;
+                self.replay(0, u64::MAX);
+            }
+            Message::Control(Control::Release) if !self.standby => self.release(),
+            Message::Control(Control::Hold | Control::Release) => {}
+        }
+    }
+
+    /// Takes a block from the queue, resampling it first if the plan says so. Its level counts
+    /// towards the chunk it starts in.
+    fn receive(&mut self, Block { samples: block, level }: Block<U>) {
+        if let Some(current) = self.current.as_mut() {
+            current.level.add(level);
+        }
+        if let Some(meter) = &mut self.plan.meter {
+            meter.add(level);
+        }
+        match &mut self.resampler {
+            Some(resampler) => {
+                let input: Vec<f32> = block.iter().map(|&s| s.to_sample()).collect();
+                let mut output = Vec::new();
+                resampler.process(&input, &mut output);
+                self.write(&output.into_iter().map(U::from_sample).collect::<Vec<_>>());
+            }
+            None => self.write(&block),
+        }
+    }
+
+    /// Writes out whatever the resampler is still holding back where the stream ends or
+    /// breaks off.
+    fn flush(&mut self) {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return;
+        };
+        let mut output = Vec::new();
+        resampler.flush(&mut output);
+        resampler.restart();
+        self.write(&output.into_iter().map(U::from_sample).collect::<Vec<_>>());
+    }
+
+    /// Closes the chunk in progress where the stream broke off, as [`Self::finish`] does at
+    /// the end, and opens the next for when it resumes. A chunk with nothing live in it is
+    /// discarded instead and opened again under the same number.
+    fn interrupt(&mut self) {
+        self.flush();
+        let Some(current) = &self.current else {
+            return;
+        };
+        let seq = current.seq;
+        let live = match &self.plan.segmenter {
+            Some(segmenter) => segmenter.speech().is_some(),
+            None => current.frames > 0,
+        };
+        if live {
+            if let Some(result) = self.finish() {
+                self.advance(result, seq + 1);
+            }
+        } else {
+            self.discard();
+            self.open_chunk(seq);
+        }
+        // What came before the break is not the pre-roll of what comes after, and the stream
+        // starts over when it resumes.
+        self.history.clear();
+        // Counted all the same, as the session file has them.
+        self.seen += (self.pending.len() / usize::from(self.plan.channels.max(1))) as u64;
+        self.pending.clear();
+        self.lookback.clear();
+        self.lookback_runs.clear();
+        self.epoch = None;
+        if let Some(segmenter) = self.plan.segmenter.as_mut() {
+            segmenter.reset();
+        }
+    }
+
+    /// Closes the chunk in progress now, as at a chunk boundary, if there is anything live in
+    /// it. With VAD chunking that means speech worth keeping.
+    fn cut_now(&mut self) {
+        let speech = match &self.plan.segmenter {
+            Some(segmenter) => match segmenter.speech() {
+                Some(speech) => Some(speech),
+                None => return,
+            },
+            None if self.current.as_ref().is_some_and(|current| current.frames > 0) => None,
+            None => return,
+        };
+        self.commit(0);
+        self.rotate(speech);
+        match self.plan.segmenter.as_mut() {
+            Some(segmenter) => segmenter.reset(),
+            None => self.replay(0, u64::MAX),
+        }
+    }
+
+    /// Closes the chunk in progress at a push-to-talk release, however short it is; one that
+    /// never got past its pre-roll is discarded and opened again under the same number.
+    fn release(&mut self) {
+        self.flush();
+        self.standby = true;
+        let Some(current) = &self.current else {
+            return;
+        };
+        if current.frames > 0 {
+            self.rotate(None);
+        } else {
+            let seq = current.seq;
+            self.discard();
+            self.open_chunk(seq);
+        }
+    }
+
+    fn write(&mut self, block: &[U]) {
+        if let Some(Err(err)) = self.plan.session.as_mut().map(|session| session.write(block)) {
+            self.end_session(Some(err));
+        }
+        if let Some(Err(err)) = self.plan.raw.as_mut().map(|raw| raw.write(block)) {
+            self.plan.raw = None;
+            match err.kind() {
+                std::io::ErrorKind::BrokenPipe => info!("stdout was closed, stopping"),
+                _ => error!("failed to write the raw stream, stopping: {err}"),
+            }
+            shutdown::request();
+        }
+        if self.epoch.is_none() && !block.is_empty() {
+            let channels = usize::from(self.plan.channels.max(1));
+            let frames = (block.len() / channels) as f64;
+            let ago = Duration::from_secs_f64(frames / f64::from(self.plan.sample_rate.max(1)));
+            // The block follows whatever of the last one the segmenter has yet to take.
+            let first = self.seen + (self.pending.len() / channels) as u64;
+            self.epoch = Some((SystemTime::now() - ago - self.plan.latency, first));
+            self.session = self.session.or(self.epoch);
+        }
+        if self.standby {
+            self.remember(block);
+        } else if self.plan.segmenter.is_some() {
+            self.segment(block);
+        } else {
+            self.cut(block);
+        }
+    }
+
+    /// Writes a block into fixed-length chunks, splitting it where a chunk fills up.
+    fn cut(&mut self, mut block: &[U]) {
+        let channels = usize::from(self.plan.channels.max(1));
+        while !block.is_empty() {
+            let Some(current) = self.current.as_mut() else {
+                self.remember(block);
+                return;
+            };
+            let target = self.plan.live_frames(current.seq);
+            let room = (target - current.frames) as usize * channels;
+            let (now, rest) = block.split_at(room.min(block.len()));
+            current.frames += (now.len() / channels) as u64;
+            let full = current.frames == target;
+            self.put(now, self.seen);
+            self.remember(now);
+            block = rest;
+            if full {
+                self.rotate(None);
+                self.replay(0, u64::MAX);
+            }
+        }
+    }
+
+    /// Feeds a block to the segmenter a frame at a time, opening and closing chunks where it
+    /// says.
+    fn segment(&mut self, block: &[U]) {
+        let Some(frame_len) = self.plan.segmenter.as_ref().map(Segmenter::frame_len) else {
+            return;
+        };
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(block);
+        let mut frames = pending.chunks_exact(frame_len);
+        for frame in &mut frames {
+            let analysed: Vec<f32> = frame.iter().map(|&s| s.to_sample()).collect();
+            let Some(segmenter) = self.plan.segmenter.as_mut() else {
+                return;
+            };
+            let decision = segmenter.push(&analysed);
+            match decision {
+                Decision::Silence => {
+                    self.remember(frame);
+                    continue;
+                }
+                Decision::Start => self.replay(0, u64::MAX),
+                Decision::Resume { gap } => self.replay(0, gap),
+                _ => {}
+            }
+            self.stage(frame, self.seen);
+            self.remember(frame);
+            match decision {
+                Decision::End(speech) => {
+                    self.commit(0);
+                    self.rotate(Some(speech));
+                }
+                Decision::Split { speech, tail } => {
+                    self.commit(tail);
+                    let mut tail_samples: &[U] = &self.lookback.drain(..).collect::<Vec<U>>();
+                    let tail_runs = std::mem::take(&mut self.lookback_runs);
+                    self.rotate(Some(speech));
+                    self.replay(tail, u64::MAX);
+                    let channels = usize::from(self.plan.channels.max(1));
+                    for run in tail_runs {
+                        let (run_samples, rest) = tail_samples.split_at((run.end - run.start) as usize * channels);
+                        self.stage(run_samples, run.start);
+                        tail_samples = rest;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.pending = frames.remainder().to_vec();
+    }
+
+    /// Writes whole frames, starting with frame `first` of the stream, to the current chunk's
+    /// file.
+    fn put(&mut self, samples: &[U], first: u64) {
+        let channels = usize::from(self.plan.channels.max(1));
+        if let Some(current) = self.current.as_mut() {
+            if current.started.is_none() && !samples.is_empty() {
+                let frames = (samples.len() / channels) as f64;
+                let ago = Duration::from_secs_f64(frames / f64::from(self.plan.sample_rate.max(1)));
+                current.started = Some(SystemTime::now() - ago);
+                current.first = Some(first);
+            }
+            current.next = first + (samples.len() / channels) as u64;
+            for (i, &sample) in samples.iter().enumerate() {
+                current.writer.write_sample(sample).ok();
+                let sample = sample.to_sample();
+                current.digest = fnv1a(current.digest, &f32::to_bits(sample).to_le_bytes());
+                current.loudness.add(sample);
+                current.channel_levels[i % channels].add_sample(sample);
+            }
+            current.written += (samples.len() / channels) as u64;
+        }
+    }
+
+    /// With VAD chunking: adds whole frames, starting with frame `first` of the stream, to the
+    /// end of the current chunk, writing out whatever falls out of reach of a split.
+    fn stage(&mut self, samples: &[U], first: u64) {
+        let end = first + (samples.len() / usize::from(self.plan.channels.max(1))) as u64;
+        match self.lookback_runs.back_mut() {
+            Some(run) if run.end == first => run.end = end,
+            _ if samples.is_empty() => {}
+            _ => self.lookback_runs.push_back(first..end),
+        }
+        self.lookback.extend(samples);
+        self.commit(self.plan.segmenter.as_ref().map_or(0, Segmenter::lookback_frames));
+    }
+
+    /// Writes the held-back end of the chunk to its file, all but its last `keep` frames.
+    fn commit(&mut self, keep: u64) {
+        let channels = usize::from(self.plan.channels.max(1));
+        let mut excess = (self.lookback.len() / channels).saturating_sub(keep as usize) as u64;
+        while excess > 0 {
+            let Some(run) = self.lookback_runs.front_mut() else {
+                return;
+            };
+            let frames = excess.min(run.end - run.start);
+            let first = run.start;
+            run.start += frames;
+            if run.is_empty() {
+                self.lookback_runs.pop_front();
+            }
+            let samples: Vec<U> = self.lookback.drain(..frames as usize * channels).collect();
+            self.put(&samples, first);
+            excess -= frames;
+        }
+    }
+
+    /// Adds samples to the pre-roll history, forgetting the oldest whole frames beyond it.
+    fn remember(&mut self, samples: &[U]) {
+        self.seen += (samples.len() / usize::from(self.plan.channels.max(1))) as u64;
+        let keep = (self.plan.history_frames() * u64::from(self.plan.channels.max(1))) as usize;
+        if keep == 0 {
+            return;
+        }
+        let samples = &samples[samples.len().saturating_sub(keep)..];
+        let excess = (self.history.len() + samples.len()).saturating_sub(keep);
+        self.history.drain(..excess);
+        self.history.extend(samples);
+    }
+
+    /// Finishes the current chunk and opens the next one unless the limit is reached.
+    fn rotate(&mut self, speech: Option<Range<u64>>) {
+        let Some(current) = self.current.take() else {
+            return;
+        };
+        let next_seq = current.seq + 1;
+        let result = self.close(current, speech);
+        self.advance(result, next_seq);
+    }
+
+    /// Hands over a finished chunk and opens chunk `next_seq` unless the limit is reached.
+    fn advance(&mut self, result: Result<Chunk, anyhow::Error>, next_seq: u64) {
+        let failed = result.is_err();
+        self.send(result);
+        if failed || self.plan.limit.is_some_and(|limit| next_seq >= limit) {
+            // No more chunks: closing the channel tells the recording loop we are done.
+            self.finished = None;
+            return;
+        }
+        self.open_chunk(next_seq);
+    }
+
+    fn open_chunk(&mut self, seq: u64) {
+        match (self.open)(seq) {
+            Ok((path, writer)) => {
+                self.current = Some(Current {
+                    seq,
+                    path,
+                    writer,
+                    written: 0,
+                    frames: 0,
+                    repeated: 0,
+                    started: None,
+                    first: None,
+                    next: 0,
+                    level: Level::default(),
+                    channel_levels: vec![Level::default(); usize::from(self.plan.channels.max(1))],
+                    loudness: self.plan.loudness(),
+                    digest: FNV_OFFSET,
+                });
+            }
+            Err(err) => {
+                self.send(Err(err));
+                self.finished = None;
+            }
+        }
+    }
+
+    /// Writes the end of the history, the pre-roll or overlap, into the current chunk: at
+    /// most `limit` frames, and leaving out the last `skip`, which are still to come. Opening
+    /// a chunk, these are its repeated frames.
+    fn replay(&mut self, skip: u64, limit: u64) {
+        let channels = usize::from(self.plan.channels.max(1));
+        let available = ((self.history.len() / channels) as u64).saturating_sub(skip);
+        let frames = available.min(self.plan.replay_frames()).min(limit);
+        let end = available as usize * channels;
+        let samples: Vec<U> = self.history.range(end - frames as usize * channels..end).copied().collect();
+        // The history ends with the frame last seen.
+        let first = self.seen - skip.min(self.seen) - frames.min(self.seen);
+        let fresh = self.lookback.is_empty();
+        if let Some(current) = self.current.as_mut().filter(|current| current.written == 0 && fresh) {
+            current.repeated = frames;
+        }
+        match self.plan.segmenter.as_mut() {
+            Some(segmenter) => {
+                segmenter.replayed(frames);
+                self.stage(&samples, first);
+            }
+            None => self.put(&samples, first),
+        }
+    }
+
+    /// Closes the chunk in progress at the end of the stream. With VAD chunking a chunk that
+    /// is not worth keeping (see [`Segmenter::speech`]) is deleted rather than returned.
+    fn finish(&mut self) -> Option<Result<Chunk, anyhow::Error>> {
+        let speech = match &self.plan.segmenter {
+            Some(segmenter) => match segmenter.speech() {
+                Some(speech) => Some(speech),
+                None => {
+                    self.discard();
+                    return None;
+                }
+            },
+            None => None,
+        };
+        // The last few milliseconds, short of a whole segmenter frame.
+        let pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            self.stage(&pending, self.seen);
+        }
+        self.commit(0);
+        let current = self.current.take()?;
+        Some(self.close(current, speech))
+    }
+
+    /// Deletes the chunk in progress.
+    fn discard(&mut self) {
+        if let Some(current) = self.current.take() {
+            current.writer.finalize().ok();
+            std::fs::remove_file(&current.path).ok();
+        }
+    }
+
+    fn close(&mut self, current: Current<W>, speech: Option<Range<u64>>) -> Result<Chunk, anyhow::Error> {
+        current.writer.finalize()?;
+        // The chunk's upload reads its frames back from the session file.
+        if let Some(session) = self.plan.session.as_mut() {
+            if let Some(first) = current.first {
+                session.mark(current.seq, first);
+            }
+            if let Err(err) = session.flush() {
+                self.end_session(Some(err));
+            }
+        }
+        let started = current.started.unwrap_or_else(SystemTime::now);
+        let clocked = match (current.first, self.epoch) {
+            (Some(first), Some((since, from))) => {
+                let rate = f64::from(self.plan.sample_rate.max(1));
+                let at = |frame: u64| since + Duration::from_secs_f64(frame.saturating_sub(from) as f64 / rate);
+                at(first)..at(current.next)
+            }
+            _ => started..started,
+        };
+        let time_reference = match (current.first, self.epoch, self.session) {
+            (Some(first), Some((since, from)), Some((start, _))) if since == start => first.saturating_sub(from),
+            (Some(_), Some(_), Some((start, _))) => {
+                let rate = f64::from(self.plan.sample_rate.max(1));
+                (clocked.start.duration_since(start).unwrap_or_default().as_secs_f64() * rate).round() as u64
+            }
+            _ => 0,
+        };
+        Ok(Chunk {
+            seq: current.seq,
+            path: current.path,
+            frames: current.written,
+            repeated_frames: current.repeated,
+            dropped_frames: self.dropped.swap(0, Ordering::Relaxed),
+            level: current.level,
+            channel_levels: current.channel_levels,
+            loudest_dbfs: current.loudness.dbfs(),
+            digest: current.digest,
+            speech,
+            started,
+            clocked,
+            time_reference,
+            span: current.first.map_or(0..0, |first| first..current.next),
+        })
+    }
+
+    /// Completes the session file, at the end or after `failure` to write to it.
+    fn end_session(&mut self, failure: Option<std::io::Error>) {
+        let Some(session) = self.plan.session.take() else {
+            return;
+        };
+        let path = session.path().to_path_buf();
+        if let Some(err) = failure {
+            error!(path = %path.display(), "failed to write to the session file, which ends here: {err}");
+        }
+        if let Err(err) = session.finalize() {
+            error!(path = %path.display(), "failed to complete the session file: {err:#}");
+        }
+    }
+
+    fn send(&self, result: Result<Chunk, anyhow::Error>) {
+        if let Some(finished) = &self.finished {
+            finished.send(result).ok();
+        }
+    }
+}
+
+impl ChunkSink {
+    /// Waits for the next finished chunk; `None` once the chunk limit has been reached or the
+    /// writer thread has stopped.
+    pub fn next_chunk(&self) -> Option<Result<Chunk, anyhow::Error>> {
+        self.finished.recv().ok()
+    }
+
+    /// Like [`Self::next_chunk`], but gives up after `timeout`.
+    pub fn next_chunk_timeout(&self, timeout: Duration) -> Result<Result<Chunk, anyhow::Error>, RecvTimeoutError> {
+        self.finished.recv_timeout(timeout)
+    }
+
+    /// Stops the writer thread and returns the chunk that was in progress, cut short, if any.
+    pub fn finish(self) -> Option<Result<Chunk, anyhow::Error>> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().ok().flatten()
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// A dither seed that differs from run to run.
+fn seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0x9e37_79b9_7f4a_7c15)
+}
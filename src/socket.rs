@@ -0,0 +1,175 @@
+//! `--unix-socket`: uploading to a server that listens on a Unix domain socket rather than a
+//! TCP port, as a sidecar on the same host may.
+//!
+//! The request is the one an HTTP upload would send, headers, credential and all; only the
+//! transport differs. The HTTP client builds it and this module writes it to the socket as
+//! HTTP/1.1, one connection per upload, and reads the answer back, whatever its framing. The
+//! socket can also be given in the URL, as `unix:///run/asr.sock:/transcribe`: the socket's
+//! path, then after a colon the path the request is for. A socket that is missing, that the
+//! user may not open, or that nothing listens on each fail with a message of their own, and
+//! are retried as a server that cannot be reached is.
+
+use crate::upload::{retry_after, Response};
+use reqwest::header::{CONTENT_LENGTH, HOST, USER_AGENT};
+use reqwest::Method;
+use std::fmt;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Uploading through the socket failed: it could not be reached, or the answer was cut off.
+#[derive(Debug)]
+pub struct SocketError(String);
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SocketError {}
+
+/// The socket and the HTTP URL of a `unix://` one, as `unix:///run/asr.sock:/transcribe`;
+/// without a path after the socket, requests are for `/`.
+pub fn split_url(url: &reqwest::Url) -> Result<(PathBuf, reqwest::Url), String> {
+    let (socket, path) = match url.path().find(":/") {
+        Some(at) => (&url.path()[..at], &url.path()[at + 1..]),
+        None => (url.path(), "/"),
+    };
+    let mut socket_url = url.clone();
+    socket_url.set_path(socket);
+    let socket = socket_url
+        .to_file_path()
+        .ok()
+        .filter(|socket| socket.file_name().is_some())
+        .ok_or_else(|| format!("`{url}` names no socket; expected e.g. unix:///run/asr.sock:/transcribe"))?;
+    let mut http = reqwest::Url::parse("http://localhost/").expect("a valid URL");
+    http.set_path(path);
+    http.set_query(url.query());
+    Ok((socket, http))
+}
+
+/// Sends `request` through the socket at `socket`, bound by the request's timeout if it has
+/// one, and returns the answer.
+pub fn send(socket: &Path, request: reqwest::blocking::Request) -> Result<Response, anyhow::Error> {
+    let body = request
+        .body()
+        .map(|body| body.as_bytes().ok_or_else(|| anyhow::anyhow!("a streamed upload cannot go over a Unix socket")))
+        .transpose()?
+        .unwrap_or_default();
+    let failed = |err: std::io::Error| SocketError(io_failure(socket, &err, request.timeout().is_some()));
+    let mut stream = UnixStream::connect(socket).map_err(|err| SocketError(connect_failure(socket, &err)))?;
+    stream.set_read_timeout(request.timeout().copied()).and_then(|()| stream.set_write_timeout(request.timeout().copied())).map_err(failed)?;
+
+    let head_only = request.method() == Method::HEAD;
+    let url = request.url();
+    let target = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_owned(),
+    };
+    let mut head = format!("{} {target} HTTP/1.1\r\n", request.method()).into_bytes();
+    let headers = request.headers();
+    if !headers.contains_key(HOST) {
+        write!(head, "Host: {}\r\n", url.host_str().unwrap_or("localhost")).ok();
+    }
+    if !headers.contains_key(USER_AGENT) {
+        write!(head, "User-Agent: {}/{}\r\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).ok();
+    }
+    for (name, value) in headers.iter().filter(|(name, _)| *name != CONTENT_LENGTH) {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    write!(head, "Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()).ok();
+    stream.write_all(&head).and_then(|()| stream.write_all(body)).and_then(|()| stream.flush()).map_err(failed)?;
+
+    let mut reader = BufReader::new(stream);
+    let (status, headers) = loop {
+        let (status, headers) = read_head(&mut reader).map_err(failed)?;
+        // An interim answer, such as 100 Continue, comes before the one that counts.
+        if !(100..200).contains(&status) {
+            break (status, headers);
+        }
+    };
+    let header = |name: &str| headers.iter().find(|(set, _)| set.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+    let mut body = Vec::new();
+    if head_only || matches!(status, 204 | 304) {
+        // The answer to a HEAD request has the headers of a whole one, but no body.
+    } else if header("Transfer-Encoding").is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked")) {
+        read_chunked(&mut reader, &mut body).map_err(failed)?;
+    } else if let Some(length) = header("Content-Length") {
+        let length = length.trim().parse().map_err(|_| SocketError(format!("the server on {} sent a bad Content-Length", socket.display())))?;
+        body.resize(length, 0);
+        reader.read_exact(&mut body).map_err(failed)?;
+    } else {
+        reader.read_to_end(&mut body).map_err(failed)?;
+    }
+    Ok(Response { status, body, retry_after: header("Retry-After").and_then(retry_after) })
+}
+
+/// Why connecting to `socket` failed, told apart by what to do about it.
+fn connect_failure(socket: &Path, err: &std::io::Error) -> String {
+    let socket = socket.display();
+    match err.kind() {
+        ErrorKind::NotFound => format!("the socket {socket} does not exist; is the server running?"),
+        ErrorKind::PermissionDenied => format!("permission denied on the socket {socket}; this user cannot connect to it"),
+        ErrorKind::ConnectionRefused => format!("nothing is listening on the socket {socket}"),
+        _ => format!("cannot connect to the socket {socket}: {err}"),
+    }
+}
+
+/// What went wrong talking to the server on `socket` once connected.
+fn io_failure(socket: &Path, err: &std::io::Error, timed: bool) -> String {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut if timed => format!("the server on {} timed out", socket.display()),
+        ErrorKind::UnexpectedEof => format!("the server on {} closed the connection before it answered", socket.display()),
+        _ => format!("talking to the server on {} failed: {err}", socket.display()),
+    }
+}
+
+/// Reads a status line and headers.
+fn read_head(reader: &mut impl BufRead) -> std::io::Result<(u16, Vec<(String, String)>)> {
+    let status_line = read_line(reader)?;
+    let status = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| std::io::Error::other(format!("not an HTTP answer: {status_line:?}")))?;
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+}
+
+/// Reads a line, without its line ending; the end of the stream is an error.
+fn read_line(reader: &mut impl BufRead) -> std::io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Reads a chunked body into `body`, up to and including its trailer.
+fn read_chunked(reader: &mut impl BufRead, body: &mut Vec<u8>) -> std::io::Result<()> {
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| std::io::Error::other(format!("bad chunk size {size:?}")))?;
+        if size == 0 {
+            while !read_line(reader)?.is_empty() {}
+            return Ok(());
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        read_line(reader)?;
+    }
+}
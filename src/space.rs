@@ -0,0 +1,81 @@
+//! `--min-free-mb` and `--low-free-mb`: keeping the recorder from filling the disk.
+//!
+//! Each time a chunk file is about to be created the free space on its filesystem is looked
+//! up, with one `statvfs` call, and compared with two marks. Below the low-water mark the
+//! uploaded chunks `--keep` is holding on to are deleted; below the minimum no more chunk
+//! files are created until there is room again. The recording goes on meanwhile: with
+//! `--low-space-in-memory` its chunks are kept in memory and uploaded from there, and
+//! otherwise they are thrown away, with an error for each.
+
+use std::path::Path;
+
+/// How full the disk is, as measured against the marks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Plenty,
+    /// Below the low-water mark: time to clean up.
+    Low,
+    /// Below the minimum: no more chunk files.
+    Exhausted,
+}
+
+/// The marks, in bytes; 0 turns a mark off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Thresholds {
+    pub low_water: u64,
+    pub minimum: u64,
+}
+
+impl Thresholds {
+    pub fn level(&self, free: u64) -> Level {
+        if free < self.minimum {
+            Level::Exhausted
+        } else if free < self.low_water {
+            Level::Low
+        } else {
+            Level::Plenty
+        }
+    }
+}
+
+/// The level the disk was last found at, so that each change is reported once.
+#[derive(Debug)]
+pub struct Watch {
+    thresholds: Thresholds,
+    level: Level,
+}
+
+impl Watch {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Watch { thresholds, level: Level::Plenty }
+    }
+
+    /// The level with `free` bytes left, and whether that is a change from the last.
+    pub fn update(&mut self, free: u64) -> (Level, bool) {
+        let level = self.thresholds.level(free);
+        let changed = level != self.level;
+        self.level = level;
+        (level, changed)
+    }
+}
+
+/// Bytes an unprivileged process can still write to the filesystem holding `dir`.
+#[cfg(unix)]
+pub fn available(dir: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes to the struct it is given, zeroed here, and reads the
+    // NUL-terminated path.
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Elsewhere the space is taken to be plentiful.
+#[cfg(not(unix))]
+pub fn available(_: &Path) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
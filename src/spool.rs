@@ -0,0 +1,270 @@
+//! `--spool-dir`: holding on to the chunks the server did not take, across restarts, until
+//! it does.
+//!
+//! A chunk whose upload fails after all its retries, or that the upload queue drops, is put
+//! into the spool under its archive name, next to a JSON sidecar of the same name giving its
+//! number, device, start time, when it was spooled and how many attempts it has had. A
+//! [`Retrier`] goes through the spool every so often, oldest chunk first, trying each once:
+//! a chunk the server takes is deleted, and the first one it does not ends the pass, the
+//! server being still away. The spool is read from disk each pass, so whatever an earlier
+//! run left in it is picked up the same way. With several devices, each retries its own.
+//!
+//! `--spool-max-mb` caps the spool: past it the oldest chunks, of any device, are deleted,
+//! with a warning for each.
+
+use crate::archive::{self, archive_name, Mode};
+use crate::json;
+use crate::logging;
+use anyhow::Context;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// How often a [`Retrier`] looks whether it has been asked to stop.
+const TICK: Duration = Duration::from_millis(100);
+
+/// A chunk in the spool, as its sidecar describes it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spooled {
+    pub seq: u64,
+    /// The label of its device, with several devices.
+    pub device: Option<String>,
+    /// The chunk's file, in the spool.
+    pub path: PathBuf,
+    /// When it started recording, in Unix milliseconds.
+    pub started_millis: u64,
+    /// When it was spooled, in Unix milliseconds.
+    pub spooled_millis: u64,
+    /// Uploads tried so far.
+    pub attempts: u32,
+}
+
+impl Spooled {
+    /// Where its sidecar is.
+    pub fn sidecar(&self) -> PathBuf {
+        self.path.with_extension("json")
+    }
+
+    fn to_json(&self) -> String {
+        let file = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let device = self.device.as_deref().map_or_else(|| String::from("null"), json::string);
+        format!(
+            "{{\"chunk\":{},\"device\":{device},\"file\":{},\"started_ms\":{},\"spooled_ms\":{},\"attempts\":{}}}\n",
+            self.seq,
+            json::string(&file),
+            self.started_millis,
+            self.spooled_millis,
+            self.attempts
+        )
+    }
+
+    /// Reads the sidecar at `path`.
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let text = std::fs::read_to_string(path)?;
+        let value = json::parse(&text).map_err(anyhow::Error::msg)?;
+        let number = |key| value.get(key).and_then(json::Value::as_u64).with_context(|| format!("no `{key}`"));
+        let file = value.get("file").and_then(json::Value::as_str).context("no `file`")?;
+        Ok(Spooled {
+            seq: number("chunk")?,
+            device: value.get("device").and_then(json::Value::as_str).map(str::to_owned),
+            path: path.with_file_name(file),
+            started_millis: number("started_ms")?,
+            spooled_millis: number("spooled_ms")?,
+            attempts: number("attempts")? as u32,
+        })
+    }
+
+    /// Writes its sidecar, whole or not at all.
+    pub fn save(&self) -> std::io::Result<()> {
+        let sidecar = self.sidecar();
+        let part = sidecar.with_extension("json.part");
+        std::fs::write(&part, self.to_json())?;
+        std::fs::rename(&part, &sidecar)
+    }
+
+    /// Deletes it from the spool, sidecar last so that a crash cannot leave audio no sidecar
+    /// speaks for.
+    pub fn remove(&self) -> std::io::Result<()> {
+        for path in [self.path.clone(), self.sidecar()] {
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes it takes up.
+    fn size(&self) -> u64 {
+        [self.path.clone(), self.sidecar()].iter().filter_map(|path| std::fs::metadata(path).ok()).map(|m| m.len()).sum()
+    }
+}
+
+/// One device's view of the spool directory.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    /// The most bytes it may hold; 0 for no cap.
+    max_bytes: u64,
+    label: Option<String>,
+    /// How chunk files get in: a scratch file, recorded to again, is copied.
+    mode: Mode,
+}
+
+impl Spool {
+    /// The spool in `dir`, created if missing, for the chunks of the device `label`.
+    pub fn open(dir: &Path, max_bytes: u64, label: Option<&str>, mode: Mode) -> Result<Self, anyhow::Error> {
+        std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Spool { dir: dir.to_path_buf(), max_bytes, label: label.map(str::to_owned), mode })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Puts chunk `seq`, which started at `started_millis` and has had `attempts` uploads, into
+    /// the spool: `data` if it is in memory, the file at `path` otherwise. Then makes room,
+    /// which may take this very chunk if it is bigger than the cap.
+    pub fn store(&self, seq: u64, path: &Path, data: Option<&[u8]>, started_millis: u64, attempts: u32) -> Result<Spooled, anyhow::Error> {
+        let name = archive_name(started_millis, seq, self.label.as_deref());
+        let name = match path.extension() {
+            Some(extension) => Path::new(&name).with_extension(extension).to_string_lossy().into_owned(),
+            None => name,
+        };
+        let stored = match data {
+            Some(data) => {
+                let part = self.dir.join(format!(".{name}.part"));
+                std::fs::write(&part, data).with_context(|| format!("failed to write {}", part.display()))?;
+                archive::archive(&self.dir, &part, &name, Mode::Move)?
+            }
+            None => archive::archive(&self.dir, path, &name, self.mode)?,
+        };
+        let spooled = Spooled {
+            seq,
+            device: self.label.clone(),
+            path: stored,
+            started_millis,
+            spooled_millis: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            attempts,
+        };
+        if let Err(err) = spooled.save() {
+            std::fs::remove_file(&spooled.path).ok();
+            return Err(err).with_context(|| format!("failed to write {}", spooled.sidecar().display()));
+        }
+        self.evict();
+        Ok(spooled)
+    }
+
+    /// Every chunk in the spool, of every device, oldest first.
+    pub fn all(&self) -> Vec<Spooled> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut spooled: Vec<Spooled> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .filter_map(|path| match Spooled::read(&path) {
+                Ok(spooled) => Some(spooled),
+                Err(err) => {
+                    warn!(path = %path.display(), "skipping a broken spool sidecar: {err:#}");
+                    None
+                }
+            })
+            .collect();
+        spooled.sort_by(|a, b| (a.started_millis, a.seq, &a.device).cmp(&(b.started_millis, b.seq, &b.device)));
+        spooled
+    }
+
+    /// This device's chunks in the spool, oldest first.
+    pub fn chunks(&self) -> Vec<Spooled> {
+        let mut spooled = self.all();
+        spooled.retain(|spooled| spooled.device == self.label);
+        spooled
+    }
+
+    /// Deletes the oldest chunks until the spool is within its cap.
+    fn evict(&self) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let spooled = self.all();
+        let mut size: u64 = spooled.iter().map(Spooled::size).sum();
+        for oldest in spooled {
+            if size <= self.max_bytes {
+                break;
+            }
+            size = size.saturating_sub(oldest.size());
+            match oldest.remove() {
+                Ok(()) => warn!(chunk = oldest.seq, path = %oldest.path.display(), "spool over --spool-max-mb; deleted its oldest chunk"),
+                Err(err) => warn!(chunk = oldest.seq, path = %oldest.path.display(), "failed to delete a spooled chunk: {err}"),
+            }
+        }
+    }
+
+    /// Starts a thread trying this device's chunks again with `upload`, at once and then every
+    /// `interval`. `upload` says whether the server took the chunk.
+    pub fn retry(&self, interval: Duration, upload: impl FnMut(&Spooled) -> bool + Send + 'static) -> Retrier {
+        self.retry_when(interval, || true, upload)
+    }
+
+    /// As [`Spool::retry`], but a pass stops short, trying no more chunks, whenever `ready`
+    /// says the server is not to be tried now.
+    pub fn retry_when(
+        &self,
+        interval: Duration,
+        ready: impl Fn() -> bool + Send + 'static,
+        mut upload: impl FnMut(&Spooled) -> bool + Send + 'static,
+    ) -> Retrier {
+        let spool = self.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let handle = logging::spawn(move || {
+            let waiting = spool.chunks().len();
+            if waiting > 0 {
+                info!(chunks = waiting, "spooled chunks from an earlier run; uploading them oldest first");
+            }
+            while !stopping.load(Ordering::Relaxed) {
+                for mut spooled in spool.chunks() {
+                    if stopping.load(Ordering::Relaxed) || !ready() {
+                        break;
+                    }
+                    if upload(&spooled) {
+                        if let Err(err) = spooled.remove() {
+                            warn!(chunk = spooled.seq, path = %spooled.path.display(), "failed to delete a spooled chunk: {err}");
+                        }
+                        continue;
+                    }
+                    spooled.attempts += 1;
+                    // A chunk evicted meanwhile is gone for good.
+                    if spooled.path.exists() {
+                        spooled.save().ok();
+                    }
+                    break;
+                }
+                let slept = Instant::now();
+                while slept.elapsed() < interval && !stopping.load(Ordering::Relaxed) {
+                    std::thread::sleep(TICK.min(interval));
+                }
+            }
+        });
+        Retrier { stop, handle }
+    }
+}
+
+/// The thread going through the spool.
+pub struct Retrier {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Retrier {
+    /// Stops it once the upload it may be on is done, and waits for that.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().ok();
+    }
+}
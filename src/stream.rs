@@ -0,0 +1,243 @@
+//! `--stream-upload`: uploading each chunk while it is still being recorded, so that the
+//! answer comes about an upload's time sooner.
+//!
+//! When a chunk opens, its upload opens with it: a POST with a chunked body, fed by a [`Tee`]
+//! that sits between the chunk's encoder and its file and passes on every byte written past
+//! the end so far. The sizes an encoder goes back to fill in once the chunk is complete are
+//! left as the stream had them: a streamed WAV says `0xFFFFFFFF`, as long as it goes, which
+//! the tee puts into the header on its way out, and FLAC leaves the length unknown anyway.
+//! The body ends when the chunk does. The writer thread never waits on the network; what the
+//! request has not taken yet waits in memory.
+//!
+//! A streamed upload is only a head start. If the server cannot be reached within
+//! [`STREAM_CONNECT_WAIT`], the request fails or the answer is not a success, the chunk is
+//! uploaded whole once complete, as without `--stream-upload`, retries, spool and all. A
+//! server that turns a streamed upload down but takes the whole chunk is not streamed to
+//! again.
+
+use crate::encode::Format;
+use crate::logging;
+use crate::upload::{Endpoint, Response};
+pub use crate::upload::STREAM_CONNECT_WAIT;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Bytes the tee gathers before passing them on.
+const BATCH: usize = 4096;
+
+/// The streamed uploads of a recording, by chunk.
+#[derive(Clone, Default)]
+pub struct Streams {
+    open: Arc<Mutex<HashMap<u64, Stream>>>,
+    /// Set once the server has turned a streamed upload down.
+    refused: Arc<AtomicBool>,
+}
+
+impl Streams {
+    /// Starts the upload of chunk `seq` to `endpoint`, as the file at `path` will be once
+    /// complete, and returns what to feed it through; `None` once streaming is off. The
+    /// request has `longest`, the most a chunk lasts, on top of the endpoint's timeout.
+    pub fn start(&self, seq: u64, endpoint: &Endpoint, path: &Path, longest: Duration) -> Option<Body> {
+        if self.refused.load(Ordering::Relaxed) {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel();
+        let (answer_tx, answer) = mpsc::channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let reader = BodyReader { rx, chunk: Vec::new(), at: 0, abandoned: abandoned.clone() };
+        let (endpoint, upload_path) = (endpoint.clone(), path.to_path_buf());
+        logging::spawn(move || {
+            let result = endpoint.upload_stream(&upload_path, reader, longest);
+            answer_tx.send((result, Instant::now())).ok();
+        });
+        let stream = Stream { answer, abandoned, started: Instant::now(), path: path.to_path_buf() };
+        self.open.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(seq, stream);
+        let header = (Format::of(path) == Some(Format::Wav)).then(Vec::new);
+        Some(Body { tx: Some(tx), pending: Vec::with_capacity(BATCH), header })
+    }
+
+    /// Chunk `seq`'s streamed upload, if it has one.
+    pub fn take(&self, seq: u64) -> Option<Stream> {
+        self.open.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&seq)
+    }
+
+    /// Stops streaming, as the server turned a streamed upload down and took the whole chunk.
+    pub fn refuse(&self) {
+        if !self.refused.swap(true, Ordering::Relaxed) {
+            warn!("the server turned down a streamed upload; uploading chunks whole from now on");
+        }
+    }
+}
+
+/// One chunk's streamed upload.
+pub struct Stream {
+    answer: mpsc::Receiver<(Result<Response, anyhow::Error>, Instant)>,
+    abandoned: Arc<AtomicBool>,
+    /// When the request was opened, with the chunk.
+    pub started: Instant,
+    path: PathBuf,
+}
+
+impl Stream {
+    /// Waits for the server's answer, and returns it with when it came.
+    pub fn answer(self) -> (Result<Response, anyhow::Error>, Instant) {
+        self.answer.recv().unwrap_or_else(|_| {
+            (Err(anyhow::anyhow!("the streamed upload of {} went away", self.path.display())), Instant::now())
+        })
+    }
+
+    /// Gives up on it: the request fails instead of sending the rest of the chunk, which the
+    /// server is not to transcribe.
+    pub fn abandon(self) {
+        self.abandoned.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The writer's end of a streamed upload's body.
+pub struct Body {
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    pending: Vec<u8>,
+    /// A WAV's header, held until its `data` chunk starts so that its sizes can be set.
+    header: Option<Vec<u8>>,
+}
+
+impl Body {
+    fn push(&mut self, bytes: &[u8]) {
+        match &mut self.header {
+            Some(header) => {
+                header.extend_from_slice(bytes);
+                if let Some(data_at) = data_chunk(header) {
+                    header[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+                    header[data_at + 4..data_at + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+                    self.pending.append(header);
+                    self.header = None;
+                }
+            }
+            None => self.pending.extend_from_slice(bytes),
+        }
+        if self.pending.len() >= BATCH {
+            self.send();
+        }
+    }
+
+    fn send(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.pending, Vec::with_capacity(BATCH));
+        // A request that failed takes no more; the chunk is uploaded whole instead.
+        if let Some(tx) = &self.tx {
+            if tx.send(batch).is_err() {
+                self.tx = None;
+            }
+        }
+    }
+
+    /// Ends the body with what is left.
+    fn close(&mut self) {
+        if let Some(header) = self.header.take() {
+            self.pending.splice(0..0, header);
+        }
+        self.send();
+        self.tx = None;
+    }
+}
+
+/// Where the `data` chunk of the WAV header in `header` starts, once all of its own header is
+/// there.
+fn data_chunk(header: &[u8]) -> Option<usize> {
+    let mut at = 12;
+    while header.len() >= at + 8 {
+        if &header[at..at + 4] == b"data" {
+            return Some(at);
+        }
+        let size = u32::from_le_bytes(header[at + 4..at + 8].try_into().expect("four bytes")) as usize;
+        at += 8 + size + size % 2;
+    }
+    None
+}
+
+/// What the request reads the body from.
+struct BodyReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    at: usize,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.at == self.chunk.len() {
+            if self.abandoned.load(Ordering::Relaxed) {
+                return Err(std::io::Error::other("the chunk was dropped"));
+            }
+            match self.rx.recv() {
+                Ok(chunk) => (self.chunk, self.at) = (chunk, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        if self.abandoned.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("the chunk was dropped"));
+        }
+        let n = buf.len().min(self.chunk.len() - self.at);
+        buf[..n].copy_from_slice(&self.chunk[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+/// A chunk's file, which with `--stream-upload` also feeds its streamed upload.
+pub struct Tee<W> {
+    inner: W,
+    body: Option<Body>,
+    /// Where the next write goes.
+    at: u64,
+    /// How far the file has been passed on.
+    sent: u64,
+}
+
+impl<W> Tee<W> {
+    pub fn new(inner: W, body: Option<Body>) -> Self {
+        Tee { inner, body, at: 0, sent: 0 }
+    }
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let end = self.at + n as u64;
+        if let Some(body) = &mut self.body {
+            // Going back over what was sent, to fill in sizes, is for the file alone.
+            if end > self.sent && self.at <= self.sent {
+                body.push(&buf[(self.sent - self.at) as usize..n]);
+                self.sent = end;
+            }
+        }
+        self.at = end;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for Tee<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.at = self.inner.seek(pos)?;
+        Ok(self.at)
+    }
+}
+
+impl<W> Drop for Tee<W> {
+    fn drop(&mut self) {
+        if let Some(body) = &mut self.body {
+            body.close();
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Reading the server's answer to an upload as a transcription.
+//!
+//! A server answers with JSON such as whisper's `{"text": "...", "segments": [...]}`, or with
+//! the bare text. JSON with a `text` is read into a [`Transcription`]: its text, and what of
+//! its segments, language and confidence it gives, anything else being ignored but kept with
+//! the rest of the answer. An answer that is not an object is text. One that starts as an
+//! object but does not parse is taken as text too, and said to be malformed so that the
+//! caller can log it against its chunk. Only text is printed and written to the transcript
+//! log, by [`deliver`] for the transcripts of a stream; the whole answer goes to
+//! `transcribe-dir --combined`.
+
+use crate::json::{self, Value};
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use tracing::error;
+
+/// A server's answer, as far as it could be read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transcription {
+    pub text: String,
+    /// The timed pieces of the text, if the answer has them.
+    pub segments: Vec<Segment>,
+    /// The language the server heard, if it says.
+    pub language: Option<String>,
+    pub confidence: Option<f64>,
+    /// The whole answer, if it was JSON.
+    pub json: Option<Value>,
+    /// Why the answer, which looked like JSON, did not parse; it is then taken as text.
+    pub malformed: Option<String>,
+}
+
+/// One timed piece of a transcription, in seconds from the chunk's start.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub text: String,
+    pub confidence: Option<f64>,
+}
+
+/// What an answer turned out to be.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Answer {
+    /// JSON with a `text`.
+    Transcription(Transcription),
+    /// JSON without one, such as an error.
+    Other(Value),
+    /// Not JSON: the text itself.
+    Text,
+    /// An object that does not parse, and why.
+    Malformed(String),
+}
+
+impl Transcription {
+    /// `body` taken as the text itself.
+    pub fn plain(body: &[u8]) -> Self {
+        Transcription {
+            text: String::from_utf8_lossy(body).into_owned(),
+            segments: Vec::new(),
+            language: None,
+            confidence: None,
+            json: None,
+            malformed: None,
+        }
+    }
+}
+
+/// Reads `body`, an answer to an upload.
+pub fn read(body: &[u8]) -> Answer {
+    let text = String::from_utf8_lossy(body);
+    // A bare transcript may well start with `[`, as whisper's `[BLANK_AUDIO]` does.
+    if !text.trim_start().starts_with('{') {
+        return Answer::Text;
+    }
+    let value = match json::parse(&text) {
+        Ok(value) => value,
+        Err(err) => return Answer::Malformed(err),
+    };
+    let Some(said) = value.get("text").and_then(Value::as_str) else {
+        return Answer::Other(value);
+    };
+    let segments = match value.get("segments") {
+        Some(Value::Array(segments)) => segments
+            .iter()
+            .filter_map(|segment| {
+                Some(Segment {
+                    start: segment.get("start").and_then(Value::as_f64),
+                    end: segment.get("end").and_then(Value::as_f64),
+                    text: segment.get("text")?.as_str()?.trim().to_owned(),
+                    confidence: segment.get("confidence").and_then(Value::as_f64),
+                })
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Answer::Transcription(Transcription {
+        text: said.trim().to_owned(),
+        segments,
+        language: value.get("language").and_then(Value::as_str).map(str::to_owned),
+        confidence: value.get("confidence").and_then(Value::as_f64),
+        malformed: None,
+        json: Some(value),
+    })
+}
+
+/// Prints `text`, a final transcript from a stream rather than a chunk's, and appends it to
+/// `log`, after `language` and a tab if given, as a chunk's transcript is.
+pub fn deliver(text: &str, log: Option<&Mutex<File>>, language: Option<&str>) {
+    println!("{text}");
+    let Some(log) = log else {
+        return;
+    };
+    let mut line = String::with_capacity(text.len() + 8);
+    if let Some(language) = language {
+        line.push_str(language);
+        line.push('\t');
+    }
+    line.push_str(text);
+    line.push('\n');
+    if let Err(err) = log.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).write_all(line.as_bytes()) {
+        error!("failed to write the transcript to the log: {err}");
+    }
+}
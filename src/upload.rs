@@ -0,0 +1,1091 @@
+//! Sending chunks to the transcription server.
+//!
+//! Each chunk goes as the body of a POST through one shared HTTP client, and the caller gets
+//! the response's status and body back. With `--upload-mode multipart` the body is a form
+//! instead, as whisper.cpp's server reads it: the chunk is its `file` part, after it come the
+//! `--form` fields. In any mode the transcript is the `text` of the JSON the server answers
+//! with, as [`crate::transcript`] reads it, or the answer itself if it has none.
+//! `--upload-mode openai` sends the form an OpenAI-style transcription API takes, and expects
+//! JSON back: any other answer is logged as such and has no transcript. `--compress-upload`
+//! gzips the body with [`crate::gzip`]. A live chunk also carries its [`Metadata`], as
+//! headers or, in the multipart mode, as form fields.
+//!
+//! `https://` URLs are checked against the system's roots, and those of `--ca-cert` for a
+//! server with a certificate of a private CA. `--insecure` checks nothing, and says so. The
+//! client goes through the proxy [`crate::proxy`] picks, or none, and logs which, though only
+//! once [`crate::health`] has logged its probe. On Unix, `--unix-socket` has the same requests
+//! go through a socket instead, by way of `socket`.
+//!
+//! A server that takes a stream rather than chunks is sent one instead: over a WebSocket by
+//! `websocket`, with `--ws-url`, or over a gRPC call by `grpc`, with `--grpc-endpoint`.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use clap::ValueEnum;
+use reqwest::blocking::{Body, Client, RequestBuilder};
+use reqwest::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::Method;
+use tracing::{error, info, warn};
+use crate::encode::Format;
+use crate::gzip;
+use crate::output::{open_log, prepare_output_dir};
+use crate::proxy::Proxy;
+use crate::transcript::{self, Answer, Transcription};
+
+/// An extra HTTP header sent with every upload, given as `--header "Name: value"`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+/// Parses `Name: value`. The name must be a valid HTTP token; the value may be empty.
+pub fn parse_header(s: &str) -> Result<Header, String> {
+    const EXAMPLE: &str = "expected `Name: value`, e.g. --header \"X-Api-Key: secret\"";
+    let (name, value) = s.split_once(':').ok_or_else(|| format!("missing `:`; {EXAMPLE}"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("empty header name; {EXAMPLE}"));
+    }
+    if let Some(c) = name.chars().find(|&c| !is_token_char(c)) {
+        return Err(format!("invalid character {c:?} in header name `{name}`; {EXAMPLE}"));
+    }
+    Ok(Header { name: name.to_owned(), value: value.trim().to_owned() })
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// What is known of a chunk, such as its sample rate, number and start, to send along with
+/// it so that the server need not read it out of the audio. Each field has a header name, for
+/// the raw and openai modes, and a form field name, for the multipart mode; the uploader only
+/// passes them on, so a new field is one more [`Metadata::with`] where the chunk is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata(Vec<MetadataField>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetadataField {
+    pub header: &'static str,
+    pub field: &'static str,
+    pub value: String,
+}
+
+impl Metadata {
+    /// Adds `value` as the header `header` or the form field `field`.
+    pub fn with(mut self, header: &'static str, field: &'static str, value: impl ToString) -> Self {
+        self.0.push(MetadataField { header, field, value: value.to_string() });
+        self
+    }
+
+    pub fn fields(&self) -> &[MetadataField] {
+        &self.0
+    }
+}
+
+/// `value` as a header can carry it: what is not printable ASCII, such as the `é` of a device
+/// name, is percent-encoded as UTF-8, and so is `%` itself.
+fn header_safe(value: &str) -> String {
+    let mut safe = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ' ' || (c.is_ascii_graphic() && c != '%') {
+            safe.push(c);
+        } else {
+            for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                safe.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    safe
+}
+
+/// A credential. It prints as `<redacted>`, so that no `Debug` output or log line can give it
+/// away.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Secret(secret.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// How the `--api-key-env` credential goes with each request, as `--auth-scheme` gives it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthScheme {
+    /// `Authorization: Bearer <key>`.
+    Bearer,
+    /// `Authorization: Basic ...`, the credential being `user:password`.
+    Basic,
+    /// The credential as the whole value of a header of this name.
+    Header(String),
+}
+
+/// Parses `bearer`, `basic` or `header:<name>`.
+pub fn parse_auth_scheme(s: &str) -> Result<AuthScheme, String> {
+    const EXAMPLE: &str = "expected bearer, basic or header:<name>, e.g. header:X-Api-Key";
+    match s.split_once(':') {
+        _ if s.eq_ignore_ascii_case("bearer") => Ok(AuthScheme::Bearer),
+        _ if s.eq_ignore_ascii_case("basic") => Ok(AuthScheme::Basic),
+        Some((scheme, name)) if scheme.eq_ignore_ascii_case("header") => {
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(is_token_char) {
+                return Err(format!("`{name}` is not a header name; {EXAMPLE}"));
+            }
+            Ok(AuthScheme::Header(name.to_owned()))
+        }
+        _ => Err(format!("unknown scheme `{s}`; {EXAMPLE}")),
+    }
+}
+
+impl AuthScheme {
+    /// The header carrying `credential` this way.
+    pub fn auth(&self, credential: &str) -> Result<Auth, String> {
+        let (header, value) = match self {
+            AuthScheme::Bearer => (String::from("Authorization"), format!("Bearer {credential}")),
+            AuthScheme::Basic if !credential.contains(':') => return Err(String::from("basic authentication takes `user:password`")),
+            AuthScheme::Basic => (String::from("Authorization"), format!("Basic {}", base64(credential.as_bytes()))),
+            AuthScheme::Header(name) => (name.clone(), credential.to_owned()),
+        };
+        Ok(Auth { header, value: Secret(value) })
+    }
+}
+
+/// The header that authorizes each upload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Auth {
+    pub header: String,
+    pub value: Secret,
+}
+
+/// `data` in standard, padded base64.
+pub(crate) fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..=3 {
+            out.push(if i <= group.len() { char::from(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize]) } else { '=' });
+        }
+    }
+    out
+}
+
+/// How a chunk is put into the request, as `--upload-mode` picks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum UploadMode {
+    /// The chunk is the body
+    #[default]
+    Raw,
+    /// A multipart/form-data form with the chunk as its `file` field, as whisper.cpp's server
+    /// takes it
+    Multipart,
+    /// The form of an OpenAI-style /v1/audio/transcriptions API: the chunk, --model, and
+    /// --language and --prompt if given, authorized by --openai-api-key
+    Openai,
+}
+
+/// The form of a multipart upload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Form {
+    /// The fields after the chunk.
+    pub fields: Vec<FormField>,
+    /// Whether only the `text` of a successful JSON answer is a transcript, as from an
+    /// OpenAI-style API; otherwise an answer without one is taken whole.
+    pub strict: bool,
+}
+
+/// An extra field of a multipart upload, given as `--form key=value`, or a `--query`
+/// parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormField {
+    pub name: String,
+    pub value: String,
+}
+
+/// Parses `key=value`. The key goes into the part's header, so it cannot hold quotes or line
+/// breaks; the value may be anything, empty included.
+pub fn parse_form_field(s: &str) -> Result<FormField, String> {
+    parse_pair(s, "expected `key=value`, e.g. --form temperature=0.2")
+}
+
+/// Parses `key=value` for `--query`. In the multipart modes it is a form field, so the key
+/// is held to the same rules.
+pub fn parse_query_param(s: &str) -> Result<FormField, String> {
+    parse_pair(s, "expected `key=value`, e.g. --query beam_size=5")
+}
+
+fn parse_pair(s: &str, example: &str) -> Result<FormField, String> {
+    let (name, value) = s.split_once('=').ok_or_else(|| format!("missing `=`; {example}"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("empty field name; {example}"));
+    }
+    if let Some(c) = name.chars().find(|&c| c == '"' || c.is_control()) {
+        return Err(format!("invalid character {c:?} in field name `{name}`; {example}"));
+    }
+    Ok(FormField { name: name.to_owned(), value: value.to_owned() })
+}
+
+/// `data`, the chunk at `path`, as the `file` part of a form followed by `fields`, and the
+/// content type that says where the parts end.
+pub fn multipart(path: &Path, data: &[u8], fields: &[FormField]) -> (Vec<u8>, String) {
+    let (head, tail, content_type) = form_around(path, data, fields);
+    let mut body = head;
+    body.extend_from_slice(data);
+    body.extend_from_slice(&tail);
+    (body, content_type)
+}
+
+/// What goes before and after `data`, the chunk at `path`, in a form followed by `fields`,
+/// and the form's content type. `data` is only looked through for the boundary, so a chunk
+/// still being recorded can be left out.
+fn form_around(path: &Path, data: &[u8], fields: &[FormField]) -> (Vec<u8>, Vec<u8>, String) {
+    // A boundary must not turn up inside the parts; a fresh random one hardly ever does.
+    let boundary = loop {
+        let boundary = format!("audiotok-{:016x}", (jitter() * (1u64 << 53) as f64) as u64);
+        let inside = |bytes: &[u8]| bytes.windows(boundary.len()).any(|window| window == boundary.as_bytes());
+        if !inside(data) && !fields.iter().any(|field| inside(field.value.as_bytes())) {
+            break boundary;
+        }
+    };
+    let name = path.file_name().map_or(Cow::Borrowed("chunk.wav"), |name| name.to_string_lossy());
+    let content_type = Format::of(path).unwrap_or_default().content_type();
+    let mut head = Vec::new();
+    write!(
+        head,
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {content_type}\r\n\r\n",
+        name.replace(['"', '\r', '\n'], "_")
+    )
+    .ok();
+    let mut tail = Vec::with_capacity(256 * (fields.len() + 1));
+    for field in fields {
+        write!(tail, "\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}", field.name, field.value).ok();
+    }
+    write!(tail, "\r\n--{boundary}--\r\n").ok();
+    (head, tail, format!("multipart/form-data; boundary={boundary}"))
+}
+
+/// What a compressed upload is read from.
+enum Source<'a> {
+    File(File),
+    Memory(&'a [u8]),
+}
+
+/// A reader that keeps the error it failed with, to tell a chunk that could not be read from
+/// a request that failed.
+struct Watched<R> {
+    inner: R,
+    failure: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+impl<R: Read> Read for Watched<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).inspect_err(|err| {
+            *self.failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(std::io::Error::new(err.kind(), err.to_string()));
+        })
+    }
+}
+
+/// How long a streamed upload (`--stream-upload`) waits for the server to take the connection
+/// before the chunk is left to the buffered path.
+pub const STREAM_CONNECT_WAIT: Duration = Duration::from_secs(2);
+
+/// Where and how chunks are uploaded; shared by live recording and the file subcommands so
+/// they send identical requests.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    pub url: String,
+    pub headers: Vec<Header>,
+    /// Bound on each attempt, connecting included; `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// With `--compress-upload`.
+    pub compression: Option<Compression>,
+    /// With `--upload-mode multipart` or `openai`, the form each chunk goes in.
+    pub form: Option<Form>,
+    /// With `--api-key-env`, or `--openai-api-key` in the openai mode.
+    pub auth: Option<Auth>,
+    pub client: ClientOptions,
+}
+
+/// How the HTTP client connects to the server.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// With `--ca-cert`, a PEM file of certificates to trust besides the system's.
+    pub ca_cert: Option<PathBuf>,
+    /// With `--insecure`: any certificate goes, for any host.
+    pub insecure: bool,
+    /// Where [`crate::proxy::resolve`] sends the uploads; `None` is straight to the server,
+    /// whatever the environment says.
+    pub proxy: Option<Proxy>,
+    /// With `--unix-socket`, or a `unix://` URL, the socket the uploads go through instead.
+    #[cfg(unix)]
+    pub unix_socket: Option<PathBuf>,
+}
+
+impl ClientOptions {
+    /// TLS that trusts what the uploads trust, for the streams that do not go through the HTTP
+    /// client.
+    #[cfg(any(feature = "websocket", feature = "grpc"))]
+    pub fn tls(&self) -> Result<native_tls::TlsConnectorBuilder, anyhow::Error> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(path) = &self.ca_cert {
+            let pem = std::fs::read_to_string(path).with_context(|| format!("failed to read --ca-cert {}", path.display()))?;
+            const END: &str = "-----END CERTIFICATE-----";
+            let mut found = false;
+            for block in pem.split_inclusive(END).filter(|block| block.contains("-----BEGIN CERTIFICATE-----")) {
+                let cert = native_tls::Certificate::from_pem(block.as_bytes()).with_context(|| format!("--ca-cert {}", path.display()))?;
+                builder.add_root_certificate(cert);
+                found = true;
+            }
+            if !found {
+                anyhow::bail!("--ca-cert {} has no PEM certificate in it", path.display());
+            }
+        }
+        if self.insecure {
+            builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder)
+    }
+}
+
+/// Whether uploads still go gzip-compressed; shared by the clones of an [`Endpoint`], so the
+/// first refusal switches all of them back.
+#[derive(Clone, Debug, Default)]
+pub struct Compression(Arc<AtomicBool>);
+
+impl Compression {
+    fn active(&self) -> bool {
+        !self.0.load(Ordering::Relaxed)
+    }
+
+    /// Stops compressing, returning whether this is the first refusal.
+    fn refuse(&self) -> bool {
+        !self.0.swap(true, Ordering::Relaxed)
+    }
+}
+
+/// What the server answered an upload with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+    /// How long a 429 or 503 answer asks to wait before asking again, if it says.
+    pub retry_after: Option<Duration>,
+}
+
+/// Whether `status` turns an upload down for good: a 4xx other than 429, which asks to wait.
+/// Sending it again would only be answered the same way.
+pub fn rejected(status: u16) -> bool {
+    (400..500).contains(&status) && status != 429
+}
+
+/// The start of `body`, for a log line.
+pub fn excerpt(body: &[u8]) -> String {
+    const MAX: usize = 200;
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+    match text.char_indices().nth(MAX) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+impl Endpoint {
+    /// Plain uploads to `url`: the raw mode, with no headers, credential or timeout.
+    pub fn new(url: impl Into<String>) -> Endpoint {
+        Endpoint { url: url.into(), headers: Vec::new(), timeout: None, compression: None, form: None, auth: None, client: ClientOptions::default() }
+    }
+
+    /// POSTs the file at `path` as the raw request body and returns the response.
+    pub fn upload_file(&self, path: &Path) -> Result<Response, anyhow::Error> {
+        if let Some(compression) = self.compressing() {
+            let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+            if let Some(response) = self.post_compressed(path, Source::File(file), compression)? {
+                return Ok(response);
+            }
+        }
+        let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        self.post(path, data)
+    }
+
+    /// POSTs `data`, a chunk held in memory that would be at `path` on disk, as the raw request
+    /// body and returns the response.
+    pub fn upload_bytes(&self, path: &Path, data: &[u8]) -> Result<Response, anyhow::Error> {
+        if let Some(compression) = self.compressing() {
+            if let Some(response) = self.post_compressed(path, Source::Memory(data), compression)? {
+                return Ok(response);
+            }
+        }
+        self.post(path, data.to_vec())
+    }
+
+    /// The transcription in `response`: the JSON in it read as [`transcript`] has it, or the
+    /// whole body if it has no `text`. Only a success has one, lest an error page end up in
+    /// the transcript log, and a strict form's success only with a `text`; the error then
+    /// has an excerpt for the log.
+    pub fn transcript(&self, response: &Response) -> Result<Transcription, String> {
+        let strict = self.form.as_ref().is_some_and(|form| form.strict);
+        let success = (200..300).contains(&response.status);
+        match transcript::read(&response.body) {
+            _ if !strict && !success => Err(excerpt(&response.body)),
+            Answer::Transcription(transcription) if success => Ok(transcription),
+            Answer::Text | Answer::Malformed(_) if strict => Err(format!("not JSON: {}", excerpt(&response.body))),
+            _ if strict => Err(excerpt(&response.body)),
+            Answer::Malformed(err) => Ok(Transcription { malformed: Some(err), ..Transcription::plain(&response.body) }),
+            Answer::Other(json) => Ok(Transcription { json: Some(json), ..Transcription::plain(&response.body) }),
+            _ => Ok(Transcription::plain(&response.body)),
+        }
+    }
+
+    /// This endpoint for a chunk described by `metadata`: in the multipart mode each of its
+    /// fields is one more form field, otherwise one more header; an OpenAI-style API turns
+    /// down form fields it does not know. A `--header` or `--form` of the same name wins.
+    pub fn for_chunk(&self, metadata: &Metadata) -> Endpoint {
+        let mut endpoint = self.clone();
+        match endpoint.form.as_mut().filter(|form| !form.strict) {
+            Some(form) => {
+                for field in metadata.fields() {
+                    if !form.fields.iter().any(|set| set.name == field.field) {
+                        form.fields.push(FormField { name: field.field.to_owned(), value: field.value.clone() });
+                    }
+                }
+            }
+            None => {
+                for field in metadata.fields() {
+                    if !endpoint.headers.iter().any(|set| set.name.eq_ignore_ascii_case(field.header)) {
+                        endpoint.headers.push(Header { name: field.header.to_owned(), value: header_safe(&field.value) });
+                    }
+                }
+            }
+        }
+        endpoint
+    }
+
+    /// The language the server is told the chunks are in: the `language` form field, or in the
+    /// raw mode the `language` query parameter.
+    pub fn language(&self) -> Option<String> {
+        match &self.form {
+            Some(form) => form.fields.iter().find(|field| field.name == "language").map(|field| field.value.clone()),
+            None => {
+                let url = reqwest::Url::parse(&self.url).ok()?;
+                let language = url.query_pairs().find(|(name, _)| name == "language")?.1.into_owned();
+                Some(language)
+            }
+        }
+    }
+
+    /// Sets up the HTTP client now, so that a bad `--ca-cert` fails before anything is
+    /// recorded.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        client(&self.client, None).map(drop)
+    }
+
+    /// POSTs the chunk at `path` as it is recorded, read from `body` until the chunk ends, and
+    /// returns the response. The request is sent chunked, and never compressed; it has `longest`,
+    /// the most the chunk can last, on top of the timeout, and gives up if the server cannot be
+    /// reached within [`STREAM_CONNECT_WAIT`].
+    pub fn upload_stream(&self, path: &Path, body: impl Read + Send + 'static, longest: Duration) -> Result<Response, anyhow::Error> {
+        let (head, tail, content_type) = match &self.form {
+            Some(form) => form_around(path, &[], &form.fields),
+            None => (Vec::new(), Vec::new(), Format::of(path).unwrap_or_default().content_type().to_owned()),
+        };
+        let body = std::io::Cursor::new(head).chain(body).chain(std::io::Cursor::new(tail));
+        let timeout = self.timeout.map(|timeout| timeout + longest);
+        self.send(path, Body::new(body), &content_type, false, timeout, Some(STREAM_CONNECT_WAIT))
+    }
+
+    /// Sends `data`, the chunk at `path`, as it is.
+    fn post(&self, path: &Path, data: Vec<u8>) -> Result<Response, anyhow::Error> {
+        let (data, content_type) = match &self.form {
+            Some(form) => multipart(path, &data, &form.fields),
+            // Anything else is uploaded as the WAV it was checked to be.
+            None => (data, Format::of(path).unwrap_or_default().content_type().to_owned()),
+        };
+        self.send(path, data.into(), &content_type, false, self.timeout, None)
+    }
+
+    /// The compression uploads go with, unless the server has refused it.
+    fn compressing(&self) -> Option<&Compression> {
+        self.compression.as_ref().filter(|compression| compression.active())
+    }
+
+    /// Sends the chunk at `path`, from `source`, gzip-compressed. A file is compressed as it is
+    /// read, straight into the request; only over a Unix socket, which takes a whole body, is
+    /// what comes out gathered first. `None` if it is to go uncompressed instead: the server
+    /// turned it down, or the chunk could not be compressed.
+    fn post_compressed(&self, path: &Path, source: Source, compression: &Compression) -> Result<Option<Response>, anyhow::Error> {
+        let (head, tail, content_type) = match (&self.form, &source) {
+            (Some(form), Source::Memory(data)) => form_around(path, data, &form.fields),
+            // A file that is streamed is not searched for the boundary; a fresh random one
+            // turning up in it is as unlikely as it is in the fields.
+            (Some(form), Source::File(_)) => form_around(path, &[], &form.fields),
+            (None, _) => (Vec::new(), Vec::new(), Format::of(path).unwrap_or_default().content_type().to_owned()),
+        };
+        let failure = Arc::new(Mutex::new(None));
+        let read = |reader: &mut dyn Read| {
+            let mut compressed = Vec::new();
+            gzip::Gzip::new(reader).read_to_end(&mut compressed).map(|_| compressed)
+        };
+        let body = match source {
+            Source::Memory(data) => read(&mut (&head[..]).chain(data).chain(&tail[..])).map(Body::from),
+            Source::File(file) if self.whole_bodies() => read(&mut (&head[..]).chain(file).chain(&tail[..])).map(Body::from),
+            Source::File(file) => {
+                let file = Watched { inner: file, failure: failure.clone() };
+                let body = std::io::Cursor::new(head).chain(file).chain(std::io::Cursor::new(tail));
+                Ok(Body::new(gzip::Gzip::new(body)))
+            }
+        };
+        let failed = |err: &dyn std::fmt::Display| {
+            warn!(path = %path.display(), "failed to compress the upload; sending it uncompressed: {err}");
+            Ok(None)
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(err) => return failed(&err),
+        };
+        let response = match self.send(path, body, &content_type, true, self.timeout, None) {
+            Ok(response) => response,
+            Err(err) => match failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take() {
+                Some(err) => return failed(&err),
+                None => return Err(err),
+            },
+        };
+        if !matches!(response.status, 400 | 415) {
+            return Ok(Some(response));
+        }
+        if compression.refuse() {
+            warn!(status = response.status, "the server refused a gzip-compressed upload; sending uploads uncompressed from now on");
+        }
+        Ok(None)
+    }
+
+    /// Whether requests go where a body has to be had whole before it is sent.
+    fn whole_bodies(&self) -> bool {
+        #[cfg(unix)]
+        return self.client.unix_socket.is_some();
+        #[cfg(not(unix))]
+        false
+    }
+
+    /// POSTs `body`, the chunk at `path` as `content_type` and gzip-compressed if `gzip` is
+    /// set, whatever the status of the response. The attempt is bound by `timeout`, and by
+    /// `connect_timeout` while connecting.
+    fn send(
+        &self,
+        path: &Path,
+        body: Body,
+        content_type: &str,
+        gzip: bool,
+        timeout: Option<Duration>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Response, anyhow::Error> {
+        let mut request = self.request(&client(&self.client, connect_timeout)?, Method::POST, timeout, Some(content_type))?;
+        if gzip {
+            request = request.header(CONTENT_ENCODING, "gzip");
+        }
+        self.execute(request.body(body), &format!("upload of {}", path.display()))
+    }
+
+    /// Asks the server whether it is there, before anything is recorded: a HEAD request to the
+    /// URL, or with `silence`, a WAV of it, a POST of it as `healthcheck.wav` in the upload
+    /// mode's form. The client logs nothing of itself, so that the answer can come first.
+    pub fn probe(&self, silence: Option<&[u8]>, timeout: Option<Duration>) -> Result<Response, anyhow::Error> {
+        let client = set_up(&self.client, None)?;
+        let request = match silence {
+            Some(wav) => {
+                let path = Path::new("healthcheck.wav");
+                let (data, content_type) = match &self.form {
+                    Some(form) => multipart(path, wav, &form.fields),
+                    None => (wav.to_vec(), Format::Wav.content_type().to_owned()),
+                };
+                self.request(&client, Method::POST, timeout, Some(&content_type))?.body(data)
+            }
+            None => self.request(&client, Method::HEAD, timeout, None)?,
+        };
+        self.execute(request, "health check")
+    }
+
+    /// A `method` request to the URL through `client`, with the endpoint's headers and
+    /// credential, bound by `timeout`. A body is `content_type` unless `--header` sets the type.
+    fn request(&self, client: &Client, method: Method, timeout: Option<Duration>, content_type: Option<&str>) -> Result<RequestBuilder, anyhow::Error> {
+        let mut request = client.request(method, &self.url);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        for header in &self.headers {
+            request = request.header(&header.name, &header.value);
+        }
+        if let Some(auth) = &self.auth {
+            let mut value = HeaderValue::from_str(auth.value.expose()).context("the credential cannot go in a header")?;
+            value.set_sensitive(true);
+            request = request.header(&auth.header, value);
+        }
+        // Unless set by --header, the type goes by the chunk's format.
+        let typed = self.headers.iter().any(|header| header.name.eq_ignore_ascii_case("Content-Type"));
+        if let (false, Some(content_type)) = (typed, content_type) {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        Ok(request)
+    }
+
+    /// Sends `request`, the `what` of the errors, and returns the response whatever its status.
+    fn execute(&self, request: RequestBuilder, what: &str) -> Result<Response, anyhow::Error> {
+        #[cfg(unix)]
+        if let Some(socket) = &self.client.unix_socket {
+            let request = request.build().with_context(|| format!("{what} failed"))?;
+            return crate::socket::send(socket, request).with_context(|| format!("{what} failed"));
+        }
+        let started = Instant::now();
+        let response = request.send().and_then(|response| {
+            let status = response.status().as_u16();
+            let retry_after = response.headers().get(RETRY_AFTER).and_then(|value| retry_after(value.to_str().ok()?));
+            Ok(Response { status, body: response.bytes()?.to_vec(), retry_after })
+        });
+        match response {
+            Ok(response) => Ok(response),
+            Err(err) if err.is_timeout() => {
+                Err(anyhow::Error::new(err).context(format!("{what} timed out after {:.1}s", started.elapsed().as_secs_f64())))
+            }
+            Err(err) => {
+                let context = match tls_failure(&err) {
+                    Some(failure) => {
+                        let host = err.url().and_then(reqwest::Url::host_str).unwrap_or("the server").to_owned();
+                        format!("{what} failed: TLS handshake with {host} failed ({failure})")
+                    }
+                    None => format!("{what} failed"),
+                };
+                Err(anyhow::Error::new(err).context(context))
+            }
+        }
+    }
+}
+
+/// How long a `Retry-After` of `value` asks to wait: only a number of seconds, as a date
+/// falls back on the usual waits.
+pub(crate) fn retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Whether `err` is the server not being reached or not answering, rather than the upload
+/// going wrong on this side.
+fn unreachable(err: &anyhow::Error) -> bool {
+    #[cfg(unix)]
+    if err.downcast_ref::<crate::socket::SocketError>().is_some() {
+        return true;
+    }
+    err.downcast_ref::<reqwest::Error>().is_some()
+}
+
+/// What went wrong with the certificate, if `err` is a failed TLS handshake: the innermost
+/// cause, which OpenSSL ends with its verification error, e.g. `(certificate has expired)`.
+fn tls_failure(err: &reqwest::Error) -> Option<String> {
+    let mut causes = std::iter::successors(std::error::Error::source(err), |cause| cause.source()).map(ToString::to_string);
+    let tls = causes.find(|cause| ["certificate", "SSL", "TLS", "handshake"].iter().any(|word| cause.contains(word)))?;
+    // The rest of OpenSSL's message is where in its source it gave up.
+    let kind = tls.rsplit_once(" (").map_or(tls.as_str(), |(_, kind)| kind.trim_end_matches(')'));
+    let hint = if kind.contains("issuer") || kind.contains("self-signed") || kind.contains("self signed") {
+        "; if the server's certificate comes from a private CA, trust it with --ca-cert"
+    } else {
+        ""
+    };
+    Some(format!("{kind}{hint}"))
+}
+
+/// How often a wait between retries looks whether the program is shutting down.
+const RETRY_TICK: Duration = Duration::from_millis(100);
+
+/// How a chunk whose upload failed is tried again: `--retries`. Connection errors, timeouts,
+/// 5xx responses and 429 (too many requests) are retried, after the wait a `Retry-After`
+/// asks for if there is one, up to `max_delay`; any other 4xx response means the request
+/// itself is wrong, and sending it again would only be answered the same way. A shutdown
+/// cuts the waits short, so the retries left go at once within the shutdown grace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retries {
+    /// Attempts after the first.
+    pub retries: u32,
+    /// The wait before the first retry, doubled for each one after it.
+    pub first_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Retries {
+    /// The wait before retry `n`, counting from 0: the doubled delay, capped, then cut by up
+    /// to half by `jitter` (in 0..1) so that chunks which failed together do not all retry
+    /// together.
+    pub fn delay(&self, n: u32, jitter: f64) -> Duration {
+        let full = self.first_delay.saturating_mul(1 << n.min(20)).min(self.max_delay);
+        full.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+
+    /// Runs `upload`, the upload of chunk `seq`, until it succeeds, fails for good or is out
+    /// of retries. Returns how that went and the number of attempts it took; a 5xx or 429
+    /// response still standing at the end is a failure.
+    pub fn run(&self, seq: u64, mut upload: impl FnMut() -> Result<Response, anyhow::Error>) -> (Result<Response, anyhow::Error>, u32) {
+        let mut attempts = 0;
+        loop {
+            let result = upload();
+            attempts += 1;
+            let failure = match &result {
+                Ok(response) if response.status >= 500 || response.status == 429 => format!("the server answered {}", response.status),
+                Err(err) if unreachable(err) => format!("{err:#}"),
+                _ => return (result, attempts),
+            };
+            if attempts > self.retries {
+                let result = result.and_then(|_| Err(anyhow::anyhow!("upload failed: {failure}")));
+                return (result, attempts);
+            }
+            let asked = result.as_ref().ok().and_then(|response| response.retry_after);
+            let delay = asked.map_or_else(|| self.delay(attempts - 1, jitter()), |asked| asked.min(self.max_delay));
+            warn!(chunk = seq, attempt = attempts, retry_in_ms = delay.as_millis() as u64, "upload failed, retrying: {failure}");
+            let slept = Instant::now();
+            while !crate::shutdown::requested() {
+                let left = delay.saturating_sub(slept.elapsed());
+                if left.is_zero() {
+                    break;
+                }
+                std::thread::sleep(left.min(RETRY_TICK));
+            }
+        }
+    }
+}
+
+/// A number in 0..1, different each time; retries need no better randomness than this.
+pub(crate) fn jitter() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The client all uploads share, so that connections to the server are kept and reused.
+/// One is set up for each [`ClientOptions`] asked for, which is one a run, and each
+/// `connect_timeout`: streamed uploads have one client more. What it is set up with is
+/// logged once for the options.
+fn client(options: &ClientOptions, connect_timeout: Option<Duration>) -> Result<Client, anyhow::Error> {
+    let client = set_up(options, connect_timeout)?;
+    announce(options);
+    Ok(client)
+}
+
+/// As [`client`], logging nothing: the health check's answer comes before what the client
+/// is set up with.
+fn set_up(options: &ClientOptions, connect_timeout: Option<Duration>) -> Result<Client, anyhow::Error> {
+    static CLIENTS: Mutex<Vec<(ClientOptions, Option<Duration>, Client)>> = Mutex::new(Vec::new());
+    let mut clients = CLIENTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((_, _, client)) = clients.iter().find(|(set_up, wait, _)| set_up == options && *wait == connect_timeout) {
+        return Ok(client.clone());
+    }
+    // Each upload has its own timeout, if any.
+    let mut builder = Client::builder()
+        .timeout(None)
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")));
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if let Some(path) = &options.ca_cert {
+        let pem = std::fs::read(path).with_context(|| format!("failed to read --ca-cert {}", path.display()))?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem).with_context(|| format!("--ca-cert {}", path.display()))?;
+        if certs.is_empty() {
+            anyhow::bail!("--ca-cert {} has no PEM certificate in it", path.display());
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if options.insecure {
+        builder = builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+    }
+    builder = match &options.proxy {
+        Some(proxy) => {
+            let url = proxy.url.clone();
+            builder.proxy(reqwest::Proxy::all(url).map_err(|err| anyhow::anyhow!("proxy {}: {err}", proxy.redacted()))?)
+        }
+        None => builder.no_proxy(),
+    };
+    let client = builder.build().map_err(|err| anyhow::anyhow!("failed to set up the HTTP client: {err}"))?;
+    clients.push((options.clone(), connect_timeout, client.clone()));
+    Ok(client)
+}
+
+/// Logs what the client for `options` is set up with, the first time it is asked for.
+fn announce(options: &ClientOptions) {
+    static ANNOUNCED: Mutex<Vec<ClientOptions>> = Mutex::new(Vec::new());
+    let mut announced = ANNOUNCED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if announced.contains(options) {
+        return;
+    }
+    announced.push(options.clone());
+    if options.insecure {
+        warn!("--insecure: the server's certificate is not checked, so anyone on the way to it can read and change the uploads");
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &options.unix_socket {
+        info!("uploads go through the Unix socket {}", socket.display());
+        return;
+    }
+    match &options.proxy {
+        Some(proxy) => info!("uploads go through the proxy {proxy}"),
+        None => info!("uploads go straight to the server, through no proxy"),
+    }
+}
+
+/// The layout of `--format raw` audio, which has no header to carry it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawLayout {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// The headers that go with a raw upload in `layout`, for the server to read it by.
+pub fn raw_headers(layout: RawLayout) -> Vec<Header> {
+    let header = |name: &str, value: &str| Header { name: name.to_owned(), value: value.to_owned() };
+    vec![
+        header("X-Sample-Rate", &layout.sample_rate.to_string()),
+        channels_header(layout.channels),
+        header("X-Sample-Format", "s16le"),
+    ]
+}
+
+/// The header telling the server how many channels a live chunk has.
+pub fn channels_header(channels: u16) -> Header {
+    Header { name: "X-Channels".to_owned(), value: channels.to_string() }
+}
+
+/// The `upload` subcommand: sends existing WAV, FLAC or raw files (the latter in `raw`'s
+/// layout) through the same upload path as live chunks, logging each response under its file
+/// name. With `fail_fast`, the first file the server turns down for good ends it.
+pub fn upload_files(
+    endpoint: &Endpoint,
+    log: Option<&Path>,
+    inputs: &[String],
+    raw: Option<RawLayout>,
+    fail_fast: bool,
+) -> Result<(), anyhow::Error> {
+    let files = expand_inputs(inputs)?;
+    let raw_endpoint = raw.map(|layout| {
+        let mut endpoint = endpoint.clone();
+        endpoint.headers.extend(raw_headers(layout));
+        endpoint
+    });
+    let mut log = match log {
+        Some(path) => {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                prepare_output_dir(dir)?;
+            }
+            Some(open_log(path)?)
+        }
+        None => None,
+    };
+    let mut failed = 0;
+    for path in &files {
+        let endpoint = match Format::of(path) {
+            Some(Format::Raw) => raw_endpoint.as_ref().unwrap_or(endpoint),
+            _ => endpoint,
+        };
+        let result = validate_file(path, raw).and_then(|()| endpoint.upload_file(path));
+        match result {
+            Ok(response) if !(200..300).contains(&response.status) => {
+                failed += 1;
+                error!(status = response.status, body = excerpt(&response.body), "{}: the server did not take the upload", path.display());
+                if fail_fast && rejected(response.status) {
+                    anyhow::bail!("the server answered {} to {} (--fail-fast-on-4xx)", response.status, path.display());
+                }
+            }
+            Ok(response) => match endpoint.transcript(&response) {
+                Ok(transcription) => {
+                    if let Some(err) = &transcription.malformed {
+                        warn!(body = excerpt(&response.body), "{}: the answer is not valid JSON ({err}); taking it as text", path.display());
+                    }
+                    let text = transcription.text;
+                    println!("{text}");
+                    if let Some(log) = &mut log {
+                        match endpoint.language() {
+                            Some(language) => writeln!(log, "{}\t{language}\t{text}", path.display())?,
+                            None => writeln!(log, "{}\t{text}", path.display())?,
+                        }
+                    }
+                    info!(status = response.status, "{}: ok", path.display());
+                }
+                Err(body) => {
+                    failed += 1;
+                    error!(status = response.status, body, "{}: no transcript in the answer", path.display());
+                }
+            },
+            Err(err) => {
+                failed += 1;
+                error!("{}: {err:#}", path.display());
+            }
+        }
+    }
+    info!("{} of {} file(s) uploaded", files.len() - failed, files.len());
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} upload(s) failed", files.len());
+    }
+    Ok(())
+}
+
+/// Checks that `path` holds what its extension says before it is uploaded: a FLAC stream, an
+/// MP3 stream, raw PCM in whole frames of `raw`, which has to be given, or otherwise a WAV file.
+pub fn validate_file(path: &Path, raw: Option<RawLayout>) -> Result<(), anyhow::Error> {
+    match Format::of(path) {
+        Some(Format::Flac) => {
+            let mut magic = [0; 4];
+            File::open(path)
+                .and_then(|mut file| file.read_exact(&mut magic))
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if &magic != b"fLaC" {
+                anyhow::bail!("{} is not a FLAC file", path.display());
+            }
+            Ok(())
+        }
+        #[cfg(feature = "mp3")]
+        Some(Format::Mp3) => {
+            let mut magic = [0; 3];
+            File::open(path)
+                .and_then(|mut file| file.read_exact(&mut magic))
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            // A frame header's sync bits, or the ID3 tag other encoders put first.
+            if !(magic[0] == 0xFF && magic[1] & 0xE0 == 0xE0) && &magic != b"ID3" {
+                anyhow::bail!("{} is not an MP3 file", path.display());
+            }
+            Ok(())
+        }
+        Some(Format::Raw) => {
+            let Some(layout) = raw else {
+                anyhow::bail!(
+                    "{} is raw PCM, which says nothing of its layout; give its --raw-rate and --raw-channels",
+                    path.display()
+                );
+            };
+            let len = std::fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?.len();
+            let frame = 2 * u64::from(layout.channels);
+            if len % frame != 0 {
+                anyhow::bail!(
+                    "{} is {len} bytes, not whole frames of {} 16-bit channel(s)",
+                    path.display(),
+                    layout.channels
+                );
+            }
+            Ok(())
+        }
+        Some(Format::Wav) | None => validate_wav(path),
+    }
+}
+
+/// Checks that `path` is a readable RIFF/WAVE file before it is uploaded.
+pub fn validate_wav(path: &Path) -> Result<(), anyhow::Error> {
+    hound::WavReader::open(path)
+        .map(drop)
+        .with_context(|| format!("{} is not a readable WAV file", path.display()))
+}
+
+/// Expands the `upload` arguments: directories become the `.wav` files directly inside them,
+/// arguments whose file name contains `*` or `?` are matched against their directory, and
+/// anything else is taken as a path. Each group is sorted by name.
+pub fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        if path.is_dir() {
+            files.extend(list_dir(path, has_wav_extension)?);
+        } else if let Some(pattern) = name.filter(|n| n.contains(['*', '?'])) {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let matches = list_dir(dir, |name| glob_match(&pattern, name))?;
+            if matches.is_empty() {
+                anyhow::bail!("no files match {input}");
+            }
+            files.extend(matches);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+/// All `.wav` files under `dir`, descending into subdirectories when `recursive` is set.
+pub fn find_wavs(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = list_dir(dir, has_wav_extension)?;
+    if recursive {
+        let mut subdirs = Vec::new();
+        for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                subdirs.push(entry.path());
+            }
+        }
+        subdirs.sort();
+        for subdir in subdirs {
+            files.extend(find_wavs(&subdir, true)?);
+        }
+    }
+    Ok(files)
+}
+
+fn list_dir(dir: &Path, keep: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && keep(&entry.file_name().to_string_lossy()) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn has_wav_extension(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+}
+
+/// Shell-style matching of a single path component: `*` matches any run of characters and `?`
+/// exactly one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was and how much of `name` it has swallowed so far.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
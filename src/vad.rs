@@ -0,0 +1,264 @@
+//! `--vad`: cutting chunks at pauses in speech instead of every `--duration`.
+//!
+//! The writer thread hands the [`Segmenter`] the stream one short frame at a time, and the
+//! segmenter says whether that frame opens a chunk, belongs to the open one, closes it, or is
+//! silence between chunks that goes nowhere. A chunk opens on the first speech frame (after
+//! the pre-roll) and closes once the speech has been followed by `--vad-silence-ms` of
+//! silence. A burst of speech that pauses before `--min-chunk` is not closed on its own: the
+//! chunk waits, without recording the silence, and carries on with the next speech, so a
+//! lone cough never becomes a file. At `--max-chunk` a chunk is cut at the quietest frame of
+//! its last second, so the cut falls between words where it can; the writer holds that
+//! second back from the file until it knows where the cut goes.
+//!
+//! What counts as speech is up to a [`Vad`], so detectors can be swapped without touching
+//! the segmentation.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/// Default length of the frames speech is detected in.
+pub const FRAME_MS: u64 = 10;
+
+/// How far back from `--max-chunk` the segmenter looks for a quiet moment to cut at.
+pub const SPLIT_SEARCH_MS: u64 = 1000;
+
+/// A voice activity detector: classifies frames of interleaved f32 audio as speech or not.
+pub trait Vad: Send {
+    /// Length of the frames it classifies.
+    fn frame_ms(&self) -> u64 {
+        FRAME_MS
+    }
+
+    fn is_speech(&mut self, frame: &[f32]) -> bool;
+}
+
+/// `--vad energy`: classifies frames by their RMS level alone.
+pub struct EnergyVad {
+    /// Mean power per sample from which a frame counts as speech.
+    threshold: f64,
+}
+
+impl EnergyVad {
+    pub fn new(threshold_dbfs: f32) -> Self {
+        EnergyVad { threshold: 10f64.powf(f64::from(threshold_dbfs) / 10.0) }
+    }
+}
+
+impl Vad for EnergyVad {
+    fn is_speech(&mut self, frame: &[f32]) -> bool {
+        let energy: f64 = frame.iter().map(|&s| f64::from(s) * f64::from(s)).sum();
+        !frame.is_empty() && energy / frame.len() as f64 >= self.threshold
+    }
+}
+
+/// Chunk lengths for the [`Segmenter`], in frames at the chunks' sample rate. Lengths count
+/// every frame of the chunk, pre-roll included.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Silence after speech that ends a chunk.
+    pub silence_frames: u64,
+    /// Chunks shorter than this are not closed at a pause but joined to the next speech.
+    pub min_frames: u64,
+    /// Chunks are cut by this length.
+    pub max_frames: u64,
+}
+
+/// What the writer does with a frame. Ranges are where a chunk holds speech, in frames from
+/// its start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Silence outside any chunk, or in a paused one: kept for the pre-roll, not written.
+    Silence,
+    /// Speech begins: open the chunk with the pre-roll, report it through
+    /// [`Segmenter::replayed`], then write the frame.
+    Start,
+    /// Write the frame to the open chunk.
+    Continue,
+    /// Write the frame, then close the chunk.
+    End(Range<u64>),
+    /// Write the frame and keep the chunk open, writing nothing more until speech resumes.
+    Pause,
+    /// Speech after a pause in which `gap` frames went unwritten: write up to that much of the
+    /// pre-roll, report it through [`Segmenter::replayed`], then the frame.
+    Resume { gap: u64 },
+    /// The chunk reached its maximum in speech: write the frame, close the chunk before its
+    /// last `tail` frames, and open the next with the pre-roll before those, reported through
+    /// [`Segmenter::replayed`], followed by the tail.
+    Split { speech: Range<u64>, tail: u64 },
+}
+
+/// An analysed frame of the open chunk.
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    start: u64,
+    end: u64,
+    speech: bool,
+    /// Mean square of its samples.
+    energy: f64,
+}
+
+/// The open chunk as the segmenter sees it.
+#[derive(Default)]
+struct Segment {
+    /// Frames so far, pre-roll included.
+    length: u64,
+    /// From the first speech frame to the end of the last.
+    speech: Option<Range<u64>>,
+    /// The frames of the last [`SPLIT_SEARCH_MS`], where a cut may go.
+    recent: VecDeque<Frame>,
+    /// End of the last speech frame that has left `recent`.
+    settled_speech_end: Option<u64>,
+    /// Frames gone unwritten since a [`Decision::Pause`].
+    gap: Option<u64>,
+    /// Frames that follow the replay the writer is about to report, from offset 0.
+    following: Vec<Frame>,
+}
+
+impl Segment {
+    fn add(&mut self, frame: Frame, search_frames: u64) {
+        let frame = Frame { start: self.length, end: self.length + frame.end - frame.start, ..frame };
+        self.length = frame.end;
+        if frame.speech {
+            let start = self.speech.as_ref().map_or(frame.start, |speech| speech.start);
+            self.speech = Some(start..frame.end);
+        }
+        self.recent.push_back(frame);
+        while self.recent.front().is_some_and(|f| f.start + search_frames < self.length) {
+            if let Some(old) = self.recent.pop_front().filter(|f| f.speech) {
+                self.settled_speech_end = Some(old.end);
+            }
+        }
+    }
+
+    /// Where to cut at the maximum length: the start of the quietest recent frame after the
+    /// speech began, the latest of equals. `None` if there is none.
+    fn quietest(&self) -> Option<usize> {
+        let speech_start = self.speech.as_ref()?.start;
+        let mut best: Option<(usize, f64)> = None;
+        for (i, frame) in self.recent.iter().enumerate().filter(|(_, f)| f.start > speech_start) {
+            if best.is_none_or(|(_, energy)| frame.energy <= energy) {
+                best = Some((i, frame.energy));
+            }
+        }
+        best.map(|(i, _)| i)
+    }
+}
+
+pub struct Segmenter {
+    vad: Box<dyn Vad>,
+    channels: usize,
+    frame_frames: usize,
+    search_frames: u64,
+    limits: Limits,
+    segment: Option<Segment>,
+}
+
+impl Segmenter {
+    /// A segmenter for a stream of `channels` at `sample_rate`.
+    pub fn new(vad: Box<dyn Vad>, channels: u16, sample_rate: u32, limits: Limits) -> Self {
+        let frame_frames = (u64::from(sample_rate) * vad.frame_ms() / 1000).max(1) as usize;
+        Segmenter {
+            vad,
+            channels: usize::from(channels.max(1)),
+            frame_frames,
+            search_frames: u64::from(sample_rate) * SPLIT_SEARCH_MS / 1000,
+            limits,
+            segment: None,
+        }
+    }
+
+    /// Samples to pass to each [`Self::push`], interleaved.
+    pub fn frame_len(&self) -> usize {
+        self.frame_frames * self.channels
+    }
+
+    /// Frames at the end of the open chunk a [`Decision::Split`] may move to the next one.
+    pub fn lookback_frames(&self) -> u64 {
+        self.search_frames + self.frame_frames as u64
+    }
+
+    /// Decides what happens to the next frame.
+    pub fn push(&mut self, frame: &[f32]) -> Decision {
+        let frames = (frame.len() / self.channels) as u64;
+        let energy = frame.iter().map(|&s| f64::from(s) * f64::from(s)).sum::<f64>() / frame.len().max(1) as f64;
+        let this = Frame { start: 0, end: frames, speech: self.vad.is_speech(frame), energy };
+        let Some(segment) = &mut self.segment else {
+            if !this.speech {
+                return Decision::Silence;
+            }
+            self.segment = Some(Segment { following: vec![this], ..Segment::default() });
+            return Decision::Start;
+        };
+        if let Some(gap) = segment.gap {
+            if !this.speech {
+                segment.gap = Some(gap + frames);
+                return Decision::Silence;
+            }
+            segment.gap = None;
+            segment.following = vec![this];
+            return Decision::Resume { gap };
+        }
+        segment.add(this, self.search_frames);
+        let speech = segment.speech.clone().unwrap_or(0..0);
+        let silence = segment.length - speech.end;
+        if segment.length >= self.limits.max_frames {
+            if !this.speech {
+                self.segment = None;
+                return Decision::End(speech);
+            }
+            return self.split();
+        }
+        if silence >= self.limits.silence_frames {
+            if segment.length >= self.limits.min_frames {
+                self.segment = None;
+                return Decision::End(speech);
+            }
+            segment.gap = Some(0);
+            return Decision::Pause;
+        }
+        Decision::Continue
+    }
+
+    /// Cuts the open chunk at its quietest recent frame, carrying what follows into a new one.
+    fn split(&mut self) -> Decision {
+        let Some(segment) = self.segment.take() else {
+            return Decision::Continue;
+        };
+        let speech = segment.speech.clone().unwrap_or(0..0);
+        let Some(cut) = segment.quietest() else {
+            self.segment = Some(Segment::default());
+            return Decision::Split { speech, tail: 0 };
+        };
+        let at = segment.recent[cut].start;
+        let end = segment.recent.range(..cut).rev().find(|f| f.speech).map(|f| f.end).or(segment.settled_speech_end);
+        let following = segment.recent.range(cut..).map(|f| Frame { start: f.start - at, end: f.end - at, ..*f }).collect();
+        self.segment = Some(Segment { following, ..Segment::default() });
+        Decision::Split { speech: speech.start..end.unwrap_or(at), tail: segment.length - at }
+    }
+
+    /// Accounts for the `frames` of pre-roll the writer replayed into the chunk after a
+    /// [`Decision::Start`], [`Decision::Resume`] or [`Decision::Split`], and for what follows it.
+    pub fn replayed(&mut self, frames: u64) {
+        let search_frames = self.search_frames;
+        if let Some(segment) = &mut self.segment {
+            if frames > 0 {
+                // Only a replay into a pause can come after the speech began, and so be cut at.
+                segment.add(Frame { start: 0, end: frames, speech: false, energy: 0.0 }, search_frames);
+            }
+            for frame in std::mem::take(&mut segment.following) {
+                segment.add(frame, search_frames);
+            }
+        }
+    }
+
+    /// Forgets the open chunk, as when the stream breaks off.
+    pub fn reset(&mut self) {
+        self.segment = None;
+    }
+
+    /// Where the open chunk holds speech, if a chunk is open and worth keeping: one paused
+    /// before its minimum length, or holding no speech, is not.
+    pub fn speech(&self) -> Option<Range<u64>> {
+        self.segment.as_ref().filter(|segment| segment.gap.is_none()).and_then(|segment| segment.speech.clone())
+    }
+}
@@ -0,0 +1,342 @@
+//! `--vad webrtc`: the Gaussian mixture detector of WebRTC's VAD, in floating point.
+//!
+//! Each frame is mixed to mono, taken to 8 kHz and measured in six bands (80-250, 250-500,
+//! 500-1000, 1000-2000, 2000-3000 and 3000-4000 Hz). Two Gaussian mixtures, one for noise and
+//! one for speech, each start from libwebrtc's trained means, deviations and weights, and
+//! their likelihood ratio in each band, and over all of them, decides against thresholds set
+//! by `--vad-aggressiveness`. Both models keep adapting to the frames they win, the noise one
+//! also anchored to the quietest level each band has had lately, and a few frames of
+//! hangover follow speech. The model, its updates and its thresholds are libwebrtc's;
+//! the band levels come from a DFT of the frame rather than its fixed-point filter bank,
+//! so decisions are close to libwebrtc's but not bit for bit the same.
+
+use crate::resample::Resampler;
+use crate::vad::Vad;
+use std::collections::VecDeque;
+
+/// Length of the frames classified, the longest libwebrtc takes.
+pub const FRAME_MS: u64 = 30;
+
+/// The rate the bands are measured at.
+const RATE: u32 = 8000;
+
+/// Samples per frame at [`RATE`].
+const FRAME: usize = (RATE as u64 * FRAME_MS / 1000) as usize;
+
+const BANDS: usize = 6;
+const GAUSSIANS: usize = 2;
+
+/// Band edges in Hz, and how many times libwebrtc's filter bank decimates each band.
+const BAND_EDGES: [(f32, f32); BANDS] = [(80.0, 250.0), (250.0, 500.0), (500.0, 1000.0), (1000.0, 2000.0), (2000.0, 3000.0), (3000.0, 4001.0)];
+const DECIMATION: [f32; BANDS] = [16.0, 16.0, 8.0, 4.0, 4.0, 4.0];
+
+/// Added to each band's level in dB.
+const OFFSET_DB: [f32; BANDS] = [23.0, 23.0, 17.0, 11.0, 11.0, 11.0];
+
+/// Frame energy (of 16-bit samples) at or below which a frame is silence and the models are
+/// left alone.
+const MIN_ENERGY: f32 = 10.0;
+
+/// Weight of each band in the overall likelihood ratio.
+const SPECTRUM_WEIGHT: [f32; BANDS] = [6.0, 8.0, 10.0, 12.0, 14.0, 16.0];
+
+/// libwebrtc's starting models, by Gaussian then band: mixture weights, and means and
+/// standard deviations in dB.
+const NOISE_WEIGHTS: [f32; GAUSSIANS * BANDS] = q7([34, 62, 72, 66, 53, 25, 94, 66, 56, 62, 75, 103]);
+const SPEECH_WEIGHTS: [f32; GAUSSIANS * BANDS] = q7([48, 82, 45, 87, 50, 47, 80, 46, 83, 41, 78, 81]);
+const NOISE_MEANS: [f32; GAUSSIANS * BANDS] = q7([6738, 4892, 7065, 6715, 6771, 3369, 7646, 3863, 7820, 7266, 5020, 4362]);
+const SPEECH_MEANS: [f32; GAUSSIANS * BANDS] = q7([8306, 10085, 10078, 11823, 11843, 6309, 9473, 9571, 10879, 7581, 8180, 7483]);
+const NOISE_STDS: [f32; GAUSSIANS * BANDS] = q7([378, 1064, 493, 582, 688, 593, 474, 697, 475, 688, 421, 455]);
+const SPEECH_STDS: [f32; GAUSSIANS * BANDS] = q7([555, 505, 567, 524, 585, 1231, 509, 828, 492, 1540, 1079, 850]);
+
+/// Step sizes of the model updates.
+const NOISE_UPDATE: f32 = 0.02;
+const SPEECH_UPDATE: f32 = 0.2;
+const NOISE_STD_UPDATE: f32 = 0.001;
+const SPEECH_STD_UPDATE: f32 = 0.025;
+
+/// How far each frame pulls the noise means toward the quietest recent level.
+const BACK_ETA: f32 = 154.0 / 256.0;
+
+/// Limits on the models, in dB.
+const MIN_STD: f32 = 3.0;
+const MINIMUM_DIFFERENCE: [f32; BANDS] = [17.0, 17.0, 18.0, 18.0, 18.0, 18.0];
+const MAXIMUM_SPEECH: [f32; BANDS] = [89.0, 89.0, 90.0, 90.0, 90.0, 90.0];
+const MINIMUM_MEAN: [f32; GAUSSIANS] = [5.0, 6.0];
+const MAXIMUM_NOISE: [f32; BANDS] = [72.0, 71.0, 70.0, 69.0, 68.0, 67.0];
+
+/// Smallest likelihood told apart from zero, as in libwebrtc's Q27 arithmetic.
+const MIN_LIKELIHOOD: f32 = 1.0 / (1u32 << 27) as f32;
+
+/// Frames of speech after which the longer hangover applies.
+const MAX_SPEECH_FRAMES: u32 = 6;
+
+/// Recent lowest levels each band keeps, and for how many frames.
+const MINIMA: usize = 16;
+const MINIMUM_AGE: u32 = 100;
+
+const fn q7<const N: usize>(values: [i32; N]) -> [f32; N] {
+    let mut out = [0.0; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = values[i] as f32 / 128.0;
+        i += 1;
+    }
+    out
+}
+
+/// libwebrtc's thresholds for 30 ms frames at each aggressiveness: the two hangovers in
+/// frames, then the likelihood ratio one band or all of them must pass.
+const MODES: [(u32, u32, f32, f32); 4] = [(3, 5, 24.0, 57.0), (3, 5, 37.0, 100.0), (2, 3, 82.0, 285.0), (2, 3, 94.0, 1100.0)];
+
+pub struct WebrtcVad {
+    channels: usize,
+    resampler: Option<Resampler>,
+    /// The last frame at [`RATE`], scaled to 16-bit sample values.
+    history: VecDeque<f32>,
+    /// `cos` and `sin` of `2πk/FRAME`, and the band each frequency bin belongs to.
+    basis: Vec<(f32, f32)>,
+    bands: Vec<Option<usize>>,
+    mode: (u32, u32, f32, f32),
+    noise_means: [f32; GAUSSIANS * BANDS],
+    speech_means: [f32; GAUSSIANS * BANDS],
+    noise_stds: [f32; GAUSSIANS * BANDS],
+    speech_stds: [f32; GAUSSIANS * BANDS],
+    /// Per band, the lowest recent levels in rising order with their ages, and their
+    /// smoothed median.
+    minima: [[(f32, u32); MINIMA]; BANDS],
+    floor: [f32; BANDS],
+    /// Frames the models have been updated on.
+    frames: u64,
+    speech_frames: u32,
+    hangover: u32,
+}
+
+impl WebrtcVad {
+    /// A detector for `channels` at `sample_rate`, at an aggressiveness from 0 to 3.
+    pub fn new(channels: u16, sample_rate: u32, aggressiveness: u8) -> Self {
+        let resampler = (sample_rate != RATE).then(|| Resampler::new(1, sample_rate, RATE));
+        let bands = (0..=FRAME / 2)
+            .map(|k| {
+                let freq = k as f32 * RATE as f32 / FRAME as f32;
+                BAND_EDGES.iter().position(|&(low, high)| freq >= low && freq < high)
+            })
+            .collect();
+        let basis = (0..FRAME)
+            .map(|i| {
+                let angle = 2.0 * std::f64::consts::PI * i as f64 / FRAME as f64;
+                (angle.cos() as f32, angle.sin() as f32)
+            })
+            .collect();
+        WebrtcVad {
+            channels: usize::from(channels.max(1)),
+            resampler,
+            history: std::iter::repeat_n(0.0, FRAME).collect(),
+            basis,
+            bands,
+            mode: MODES[usize::from(aggressiveness.min(3))],
+            noise_means: NOISE_MEANS,
+            speech_means: SPEECH_MEANS,
+            noise_stds: NOISE_STDS,
+            speech_stds: SPEECH_STDS,
+            minima: [[(625.0, 0); MINIMA]; BANDS],
+            floor: [100.0; BANDS],
+            frames: 0,
+            speech_frames: 0,
+            hangover: 0,
+        }
+    }
+
+    /// The level of each band of the last frame in dB, and the frame's energy.
+    fn features(&self) -> ([f32; BANDS], f32) {
+        let mut energy = [0.0f32; BANDS];
+        for (k, band) in self.bands.iter().enumerate() {
+            let Some(band) = *band else { continue };
+            let (mut re, mut im) = (0.0, 0.0);
+            for (n, &sample) in self.history.iter().enumerate() {
+                let (cos, sin) = self.basis[k * n % FRAME];
+                re += sample * cos;
+                im -= sample * sin;
+            }
+            // Parseval's theorem over both halves of the spectrum, but Nyquist once.
+            let sides = if k == FRAME / 2 { 1.0 } else { 2.0 };
+            energy[band] += sides * (re * re + im * im) / FRAME as f32;
+        }
+        let mut features = [0.0; BANDS];
+        let mut total = 0.0;
+        for band in 0..BANDS {
+            // The energy of the band as libwebrtc's filter bank leaves it, decimated.
+            let decimated = energy[band] / DECIMATION[band];
+            total += decimated;
+            let level = if decimated > 0.0 { (10.0 * decimated.log10()).max(0.0) } else { 0.0 };
+            features[band] = level + OFFSET_DB[band];
+        }
+        (features, total)
+    }
+
+    /// Keeps the lowest recent levels of `band`, returning their smoothed median.
+    fn minimum(&mut self, band: usize, level: f32) -> f32 {
+        let minima = &mut self.minima[band];
+        for i in 0..MINIMA {
+            if minima[i].1 == MINIMUM_AGE {
+                minima.copy_within(i + 1.., i);
+                minima[MINIMA - 1] = (625.0, MINIMUM_AGE + 1);
+            } else {
+                minima[i].1 += 1;
+            }
+        }
+        if let Some(position) = minima.iter().position(|&(value, _)| level < value) {
+            minima.copy_within(position..MINIMA - 1, position + 1);
+            minima[position] = (level, 1);
+        }
+        let median = match self.frames {
+            0 => self.floor[band],
+            1 | 2 => minima[0].0,
+            _ => minima[2].0,
+        };
+        if self.frames > 0 {
+            // Falls fast and rises slowly.
+            let alpha = if median < self.floor[band] { 0.2 } else { 0.99 };
+            self.floor[band] = alpha * self.floor[band] + (1.0 - alpha) * median;
+        } else {
+            self.floor[band] = median;
+        }
+        self.floor[band]
+    }
+
+    /// Whether the last frame holds speech, before the hangover, updating the models.
+    fn classify(&mut self, features: [f32; BANDS]) -> bool {
+        let (_, _, local, global) = self.mode;
+        let mut speech = false;
+        let mut sum = 0.0;
+        let mut noise_share = [0.0; GAUSSIANS * BANDS];
+        let mut speech_share = [0.0; GAUSSIANS * BANDS];
+        let mut noise_delta = [0.0; GAUSSIANS * BANDS];
+        let mut speech_delta = [0.0; GAUSSIANS * BANDS];
+        for band in 0..BANDS {
+            let (mut noise, mut voice) = ([0.0; GAUSSIANS], [0.0; GAUSSIANS]);
+            for k in 0..GAUSSIANS {
+                let g = band + k * BANDS;
+                let (p, delta) = gaussian(features[band], self.noise_means[g], self.noise_stds[g]);
+                (noise[k], noise_delta[g]) = (NOISE_WEIGHTS[g] * p, delta);
+                let (p, delta) = gaussian(features[band], self.speech_means[g], self.speech_stds[g]);
+                (voice[k], speech_delta[g]) = (SPEECH_WEIGHTS[g] * p, delta);
+            }
+            let (h0, h1): (f32, f32) = (noise.iter().sum(), voice.iter().sum());
+            let ratio = (h1.max(MIN_LIKELIHOOD) / h0.max(MIN_LIKELIHOOD)).log2();
+            sum += ratio * SPECTRUM_WEIGHT[band];
+            speech |= ratio * 4.0 > local;
+            // Which Gaussian of each model the frame most likely came from.
+            if h0 >= 1.0 / 32768.0 {
+                noise_share[band] = noise[0] / h0;
+                noise_share[band + BANDS] = 1.0 - noise_share[band];
+            } else {
+                noise_share[band] = 1.0;
+            }
+            if h1 >= 1.0 / 32768.0 {
+                speech_share[band] = voice[0] / h1;
+                speech_share[band + BANDS] = 1.0 - speech_share[band];
+            }
+        }
+        speech |= sum >= global;
+
+        let mut max_speech = 100.0;
+        for band in 0..BANDS {
+            let floor = self.minimum(band, features[band]);
+            let noise_mean = weighted(&self.noise_means, &NOISE_WEIGHTS, band);
+            for (k, &lowest) in MINIMUM_MEAN.iter().enumerate() {
+                let g = band + k * BANDS;
+                let (nm, sm) = (self.noise_means[g], self.speech_means[g]);
+                let mut mean = nm;
+                if !speech {
+                    mean += NOISE_UPDATE * noise_share[g] * noise_delta[g];
+                }
+                // Long-term correction toward the quietest recent level.
+                mean += BACK_ETA * (floor - noise_mean);
+                self.noise_means[g] = mean.clamp(k as f32 + 5.0, 72.0 + k as f32 - band as f32);
+                if speech {
+                    let mean = sm + SPEECH_UPDATE * speech_share[g] * speech_delta[g];
+                    self.speech_means[g] = mean.clamp(lowest, max_speech + 5.0);
+                    let z = speech_delta[g] * (features[band] - sm) - 1.0;
+                    let std = self.speech_stds[g];
+                    self.speech_stds[g] = (std + SPEECH_STD_UPDATE * speech_share[g] * z / std).max(MIN_STD);
+                } else {
+                    let z = noise_delta[g] * (features[band] - nm) - 1.0;
+                    let std = self.noise_stds[g];
+                    self.noise_stds[g] = (std + NOISE_STD_UPDATE * noise_share[g] * z / std).max(MIN_STD);
+                }
+            }
+            // Keep the models apart, and within bounds.
+            let noise_mean = weighted(&self.noise_means, &NOISE_WEIGHTS, band);
+            let speech_mean = weighted(&self.speech_means, &SPEECH_WEIGHTS, band);
+            let gap = MINIMUM_DIFFERENCE[band] - (speech_mean - noise_mean);
+            if gap > 0.0 {
+                for k in 0..GAUSSIANS {
+                    self.speech_means[band + k * BANDS] += 0.8 * gap;
+                    self.noise_means[band + k * BANDS] -= 0.2 * gap;
+                }
+            }
+            max_speech = MAXIMUM_SPEECH[band];
+            let speech_excess = weighted(&self.speech_means, &SPEECH_WEIGHTS, band) - max_speech;
+            let noise_excess = weighted(&self.noise_means, &NOISE_WEIGHTS, band) - MAXIMUM_NOISE[band];
+            for k in 0..GAUSSIANS {
+                if speech_excess > 0.0 {
+                    self.speech_means[band + k * BANDS] -= speech_excess;
+                }
+                if noise_excess > 0.0 {
+                    self.noise_means[band + k * BANDS] -= noise_excess;
+                }
+            }
+        }
+        self.frames += 1;
+        speech
+    }
+}
+
+impl Vad for WebrtcVad {
+    fn frame_ms(&self) -> u64 {
+        FRAME_MS
+    }
+
+    fn is_speech(&mut self, frame: &[f32]) -> bool {
+        let mono: Vec<f32> =
+            frame.chunks_exact(self.channels).map(|f| f.iter().sum::<f32>() / self.channels as f32 * 32768.0).collect();
+        let mut low = Vec::new();
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(&mono, &mut low),
+            None => low = mono,
+        }
+        self.history.extend(low);
+        while self.history.len() > FRAME {
+            self.history.pop_front();
+        }
+        let (features, energy) = self.features();
+        let speech = energy > MIN_ENERGY && self.classify(features);
+        let (short, long, _, _) = self.mode;
+        if speech {
+            self.speech_frames = (self.speech_frames + 1).min(MAX_SPEECH_FRAMES + 1);
+            self.hangover = if self.speech_frames > MAX_SPEECH_FRAMES { long } else { short };
+            true
+        } else {
+            self.speech_frames = 0;
+            let hanging = self.hangover > 0;
+            self.hangover = self.hangover.saturating_sub(1);
+            hanging
+        }
+    }
+}
+
+/// The likelihood of `x` under a normal distribution, without the `1/√(2π)` all of them
+/// share, and `(x - mean) / std²`, which the updates step along.
+fn gaussian(x: f32, mean: f32, std: f32) -> (f32, f32) {
+    let delta = (x - mean) / (std * std);
+    let exponent = delta * (x - mean) / 2.0;
+    // libwebrtc rounds anything this unlikely down to zero.
+    let p = if exponent < 22005.0 / 1024.0 { (-exponent).exp() / std } else { 0.0 };
+    (p, delta)
+}
+
+/// The mean of `band`'s Gaussians under their mixture weights.
+fn weighted(means: &[f32; GAUSSIANS * BANDS], weights: &[f32; GAUSSIANS * BANDS], band: usize) -> f32 {
+    (0..GAUSSIANS).map(|k| means[band + k * BANDS] * weights[band + k * BANDS]).sum()
+}
@@ -0,0 +1,661 @@
+//! `--ws-url`: streaming the recording to a transcription server over one WebSocket, for the
+//! servers that take a stream and answer as they go rather than take chunks.
+//!
+//! The writer thread hands every frame to a [`WsStream`] through [`crate::pipe::RawStream`],
+//! as it does to stdout with `--stdout-raw`, so the samples are laid out the same way: s16le
+//! unless `--bit-depth` says otherwise. They go to the server as binary messages of
+//! `--ws-frame-ms` each, from a thread of the socket's own, so the writer thread never waits on
+//! the network. The upgrade request has the uploads' headers and credential, and gives the
+//! layout in `X-Sample-Rate`, `X-Channels` and `X-Sample-Format`, as a raw upload does.
+//!
+//! The server's text messages are transcripts, read as the answer to an upload is. One that
+//! says it is partial (`"partial": true`, `"final": false`, `"is_final": false` or `"type":
+//! "partial"`) is only logged, at debug level, as later ones take it back; any other is
+//! printed and written to the transcript log. Messages that are JSON without a `text`, such as
+//! a server's greeting, are logged at debug level too.
+//!
+//! A lost socket is connected again at once, and then with the backoff failed uploads have,
+//! for as long as the recording goes on. Once it is back, the last `--preroll-ms` of audio is
+//! sent again before the stream goes on, so that a word cut off by the loss is heard whole;
+//! what was recorded before that, while the socket was down, is lost, and how much is logged.
+//! At the end the socket is closed, and the server has up to [`CLOSE_WAIT`] to send what it
+//! has yet to and close its end. The socket goes straight to the server: proxies are not gone
+//! through. A server that cannot be reached, or that turns the upgrade down, fails the
+//! recording up front, as a FIFO without a reader does.
+//!
+//! As little of RFC 6455 as a client needs is done here rather than by a crate: the upgrade,
+//! with the SHA-1 of its accept key, masked frames out, and whole or fragmented messages in,
+//! with pings answered. There are no extensions, so no compression of the messages.
+
+use crate::json::Value;
+use crate::pipe::layout_name;
+use crate::transcript::{self, Answer};
+use crate::upload::{base64, channels_header, excerpt, jitter, Auth, ClientOptions, Header, Retries};
+use anyhow::Context;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// The most connecting, the TLS handshake and the upgrade may take, and a write may wait.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the server has, once the recording is over, to send its last transcripts and close.
+pub const CLOSE_WAIT: Duration = Duration::from_secs(2);
+
+/// How long a read waits for the server before the socket's thread looks for audio again.
+const POLL: Duration = Duration::from_millis(10);
+
+/// The largest message taken from the server; a longer one is as good as a lost socket.
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// What RFC 6455 has the server hash the key with.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// What the socket is opened with.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// A ws:// or wss:// URL.
+    pub url: reqwest::Url,
+    /// Sent with the upgrade request, as with each upload.
+    pub headers: Vec<Header>,
+    pub auth: Option<Auth>,
+    /// The `--ca-cert` and `--insecure` of a wss:// URL; its proxy is not used.
+    pub client: ClientOptions,
+    /// How much audio goes in each message.
+    pub frame: Duration,
+    /// How much audio is sent again once a lost socket is back.
+    pub replay: Duration,
+    /// The waits between reconnects; the socket is reconnected for as long as it takes, so its
+    /// count of retries is not looked at.
+    pub retries: Retries,
+    /// Where final transcripts are appended.
+    pub log: Option<Arc<Mutex<File>>>,
+    /// The language each transcript in the log is marked with, as with uploads.
+    pub language: Option<String>,
+}
+
+/// The writing end of the socket. What is written is sent a message at a time by the socket's
+/// thread; dropping it sends what is left, closes the socket and waits for the thread.
+pub struct WsStream {
+    frames: Option<Sender<Vec<u8>>>,
+    /// The message being filled.
+    frame: Vec<u8>,
+    frame_bytes: usize,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl WsStream {
+    /// Connects to `options.url` for samples laid out as `spec`.
+    pub fn connect(options: Options, spec: hound::WavSpec) -> Result<WsStream, anyhow::Error> {
+        let block_align = usize::from(spec.channels) * usize::from(spec.bits_per_sample / 8);
+        let frames_of = |duration: Duration| (duration.as_secs_f64() * f64::from(spec.sample_rate)).round() as usize;
+        let frame_bytes = frames_of(options.frame).max(1) * block_align;
+        let tls = tls(&options).with_context(|| format!("cannot set up TLS for {}", options.url))?;
+        let layout = layout(spec);
+        let socket = Socket::connect(&options, tls.as_ref(), &layout).with_context(|| format!("cannot open a WebSocket to {}", options.url))?;
+        info!("Streaming to: {}", options.url);
+        let worker = Worker {
+            replay_bytes: frames_of(options.replay) * block_align,
+            bytes_per_second: f64::from(spec.sample_rate) * block_align as f64,
+            options,
+            tls,
+            layout,
+            socket: Some(socket),
+            recent: VecDeque::new(),
+            block_align,
+            frame_bytes,
+            missed: 0,
+            failures: 0,
+            next_try: Instant::now(),
+        };
+        let (frames, received) = mpsc::channel();
+        let worker = std::thread::Builder::new().name(String::from("websocket")).spawn(move || worker.run(received))?;
+        Ok(WsStream { frames: Some(frames), frame: Vec::with_capacity(frame_bytes), frame_bytes, worker: Some(worker) })
+    }
+
+    /// Hands the message filled so far to the socket's thread.
+    fn send(&mut self) -> std::io::Result<()> {
+        let frame = std::mem::replace(&mut self.frame, Vec::with_capacity(self.frame_bytes));
+        match &self.frames {
+            Some(frames) if frames.send(frame).is_ok() => Ok(()),
+            _ => Err(std::io::Error::other("the WebSocket's thread has ended")),
+        }
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let take = (self.frame_bytes - self.frame.len()).min(rest.len());
+            self.frame.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.frame.len() == self.frame_bytes {
+                self.send()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// A message goes once it is whole, not at each flush.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for WsStream {
+    fn drop(&mut self) {
+        if !self.frame.is_empty() {
+            self.send().ok();
+        }
+        // Which tells the thread to close the socket.
+        self.frames = None;
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+    }
+}
+
+/// The socket's thread: sends the audio, reads the transcripts and reconnects.
+struct Worker {
+    options: Options,
+    tls: Option<native_tls::TlsConnector>,
+    layout: Vec<Header>,
+    /// `None` while the socket is lost.
+    socket: Option<Socket>,
+    /// The last `replay_bytes` of audio, whole frames of it, to send again after a reconnect.
+    recent: VecDeque<u8>,
+    replay_bytes: usize,
+    block_align: usize,
+    bytes_per_second: f64,
+    frame_bytes: usize,
+    /// Bytes of audio come since the socket was lost.
+    missed: u64,
+    /// Reconnects failed in a row, and when to try the next.
+    failures: u32,
+    next_try: Instant,
+}
+
+impl Worker {
+    fn run(mut self, frames: Receiver<Vec<u8>>) {
+        loop {
+            match frames.recv_timeout(POLL) {
+                Ok(frame) => self.send(frame),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.receive();
+            if self.socket.is_none() && Instant::now() >= self.next_try {
+                self.reconnect();
+            }
+        }
+        self.close();
+    }
+
+    /// Sends `frame`, or counts it as missed while the socket is lost, and keeps it for a replay.
+    fn send(&mut self, frame: Vec<u8>) {
+        self.recent.extend(&frame);
+        if self.recent.len() > self.replay_bytes {
+            let excess = (self.recent.len() - self.replay_bytes).div_ceil(self.block_align) * self.block_align;
+            self.recent.drain(..excess.min(self.recent.len()));
+        }
+        match &mut self.socket {
+            Some(socket) => {
+                if let Err(err) = socket.send(BINARY, &frame) {
+                    self.lost(&format!("{err}"));
+                }
+            }
+            None => self.missed += frame.len() as u64,
+        }
+    }
+
+    /// Takes in what the server has sent until now.
+    fn receive(&mut self) {
+        while let Some(socket) = &mut self.socket {
+            match socket.receive() {
+                Ok(Some(Message::Text(text))) => self.transcript(&text),
+                Ok(Some(Message::Binary(len))) => debug!(bytes = len, "ignoring a binary message from the server"),
+                Ok(Some(Message::Close(why))) => {
+                    socket.send(CLOSE, &[]).ok();
+                    self.lost(&format!("the server closed it ({why})"));
+                }
+                Ok(None) => break,
+                Err(err) => self.lost(&format!("{err:#}")),
+            }
+        }
+    }
+
+    fn lost(&mut self, why: &str) {
+        warn!(url = %self.options.url, "the WebSocket was lost, reconnecting: {why}");
+        self.socket = None;
+        self.missed = 0;
+        self.failures = 0;
+        self.next_try = Instant::now();
+    }
+
+    /// Connects again and sends the audio kept for it; or, failing that, sets when to try next.
+    fn reconnect(&mut self) {
+        let mut socket = match Socket::connect(&self.options, self.tls.as_ref(), &self.layout) {
+            Ok(socket) => socket,
+            Err(err) => {
+                let delay = self.options.retries.delay(self.failures, jitter());
+                self.failures += 1;
+                let retry_in_ms = delay.as_millis() as u64;
+                warn!(url = %self.options.url, attempt = self.failures, retry_in_ms, "failed to reconnect the WebSocket: {err:#}");
+                self.next_try = Instant::now() + delay;
+                return;
+            }
+        };
+        for frame in self.recent.make_contiguous().chunks(self.frame_bytes) {
+            if let Err(err) = socket.send(BINARY, frame) {
+                return self.lost(&format!("{err}"));
+            }
+        }
+        let replayed = self.recent.len() as f64 / self.bytes_per_second;
+        let lost = self.missed.saturating_sub(self.recent.len() as u64) as f64 / self.bytes_per_second;
+        info!(url = %self.options.url, replayed_secs = format!("{replayed:.2}"), lost_secs = format!("{lost:.2}"), "the WebSocket is back");
+        self.socket = Some(socket);
+    }
+
+    /// Closes the socket, taking in the transcripts the server sends before it closes its end.
+    fn close(mut self) {
+        let Some(mut socket) = self.socket.take() else {
+            return;
+        };
+        if let Err(err) = socket.send(CLOSE, &1000u16.to_be_bytes()) {
+            debug!("the WebSocket could not be closed: {err}");
+            return;
+        }
+        let deadline = Instant::now() + CLOSE_WAIT;
+        while Instant::now() < deadline {
+            match socket.receive() {
+                Ok(Some(Message::Text(text))) => self.transcript(&text),
+                Ok(Some(Message::Close(_))) => return,
+                Ok(_) => {}
+                Err(err) => {
+                    debug!("the WebSocket closed without a close message: {err:#}");
+                    return;
+                }
+            }
+        }
+        warn!(url = %self.options.url, "the server did not close the WebSocket within {CLOSE_WAIT:?}; its last transcripts may be missing");
+    }
+
+    /// Prints the transcript in `message` and appends it to the log, unless it is partial.
+    fn transcript(&self, message: &[u8]) {
+        let text = match transcript::read(message) {
+            Answer::Transcription(transcription) if transcription.json.as_ref().is_some_and(partial) => {
+                debug!(text = transcription.text, "partial transcript");
+                return;
+            }
+            Answer::Transcription(transcription) => transcription.text,
+            Answer::Text => String::from_utf8_lossy(message).trim().to_owned(),
+            Answer::Other(value) => {
+                debug!(message = %value, "a message from the server without a transcript");
+                return;
+            }
+            Answer::Malformed(err) => {
+                warn!(body = excerpt(message), "the server's message is not valid JSON ({err}); taking it as text");
+                String::from_utf8_lossy(message).trim().to_owned()
+            }
+        };
+        if !text.is_empty() {
+            transcript::deliver(&text, self.options.log.as_deref(), self.options.language.as_deref());
+        }
+    }
+}
+
+/// Whether a transcript says it is partial, to be followed by a final one.
+fn partial(value: &Value) -> bool {
+    let flag = |key: &str| match value.get(key) {
+        Some(&Value::Bool(flag)) => Some(flag),
+        _ => None,
+    };
+    flag("partial") == Some(true)
+        || flag("final") == Some(false)
+        || flag("is_final") == Some(false)
+        || value.get("type").and_then(Value::as_str) == Some("partial")
+}
+
+/// The headers that tell the server how the samples of `spec` are laid out.
+fn layout(spec: hound::WavSpec) -> Vec<Header> {
+    let header = |name: &str, value: &str| Header { name: name.to_owned(), value: value.to_owned() };
+    vec![header("X-Sample-Rate", &spec.sample_rate.to_string()), channels_header(spec.channels), header("X-Sample-Format", layout_name(spec))]
+}
+
+/// The TLS a wss:// URL is connected with, trusting what uploads trust.
+fn tls(options: &Options) -> Result<Option<native_tls::TlsConnector>, anyhow::Error> {
+    if options.url.scheme() != "wss" {
+        return Ok(None);
+    }
+    Ok(Some(options.client.tls()?.build()?))
+}
+
+/// What the server sent, bar pings and pongs.
+enum Message {
+    Text(Vec<u8>),
+    /// Of this many bytes, which mean nothing here.
+    Binary(usize),
+    /// With the code and reason the server gave, if any.
+    Close(String),
+}
+
+/// The connection, with or without TLS.
+enum Link {
+    Plain(TcpStream),
+    Tls(Box<native_tls::TlsStream<TcpStream>>),
+}
+
+impl Link {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Link::Plain(tcp) => tcp,
+            Link::Tls(tls) => tls.get_ref(),
+        }
+    }
+}
+
+impl Read for Link {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Link::Plain(tcp) => tcp.read(buf),
+            Link::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Link {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Link::Plain(tcp) => tcp.write(buf),
+            Link::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Link::Plain(tcp) => tcp.flush(),
+            Link::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// An open WebSocket.
+struct Socket {
+    link: Link,
+    /// Read, and not yet taken as frames.
+    received: Vec<u8>,
+    /// The opcode and payload so far of a message that comes in fragments.
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl Socket {
+    /// Connects to the server, with `tls` for a wss:// URL, and upgrades the connection.
+    fn connect(options: &Options, tls: Option<&native_tls::TlsConnector>, layout: &[Header]) -> Result<Socket, anyhow::Error> {
+        let url = &options.url;
+        let host = url.host_str().unwrap_or_default();
+        let addrs = url.socket_addrs(|| None).with_context(|| format!("cannot resolve {host}"))?;
+        let mut failure = None;
+        let tcp = addrs.iter().find_map(|addr| TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).map_err(|err| failure = Some(err)).ok());
+        let tcp = match (tcp, failure) {
+            (Some(tcp), _) => tcp,
+            (None, Some(err)) => return Err(err).with_context(|| format!("cannot connect to {host}")),
+            (None, None) => anyhow::bail!("{host} has no address"),
+        };
+        tcp.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        tcp.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        tcp.set_nodelay(true)?;
+        let link = match tls {
+            Some(tls) => {
+                let domain = host.trim_start_matches('[').trim_end_matches(']');
+                Link::Tls(Box::new(tls.connect(domain, tcp).map_err(|err| anyhow::anyhow!("the TLS handshake failed: {err}"))?))
+            }
+            None => Link::Plain(tcp),
+        };
+        let mut socket = Socket { link, received: Vec::new(), fragments: None };
+        socket.upgrade(options, layout)?;
+        socket.link.tcp().set_read_timeout(Some(POLL))?;
+        Ok(socket)
+    }
+
+    /// Asks the server to take the connection as a WebSocket, and checks that it does.
+    fn upgrade(&mut self, options: &Options, layout: &[Header]) -> Result<(), anyhow::Error> {
+        let url = &options.url;
+        let target = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        };
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let key = base64(&[random().to_le_bytes(), random().to_le_bytes()].concat());
+        let mut request = format!("GET {target} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n");
+        write!(request, "Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n").ok();
+        write!(request, "User-Agent: {}/{}\r\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).ok();
+        for header in options.headers.iter().chain(layout) {
+            write!(request, "{}: {}\r\n", header.name, header.value).ok();
+        }
+        if let Some(auth) = &options.auth {
+            write!(request, "{}: {}\r\n", auth.header, auth.value.expose()).ok();
+        }
+        request.push_str("\r\n");
+        self.link.write_all(request.as_bytes())?;
+        self.link.flush()?;
+
+        let end = loop {
+            if let Some(at) = self.received.windows(4).position(|window| window == b"\r\n\r\n") {
+                break at + 4;
+            }
+            if self.received.len() > 16 << 10 {
+                anyhow::bail!("the server's answer to the upgrade has no end to its head");
+            }
+            let mut buf = [0; 4096];
+            match self.link.read(&mut buf)? {
+                0 => anyhow::bail!("the server closed the connection instead of answering the upgrade"),
+                n => self.received.extend_from_slice(&buf[..n]),
+            }
+        };
+        let head = String::from_utf8_lossy(&self.received[..end]).into_owned();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            let body = excerpt(&self.received[end..]);
+            anyhow::bail!("the server answered `{status}` rather than switching to a WebSocket{}", if body.is_empty() { String::new() } else { format!(": {body}") });
+        }
+        let answer = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("Sec-WebSocket-Accept").then(|| value.trim())
+        });
+        if answer != Some(accept(&key).as_str()) {
+            anyhow::bail!("the server's Sec-WebSocket-Accept does not answer the key; is it a WebSocket server?");
+        }
+        // Anything after the head is the first of the messages.
+        self.received.drain(..end);
+        Ok(())
+    }
+
+    /// Sends a frame of `opcode`, the whole of `payload`, masked as a client's must be.
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mask = (random() as u32).to_ne_bytes();
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask));
+        self.link.write_all(&frame)?;
+        self.link.flush()
+    }
+
+    /// The next message from the server if a whole one has come, answering pings on the way;
+    /// `None` if there is none within [`POLL`].
+    fn receive(&mut self) -> Result<Option<Message>, anyhow::Error> {
+        loop {
+            while let Some((fin, opcode, payload)) = self.frame()? {
+                let (opcode, payload) = match opcode {
+                    PING => {
+                        self.send(PONG, &payload)?;
+                        continue;
+                    }
+                    PONG => continue,
+                    CLOSE => {
+                        let why = match payload.split_at_checked(2) {
+                            Some((code, reason)) => format!("{} {}", u16::from_be_bytes([code[0], code[1]]), String::from_utf8_lossy(reason)),
+                            None => String::from("no code"),
+                        };
+                        return Ok(Some(Message::Close(why.trim().to_owned())));
+                    }
+                    CONTINUATION => {
+                        let (opcode, mut message) = self.fragments.take().context("the server continued a message it never began")?;
+                        message.extend_from_slice(&payload);
+                        if message.len() > MAX_MESSAGE {
+                            anyhow::bail!("the server sent a message of more than {MAX_MESSAGE} bytes");
+                        }
+                        (opcode, message)
+                    }
+                    TEXT | BINARY if self.fragments.is_some() => anyhow::bail!("the server began a message before it ended the last"),
+                    TEXT | BINARY => (opcode, payload),
+                    opcode => anyhow::bail!("the server sent a frame of unknown opcode {opcode:#x}"),
+                };
+                if !fin {
+                    self.fragments = Some((opcode, payload));
+                    continue;
+                }
+                return Ok(Some(match opcode {
+                    TEXT => Message::Text(payload),
+                    _ => Message::Binary(payload.len()),
+                }));
+            }
+            let mut buf = [0; 16 << 10];
+            match self.link.read(&mut buf) {
+                Ok(0) => anyhow::bail!("the server closed the connection"),
+                Ok(n) => self.received.extend_from_slice(&buf[..n]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// The next whole frame in what was read, as whether it ends its message, its opcode and
+    /// its payload.
+    fn frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, anyhow::Error> {
+        let buf = &self.received;
+        let [first, second, ..] = buf[..] else {
+            return Ok(None);
+        };
+        let (len, mut at) = match second & 0x7F {
+            126 => match buf.get(2..4) {
+                Some(len) => (u64::from(u16::from_be_bytes([len[0], len[1]])), 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into().expect("eight bytes")), 10),
+                None => return Ok(None),
+            },
+            len => (u64::from(len), 2),
+        };
+        if len > MAX_MESSAGE as u64 {
+            anyhow::bail!("the server sent a frame of {len} bytes, more than the {MAX_MESSAGE} taken");
+        }
+        // A server should not mask its frames, but one that does can still be read.
+        let mask = match second & 0x80 {
+            0 => None,
+            _ => {
+                let mask = buf.get(at..at + 4).map(|mask| [mask[0], mask[1], mask[2], mask[3]]);
+                at += 4;
+                match mask {
+                    Some(mask) => Some(mask),
+                    None => return Ok(None),
+                }
+            }
+        };
+        let end = at + len as usize;
+        let Some(payload) = buf.get(at..end) else {
+            return Ok(None);
+        };
+        let payload = match mask {
+            Some(mask) => payload.iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask).collect(),
+            None => payload.to_vec(),
+        };
+        self.received.drain(..end);
+        Ok(Some((first & 0x80 != 0, first & 0x0F, payload)))
+    }
+}
+
+/// The `Sec-WebSocket-Accept` a server answers `key` with.
+pub fn accept(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// The SHA-1 digest of `data`. The upgrade still hashes its key with it; nothing else should.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    message.resize(message.len().div_ceil(64) * 64, 0);
+    if message.len() - data.len() < 9 {
+        message.resize(message.len() + 64, 0);
+    }
+    let bits = (data.len() as u64).wrapping_mul(8).to_be_bytes();
+    let len = message.len();
+    message[len - 8..].copy_from_slice(&bits);
+    for block in message.chunks_exact(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let next = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, next);
+        }
+        for (state, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(add);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// 64 random bits, for the key and the masks.
+fn random() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new().build_hasher().finish()
+}